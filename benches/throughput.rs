@@ -0,0 +1,229 @@
+//! Throughput of netpipe end to end over loopback: lines read from stdin, datagrams received
+//! by a UDP source, and messages broadcast to WebSocket clients. Each benchmark runs the
+//! netpipe binary built along with it, so the numbers take in everything a deployment goes
+//! through, from parsing to writing.
+//!
+//! `cargo bench` runs them all, and `cargo bench -- udp` the ones whose name matches.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream, UdpSocket},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tungstenite::Message;
+
+/// A message of a typical size for a sensor reading or log line.
+const MESSAGE: &str = r#"{"sensor":"boiler-2","celsius":71.25,"ok":true}"#;
+/// Lines written to stdin per run.
+const LINES: u64 = 200_000;
+/// Messages sent per iteration of the benchmarks that keep netpipe running.
+const BATCH: u64 = 10_000;
+/// Datagrams sent ahead of those received at most, so that none overflow the socket's buffer.
+const WINDOW: u64 = 128;
+/// Receiving stopping for this long means messages were lost.
+const STALL: Duration = Duration::from_secs(5);
+
+/// A netpipe process, killed once the benchmark is done with it.
+struct Netpipe(Child);
+
+impl Netpipe {
+    fn spawn(args: &[&str], stdin: Stdio, stdout: Stdio) -> Netpipe {
+        let child = Command::new(env!("CARGO_BIN_EXE_netpipe"))
+            .args(args)
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start netpipe");
+        Netpipe(child)
+    }
+
+    fn stdin(&mut self) -> ChildStdin {
+        self.0.stdin.take().unwrap()
+    }
+
+    /// Counts the lines netpipe writes to stdout.
+    fn count_lines(&mut self) -> Arc<AtomicU64> {
+        let count = Arc::new(AtomicU64::new(0));
+        let stdout = BufReader::new(self.0.stdout.take().unwrap());
+        let counted = count.clone();
+        thread::spawn(move || {
+            for _ in stdout.split(b'\n') {
+                counted.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        count
+    }
+}
+
+impl Drop for Netpipe {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A port free on loopback, for netpipe to listen on.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap()
+        .port()
+}
+
+/// Waits for `counts` to add up to `total`, panicking if they stop short of it.
+fn wait_for(counts: &[Arc<AtomicU64>], total: u64) {
+    let sum = || {
+        counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum::<u64>()
+    };
+    let (mut last, mut since) = (sum(), Instant::now());
+    while last < total {
+        thread::yield_now();
+        let now = sum();
+        if now != last {
+            (last, since) = (now, Instant::now());
+        } else if since.elapsed() > STALL {
+            panic!("received {last} of {total} messages");
+        }
+    }
+}
+
+/// Lines piped through stdin to stdout, read a line at a time and, with `--raw-stdin`, in
+/// batches. On a single core, the first came to about 1.04M lines/s and `--raw-stdin` to
+/// 0.88M to 0.99M, so batching doesn't pay for itself without a core to spare for reading.
+fn stdin(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stdin");
+    group.throughput(Throughput::Elements(LINES));
+    group.sample_size(10);
+    let input = format!("{MESSAGE}\n").repeat(LINES as usize);
+    let cases: [(&str, &[&str]); 2] = [
+        ("lines", &["stdin", "stdout"]),
+        ("raw_lines", &["--raw-stdin", "stdin", "stdout"]),
+    ];
+    for (name, args) in cases {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut netpipe = Netpipe::spawn(args, Stdio::piped(), Stdio::null());
+                let mut stdin = netpipe.stdin();
+                stdin.write_all(input.as_bytes()).unwrap();
+                drop(stdin);
+                assert!(netpipe.0.wait().unwrap().success());
+            })
+        });
+    }
+    group.finish();
+}
+
+fn udp(c: &mut Criterion) {
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .and_then(|socket| socket.local_addr())
+        .unwrap()
+        .port();
+    let source = format!("127.0.0.1:{port}");
+    let mut netpipe = Netpipe::spawn(&[&source, "stdout"], Stdio::null(), Stdio::piped());
+    let count = netpipe.count_lines();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(&source).unwrap();
+    // Datagrams sent before netpipe is listening are lost, or refused, so the first to get
+    // through shows that it is.
+    while count.load(Ordering::Relaxed) == 0 {
+        let _ = socket.send(MESSAGE.as_bytes());
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100));
+    let mut sent = count.load(Ordering::Relaxed);
+
+    let mut group = c.benchmark_group("udp");
+    group.throughput(Throughput::Elements(BATCH));
+    group.bench_function("receive", |b| {
+        b.iter(|| {
+            let total = sent + BATCH;
+            while sent < total {
+                if sent - count.load(Ordering::Relaxed) < WINDOW {
+                    socket.send(MESSAGE.as_bytes()).unwrap();
+                    sent += 1;
+                } else {
+                    thread::yield_now();
+                }
+            }
+            wait_for(std::slice::from_ref(&count), total);
+        })
+    });
+    group.finish();
+}
+
+fn websocket(c: &mut Criterion) {
+    let mut group = c.benchmark_group("websocket");
+    group.sample_size(20);
+    for clients in [1, 10, 100] {
+        let addr = format!("127.0.0.1:{}", free_port());
+        let url = format!("ws://{addr}/");
+        let mut netpipe = Netpipe::spawn(&["stdin", &url], Stdio::piped(), Stdio::null());
+        let mut stdin = netpipe.stdin();
+        let counts: Vec<Arc<AtomicU64>> = (0..clients)
+            .map(|_| {
+                let count = Arc::new(AtomicU64::new(0));
+                let mut socket = loop {
+                    match TcpStream::connect(&addr) {
+                        Ok(stream) => break tungstenite::client(url.as_str(), stream).unwrap().0,
+                        Err(_) => thread::sleep(Duration::from_millis(10)),
+                    }
+                };
+                let counted = count.clone();
+                thread::spawn(move || {
+                    while let Ok(message) = socket.read_message() {
+                        if let Message::Text(_) | Message::Binary(_) = message {
+                            counted.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+                count
+            })
+            .collect();
+        // Clients count from the first message they all got, as those that connected first
+        // may have gotten some before the others joined.
+        while counts
+            .iter()
+            .any(|count| count.load(Ordering::Relaxed) == 0)
+        {
+            writeln!(stdin, "{MESSAGE}").unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(100));
+        for count in &counts {
+            count.store(0, Ordering::Relaxed);
+        }
+
+        // As many messages for each client as make a batch between them.
+        let lines = BATCH / clients;
+        let batch = format!("{MESSAGE}\n").repeat(lines as usize);
+        let mut expected = 0;
+        group.throughput(Throughput::Elements(lines * clients));
+        group.bench_with_input(
+            BenchmarkId::new("broadcast", clients),
+            &clients,
+            |b, &clients| {
+                b.iter(|| {
+                    stdin.write_all(batch.as_bytes()).unwrap();
+                    stdin.flush().unwrap();
+                    expected += lines * clients;
+                    wait_for(&counts, expected);
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, stdin, udp, websocket);
+criterion_main!(benches);
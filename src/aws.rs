@@ -0,0 +1,241 @@
+//! Signing requests to AWS services with Signature Version 4, and finding the credentials to
+//! sign them with, for `kinesis://` destinations.
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, env, fs, path::PathBuf};
+use url::Url;
+
+/// An access key, along with the session token that temporary ones come with.
+pub struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    /// Finds credentials the way the AWS CLI and SDKs do, short of those that take a request
+    /// of their own, such as from instance metadata or SSO: in `AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`, or else in the profile that
+    /// `AWS_PROFILE` names, `default` if none, of the shared credentials file,
+    /// `~/.aws/credentials` unless `AWS_SHARED_CREDENTIALS_FILE` says otherwise.
+    pub fn load() -> Result<Credentials, String> {
+        if let (Ok(access_key_id), Ok(secret_access_key)) = (
+            env::var("AWS_ACCESS_KEY_ID"),
+            env::var("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            return Ok(Credentials {
+                access_key_id,
+                secret_access_key,
+                session_token: env::var("AWS_SESSION_TOKEN").ok(),
+            });
+        }
+        let path = match env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => home()
+                .ok_or("no AWS credentials in the environment, and no home directory")?
+                .join(".aws")
+                .join("credentials"),
+        };
+        let profile = env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            format!(
+                "no AWS credentials in the environment, and {} can't be read: {e}",
+                path.display()
+            )
+        })?;
+        let mut settings = profile_settings(&contents, &profile);
+        let mut setting = |key| {
+            settings
+                .remove(key)
+                .ok_or_else(|| format!("no {key} for profile {profile} in {}", path.display()))
+        };
+        Ok(Credentials {
+            access_key_id: setting("aws_access_key_id")?,
+            secret_access_key: setting("aws_secret_access_key")?,
+            session_token: setting("aws_session_token").ok(),
+        })
+    }
+}
+
+fn home() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
+
+/// The `key = value` settings of the `[profile]` section of an INI file.
+fn profile_settings(contents: &str, profile: &str) -> HashMap<String, String> {
+    let mut settings = HashMap::new();
+    let mut within = false;
+    for line in contents.lines().map(str::trim) {
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            within = section.trim() == profile;
+        } else if let Some((key, value)) = line.split_once('=').filter(|_| within) {
+            settings.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    settings
+}
+
+/// The region in `AWS_REGION` or `AWS_DEFAULT_REGION`, for destinations that don't give one.
+pub fn region() -> Option<String> {
+    env::var("AWS_REGION")
+        .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .ok()
+        .filter(|region| !region.is_empty())
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// The path of a request as it is signed: its segments URI-encoded once more on top of the
+/// encoding they have in a URL, as services other than S3 expect.
+fn canonical_uri(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Signs a POST of `body` to `endpoint`, along with `headers`, whose names are lowercase.
+/// Returns the headers to send on top of those: the date, the session token if any, and the
+/// authorization.
+pub fn sign(
+    credentials: &Credentials,
+    service: &str,
+    region: &str,
+    endpoint: &Url,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Vec<(&'static str, String)> {
+    // The `Host` header as reqwest sends it, with the port only if the URL gives one.
+    let host = match endpoint.port() {
+        Some(port) => format!("{}:{port}", endpoint.host_str().unwrap_or_default()),
+        None => endpoint.host_str().unwrap_or_default().to_string(),
+    };
+    let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = &timestamp[..8];
+    let mut added = vec![("x-amz-date", timestamp.clone())];
+    if let Some(token) = &credentials.session_token {
+        added.push(("x-amz-security-token", token.clone()));
+    }
+    let mut signed: Vec<(&str, &str)> = headers.to_vec();
+    signed.push(("host", &host));
+    signed.extend(added.iter().map(|(name, value)| (*name, value.as_str())));
+    signed.sort();
+    let names = signed
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = signed
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect();
+    let canonical_request = format!(
+        "POST\n{}\n\n{canonical_headers}\n{names}\n{}",
+        canonical_uri(endpoint.path()),
+        hex(&Sha256::digest(body))
+    );
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = [date, region, service, "aws4_request"].into_iter().fold(
+        format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+        |key, part| hmac(&key, part),
+    );
+    let signature = hex(&hmac(&key, &string_to_sign));
+    added.push((
+        "authorization",
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={names}, Signature={signature}",
+            credentials.access_key_id
+        ),
+    ));
+    added
+}
+
+#[cfg(test)]
+impl Credentials {
+    pub fn new(access_key_id: &str, secret_access_key: &str) -> Credentials {
+        Credentials {
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            session_token: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// The `post-vanilla` case of AWS's Signature Version 4 test suite.
+    #[test]
+    fn requests_are_signed_as_in_the_aws_test_suite() {
+        let credentials =
+            Credentials::new("AKIDEXAMPLE", "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY");
+        let endpoint = Url::parse("https://example.amazonaws.com/").unwrap();
+        let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+        let signed = sign(
+            &credentials,
+            "service",
+            "us-east-1",
+            &endpoint,
+            &[],
+            b"",
+            now,
+        );
+        assert_eq!(
+            signed,
+            [
+                ("x-amz-date", "20150830T123600Z".to_string()),
+                (
+                    "authorization",
+                    "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+                     SignedHeaders=host;x-amz-date, \
+                     Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_path_is_signed_encoded_once_more() {
+        let endpoint = Url::parse("http://localhost:4566/kinesis/a b~").unwrap();
+        assert_eq!(canonical_uri(endpoint.path()), "/kinesis/a%2520b~");
+        assert_eq!(canonical_uri("/"), "/");
+    }
+
+    #[test]
+    fn settings_come_from_the_profile_asked_for() {
+        let contents = "[default]\naws_access_key_id = A\n\n[ other ]\naws_access_key_id=B\n";
+        assert_eq!(
+            profile_settings(contents, "other")["aws_access_key_id"],
+            "B"
+        );
+        assert_eq!(
+            profile_settings(contents, "default")["aws_access_key_id"],
+            "A"
+        );
+        assert!(profile_settings(contents, "missing").is_empty());
+    }
+}
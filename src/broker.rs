@@ -1,195 +1,1839 @@
+use crate::color::Colorizer;
+use crate::error::{NetpipeError, Result};
+use crate::http;
+use crate::net::{self, ListenOptions, Listener, Stream};
+use crate::options::{parse_duration, parse_size};
+use crate::payload::Payload;
+use crate::route::Route;
+use crate::stats::{DropCounters, Reason};
+use crate::{strict, threads};
 use regex::Regex;
-use std::cell::RefCell;
-use std::io::ErrorKind::{ConnectionAborted, ConnectionReset, WouldBlock};
-use std::net::{TcpStream, ToSocketAddrs};
+use serde_json::Value;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind::{self, ConnectionAborted, ConnectionReset, WouldBlock};
+use std::io::{self, stderr, stdout, IsTerminal, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
-    net::{TcpListener, UdpSocket},
-    sync::{Arc, Mutex},
-    thread,
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
 };
 use tungstenite::error::Error::{Io, Protocol};
-use tungstenite::{accept, Message, WebSocket};
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::protocol::frame::coding::{Data, OpCode};
+use tungstenite::protocol::frame::Frame;
+use tungstenite::{accept_hdr, Message, WebSocket};
+use url::Url;
 
-pub trait Broker {
-    fn matches(&self, option: &String) -> bool;
-    fn add_destination(&self, option: &String);
-    fn send(&self, message: &String);
+#[cfg(feature = "amqp")]
+mod amqp;
+mod csv;
+mod exec;
+#[cfg(unix)]
+mod fd;
+mod file;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(feature = "kinesis")]
+mod kinesis;
+#[cfg(target_os = "linux")]
+mod mq;
+#[cfg(windows)]
+mod pipe;
+mod prometheus;
+#[cfg(feature = "tls")]
+mod spool;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod tcp;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unixgram;
+mod wsclient;
+#[cfg(feature = "amqp")]
+pub use amqp::{amqp_option, AmqpBroker};
+pub use exec::ExecBroker;
+#[cfg(unix)]
+pub use fd::FdBroker;
+pub use file::FileBroker;
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcBroker;
+#[cfg(feature = "kafka")]
+pub use kafka::{kafka_option, KafkaBroker};
+#[cfg(feature = "kinesis")]
+pub use kinesis::KinesisBroker;
+#[cfg(target_os = "linux")]
+pub use mq::MessageQueueBroker;
+#[cfg(windows)]
+pub use pipe::PipeBroker;
+pub use prometheus::PrometheusBroker;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteBroker;
+pub use tcp::{TcpBroker, TcpRawBroker};
+#[cfg(feature = "tls")]
+pub use tls::TlsBroker;
+#[cfg(unix)]
+pub use unixgram::UnixDatagramBroker;
+pub use wsclient::WebSocketClientBroker;
+
+pub trait Broker: Send {
+    fn matches(&self, option: &str) -> bool;
+    /// Binds or connects to the destination right away, so that a destination that can't be
+    /// set up fails at startup rather than with the first message. See [`Lazy`] for those
+    /// given `?lazy=true`.
+    fn add_destination(&self, option: &str) -> Result<()>;
+    /// Fails only when none of the broker's destinations could take the message.
+    fn send(&self, message: &Payload) -> Result<()>;
+    /// Winds the destinations up after the last message, as netpipe exits, so that they see
+    /// netpipe leave rather than a lost connection: writes what is still pending and closes
+    /// connections the way their protocol has them closed.
+    fn shutdown(&self) {}
+}
+
+/// Combines the results of sending a message to each of a broker's destinations, failing
+/// only if every one of them failed, or under `--strict` if any did.
+pub fn unless_all_failed(results: Vec<Result<()>>) -> Result<()> {
+    if strict::enabled() {
+        return results.into_iter().find(Result::is_err).unwrap_or(Ok(()));
+    }
+    match results.iter().any(|result| result.is_ok()) {
+        true => Ok(()),
+        false => results.into_iter().last().unwrap_or(Ok(())),
+    }
+}
+
+/// Leaves the setting up of a broker's destinations given `?lazy=true` until there is a
+/// message for them, so that a netpipe that may get none for a long time doesn't hold idle
+/// connections. A lazy destination that then fails to be set up is retried with the next
+/// message, and the message fails if the broker has no destination set up yet.
+pub struct Lazy {
+    broker: Box<dyn Broker>,
+    pending: RefCell<Vec<String>>,
+    set_up: Cell<bool>,
+}
+
+impl Lazy {
+    pub fn new(broker: Box<dyn Broker>) -> Lazy {
+        Lazy {
+            broker,
+            pending: RefCell::new(vec![]),
+            set_up: Cell::new(false),
+        }
+    }
+
+    /// Sets up the lazy destinations, keeping those that fail for next time.
+    fn set_up_pending(&self) -> Result<()> {
+        let mut failure = Ok(());
+        self.pending
+            .borrow_mut()
+            .retain(|option| match self.broker.add_destination(option) {
+                Ok(()) => {
+                    eprintln!("Added destination {option}.");
+                    self.set_up.set(true);
+                    false
+                }
+                Err(e) => {
+                    eprintln!("Failed to set up {option}: {e}.");
+                    failure = Err(e);
+                    true
+                }
+            });
+        failure
+    }
+}
+
+/// Takes the query parameters named `keys` out of a destination option, returning what is
+/// left of the option, unchanged if it has none of them, and their values.
+fn take_params<const N: usize>(option: &str, keys: [&str; N]) -> (String, [Option<String>; N]) {
+    let mut values = [const { None }; N];
+    let Some((base, query)) = option.split_once('?') else {
+        return (option.to_string(), values);
+    };
+    let mut rest = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match keys.iter().position(|k| *k == key) {
+            Some(index) => values[index] = Some(value.into_owned()),
+            None => {
+                rest.append_pair(&key, &value);
+            }
+        }
+    }
+    if values.iter().all(Option::is_none) {
+        return (option.to_string(), values);
+    }
+    let rest = rest.finish();
+    match rest.is_empty() {
+        true => (base.to_string(), values),
+        false => (format!("{base}?{rest}"), values),
+    }
+}
+
+/// Takes the `lazy` query parameter out of a destination option, returning what is left of the
+/// option and whether it was `true`.
+fn take_lazy(option: &str) -> Result<(String, bool)> {
+    let (option, [lazy]) = take_params(option, ["lazy"]);
+    let lazy = match lazy.as_deref() {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(value) => return Err(NetpipeError::invalid(format!("invalid lazy {value}"))),
+    };
+    Ok((option, lazy))
+}
+
+/// Messages held for a destination within its startup delay, unless it says otherwise.
+const DEFAULT_STARTUP_BUFFER: usize = 10_000;
+
+/// Holds back a destination given `?startup_delay=<duration>` for that long after startup,
+/// for a downstream service that needs time to become ready, and only sets it up then. Until
+/// then, up to `?startup_buffer=` messages (10000 by default) are held, and any beyond that
+/// dropped, or with `?startup_policy=drop` all of them are. The held messages go out ahead of
+/// the first message after the delay. Like a lazy destination, one that then fails to be set
+/// up is retried with the next message. Each such destination gets a broker of its own, so
+/// that the others of its kind aren't held back with it.
+pub struct StartupDelay {
+    broker: Box<dyn Broker>,
+    /// The destination, until it is set up, and when that may happen.
+    pending: RefCell<Option<(String, Instant)>>,
+    /// Messages held at most, or `None` to drop them.
+    buffer: Cell<Option<usize>>,
+    held: RefCell<VecDeque<Payload>>,
+    drops: DropCounters,
+}
+
+impl StartupDelay {
+    pub fn new(broker: Box<dyn Broker>, drops: DropCounters) -> StartupDelay {
+        StartupDelay {
+            broker,
+            pending: RefCell::new(None),
+            buffer: Cell::new(None),
+            held: RefCell::new(VecDeque::new()),
+            drops,
+        }
+    }
+
+    /// Whether a destination option asks for a startup delay.
+    pub fn wanted(option: &str) -> bool {
+        take_params(option, ["startup_delay"]).1[0].is_some()
+    }
+
+    fn hold(&self, message: &Payload) {
+        let mut held = self.held.borrow_mut();
+        match self.buffer.get() {
+            Some(buffer) if held.len() < buffer => held.push_back(message.clone()),
+            Some(_) => self.drops.count_lost(Reason::Backlog, message),
+            None => self.drops.count(Reason::Startup),
+        }
+    }
+}
+
+impl Broker for StartupDelay {
+    fn matches(&self, option: &str) -> bool {
+        self.broker.matches(option)
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (option, [delay, policy, buffer]) = take_params(
+            option,
+            ["startup_delay", "startup_policy", "startup_buffer"],
+        );
+        let delay = delay.unwrap_or_default();
+        let delay = parse_duration(&delay)
+            .map_err(|_| NetpipeError::invalid(format!("invalid startup_delay {delay}")))?;
+        let buffer = match buffer {
+            None => DEFAULT_STARTUP_BUFFER,
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|&value| value > 0)
+                .ok_or_else(|| NetpipeError::invalid(format!("invalid startup_buffer {value}")))?,
+        };
+        self.buffer.set(match policy.as_deref() {
+            None | Some("buffer") => Some(buffer),
+            Some("drop") => None,
+            Some(other) => {
+                return Err(NetpipeError::invalid(format!(
+                    "unknown startup_policy {other}"
+                )))
+            }
+        });
+        *self.pending.borrow_mut() = Some((option, Instant::now() + delay));
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let mut pending = self.pending.borrow_mut();
+        if let Some((option, due)) = pending.as_ref() {
+            if Instant::now() < *due {
+                self.hold(message);
+                return Ok(());
+            }
+            if let Err(e) = self.broker.add_destination(option) {
+                eprintln!("Failed to set up {option}: {e}.");
+                return Err(e);
+            }
+            eprintln!("Added destination {option}.");
+            *pending = None;
+            for held in self.held.take() {
+                if self.broker.send(&held).is_err() {
+                    self.drops.deadletter(&held);
+                }
+            }
+        }
+        self.broker.send(message)
+    }
+
+    fn shutdown(&self) {
+        self.broker.shutdown();
+    }
+}
+
+/// Only passes on the messages that the rule of a destination from `--destinations-file`
+/// takes. Each such destination gets a broker of its own, so that the others of its kind get
+/// all messages still.
+pub struct Routed {
+    broker: Box<dyn Broker>,
+    route: Route,
+}
+
+impl Routed {
+    pub fn new(broker: Box<dyn Broker>, route: Route) -> Routed {
+        Routed { broker, route }
+    }
+}
+
+impl Broker for Routed {
+    fn matches(&self, option: &str) -> bool {
+        self.broker.matches(option)
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        self.broker.add_destination(option)
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        match self.route.accepts(message) {
+            true => self.broker.send(message),
+            false => Ok(()),
+        }
+    }
+
+    fn shutdown(&self) {
+        self.broker.shutdown();
+    }
+}
+
+impl Broker for Lazy {
+    fn matches(&self, option: &str) -> bool {
+        self.broker.matches(option)
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (option, lazy) = take_lazy(option)?;
+        if lazy {
+            self.pending.borrow_mut().push(option);
+            return Ok(());
+        }
+        self.broker.add_destination(&option)?;
+        self.set_up.set(true);
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        if !self.pending.borrow().is_empty() {
+            let failure = self.set_up_pending();
+            if !self.set_up.get() {
+                return failure;
+            }
+        }
+        self.broker.send(message)
+    }
+
+    fn shutdown(&self) {
+        if self.set_up.get() {
+            self.broker.shutdown();
+        }
+    }
+}
+
+/// Splits a `<scheme>://<path>?<query>` option, whose path is a local file's rather than part
+/// of a URL, into the path and its query parameters.
+pub fn file_option(option: &str) -> (&str, HashMap<String, String>) {
+    let rest = option.split_once("://").map_or(option, |(_, rest)| rest);
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let params = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    (path, params)
+}
+
+/// Writes a message followed by a newline, binary messages byte for byte.
+pub fn write_line(writer: &mut impl Write, message: &Payload) -> io::Result<()> {
+    writer.write_all(message.as_bytes())?;
+    writer.write_all(b"\n")
+}
+
+/// Returns the value of a destination option's query parameter.
+pub fn query_param(url: &Url, key: &str) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// A `stdout` or `stderr` destination, optionally only writing messages that match
+/// `?filter=`, and with `?format=json` writing each as a `{"ts":...,"seq":...,"data":...}`
+/// envelope rather than as is.
+struct Console {
+    stderr: bool,
+    filter: Option<Regex>,
+    envelope: bool,
+    seq: u64,
+    colorizer: Option<Colorizer>,
 }
 
+impl Console {
+    /// Writes a message as a line, binary messages byte for byte unless enveloped, and under
+    /// `--binary` without the newline, flushing stdout since its line buffer then wouldn't.
+    fn write(&mut self, message: &Payload, binary: bool) -> io::Result<()> {
+        let enveloped;
+        let message = match self.envelope {
+            true => {
+                self.seq += 1;
+                enveloped = Payload::Text(envelope(self.seq, &message.to_text()));
+                &enveloped
+            }
+            false => message,
+        };
+        let mut line = match (message, &self.colorizer) {
+            (Payload::Text(text), Some(colorizer)) => colorizer.colorize(text).into_bytes(),
+            _ => message.as_bytes().to_vec(),
+        };
+        let newline = !binary || self.envelope;
+        if newline {
+            line.push(b'\n');
+        }
+        if self.stderr {
+            return stderr().write_all(&line);
+        }
+        let mut stdout = stdout().lock();
+        stdout.write_all(&line)?;
+        if !newline {
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Wraps a message in a `{"ts":...,"seq":...,"data":...}` JSON object, with the time in
+/// milliseconds since the Unix epoch.
+fn envelope(seq: u64, message: &str) -> String {
+    envelope_at(now_millis(), seq, message)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn envelope_at(ts: u64, seq: u64, message: &str) -> String {
+    format!(
+        r#"{{"ts":{ts},"seq":{seq},"data":{}}}"#,
+        Value::from(message)
+    )
+}
+
+/// Writes to `stdout` and `stderr`, so that, say, errors can be split off to stderr while
+/// everything goes to stdout.
 pub struct StdoutBroker {
-    enabled: RefCell<bool>,
+    color: bool,
+    /// Whether a closed stream, as in `netpipe ... stdout | head`, only disables that
+    /// destination rather than stopping netpipe.
+    ignore_broken_pipe: bool,
+    /// Whether messages are written as they are, without a newline after each.
+    binary: bool,
+    consoles: RefCell<Vec<Console>>,
 }
 
 impl StdoutBroker {
-    pub fn new() -> StdoutBroker {
+    /// Colors are only applied when requested and the stream is a terminal, so piped output
+    /// is always passed through byte for byte.
+    pub fn new(color: bool, ignore_broken_pipe: bool, binary: bool) -> StdoutBroker {
         StdoutBroker {
-            enabled: RefCell::new(false),
+            color,
+            ignore_broken_pipe,
+            binary,
+            consoles: RefCell::new(vec![]),
         }
     }
 }
 
 impl Broker for StdoutBroker {
-    fn matches(&self, option: &String) -> bool {
-        return option.eq("stdout");
+    fn matches(&self, option: &str) -> bool {
+        let (name, _) = option.split_once('?').unwrap_or((option, ""));
+        name == "stdout" || name == "stderr"
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (name, params) = file_option(option);
+        let stderr = name == "stderr";
+        let filter = params
+            .get("filter")
+            .map(|pattern| Regex::new(pattern))
+            .transpose()
+            .map_err(NetpipeError::invalid)?;
+        let envelope = match params.get("format").map(String::as_str) {
+            None | Some("raw") => false,
+            Some("json") => true,
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown format {other}"))),
+        };
+        let terminal = match stderr {
+            true => io::stderr().is_terminal(),
+            false => stdout().is_terminal(),
+        };
+        self.consoles.borrow_mut().push(Console {
+            stderr,
+            filter,
+            envelope,
+            seq: 0,
+            colorizer: (self.color && terminal).then(Colorizer::new),
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let mut consoles = self.consoles.borrow_mut();
+        let mut results = vec![];
+        let mut closed = vec![];
+        let text = message.to_text();
+        for (index, console) in consoles.iter_mut().enumerate() {
+            if !console.filter.as_ref().is_none_or(|f| f.is_match(&text)) {
+                continue;
+            }
+            match console.write(message, self.binary) {
+                Err(e) if e.kind() == ErrorKind::BrokenPipe && !self.ignore_broken_pipe => {
+                    return Err(NetpipeError::Closed);
+                }
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                    let name = if console.stderr { "Stderr" } else { "Stdout" };
+                    eprintln!("{name} closed, no longer writing to it.");
+                    closed.push(index);
+                    results.push(Err(NetpipeError::Io(e)));
+                }
+                result => results.push(result.map_err(NetpipeError::Io)),
+            }
+        }
+        for index in closed.into_iter().rev() {
+            consoles.remove(index);
+        }
+        unless_all_failed(results)
+    }
+
+    fn shutdown(&self) {
+        let _ = stdout().flush();
+        let _ = stderr().flush();
+    }
+}
+
+/// Sockets connected to one listener path, optionally only receiving messages that match
+/// `filter`.
+struct Channel {
+    path: String,
+    filter: Option<Regex>,
+    /// Whether messages are wrapped in a `{"ts":...,"seq":...,"data":...}` JSON object, with
+    /// the time in milliseconds since the Unix epoch and the message's number on this channel,
+    /// for clients that didn't pick a subprotocol.
+    envelope: bool,
+    /// Whether text frames from clients are taken as commands, see [`Subscriber::command`].
+    control: bool,
+    seq: u64,
+    replay: Option<Replay>,
+    /// The largest frame sent to clients, beyond which messages are split into fragments.
+    fragment: Option<usize>,
+    limits: FrameLimits,
+    /// Whether a plain GET request for the path gets [`VIEWER`].
+    viewer: bool,
+    sockets: Vec<Subscriber>,
+}
+
+/// A message sent on a channel, with the time it was sent and its number on the channel, so
+/// that it can be framed for any client.
+struct Sent {
+    ts: u64,
+    seq: u64,
+    message: Payload,
+}
+
+impl Sent {
+    /// The frame for the message: a text or binary one as it came, or, with `envelope`, a
+    /// text one holding its envelope.
+    fn frame(&self, envelope: bool) -> Message {
+        match (envelope, &self.message) {
+            (true, message) => Message::Text(envelope_at(self.ts, self.seq, &message.to_text())),
+            (false, Payload::Text(text)) => Message::Text(text.clone()),
+            (false, Payload::Binary(bytes)) => Message::Binary(bytes.clone()),
+        }
+    }
+}
+
+/// The latest messages sent on a channel, replayed to each client as it connects. Beyond
+/// `max_frames` messages or `max_bytes` bytes of them, the oldest are evicted.
+struct Replay {
+    sent: VecDeque<Sent>,
+    bytes: usize,
+    max_frames: Option<usize>,
+    max_bytes: Option<usize>,
+}
+
+impl Replay {
+    fn record(&mut self, sent: Sent) {
+        // A message that doesn't fit at all would only evict everything else.
+        if self.max_bytes.is_some_and(|max| sent.message.len() > max) {
+            return;
+        }
+        self.bytes += sent.message.len();
+        self.sent.push_back(sent);
+        while self.max_frames.is_some_and(|max| self.sent.len() > max)
+            || self.max_bytes.is_some_and(|max| self.bytes > max)
+        {
+            let oldest = self.sent.pop_front().unwrap();
+            self.bytes -= oldest.message.len();
+        }
+    }
+}
+
+impl Channel {
+    fn accepts(&self, message: &str) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.is_match(message))
+    }
+}
+
+/// The subprotocols that clients may ask for in the `Sec-WebSocket-Protocol` header of their
+/// upgrade request, and whether each gets envelopes: `json` does, `raw` gets messages as they
+/// came.
+const SUBPROTOCOLS: [(&str, bool); 2] = [("json", true), ("raw", false)];
+
+/// Picks the first of the subprotocols a client asks for that netpipe speaks.
+fn negotiate(request: &Request) -> Option<(&'static str, bool)> {
+    request
+        .headers()
+        .get_all("sec-websocket-protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|name| {
+            SUBPROTOCOLS
+                .into_iter()
+                .find(|(protocol, _)| *protocol == name.trim())
+        })
+}
+
+/// Bounds on what is read from the other end of a WebSocket, `?max_frame=<size>` and
+/// `?max_message=<size>`, and tungstenite's 16 MiB and 64 MiB where not given.
+#[derive(Clone, Copy, Default)]
+pub struct FrameLimits {
+    max_frame: Option<usize>,
+    max_message: Option<usize>,
+}
+
+impl FrameLimits {
+    pub fn parse(url: &Url) -> Result<FrameLimits> {
+        let size = |key: &str| {
+            query_param(url, key)
+                .map(|size| {
+                    parse_size(&size)
+                        .ok()
+                        .filter(|&size| size > 0)
+                        .ok_or_else(|| NetpipeError::invalid(format!("invalid {key} {size}")))
+                })
+                .transpose()
+        };
+        Ok(FrameLimits {
+            max_frame: size("max_frame")?,
+            max_message: size("max_message")?,
+        })
+    }
+
+    pub fn apply<S>(self, socket: &mut WebSocket<S>) {
+        socket.set_config(|config| {
+            if let Some(max_frame) = self.max_frame {
+                config.max_frame_size = Some(max_frame);
+            }
+            if let Some(max_message) = self.max_message {
+                config.max_message_size = Some(max_message);
+            }
+        });
+    }
+}
+
+/// Splits a message into frames of up to `size` bytes, the first of the message's own type
+/// and the others continuations of it.
+fn fragments(message: Message, size: usize) -> Vec<Message> {
+    let (opcode, data) = match message {
+        Message::Text(text) => (Data::Text, text.into_bytes()),
+        Message::Binary(bytes) => (Data::Binary, bytes),
+        message => return vec![message],
+    };
+    let count = data.len().div_ceil(size).max(1);
+    data.chunks(size)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let opcode = if index == 0 { opcode } else { Data::Continue };
+            let frame = Frame::message(chunk.to_vec(), OpCode::Data(opcode), index + 1 == count);
+            Message::Frame(frame)
+        })
+        .collect()
+}
+
+/// Bounds on the messages held for WebSocket clients that don't keep up.
+#[derive(Clone)]
+pub struct SendQueue {
+    /// Messages held per client, beyond which the oldest are dropped. Unbounded if `None`.
+    pub limit: Option<usize>,
+    /// Depth from which a client is reported as slow.
+    pub slow: usize,
+    /// How long a client may stay slow before it is disconnected, indefinitely if `None`.
+    pub slow_timeout: Option<Duration>,
+    pub drops: DropCounters,
+}
+
+/// A connected socket, along with what its client asked for over the control channel and the
+/// messages waiting for its socket to take them.
+struct Subscriber {
+    socket: WebSocket<Stream>,
+    /// Whether the client gets envelopes, as its subprotocol says, or as the channel's
+    /// `envelope` does if `None`.
+    envelope: Option<bool>,
+    /// As the channel's `fragment`.
+    fragment: Option<usize>,
+    /// Set by `subscribe`, on top of the channel's own filter.
+    pattern: Option<Regex>,
+    paused: bool,
+    queue: VecDeque<Message>,
+    /// Messages dropped from a full queue since the client became slow.
+    dropped: usize,
+    slow_since: Option<Instant>,
+}
+
+impl Subscriber {
+    fn new(
+        socket: WebSocket<Stream>,
+        envelope: Option<bool>,
+        fragment: Option<usize>,
+    ) -> Subscriber {
+        Subscriber {
+            socket,
+            envelope,
+            fragment,
+            pattern: None,
+            paused: false,
+            queue: VecDeque::new(),
+            dropped: 0,
+            slow_since: None,
+        }
+    }
+
+    fn enqueue(&mut self, message: Message, limits: &SendQueue) {
+        if limits.limit.is_some_and(|limit| self.queue.len() >= limit) {
+            self.queue.pop_front();
+            self.dropped += 1;
+            limits.drops.count(Reason::SlowClient);
+        }
+        self.queue.push_back(message);
+    }
+
+    /// Writes the queued messages for as long as the socket takes them, returning whether the
+    /// socket is still usable. A client whose queue stays deep for longer than the slow
+    /// timeout is disconnected.
+    fn flush(&mut self, limits: &SendQueue) -> bool {
+        let mut blocked = match self.socket.write_pending() {
+            Ok(()) => false,
+            Err(Io(e)) if is_benign(&e) => true,
+            Err(e) => {
+                report_write_error(&self.socket, e);
+                return false;
+            }
+        };
+        while !blocked {
+            let Some(message) = self.queue.pop_front() else {
+                break;
+            };
+            blocked = match self.write(message) {
+                Ok(()) => false,
+                // The frame stays buffered and is flushed on a later write.
+                Err(Io(e)) if is_benign(&e) => true,
+                Err(e) => {
+                    report_write_error(&self.socket, e);
+                    return false;
+                }
+            };
+        }
+        self.socket.can_write() && self.track(limits)
+    }
+
+    /// Writes a message, in fragments if it is larger than `fragment`. Fragments that the
+    /// socket doesn't take right away are buffered along with the rest, as is a whole message.
+    #[allow(clippy::result_large_err)]
+    fn write(&mut self, message: Message) -> tungstenite::Result<()> {
+        let Some(size) = self.fragment.filter(|&size| message.len() > size) else {
+            return self.socket.write_message(message);
+        };
+        let mut outcome = Ok(());
+        for fragment in fragments(message, size) {
+            match self.socket.write_message(fragment) {
+                Ok(()) => {}
+                Err(Io(e)) if is_benign(&e) => outcome = Err(Io(e)),
+                Err(e) => return Err(e),
+            }
+        }
+        outcome
+    }
+
+    /// Writes what is queued and then a close frame, returning whether that is done with,
+    /// because it was all written or because the socket failed.
+    fn close(&mut self, limits: &SendQueue) -> bool {
+        if !self.flush(limits) {
+            return true;
+        }
+        if !self.queue.is_empty() {
+            return false;
+        }
+        !matches!(self.socket.close(None), Err(Io(e)) if is_benign(&e))
+    }
+
+    /// Logs when the client falls behind and when it catches up again, returning whether it
+    /// may stay connected.
+    fn track(&mut self, limits: &SendQueue) -> bool {
+        let peer = peer(&self.socket);
+        let depth = self.queue.len();
+        match self.slow_since {
+            None if depth >= limits.slow => {
+                eprintln!("Slow client {peer}: {depth} messages queued.");
+                self.slow_since = Some(Instant::now());
+            }
+            Some(_) if depth < limits.slow => {
+                match self.dropped {
+                    0 => eprintln!("Client {peer} caught up."),
+                    dropped => eprintln!("Client {peer} caught up, {dropped} messages dropped."),
+                }
+                self.slow_since = None;
+                self.dropped = 0;
+            }
+            Some(since) if limits.slow_timeout.is_some_and(|t| since.elapsed() > t) => {
+                eprintln!("Disconnected slow client {peer}: {depth} messages queued.");
+                limits.drops.count_many(Reason::Disconnected, depth);
+                return false;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn wants(&self, message: &str) -> bool {
+        !self.paused && self.pattern.as_ref().is_none_or(|p| p.is_match(message))
+    }
+
+    /// Applies one of `subscribe <pattern>`, `unsubscribe`, `pause` or `resume` to this
+    /// socket's feed.
+    fn command(&mut self, command: &str) {
+        let peer = peer(&self.socket);
+        let (name, argument) = command
+            .trim()
+            .split_once(' ')
+            .unwrap_or((command.trim(), ""));
+        match (name, argument) {
+            ("subscribe", pattern) if !pattern.is_empty() => match Regex::new(pattern) {
+                Ok(pattern) => {
+                    eprintln!("Subscribed {peer} to {pattern}.");
+                    self.pattern = Some(pattern);
+                }
+                Err(e) => eprintln!("Ignored invalid pattern from {peer}: {e}."),
+            },
+            ("unsubscribe", "") => {
+                eprintln!("Unsubscribed {peer}.");
+                self.pattern = None;
+            }
+            ("pause", "") => {
+                eprintln!("Paused {peer}.");
+                self.paused = true;
+            }
+            ("resume", "") => {
+                eprintln!("Resumed {peer}.");
+                self.paused = false;
+            }
+            _ => eprintln!("Ignored unknown command from {peer}: {command}."),
+        }
     }
+}
+
+type Channels = Arc<Mutex<Vec<Channel>>>;
+
+/// How long to wait before accepting connections again after it panicked, so that a panic on
+/// every connection doesn't take up a core.
+const ACCEPT_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// How long the other ends of connections get to take what is queued for them and close as
+/// netpipe exits, beyond which the rest are left to see the connection drop.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A page that connects to the WebSocket at its own address and shows the messages coming in.
+const VIEWER: &str = include_str!("broker/viewer.html");
+
+/// Answers a browser asking for the page of a channel with `?viewer=true`.
+fn serve_viewer(stream: &TcpStream, channels: &Mutex<Vec<Channel>>) -> io::Result<()> {
+    http::respond(stream, &|path| {
+        let channels = channels.lock().unwrap_or_else(PoisonError::into_inner);
+        match route(&channels, path) {
+            Some(index) if channels[index].viewer => {
+                http::Response::new(200, "text/html; charset=utf-8", VIEWER.to_string())
+            }
+            _ => http::Response::not_found(),
+        }
+    })
+}
+
+/// Picks the channel for a request path: an exact match, falling back to a channel on `/` so
+/// that a listener with a single root destination keeps accepting any path.
+fn route(channels: &[Channel], path: &str) -> Option<usize> {
+    channels
+        .iter()
+        .position(|c| c.path == path)
+        .or_else(|| channels.iter().position(|c| c.path == "/"))
+}
+
+/// Headers whose values are left out when handshakes are logged, as they tend to carry
+/// credentials.
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
 
-    fn add_destination(&self, _option: &String) {
-        self.enabled.replace(true);
+/// Logs the upgrade requests of incoming connections, to tell why a client fails to connect,
+/// with the values of sensitive headers and of those in `redacted` replaced.
+pub struct HandshakeLog {
+    redacted: Vec<String>,
+}
+
+impl HandshakeLog {
+    pub fn new(redacted: &[String]) -> HandshakeLog {
+        HandshakeLog {
+            redacted: redacted.iter().map(|name| name.to_lowercase()).collect(),
+        }
     }
 
-    fn send(&self, message: &String) {
-        if *self.enabled.borrow() {
-            println!("{message}");
+    fn log(&self, peer: &str, request: &Request) {
+        eprintln!(
+            "Handshake from {peer}: {} {} {:?}",
+            request.method(),
+            request.uri(),
+            request.version()
+        );
+        for (name, value) in request.headers() {
+            let value = if SENSITIVE_HEADERS.contains(&name.as_str())
+                || self
+                    .redacted
+                    .iter()
+                    .any(|redacted| redacted == name.as_str())
+            {
+                "<redacted>".into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes())
+            };
+            eprintln!("  {name}: {value}");
         }
     }
 }
 
 pub struct WebSocketBroker {
-    sockets_list: RefCell<Vec<Arc<Mutex<Vec<WebSocket<TcpStream>>>>>>,
+    listeners: RefCell<HashMap<String, Channels>>,
+    listen: ListenOptions,
+    handshake_log: Option<Arc<HandshakeLog>>,
+    /// Origins that browsers may connect from, any if `None`.
+    origin_allowlist: Option<Vec<String>>,
+    send_queue: SendQueue,
+    /// Whether TCP connections start with a PROXY protocol header, naming the client that a
+    /// load balancer connects for.
+    proxy_protocol: bool,
+    /// The certificate chain and private key that `wss://` destinations present.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    cert: Option<String>,
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    key: Option<String>,
 }
 
 impl WebSocketBroker {
-    pub fn new() -> WebSocketBroker {
+    pub fn new(
+        listen: ListenOptions,
+        handshake_log: Option<HandshakeLog>,
+        origin_allowlist: Option<Vec<String>>,
+        send_queue: SendQueue,
+        proxy_protocol: bool,
+        cert: Option<String>,
+        key: Option<String>,
+    ) -> WebSocketBroker {
         WebSocketBroker {
-            sockets_list: RefCell::new(vec![]),
+            listeners: RefCell::new(HashMap::new()),
+            listen,
+            handshake_log: handshake_log.map(Arc::new),
+            origin_allowlist,
+            send_queue,
+            proxy_protocol,
+            cert,
+            key,
         }
     }
 }
 
+/// Whether a request may connect given the allowed origins. Requests without an `Origin`
+/// header don't come from a browser, so there is no web page to guard against.
+fn origin_allowed(allowlist: Option<&[String]>, request: &Request) -> bool {
+    let (Some(allowlist), Some(origin)) = (allowlist, request.headers().get("origin")) else {
+        return true;
+    };
+    let origin = String::from_utf8_lossy(origin.as_bytes());
+    allowlist
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(&origin))
+}
+
 impl Broker for WebSocketBroker {
-    fn matches(&self, option: &String) -> bool {
-        return option.starts_with("ws://");
-    }
-
-    fn add_destination(&self, option: &String) {
-        let sockets = Arc::new(Mutex::new(Vec::new()));
-        let sockets_ref = sockets.clone();
-        let server = TcpListener::bind(get_host_port(option)).unwrap();
-        thread::spawn(move || {
-            for stream in server.incoming() {
-                let stream = stream.unwrap();
-                let socket = accept(stream).unwrap();
-                socket.get_ref().set_nonblocking(true).unwrap();
-                eprintln!("Connected: {}.", socket.get_ref().peer_addr().unwrap());
-                sockets_ref.lock().unwrap().push(socket);
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("ws://")
+            || cfg!(unix) && option.starts_with("ws+unix://")
+            || cfg!(feature = "tls") && option.starts_with("wss://")
+    }
+
+    /// Destinations sharing a host and port, or a Unix socket with `ws+unix://<socket
+    /// path>[:<request path>]`, share one listener, and each accepted socket joins the
+    /// destination whose path matches the one in its upgrade request.
+    ///
+    /// `wss://` destinations listen for TLS, presenting the certificate from `--tls-cert` with
+    /// the key from `--tls-key`, and can't share a port with `ws://` ones.
+    ///
+    /// With `?replay=<count>` or `?replay_bytes=<size>` (such as `1MB`), or both, a client
+    /// that connects is first sent the latest messages of its destination, as many as fit.
+    ///
+    /// A client asking for the `json` or `raw` subprotocol gets envelopes or messages as they
+    /// came, whatever the destination's `?envelope=`, so that clients of both kinds can share
+    /// it.
+    ///
+    /// With `?fragment=<size>`, messages larger than that are sent in fragments, rather than
+    /// in one frame, and `?max_frame=` and `?max_message=` bound what clients may send, as for
+    /// a `ws://` source.
+    ///
+    /// With `?viewer=true`, opening the destination's address in a browser shows a page that
+    /// connects to it and lists the messages as they come, for a look at the data without a
+    /// front end of its own. The page is only served over plain TCP.
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let (endpoint, path) = match url.scheme() {
+            #[cfg(unix)]
+            "ws+unix" => {
+                let (socket, path) = net::unix_target(&url);
+                (format!("unix:{socket}"), path)
+            }
+            scheme => {
+                let host_port = format!(
+                    "{}:{}",
+                    url.host_str()
+                        .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+                    url.port_or_known_default()
+                        .ok_or_else(|| NetpipeError::invalid("missing port"))?
+                );
+                // Kept apart from the plain listener on the same port, which it can't share.
+                match scheme {
+                    "wss" => (format!("wss:{host_port}"), url.path().to_string()),
+                    _ => (host_port, url.path().to_string()),
+                }
+            }
+        };
+        #[cfg(feature = "tls")]
+        let tls = match (url.scheme(), &self.cert, &self.key) {
+            ("wss", Some(cert), Some(key)) => Some(crate::tls::server_config(cert, key)?),
+            ("wss", ..) => {
+                return Err(NetpipeError::invalid(
+                    "wss:// requires --tls-cert and --tls-key",
+                ))
             }
+            _ => None,
+        };
+        let filter = query_param(&url, "filter")
+            .map(|pattern| Regex::new(&pattern))
+            .transpose()
+            .map_err(NetpipeError::invalid)?;
+        let envelope = match query_param(&url, "envelope").as_deref() {
+            None | Some("raw") => false,
+            Some("json") => true,
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown envelope {other}"))),
+        };
+        let control = match query_param(&url, "control").as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => return Err(NetpipeError::invalid(format!("invalid control {other}"))),
+        };
+        let max_frames = query_param(&url, "replay")
+            .map(|count| {
+                count
+                    .parse()
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or_else(|| NetpipeError::invalid(format!("invalid replay {count}")))
+            })
+            .transpose()?;
+        let max_bytes = query_param(&url, "replay_bytes")
+            .map(|size| {
+                parse_size(&size)
+                    .map_err(|_| NetpipeError::invalid(format!("invalid replay_bytes {size}")))
+            })
+            .transpose()?;
+        let fragment = query_param(&url, "fragment")
+            .map(|size| {
+                parse_size(&size)
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| NetpipeError::invalid(format!("invalid fragment {size}")))
+            })
+            .transpose()?;
+        let limits = FrameLimits::parse(&url)?;
+        let viewer = match query_param(&url, "viewer").as_deref() {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => return Err(NetpipeError::invalid(format!("invalid viewer {other}"))),
+        };
+        let replay = (max_frames.is_some() || max_bytes.is_some()).then(|| Replay {
+            sent: VecDeque::new(),
+            bytes: 0,
+            max_frames,
+            max_bytes,
         });
-        self.sockets_list.borrow_mut().push(sockets);
-    }
+        let channel = Channel {
+            path,
+            filter,
+            envelope,
+            control,
+            seq: 0,
+            replay,
+            fragment,
+            limits,
+            viewer,
+            sockets: vec![],
+        };
 
-    fn send(&self, message: &String) {
-        for sockets in self.sockets_list.borrow().iter() {
-            sockets.lock().unwrap().retain_mut(|socket| {
-                match socket.read_message() {
-                    Ok(message) if message.is_close() => {
-                        eprintln!("Socket closed: {}.", socket.get_ref().peer_addr().unwrap());
-                        return false;
-                    }
-                    Ok(message) => panic!("[003] unknown message: {message}"),
-                    Err(Io(e)) if e.kind() == WouldBlock => (),
-                    Err(Io(e)) if e.kind() == ConnectionReset => {
-                        eprintln!(
-                            "Connection reset: {}.",
-                            socket.get_ref().peer_addr().unwrap()
-                        );
-                        return false;
-                    }
-                    Err(Protocol(
-                        tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
-                    )) => {
-                        eprintln!(
-                            "Reset without closing handshake: {}.",
-                            socket.get_ref().peer_addr().unwrap()
-                        );
-                        return false;
-                    }
+        let mut listeners = self.listeners.borrow_mut();
+        if let Some(channels) = listeners.get(&endpoint) {
+            channels.lock().unwrap().push(channel);
+            return Ok(());
+        }
+        let channels = Arc::new(Mutex::new(vec![channel]));
+        let channels_ref = channels.clone();
+        let server = match endpoint.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(socket) => net::listen_unix(socket).map(Listener::Unix),
+            _ => {
+                let host_port = endpoint.strip_prefix("wss:").unwrap_or(&endpoint);
+                net::listen(host_port, &self.listen).map(Listener::Tcp)
+            }
+        };
+        let server = server.map_err(NetpipeError::Bind)?;
+        let handshake_log = self.handshake_log.clone();
+        let origin_allowlist = self.origin_allowlist.clone();
+        let limits = self.send_queue.clone();
+        let proxy_protocol = self.proxy_protocol;
+        let port = endpoint.rsplit(':').next().unwrap_or_default().to_string();
+        // Each connection is set up on a thread of its own, so that a client that is slow to
+        // send its request, or sends none, doesn't hold up the others.
+        let connect = Arc::new(move |stream: Stream| {
+            let stream = match stream {
+                Stream::Tcp(stream) if proxy_protocol => match net::accept_proxied(stream) {
+                    Ok(stream) => stream,
+                    Err(e) if is_benign(&e) => return,
                     Err(e) => {
-                        dbg!(e);
-                        panic!("[001] encountered unknown error");
+                        eprintln!("Failed to accept connection: {e}.");
+                        return;
+                    }
+                },
+                stream => stream,
+            };
+            let client = stream.peer();
+            #[cfg(feature = "tls")]
+            let stream = match (&tls, stream) {
+                (Some(config), Stream::Tcp(sock) | Stream::Proxied(sock, _)) => {
+                    match crate::tls::accept(sock, config) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("Handshake with {client} failed: {e}.");
+                            return;
+                        }
                     }
                 }
-                match socket.write_message(Message::text(message)) {
-                    Ok(()) => true,
-                    Err(Io(e)) if e.kind() == ConnectionAborted => {
-                        eprintln!(
-                            "Connection aborted: {}.",
-                            socket.get_ref().peer_addr().unwrap()
-                        );
-                        return false;
+                (_, stream) => stream,
+            };
+            // A browser may ask a viewer destination for its page rather than a WebSocket. Only
+            // TCP streams are on Windows without TLS.
+            #[allow(irrefutable_let_patterns)]
+            if let Stream::Tcp(tcp) | Stream::Proxied(tcp, _) = &stream {
+                if channels_ref
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .iter()
+                    .any(|c| c.viewer)
+                {
+                    let served = match http::is_upgrade(tcp) {
+                        Ok(true) => None,
+                        Ok(false) => Some(serve_viewer(tcp, &channels_ref)),
+                        Err(e) => Some(Err(e)),
+                    };
+                    if let Some(served) = served {
+                        if let Err(e) = served {
+                            eprintln!("Failed to answer HTTP request from {client}: {e}.");
+                        }
+                        return;
                     }
-                    Err(Io(e)) if e.kind() == ConnectionReset => {
-                        eprintln!(
-                            "Connection reset: {}.",
-                            socket.get_ref().peer_addr().unwrap()
-                        );
-                        return false;
+                }
+            }
+            let mut index = None;
+            let mut protocol = None;
+            #[allow(clippy::result_large_err)]
+            let socket = accept_hdr(stream, |request: &Request, mut response: Response| {
+                if let Some(handshake_log) = &handshake_log {
+                    handshake_log.log(&client, request);
+                }
+                if !origin_allowed(origin_allowlist.as_deref(), request) {
+                    eprintln!("Refused {client}: origin not allowed.");
+                    return Err(Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(None)
+                        .unwrap());
+                }
+                index = route(
+                    &channels_ref.lock().unwrap_or_else(PoisonError::into_inner),
+                    request.uri().path(),
+                );
+                protocol = negotiate(request);
+                if let Some((name, _)) = protocol {
+                    response
+                        .headers_mut()
+                        .insert("sec-websocket-protocol", HeaderValue::from_static(name));
+                }
+                match index {
+                    Some(_) => Ok(response),
+                    None => Err(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(None)
+                        .unwrap()),
+                }
+            });
+            let mut socket = match socket {
+                Ok(socket) => socket,
+                Err(e) => {
+                    if handshake_log.is_some() {
+                        eprintln!("Handshake with {client} failed: {e}.");
                     }
-                    Err(Protocol(
-                        tungstenite::error::ProtocolError::ResetWithoutClosingHandshake,
-                    )) => {
-                        eprintln!(
-                            "Reset without closing handshake: {}.",
-                            socket.get_ref().peer_addr().unwrap()
-                        );
-                        return false;
+                    return;
+                }
+            };
+            if let Err(e) = socket.get_ref().set_nonblocking(true) {
+                eprintln!("Failed to set up {}: {e}.", peer(&socket));
+                return;
+            }
+            match protocol {
+                Some((name, _)) => eprintln!("Connected: {} ({name}).", peer(&socket)),
+                None => eprintln!("Connected: {}.", peer(&socket)),
+            }
+            if let Some(index) = index {
+                let channel =
+                    &mut channels_ref.lock().unwrap_or_else(PoisonError::into_inner)[index];
+                channel.limits.apply(&mut socket);
+                let envelope = protocol.map(|(_, envelope)| envelope);
+                let mut subscriber = Subscriber::new(socket, envelope, channel.fragment);
+                if let Some(replay) = &channel.replay {
+                    let envelope = envelope.unwrap_or(channel.envelope);
+                    let frames = replay.sent.iter().map(|sent| sent.frame(envelope));
+                    subscriber.queue.extend(frames);
+                }
+                if subscriber.flush(&limits) {
+                    channel.sockets.push(subscriber);
+                }
+            }
+        });
+        let client_thread = format!("ws-client:{port}");
+        let accept = Arc::new(move || loop {
+            let stream = match server.accept() {
+                Ok(stream) => stream,
+                Err(e) if is_benign(&e) => continue,
+                Err(e) => {
+                    net::accept_failed(&e);
+                    continue;
+                }
+            };
+            let connect = connect.clone();
+            threads::spawn(client_thread.clone(), move || connect(stream));
+        });
+        // Clients that are connected already keep getting messages if accepting panics, so
+        // nothing would show that new ones can't connect; accepting starts over instead.
+        let watched = (endpoint.clone(), channels.clone());
+        threads::spawn(format!("ws-watch:{port}"), move || loop {
+            let accept = accept.clone();
+            let accepting = threads::spawn(format!("ws-accept:{port}"), move || accept());
+            // Accepting only ends by panicking, which the panic hook reported already.
+            let _ = accepting.join();
+            let (endpoint, channels) = &watched;
+            channels.clear_poison();
+            eprintln!(
+                "Stopped accepting connections on {endpoint}; restarting in {ACCEPT_RESTART_DELAY:?}."
+            );
+            thread::sleep(ACCEPT_RESTART_DELAY);
+        });
+        listeners.insert(endpoint, channels);
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let text = message.to_text();
+        for channels in self.listeners.borrow().values() {
+            // Accepting may have panicked holding the lock, before starting over.
+            let mut channels = channels.lock().unwrap_or_else(PoisonError::into_inner);
+            for channel in channels.iter_mut() {
+                if channel.accepts(&text) {
+                    channel.seq += 1;
+                    let sent = Sent {
+                        ts: now_millis(),
+                        seq: channel.seq,
+                        message: message.clone(),
+                    };
+                    // Framed once for each format that a client gets.
+                    let mut frames: [Option<Message>; 2] = [None, None];
+                    let default = channel.envelope;
+                    let control = channel.control;
+                    let limits = &self.send_queue;
+                    channel.sockets.retain_mut(|subscriber| {
+                        if !receive(subscriber, control) {
+                            return false;
+                        }
+                        if subscriber.wants(&text) {
+                            let envelope = subscriber.envelope.unwrap_or(default);
+                            let frame = frames[envelope as usize]
+                                .get_or_insert_with(|| sent.frame(envelope));
+                            subscriber.enqueue(frame.clone(), limits);
+                        }
+                        subscriber.flush(limits)
+                    });
+                    if let Some(replay) = &mut channel.replay {
+                        replay.record(sent);
                     }
-                    Err(e) => {
-                        dbg!(e);
-                        panic!("[002] encountered unknown error");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends each client what is still queued for it and a close frame, for up to
+    /// [`CLOSE_TIMEOUT`] in all.
+    fn shutdown(&self) {
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        loop {
+            let mut open = 0;
+            for channels in self.listeners.borrow().values() {
+                let mut channels = channels.lock().unwrap_or_else(PoisonError::into_inner);
+                for channel in channels.iter_mut() {
+                    channel
+                        .sockets
+                        .retain_mut(|subscriber| !subscriber.close(&self.send_queue));
+                    open += channel.sockets.len();
+                }
+            }
+            if open == 0 {
+                return;
+            }
+            if Instant::now() >= deadline {
+                eprintln!("Gave up closing {open} WebSocket connections.");
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// WSAEWOULDBLOCK and WSAEINTR (the latter raised as WSACancelBlockingCall when a blocking
+/// call is cancelled), which some Windows setups report without a matching `ErrorKind`.
+#[cfg(windows)]
+const BENIGN_OS_ERRORS: [i32; 2] = [10035, 10004];
+#[cfg(not(windows))]
+const BENIGN_OS_ERRORS: [i32; 0] = [];
+
+/// Whether an I/O error on a nonblocking socket just means "try again later".
+fn is_benign(e: &io::Error) -> bool {
+    matches!(e.kind(), WouldBlock | ErrorKind::Interrupted)
+        || e.raw_os_error()
+            .is_some_and(|code| BENIGN_OS_ERRORS.contains(&code))
+}
+
+fn peer(socket: &WebSocket<Stream>) -> String {
+    socket.get_ref().peer()
+}
+
+/// Handles what a client has sent since the last message, returning whether its socket is
+/// still usable. Text frames are commands on a control channel, and dropped otherwise.
+fn receive(subscriber: &mut Subscriber, control: bool) -> bool {
+    loop {
+        let socket = &mut subscriber.socket;
+        match socket.read_message() {
+            Ok(message) if message.is_close() => {
+                eprintln!("Socket closed: {}.", peer(socket));
+                return false;
+            }
+            Ok(Message::Text(command)) if control => subscriber.command(&command),
+            Ok(message @ (Message::Text(_) | Message::Binary(_))) => strict::check(|| {
+                let kind = if message.is_text() { "text" } else { "binary" };
+                format!(
+                    "{} sent a {kind} message, which isn't a command",
+                    peer(socket)
+                )
+            }),
+            Ok(_) => (),
+            Err(Io(e)) if is_benign(&e) => return true,
+            Err(Io(e)) if e.kind() == ConnectionReset => {
+                eprintln!("Connection reset: {}.", peer(socket));
+                return false;
+            }
+            Err(Protocol(tungstenite::error::ProtocolError::ResetWithoutClosingHandshake)) => {
+                eprintln!("Reset without closing handshake: {}.", peer(socket));
+                return false;
+            }
+            Err(e) => {
+                eprintln!("Failed to read from {}: {e}.", peer(socket));
+                return false;
+            }
+        }
+    }
+}
+
+/// Logs why a write left a socket unusable.
+fn report_write_error(socket: &WebSocket<Stream>, error: tungstenite::Error) {
+    match error {
+        Io(e) if e.kind() == ConnectionAborted => {
+            eprintln!("Connection aborted: {}.", peer(socket));
+        }
+        Io(e) if e.kind() == ConnectionReset => {
+            eprintln!("Connection reset: {}.", peer(socket));
+        }
+        Protocol(tungstenite::error::ProtocolError::ResetWithoutClosingHandshake) => {
+            eprintln!("Reset without closing handshake: {}.", peer(socket));
+        }
+        e => eprintln!("Failed to write to {}: {e}.", peer(socket)),
+    }
+}
+
+/// Largest UDP payload that fits in a single IPv4 datagram.
+const MAX_DATAGRAM_SIZE_V4: usize = 65_507;
+/// Largest UDP payload that fits in a single IPv6 datagram without jumbograms.
+const MAX_DATAGRAM_SIZE_V6: usize = 65_527;
+
+/// How often a UDP destination's host name is resolved again, to follow changes to its
+/// address, and how often while it doesn't resolve.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+const UNRESOLVED_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+fn resolve(destination: &str) -> io::Result<SocketAddr> {
+    destination
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("{destination} has no address")))
+}
+
+/// Datagrams a paced UDP destination holds by default, beyond which they are dropped.
+const PACE_QUEUE: usize = 1000;
+/// The share of a second's datagrams that a paced destination may send in a burst, to make up
+/// for sleeps running long.
+const PACE_BURST: f64 = 0.01;
+
+/// A datagram waiting for its turn, with the socket and address to send it from and to.
+type Paced = (Arc<UdpSocket>, SocketAddr, Vec<u8>);
+
+/// Spreads the datagrams of a UDP destination given `?pps=` over time, sending them from a
+/// thread of its own at up to that many per second, so that a burst of messages doesn't
+/// overrun a receiver with a small buffer. Datagrams wait in a queue of `?pace_queue=` (1000
+/// by default) while they are ahead of the pace, and are dropped when it is full. Sending the
+/// queued datagrams is finished as netpipe exits.
+struct Pacer {
+    queue: Option<SyncSender<Paced>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Pacer {
+    fn new(name: &str, pps: u32, queue: usize) -> Pacer {
+        let (queue, paced) = mpsc::sync_channel::<Paced>(queue);
+        let name = name.to_string();
+        let thread = threads::spawn("udp-pace", move || {
+            // A token bucket, filling at the pace up to a burst.
+            let rate = f64::from(pps);
+            let burst = (rate * PACE_BURST).max(1.0);
+            let (mut tokens, mut filled) = (burst, Instant::now());
+            for (socket, addr, message) in paced {
+                loop {
+                    let now = Instant::now();
+                    tokens = (tokens + (now - filled).as_secs_f64() * rate).min(burst);
+                    filled = now;
+                    if tokens >= 1.0 {
+                        tokens -= 1.0;
+                        break;
                     }
-                };
-                socket.can_write()
-            })
+                    thread::sleep(Duration::from_secs_f64((1.0 - tokens) / rate));
+                }
+                if let Err(e) = socket.send_to(&message, addr) {
+                    eprintln!("Failed to send to {name}: {e}.");
+                }
+            }
+        });
+        Pacer {
+            queue: Some(queue),
+            thread: Some(thread),
+        }
+    }
+
+    fn push(&self, datagram: Paced, drops: &DropCounters) {
+        let queue = self.queue.as_ref().unwrap();
+        if let Err(TrySendError::Full((.., message))) = queue.try_send(datagram) {
+            drops.count_lost(Reason::QueueFull, &Payload::from_bytes(message));
+        }
+    }
+
+    /// Sends the datagrams still queued, and waits for them to be sent.
+    fn finish(&mut self) {
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
         }
     }
 }
 
-fn get_host_port(uri: &String) -> String {
-    return Regex::new(r"://(.*)/?")
-        .unwrap()
-        .captures(uri)
-        .unwrap()
-        .get(1)
-        .unwrap()
-        .as_str()
-        .to_string();
+/// A UDP destination and its address, kept up to date by a background thread that resolves
+/// its name again from time to time, and stops once the destination is dropped.
+struct UdpDestination {
+    name: String,
+    addr: Arc<Mutex<Option<SocketAddr>>>,
+    pacer: Option<Pacer>,
+}
+
+impl UdpDestination {
+    /// Starts with the address the name resolves to now, if any. A name that doesn't
+    /// resolve yet, say because its DNS record isn't up, is tried again in the background.
+    fn new(name: &str, pacer: Option<Pacer>) -> UdpDestination {
+        let addr = match resolve(name) {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                eprintln!("Failed to resolve {name}: {e}. Dropping messages until it resolves.");
+                None
+            }
+        };
+        let addr = Arc::new(Mutex::new(addr));
+        let watched = Arc::downgrade(&addr);
+        let name = name.to_string();
+        let watched_name = name.clone();
+        threads::spawn("udp-resolve", move || loop {
+            let interval = match watched.upgrade() {
+                Some(addr) if addr.lock().unwrap().is_some() => RESOLVE_INTERVAL,
+                Some(_) => UNRESOLVED_RETRY_INTERVAL,
+                None => return,
+            };
+            thread::sleep(interval);
+            let Some(addr) = watched.upgrade() else {
+                return;
+            };
+            let resolved = resolve(&watched_name);
+            let mut addr = addr.lock().unwrap();
+            match (&resolved, *addr) {
+                (Ok(new), None) => eprintln!("Resolved {watched_name} to {new}."),
+                (Ok(new), Some(old)) if *new != old => {
+                    eprintln!("Resolved {watched_name} to {new}, no longer {old}.")
+                }
+                (Err(e), Some(_)) => eprintln!(
+                    "Failed to resolve {watched_name}: {e}. Dropping messages until it resolves."
+                ),
+                _ => {}
+            }
+            *addr = resolved.ok();
+        });
+        UdpDestination { name, addr, pacer }
+    }
 }
 
 pub struct UdpBroker {
-    socket: UdpSocket,
-    socket_v6: UdpSocket,
-    destinations: RefCell<Vec<String>>,
+    /// Bound when first needed, so that a host without IPv6, say, only fails for IPv6
+    /// destinations.
+    socket: OnceCell<Arc<UdpSocket>>,
+    socket_v6: OnceCell<Arc<UdpSocket>>,
+    destinations: RefCell<Vec<UdpDestination>>,
+    /// How the sending sockets are bound, only ever to a device.
+    bind: ListenOptions,
+    /// The hops that datagrams to multicast groups may take, and whether they loop back.
+    multicast_ttl: Option<u32>,
+    multicast_loop: bool,
+    drops: DropCounters,
 }
 
 impl UdpBroker {
-    pub fn new() -> UdpBroker {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        let socket_v6 = UdpSocket::bind("[::]:0").unwrap();
+    pub fn new(
+        device: Option<String>,
+        multicast_ttl: Option<u32>,
+        multicast_loop: bool,
+        drops: DropCounters,
+    ) -> UdpBroker {
         UdpBroker {
-            socket,
-            socket_v6,
+            socket: OnceCell::new(),
+            socket_v6: OnceCell::new(),
             destinations: RefCell::new(vec![]),
+            bind: ListenOptions {
+                device,
+                ..ListenOptions::default()
+            },
+            multicast_ttl,
+            multicast_loop,
+            drops,
         }
     }
+
+    /// Returns the socket for the address family of `addr`, and the largest payload it takes.
+    fn socket_for(&self, addr: &SocketAddr) -> Result<(&Arc<UdpSocket>, usize)> {
+        let (cell, local, max_size) = match addr {
+            SocketAddr::V4(_) => (&self.socket, "0.0.0.0:0", MAX_DATAGRAM_SIZE_V4),
+            SocketAddr::V6(_) => (&self.socket_v6, "[::]:0", MAX_DATAGRAM_SIZE_V6),
+        };
+        if cell.get().is_none() {
+            let socket = net::bind_udp(local, &self.bind).map_err(NetpipeError::Bind)?;
+            if self.multicast_ttl.is_some() || !self.multicast_loop {
+                net::set_multicast_options(&socket, self.multicast_ttl, self.multicast_loop)
+                    .map_err(NetpipeError::Bind)?;
+            }
+            let _ = cell.set(Arc::new(socket));
+        }
+        Ok((cell.get().unwrap(), max_size))
+    }
+
+    /// The socket to send `message` to `destination` from, and the address, or `None` if it
+    /// isn't sent from here: if it is dropped for that destination, which is counted or
+    /// logged, or handed to the destination's pacer.
+    fn target(
+        &self,
+        destination: &UdpDestination,
+        message: &[u8],
+    ) -> Result<Option<(&UdpSocket, SocketAddr)>> {
+        let Some(addr) = *destination.addr.lock().unwrap() else {
+            self.drops.count(Reason::Unresolved);
+            return Ok(None);
+        };
+        let (socket, max_size) = self.socket_for(&addr)?;
+        if message.len() > max_size {
+            eprintln!(
+                "Message of {} bytes exceeds the maximum datagram size of {max_size} bytes, not sent to {addr}.",
+                message.len()
+            );
+            self.drops.count(Reason::Oversize);
+            return Ok(None);
+        }
+        if let Some(pacer) = &destination.pacer {
+            pacer.push((socket.clone(), addr, message.to_vec()), &self.drops);
+            return Ok(None);
+        }
+        Ok(Some((socket, addr)))
+    }
+
+    fn send_to(&self, destination: &UdpDestination, message: &[u8]) -> Result<()> {
+        let Some((socket, addr)) = self.target(destination, message)? else {
+            return Ok(());
+        };
+        let sent = socket.send_to(message, addr)?;
+        if sent < message.len() {
+            eprintln!(
+                "Partially sent {sent} of {} bytes to {addr}.",
+                message.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// Sends `message` to all the destinations with a `sendmmsg` call per socket instead of a
+    /// `send_to` call each, which saves most of the system calls when fanning out widely.
+    #[cfg(target_os = "linux")]
+    fn send_to_all(&self, destinations: &[UdpDestination], message: &[u8]) -> Vec<Result<()>> {
+        let mut results = Vec::with_capacity(destinations.len());
+        // The destinations of each socket, as indices into `results`, and their addresses.
+        let mut batches: Vec<(&UdpSocket, Vec<usize>, Vec<SocketAddr>)> = vec![];
+        for destination in destinations {
+            match self.target(destination, message) {
+                Ok(Some((socket, addr))) => {
+                    let batch = match batches.iter().position(|(s, ..)| std::ptr::eq(*s, socket)) {
+                        Some(batch) => &mut batches[batch],
+                        None => {
+                            batches.push((socket, vec![], vec![]));
+                            batches.last_mut().unwrap()
+                        }
+                    };
+                    batch.1.push(results.len());
+                    batch.2.push(addr);
+                    results.push(Ok(()));
+                }
+                result => results.push(result.map(|_| ())),
+            }
+        }
+        for (socket, indices, addrs) in batches {
+            let sent = net::send_to_all(socket, message, &addrs);
+            for (index, result) in indices.into_iter().zip(sent) {
+                results[index] = result.map_err(NetpipeError::Io);
+            }
+        }
+        results
+    }
 }
 
 impl Broker for UdpBroker {
-    fn matches(&self, _option: &String) -> bool {
+    fn matches(&self, _option: &str) -> bool {
         true
     }
 
-    fn add_destination(&self, option: &String) {
-        self.destinations.borrow_mut().push(option.to_string());
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (option, [pps, queue]) = take_params(option, ["pps", "pace_queue"]);
+        let valid = option
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            return Err(NetpipeError::invalid("expected <host>:<port>"));
+        }
+        let pps = pps
+            .map(|pps| {
+                pps.parse()
+                    .ok()
+                    .filter(|&pps| pps > 0)
+                    .ok_or_else(|| NetpipeError::invalid(format!("invalid pps {pps}")))
+            })
+            .transpose()?;
+        let queue = queue
+            .map(|queue| {
+                queue
+                    .parse()
+                    .ok()
+                    .filter(|&queue| queue > 0)
+                    .ok_or_else(|| NetpipeError::invalid(format!("invalid pace_queue {queue}")))
+            })
+            .transpose()?;
+        if queue.is_some() && pps.is_none() {
+            return Err(NetpipeError::invalid("pace_queue requires pps"));
+        }
+        let pacer = pps.map(|pps| Pacer::new(&option, pps, queue.unwrap_or(PACE_QUEUE)));
+        let destination = UdpDestination::new(&option, pacer);
+        if let Some(addr) = *destination.addr.lock().unwrap() {
+            self.socket_for(&addr)?;
+        }
+        self.destinations.borrow_mut().push(destination);
+        Ok(())
     }
 
-    fn send(&self, message: &String) {
-        for addr in self.destinations.borrow().iter() {
-            let addr = addr.to_socket_addrs().unwrap().into_iter().next().unwrap();
-            if addr.is_ipv4() {
-                self.socket.send_to(message.as_bytes(), addr).unwrap();
-            } else {
-                self.socket_v6.send_to(message.as_bytes(), addr).unwrap();
+    fn send(&self, message: &Payload) -> Result<()> {
+        let destinations = self.destinations.borrow();
+        let results: Vec<Result<()>> = match destinations.len() {
+            #[cfg(target_os = "linux")]
+            2.. => self.send_to_all(&destinations, message.as_bytes()),
+            _ => destinations
+                .iter()
+                .map(|destination| self.send_to(destination, message.as_bytes()))
+                .collect(),
+        };
+        for (destination, result) in destinations.iter().zip(&results) {
+            if let Err(e) = result {
+                eprintln!("Failed to send to {}: {e}.", destination.name);
             }
         }
+        unless_all_failed(results)
+    }
+
+    fn shutdown(&self) {
+        for destination in self.destinations.borrow_mut().iter_mut() {
+            if let Some(pacer) = &mut destination.pacer {
+                pacer.finish();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay(max_frames: Option<usize>, max_bytes: Option<usize>) -> Replay {
+        Replay {
+            sent: VecDeque::new(),
+            bytes: 0,
+            max_frames,
+            max_bytes,
+        }
+    }
+
+    fn record_all(replay: &mut Replay, messages: &[&str]) {
+        for (seq, message) in messages.iter().enumerate() {
+            replay.record(Sent {
+                ts: 0,
+                seq: seq as u64,
+                message: Payload::Text(message.to_string()),
+            });
+        }
+    }
+
+    fn kept(replay: &Replay) -> Vec<String> {
+        replay
+            .sent
+            .iter()
+            .map(|sent| sent.message.to_text().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn binary_messages_go_out_in_binary_frames() {
+        let sent = |message| Sent {
+            ts: 0,
+            seq: 0,
+            message,
+        };
+        let text = sent(Payload::Text("caf\u{e9}".to_string()));
+        assert_eq!(text.frame(false), Message::Text("caf\u{e9}".to_string()));
+        let binary = sent(Payload::Binary(vec![0xff, 0x00]));
+        assert_eq!(binary.frame(false), Message::Binary(vec![0xff, 0x00]));
+    }
+
+    #[test]
+    fn replay_evicts_the_oldest_beyond_max_bytes() {
+        let mut replay = replay(None, Some(10));
+        record_all(&mut replay, &["1111", "2222", "3333", "44"]);
+        assert_eq!(kept(&replay), ["2222", "3333", "44"]);
+        assert_eq!(replay.bytes, 10);
+        record_all(&mut replay, &["555"]);
+        assert_eq!(kept(&replay), ["3333", "44", "555"]);
+        assert_eq!(replay.bytes, 9);
+    }
+
+    #[test]
+    fn replay_evicts_the_oldest_beyond_max_frames() {
+        let mut replay = replay(Some(2), Some(100));
+        record_all(&mut replay, &["1", "2", "3"]);
+        assert_eq!(kept(&replay), ["2", "3"]);
+        assert_eq!(replay.bytes, 2);
+    }
+
+    #[test]
+    fn replay_skips_a_message_larger_than_max_bytes() {
+        let mut replay = replay(None, Some(4));
+        record_all(&mut replay, &["1", "22222", "3"]);
+        assert_eq!(kept(&replay), ["1", "3"]);
+        assert_eq!(replay.bytes, 2);
     }
 }
@@ -0,0 +1,269 @@
+use super::Broker;
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::stats::{DropCounters, Reason};
+use crate::threads;
+use futures_lite::future;
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::{BasicProperties, Channel, Confirmation, Connection, ConnectionProperties};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+use url::Url;
+
+/// Messages held while the server is out of reach, unless the destination says otherwise.
+const DEFAULT_BUFFER: usize = 10_000;
+/// Messages published before waiting for the server to confirm them.
+const BATCH: usize = 100;
+
+/// Splits an `amqp://[<user>:<password>@]<host>[:<port>]/<name>?<query>` option into the URI
+/// of the server, in the virtual host given by `?vhost=` (`/` by default), the queue or
+/// exchange name and the other query parameters.
+pub fn amqp_option(option: &str) -> Result<(String, String, HashMap<String, String>)> {
+    let mut url = Url::parse(option).map_err(NetpipeError::invalid)?;
+    let name = url.path().trim_start_matches('/').to_string();
+    let mut params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    let vhost = params.remove("vhost").unwrap_or_else(|| "/".to_string());
+    url.set_query(None);
+    url.path_segments_mut()
+        .map_err(|()| NetpipeError::invalid("missing host"))?
+        .clear()
+        .push(&vhost);
+    Ok((url.to_string(), name, params))
+}
+
+/// Publishes what comes through its queue on a connection of its own, from a thread of its
+/// own.
+struct Publisher {
+    option: String,
+    uri: String,
+    exchange: String,
+    routing_key: String,
+    closing: Arc<AtomicBool>,
+    drops: DropCounters,
+}
+
+impl Publisher {
+    /// Opens a channel with publisher confirms on. The connection has to be kept for as
+    /// long as the channel is used.
+    async fn open(&self) -> lapin::Result<(Connection, Channel)> {
+        let connection = Connection::connect(&self.uri, ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+        Ok((connection, channel))
+    }
+
+    /// Publishes the batch and waits for the server to confirm it, leaving what isn't
+    /// confirmed yet in the batch if the connection fails.
+    async fn publish(&self, channel: &Channel, batch: &mut VecDeque<Payload>) -> lapin::Result<()> {
+        let mut confirms = Vec::with_capacity(batch.len());
+        for message in batch.iter() {
+            let confirm = channel
+                .basic_publish(
+                    self.exchange.as_str().into(),
+                    self.routing_key.as_str().into(),
+                    BasicPublishOptions::default(),
+                    message.as_bytes(),
+                    BasicProperties::default(),
+                )
+                .await?;
+            confirms.push(confirm);
+        }
+        for confirm in confirms {
+            let confirmation = confirm.await?;
+            let message = batch.pop_front().unwrap();
+            if let Confirmation::Nack(_) = confirmation {
+                eprintln!("Message refused by {}.", self.option);
+                self.drops.count_lost(Reason::Unconfirmed, &message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reconnects with backoff until it works or, once netpipe is exiting, fails.
+    fn reconnect(&self) -> Option<(Connection, Channel)> {
+        let backoff = Backoff {
+            max_attempts: None,
+            ..Backoff::default()
+        };
+        let mut attempt = 0;
+        loop {
+            match future::block_on(self.open()) {
+                Ok(connection) => {
+                    eprintln!("Reconnected: {}.", self.option);
+                    return Some(connection);
+                }
+                Err(e) if self.closing.load(Ordering::Relaxed) => {
+                    eprintln!("Failed to connect to {}: {e}.", self.option);
+                    return None;
+                }
+                Err(e) => {
+                    let delay = backoff.jittered_delay(attempt);
+                    eprintln!(
+                        "Failed to connect to {}: {e}. Retrying in {delay:?}.",
+                        self.option
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn run(self, mut connection: Option<(Connection, Channel)>, messages: Receiver<Payload>) {
+        let mut batch = VecDeque::new();
+        loop {
+            if batch.is_empty() {
+                let Ok(message) = messages.recv() else {
+                    // So that the server sees netpipe leave rather than lose the connection.
+                    if let Some((connection, _)) = &connection {
+                        let _ = future::block_on(connection.close(200, "Bye".into()));
+                    }
+                    return;
+                };
+                batch.push_back(message);
+                batch.extend(messages.try_iter().take(BATCH - 1));
+            }
+            let channel = match &connection {
+                Some((_, channel)) => channel,
+                None => match self.reconnect() {
+                    Some(opened) => &connection.insert(opened).1,
+                    None => {
+                        batch.extend(messages.try_iter());
+                        eprintln!("Gave up on {} messages for {}.", batch.len(), self.option);
+                        for message in &batch {
+                            self.drops.count_lost(Reason::Unconfirmed, message);
+                        }
+                        return;
+                    }
+                },
+            };
+            if let Err(e) = future::block_on(self.publish(channel, &mut batch)) {
+                eprintln!("Lost {}: {e}.", self.option);
+                connection = None;
+            }
+        }
+    }
+}
+
+struct Exchange {
+    option: String,
+    queue: Option<SyncSender<Payload>>,
+    closing: Arc<AtomicBool>,
+    publisher: Option<JoinHandle<()>>,
+}
+
+impl Exchange {
+    /// Lets the publisher get the messages still buffered confirmed, making one last attempt
+    /// if the server is out of reach, and close the connection, and waits for it.
+    fn close(&mut self) {
+        self.queue = None;
+        self.closing.store(true, Ordering::Relaxed);
+        if let Some(publisher) = self.publisher.take() {
+            let _ = publisher.join();
+        }
+    }
+}
+
+/// Publishes each message to the exchange given by
+/// `amqp://[<user>:<password>@]<host>[:<port>]/<exchange>`, in the virtual host `?vhost=`
+/// (`/` by default), with the routing key `?routing_key=`. Without an exchange, messages go
+/// to the default one, which routes them to the queue named by the routing key. Each message
+/// is confirmed by the server; refused ones are counted dropped. While the server is out of
+/// reach, up to `?buffer=` messages (10000 by default) are held for once it is back, and any
+/// beyond that are dropped. A message whose confirmation was cut off by a lost connection is
+/// published again, so it may arrive twice.
+pub struct AmqpBroker {
+    exchanges: RefCell<Vec<Exchange>>,
+    drops: DropCounters,
+}
+
+impl AmqpBroker {
+    pub fn new(drops: DropCounters) -> AmqpBroker {
+        AmqpBroker {
+            exchanges: RefCell::new(vec![]),
+            drops,
+        }
+    }
+}
+
+impl Broker for AmqpBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("amqp://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (uri, exchange, params) = amqp_option(option)?;
+        let routing_key = params.get("routing_key").cloned().unwrap_or_default();
+        if exchange.is_empty() && routing_key.is_empty() {
+            return Err(NetpipeError::invalid("missing exchange or routing_key"));
+        }
+        let buffer = match params.get("buffer") {
+            None => DEFAULT_BUFFER,
+            Some(value) => value
+                .parse()
+                .ok()
+                .filter(|&value| value > 0)
+                .ok_or_else(|| NetpipeError::invalid(format!("invalid buffer {value}")))?,
+        };
+
+        let closing = Arc::new(AtomicBool::new(false));
+        let publisher = Publisher {
+            option: option.to_string(),
+            uri,
+            exchange,
+            routing_key,
+            closing: closing.clone(),
+            drops: self.drops.clone(),
+        };
+        let connection = reconnect(&Backoff::default(), option, || {
+            future::block_on(publisher.open())
+        })
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+        let (sender, messages) = mpsc::sync_channel(buffer);
+        let publisher = threads::spawn("amqp-publish", move || {
+            publisher.run(Some(connection), messages)
+        });
+        self.exchanges.borrow_mut().push(Exchange {
+            option: option.to_string(),
+            queue: Some(sender),
+            closing,
+            publisher: Some(publisher),
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        for exchange in self.exchanges.borrow().iter() {
+            let Some(queue) = &exchange.queue else {
+                continue;
+            };
+            match queue.try_send(message.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(message)) => {
+                    self.drops.count_lost(Reason::Backlog, &message)
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    eprintln!("Publisher for {} is gone.", exchange.option)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        for exchange in self.exchanges.borrow_mut().iter_mut() {
+            exchange.close();
+        }
+    }
+}
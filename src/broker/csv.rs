@@ -0,0 +1,74 @@
+use crate::payload::Payload;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+
+/// Turns JSON-object messages into CSV rows, with a column per field in `fields` or, without
+/// them, per field of the first message, in alphabetical order. Missing fields are left
+/// empty; fields beyond the columns are left out, with a warning the first time each shows up.
+pub struct Csv {
+    fields: Option<Vec<String>>,
+    dropped_fields: HashSet<String>,
+}
+
+/// Quotes a cell that holds a comma, a quote or a line break, as RFC 4180 has it.
+fn escape(cell: &str) -> String {
+    if cell.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(text)) => escape(text),
+        Some(other) => escape(&other.to_string()),
+    }
+}
+
+impl Csv {
+    pub fn new(fields: Option<Vec<String>>) -> Csv {
+        Csv {
+            fields,
+            dropped_fields: HashSet::new(),
+        }
+    }
+
+    /// The header row, once the columns are known.
+    pub fn header(&self) -> Option<String> {
+        self.fields.as_ref().map(|fields| {
+            fields
+                .iter()
+                .map(|field| escape(field))
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+    }
+
+    /// The row for a message bound for `path`, or `None`, which is logged, if the message
+    /// isn't a JSON object.
+    pub fn row(&mut self, path: &str, message: &Payload) -> Option<String> {
+        let Ok(Value::Object(object)) = serde_json::from_slice::<Value>(message.as_bytes()) else {
+            eprintln!("Skipped a message for {path} that isn't a JSON object.");
+            return None;
+        };
+        let fields = self
+            .fields
+            .get_or_insert_with(|| object.keys().cloned().collect());
+        for field in object.keys() {
+            if !fields.contains(field) && self.dropped_fields.insert(field.clone()) {
+                eprintln!("Field {field} isn't in the CSV header of {path}, left out.");
+            }
+        }
+        Some(row(fields, &object))
+    }
+}
+
+fn row(fields: &[String], object: &Map<String, Value>) -> String {
+    fields
+        .iter()
+        .map(|field| cell(object.get(field)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
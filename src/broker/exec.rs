@@ -0,0 +1,180 @@
+use super::{file_option, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use crate::threads;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::Write,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+/// Commands run at once, and messages waiting for a turn, unless the destination says
+/// otherwise.
+const DEFAULT_WORKERS: usize = 4;
+const DEFAULT_QUEUE: usize = 100;
+
+/// How the message is handed to the command.
+#[derive(Clone, Copy)]
+enum Input {
+    Stdin,
+    /// As the first positional parameter, `$1`.
+    Argument,
+}
+
+/// Runs a command line through the shell with the message as `input`, logging how it failed.
+fn run(command: &str, input: Input, message: &Payload) {
+    let mut child = if cfg!(windows) {
+        let mut child = Command::new("cmd");
+        child.arg("/C").arg(command);
+        child
+    } else {
+        let mut child = Command::new("sh");
+        child.arg("-c").arg(command).arg("sh");
+        child
+    };
+    match input {
+        Input::Stdin => child.stdin(Stdio::piped()),
+        Input::Argument => child.arg(message.to_text().as_ref()).stdin(Stdio::null()),
+    };
+    let mut child = match child.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to run {command}: {e}.");
+            return;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that exits without reading its input is fine.
+        let _ = stdin.write_all(message.as_bytes());
+    }
+    match child.wait() {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Command {command} exited with {status}."),
+        Err(e) => eprintln!("Failed to wait for {command}: {e}."),
+    }
+}
+
+struct Sink {
+    command: String,
+    queue: Option<SyncSender<Payload>>,
+    block: bool,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Sink {
+    /// Lets the workers run the commands still queued and waits for them.
+    fn drop(&mut self) {
+        self.queue = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs the command line given by `exec-sink://<command>` through the shell for each message,
+/// passing the message on the command's stdin, or as `$1` with `?input=arg`. Up to
+/// `?workers=` commands (4 by default) run at once, and up to `?queue=` messages (100 by
+/// default) wait for a turn. With the queue full, messages are dropped, unless
+/// `?policy=block`, which holds up this destination instead. Since the query starts at the
+/// first `?`, the command itself can't contain one. Commands that fail are logged.
+pub struct ExecBroker {
+    sinks: RefCell<Vec<Sink>>,
+    drops: DropCounters,
+}
+
+impl ExecBroker {
+    pub fn new(drops: DropCounters) -> ExecBroker {
+        ExecBroker {
+            sinks: RefCell::new(vec![]),
+            drops,
+        }
+    }
+}
+
+fn positive(params: &HashMap<String, String>, name: &str, default: usize) -> Result<usize> {
+    match params.get(name) {
+        None => Ok(default),
+        Some(value) => value
+            .parse()
+            .ok()
+            .filter(|&value| value > 0)
+            .ok_or_else(|| NetpipeError::invalid(format!("invalid {name} {value}"))),
+    }
+}
+
+impl Broker for ExecBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("exec-sink://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (command, params) = file_option(option);
+        if command.trim().is_empty() {
+            return Err(NetpipeError::invalid("missing command"));
+        }
+        let input = match params.get("input").map(String::as_str) {
+            None | Some("stdin") => Input::Stdin,
+            Some("arg") => Input::Argument,
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown input {other}"))),
+        };
+        let block = match params.get("policy").map(String::as_str) {
+            None | Some("drop") => false,
+            Some("block") => true,
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown policy {other}"))),
+        };
+        let workers = positive(&params, "workers", DEFAULT_WORKERS)?;
+        let queue = positive(&params, "queue", DEFAULT_QUEUE)?;
+
+        let (sender, messages) = mpsc::sync_channel(queue);
+        let messages: Arc<Mutex<Receiver<Payload>>> = Arc::new(Mutex::new(messages));
+        let workers = (0..workers)
+            .map(|worker| {
+                let messages = messages.clone();
+                let command = command.to_string();
+                threads::spawn(format!("exec:{worker}"), move || loop {
+                    let message = match messages.lock().unwrap().recv() {
+                        Ok(message) => message,
+                        Err(_) => return,
+                    };
+                    run(&command, input, &message);
+                })
+            })
+            .collect();
+        self.sinks.borrow_mut().push(Sink {
+            command: command.to_string(),
+            queue: Some(sender),
+            block,
+            workers,
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        for sink in self.sinks.borrow().iter() {
+            let Some(queue) = &sink.queue else {
+                continue;
+            };
+            let sent = match sink.block {
+                true => queue.send(message.clone()).map_err(|_| ()),
+                false => match queue.try_send(message.clone()) {
+                    Err(TrySendError::Full(message)) => {
+                        self.drops.count_lost(Reason::Backlog, &message);
+                        Ok(())
+                    }
+                    sent => sent.map_err(|_| ()),
+                },
+            };
+            if sent.is_err() {
+                eprintln!("Workers for {} are gone.", sink.command);
+            }
+        }
+        Ok(())
+    }
+}
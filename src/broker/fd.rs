@@ -0,0 +1,73 @@
+use super::{unless_all_failed, write_line, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use std::{
+    cell::RefCell,
+    fs::File,
+    io,
+    os::unix::io::{FromRawFd, RawFd},
+};
+
+/// Writes each message as a line to an already open file descriptor, e.g. `fd://3` for one
+/// set up by the shell with `3>capture.txt` or process substitution.
+pub struct FdBroker {
+    files: RefCell<Vec<(RawFd, File)>>,
+}
+
+impl FdBroker {
+    pub fn new() -> FdBroker {
+        FdBroker {
+            files: RefCell::new(vec![]),
+        }
+    }
+}
+
+/// Checks that `fd` is open, and open for writing, before it is taken over.
+fn check_writable(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(NetpipeError::Bind(io::Error::last_os_error()));
+    }
+    match flags & libc::O_ACCMODE {
+        libc::O_WRONLY | libc::O_RDWR => Ok(()),
+        _ => Err(NetpipeError::invalid(format!(
+            "file descriptor {fd} is not writable"
+        ))),
+    }
+}
+
+impl Broker for FdBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("fd://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let fd: RawFd = option["fd://".len()..]
+            .parse()
+            .map_err(|_| NetpipeError::invalid("expected a file descriptor number"))?;
+        if self.files.borrow().iter().any(|(used, _)| *used == fd) {
+            return Err(NetpipeError::invalid(format!(
+                "file descriptor {fd} is already used"
+            )));
+        }
+        check_writable(fd)?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        self.files.borrow_mut().push((fd, file));
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .files
+            .borrow_mut()
+            .iter_mut()
+            .map(|(fd, file)| {
+                write_line(file, message).map_err(|e| {
+                    eprintln!("Failed to write to file descriptor {fd}: {e}.");
+                    NetpipeError::Io(e)
+                })
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+}
@@ -0,0 +1,404 @@
+use super::csv::Csv;
+use super::{envelope, file_option, unless_all_failed, write_line, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::options::{parse_duration, parse_size};
+use crate::payload::Payload;
+use crate::selector::Selector;
+use chrono::Local;
+use std::{
+    cell::RefCell,
+    fs::{self, File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Files kept open at once by a destination split by key, unless `?max-open=` says otherwise.
+const MAX_OPEN_FILES: usize = 64;
+
+/// How the files of a destination are written.
+#[derive(Clone, Copy)]
+enum Encoding {
+    Plain,
+    Zstd(Option<i32>),
+}
+
+impl Encoding {
+    fn parse(compress: Option<&str>, level: Option<&str>) -> Result<Encoding> {
+        match (compress, level) {
+            (None, Some(_)) => Err(NetpipeError::invalid("level requires compress")),
+            (None, None) => Ok(Encoding::Plain),
+            (Some("zstd"), level) => Ok(Encoding::Zstd(zstd_level(level)?)),
+            (Some(other), _) => Err(NetpipeError::invalid(format!(
+                "unknown compression {other}"
+            ))),
+        }
+    }
+
+    /// Opens a file for appending, or with `truncate` for writing from the start.
+    fn open(self, path: &str, truncate: bool) -> io::Result<Sink> {
+        let file = match truncate {
+            true => File::create(path)?,
+            false => OpenOptions::new().create(true).append(true).open(path)?,
+        };
+        let size = file.metadata()?.len();
+        let writer = match self {
+            Encoding::Plain => Box::new(LineWriter::new(file)),
+            Encoding::Zstd(level) => zstd_writer(file, level)?,
+        };
+        Ok(Sink {
+            writer,
+            fresh: size == 0,
+            size,
+            opened: Instant::now(),
+        })
+    }
+}
+
+/// An open file, and whether it was empty when opened, so that it still needs a header.
+struct Sink {
+    writer: Box<dyn Write + Send>,
+    fresh: bool,
+    /// Bytes in the file, before compression, and when it was opened, for rotation.
+    size: u64,
+    opened: Instant,
+}
+
+/// When the file of a destination is set aside for a fresh one: once it would grow past
+/// `max_size` bytes, or once it has been open for `every`.
+struct Rotation {
+    max_size: Option<u64>,
+    every: Option<Duration>,
+}
+
+impl Rotation {
+    fn due(&self, sink: &Sink, line: &Payload) -> bool {
+        let grown = self
+            .max_size
+            .is_some_and(|max_size| sink.size > 0 && sink.size + line.len() as u64 + 1 > max_size);
+        grown
+            || self
+                .every
+                .is_some_and(|every| sink.opened.elapsed() >= every)
+    }
+}
+
+/// Renames the file at `path` to one named after the time, such as
+/// `capture.log.20261014-093000`, with a counter added if that is taken.
+fn set_aside(path: &str) -> io::Result<()> {
+    let stamped = format!("{path}.{}", Local::now().format("%Y%m%d-%H%M%S"));
+    let mut target = stamped.clone();
+    let mut count = 1;
+    while Path::new(&target).exists() {
+        count += 1;
+        target = format!("{stamped}-{count}");
+    }
+    fs::rename(path, target)
+}
+
+/// What the lines written to a file are made of.
+enum Format {
+    /// The messages as they are.
+    Lines,
+    Csv(Csv),
+    /// `{"ts":...,"seq":...,"data":...}` envelopes, as `replay://` plays back, numbered from
+    /// the last one written.
+    Json(u64),
+}
+
+impl Format {
+    fn write(&self, sink: &mut Sink, line: &Payload) -> io::Result<()> {
+        if sink.fresh {
+            if let Format::Csv(csv) = self {
+                let header = csv.header().unwrap_or_default();
+                write_line(&mut sink.writer, &header.as_str().into())?;
+                sink.size += header.len() as u64 + 1;
+            }
+            sink.fresh = false;
+        }
+        write_line(&mut sink.writer, line)?;
+        sink.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// The files of a destination whose path holds `{key}`, one per key, opened when the first
+/// message for it arrives. Once `max_open` are open, the least recently used one is closed
+/// to make room, and reopened for appending if its key comes up again.
+struct Keyed {
+    key: Selector,
+    max_open: usize,
+    encoding: Encoding,
+    /// The open files by key, the most recently used last.
+    open: Vec<(String, Sink)>,
+}
+
+impl Keyed {
+    fn writer(&mut self, template: &str, key: String) -> io::Result<&mut Sink> {
+        match self.open.iter().position(|(open, _)| *open == key) {
+            Some(index) => {
+                let entry = self.open.remove(index);
+                self.open.push(entry);
+            }
+            None => {
+                if self.open.len() >= self.max_open {
+                    self.open.remove(0);
+                }
+                let writer = self
+                    .encoding
+                    .open(&template.replace("{key}", &key), false)?;
+                self.open.push((key, writer));
+            }
+        }
+        Ok(&mut self.open.last_mut().unwrap().1)
+    }
+}
+
+/// Makes a key safe to use in a file name, so that it can't point outside the directory.
+fn sanitize(key: &str) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    match key.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => key,
+    }
+}
+
+enum Writers {
+    Single(Sink),
+    Keyed(Keyed),
+}
+
+struct Output {
+    path: String,
+    format: Format,
+    writers: Writers,
+    /// Only for a single file.
+    rotation: Option<Rotation>,
+    encoding: Encoding,
+}
+
+impl Output {
+    fn write(&mut self, message: &Payload) -> io::Result<()> {
+        let row;
+        let line = match &mut self.format {
+            Format::Lines => message,
+            Format::Csv(csv) => match csv.row(&self.path, message) {
+                Some(text) => {
+                    row = text.into();
+                    &row
+                }
+                None => return Ok(()),
+            },
+            Format::Json(seq) => {
+                *seq += 1;
+                row = envelope(*seq, &message.to_text()).into();
+                &row
+            }
+        };
+        match &mut self.writers {
+            Writers::Single(sink) => {
+                if self.rotation.as_ref().is_some_and(|r| r.due(sink, line)) {
+                    // The old file is closed before it is renamed, which finishes a zstd stream.
+                    sink.writer = Box::new(io::sink());
+                    set_aside(&self.path)?;
+                    *sink = self.encoding.open(&self.path, false)?;
+                }
+                self.format.write(sink, line)
+            }
+            Writers::Keyed(keyed) => {
+                // The key comes from the message, not from the row made of it.
+                let key = keyed.key.select(&message.to_text());
+                let key = sanitize(key.as_deref().unwrap_or("_"));
+                self.format.write(keyed.writer(&self.path, key)?, line)
+            }
+        }
+    }
+}
+
+/// Appends each message as a line to the file at `file://<path>`, creating it if missing, or
+/// with `?mode=truncate` empties it first. With `?compress=zstd`, and the `zstd` feature, the
+/// file is a zstd stream instead, at the compression level given by `level` (3 by default),
+/// and the stream is finished when the destination is dropped as netpipe exits.
+///
+/// A path holding `{key}`, as in `file://out/{key}.log?key=json:device`, splits the messages by
+/// the field picked by the `key` selector into one file per key, with `{key}` replaced by it.
+/// Characters other than letters, digits, `-`, `_` and `.` in a key become `_`, and messages
+/// without the field go to the file for `_`. At most `?max-open=` files (64 by default) are
+/// kept open at once.
+///
+/// With `?format=csv`, JSON-object messages are written as CSV rows instead, with the columns
+/// given by `?fields=`, a comma-separated list, and a header row at the top of each file
+/// that is empty when opened. With `?format=json`, each message is written in a
+/// `{"ts":...,"seq":...,"data":...}` envelope, making a capture that `replay://` plays back.
+///
+/// With `?rotate=<size>`, such as `100MB`, or `?rotate-every=<interval>`, such as `1h`, the
+/// file is renamed after the time, as in `out.log.20261014-093000`, once it would grow past
+/// the size or has been open for the interval, and a fresh one is started. Rotation doesn't
+/// apply to a path split by key.
+pub struct FileBroker {
+    outputs: RefCell<Vec<Output>>,
+}
+
+impl FileBroker {
+    pub fn new() -> FileBroker {
+        FileBroker {
+            outputs: RefCell::new(vec![]),
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_level(level: Option<&str>) -> Result<Option<i32>> {
+    level
+        .map(|level| {
+            level
+                .parse()
+                .ok()
+                .filter(|level| zstd::compression_level_range().contains(level))
+                .ok_or_else(|| NetpipeError::invalid(format!("invalid zstd level {level}")))
+        })
+        .transpose()
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_level(_level: Option<&str>) -> Result<Option<i32>> {
+    Err(NetpipeError::invalid("built without the zstd feature"))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_writer(file: File, level: Option<i32>) -> io::Result<Box<dyn Write + Send>> {
+    let encoder = zstd::Encoder::new(file, level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL))?;
+    Ok(Box::new(encoder.auto_finish()))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_writer(_file: File, _level: Option<i32>) -> io::Result<Box<dyn Write + Send>> {
+    unreachable!("zstd_level rejects compression without the zstd feature")
+}
+
+impl Broker for FileBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("file://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (path, params) = file_option(option);
+        if path.is_empty() {
+            return Err(NetpipeError::invalid("missing file path"));
+        }
+        let encoding = Encoding::parse(
+            params.get("compress").map(String::as_str),
+            params.get("level").map(String::as_str),
+        )?;
+        let key = params
+            .get("key")
+            .map(|key| Selector::parse(key))
+            .transpose()
+            .map_err(NetpipeError::invalid)?;
+        let max_open = params
+            .get("max-open")
+            .map(|max| max.parse().ok().filter(|&max| max > 0))
+            .unwrap_or(Some(MAX_OPEN_FILES))
+            .ok_or_else(|| NetpipeError::invalid("invalid max-open"))?;
+        let format = match params.get("format").map(String::as_str) {
+            None | Some("lines") => Format::Lines,
+            Some("csv") => Format::Csv(Csv::new(
+                params
+                    .get("fields")
+                    .map(|fields| fields.split(',').map(String::from).collect()),
+            )),
+            Some("json") => Format::Json(0),
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown format {other}"))),
+        };
+        if params.contains_key("fields") && !matches!(format, Format::Csv(_)) {
+            return Err(NetpipeError::invalid("fields requires format=csv"));
+        }
+        let rotation = Rotation {
+            max_size: params
+                .get("rotate")
+                .map(|size| parse_size(size).map(|size| size as u64))
+                .transpose()
+                .map_err(|_| NetpipeError::invalid("invalid rotate"))?,
+            every: params
+                .get("rotate-every")
+                .map(|every| parse_duration(every))
+                .transpose()
+                .map_err(|_| NetpipeError::invalid("invalid rotate-every"))?,
+        };
+        let rotation =
+            (rotation.max_size.is_some() || rotation.every.is_some()).then_some(rotation);
+        let truncate = match params.get("mode").map(String::as_str) {
+            None | Some("append") => false,
+            Some("truncate") => true,
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown mode {other}"))),
+        };
+        if truncate && path.contains("{key}") {
+            return Err(NetpipeError::invalid(
+                "mode=truncate doesn't apply to {key} in the path",
+            ));
+        }
+        if rotation.is_some() && path.contains("{key}") {
+            return Err(NetpipeError::invalid(
+                "rotation doesn't apply to {key} in the path",
+            ));
+        }
+        let writers = match (path.contains("{key}"), key) {
+            (false, None) => {
+                Writers::Single(encoding.open(path, truncate).map_err(NetpipeError::Io)?)
+            }
+            (true, Some(key)) => Writers::Keyed(Keyed {
+                key,
+                max_open,
+                encoding,
+                open: vec![],
+            }),
+            (true, None) => return Err(NetpipeError::invalid("{key} in the path requires key")),
+            (false, Some(_)) => {
+                return Err(NetpipeError::invalid("key requires {key} in the path"))
+            }
+        };
+        self.outputs.borrow_mut().push(Output {
+            path: path.to_string(),
+            format,
+            writers,
+            rotation,
+            encoding,
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .outputs
+            .borrow_mut()
+            .iter_mut()
+            .map(|output| {
+                output.write(message).map_err(|e| {
+                    eprintln!("Failed to write to {}: {e}.", output.path);
+                    NetpipeError::Io(e)
+                })
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+
+    fn shutdown(&self) {
+        for output in self.outputs.borrow_mut().iter_mut() {
+            let sinks: Vec<&mut Sink> = match &mut output.writers {
+                Writers::Single(sink) => vec![sink],
+                Writers::Keyed(keyed) => keyed.open.iter_mut().map(|(_, sink)| sink).collect(),
+            };
+            for sink in sinks {
+                if let Err(e) = sink.writer.flush() {
+                    eprintln!("Failed to flush {}: {e}.", output.path);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use super::{Broker, CLOSE_TIMEOUT};
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, ListenOptions};
+use crate::payload::Payload;
+use crate::threads;
+use std::{
+    cell::RefCell,
+    convert::Infallible,
+    mem,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::body::Body;
+use tonic::codegen::{http, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tonic_prost::ProstCodec;
+use url::Url;
+
+/// Messages held for a client that doesn't keep up, beyond which it is dropped.
+const CLIENT_QUEUE: usize = 1024;
+
+/// The messages of `proto/netpipe.proto`.
+#[derive(Clone, PartialEq, prost::Message)]
+struct SubscribeRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Message {
+    #[prost(oneof = "Data", tags = "1, 2")]
+    data: Option<Data>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+enum Data {
+    #[prost(string, tag = "1")]
+    Text(String),
+    #[prost(bytes = "vec", tag = "2")]
+    Binary(Vec<u8>),
+}
+
+struct Client {
+    peer: String,
+    messages: mpsc::Sender<std::result::Result<Message, Status>>,
+}
+
+impl Client {
+    /// Queues the end of the stream, with an OK status, after the messages queued before it,
+    /// returning whether it is queued or the client is gone.
+    fn end(&self) -> bool {
+        !matches!(
+            self.messages.try_send(Err(Status::ok(""))),
+            Err(TrySendError::Full(_))
+        )
+    }
+}
+
+type Clients = Arc<Mutex<Vec<Client>>>;
+
+/// The `netpipe.Netpipe` service, as tonic-build would generate it for the one method.
+#[derive(Clone)]
+struct Netpipe {
+    clients: Clients,
+}
+
+impl NamedService for Netpipe {
+    const NAME: &'static str = "netpipe.Netpipe";
+}
+
+impl ServerStreamingService<SubscribeRequest> for Netpipe {
+    type Response = Message;
+    type ResponseStream = ReceiverStream<std::result::Result<Message, Status>>;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<SubscribeRequest>) -> Self::Future {
+        let peer = request
+            .remote_addr()
+            .map_or_else(|| "unknown peer".to_string(), |addr| addr.to_string());
+        let (messages, stream) = mpsc::channel(CLIENT_QUEUE);
+        eprintln!("Connected: {peer}.");
+        self.clients.lock().unwrap().push(Client { peer, messages });
+        Box::pin(async move { Ok(Response::new(ReceiverStream::new(stream))) })
+    }
+}
+
+impl<B> Service<http::Request<B>> for Netpipe
+where
+    B: tonic::codegen::Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<std::result::Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            Ok(match request.uri().path() {
+                "/netpipe.Netpipe/Subscribe" => {
+                    let mut grpc = Grpc::new(ProstCodec::default());
+                    grpc.server_streaming(service, request).await
+                }
+                _ => Status::unimplemented("").into_http(),
+            })
+        })
+    }
+}
+
+/// Listens on `grpc-listen://<host>:<port>` and streams each message to every client of the
+/// `Subscribe` method of the `netpipe.Netpipe` service in `proto/netpipe.proto`, as text or,
+/// if it isn't valid UTF-8, as binary. A client that falls too far behind, or goes away, is
+/// dropped.
+pub struct GrpcBroker {
+    listeners: RefCell<Vec<Clients>>,
+    listen: ListenOptions,
+}
+
+impl GrpcBroker {
+    pub fn new(listen: ListenOptions) -> GrpcBroker {
+        GrpcBroker {
+            listeners: RefCell::new(vec![]),
+            listen,
+        }
+    }
+}
+
+impl Broker for GrpcBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("grpc-listen://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host_port = format!(
+            "{}:{}",
+            url.host_str()
+                .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let listener = net::listen(host_port, &self.listen).map_err(NetpipeError::Bind)?;
+        listener.set_nonblocking(true).map_err(NetpipeError::Bind)?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(NetpipeError::Io)?;
+
+        let clients = Clients::default();
+        let service = Netpipe {
+            clients: clients.clone(),
+        };
+        let option = option.to_string();
+        threads::spawn(
+            threads::name("grpc-serve", listener.local_addr()),
+            move || {
+                let served = runtime.block_on(async move {
+                    let listener = tokio::net::TcpListener::from_std(listener)?;
+                    Server::builder()
+                        .add_service(service)
+                        .serve_with_incoming(TcpListenerStream::new(listener))
+                        .await
+                        .map_err(std::io::Error::other)
+                });
+                if let Err(e) = served {
+                    eprintln!("Stopped serving {option}: {e}.");
+                }
+            },
+        );
+        self.listeners.borrow_mut().push(clients);
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let data = match message {
+            Payload::Text(text) => Data::Text(text.clone()),
+            Payload::Binary(bytes) => Data::Binary(bytes.clone()),
+        };
+        let message = Message { data: Some(data) };
+        for clients in self.listeners.borrow().iter() {
+            clients.lock().unwrap().retain(|client| {
+                match client.messages.try_send(Ok(message.clone())) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        eprintln!("Dropped {}: too far behind.", client.peer);
+                        false
+                    }
+                    Err(TrySendError::Closed(_)) => {
+                        eprintln!("Disconnected: {}.", client.peer);
+                        false
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Ends each client's stream once it has taken what is still queued for it, so that it can
+    /// tell netpipe leaving from a lost connection, for up to [`CLOSE_TIMEOUT`] in all.
+    fn shutdown(&self) {
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        let mut clients: Vec<_> = self
+            .listeners
+            .borrow()
+            .iter()
+            .flat_map(|clients| mem::take(&mut *clients.lock().unwrap()))
+            .map(|client| (client, false))
+            .collect();
+        loop {
+            // A stream ends once the client has its status, and the server lets go of it.
+            clients.retain_mut(|(client, ended)| {
+                *ended = *ended || client.end();
+                !client.messages.is_closed()
+            });
+            if clients.is_empty() {
+                return;
+            }
+            if Instant::now() >= deadline {
+                eprintln!("Gave up ending {} gRPC streams.", clients.len());
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
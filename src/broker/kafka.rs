@@ -0,0 +1,375 @@
+use super::{file_option, unless_all_failed, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::options::parse_duration;
+use crate::payload::Payload;
+use crate::retry::Backoff;
+use crate::selector::Selector;
+use crate::threads;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer};
+use rdkafka::ClientContext;
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    mem,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How long pending messages may take to be delivered when netpipe exits.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages in a batch, and how long the first of them waits for the others, unless the
+/// destination says otherwise.
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_BATCH_LINGER: Duration = Duration::from_secs(1);
+
+/// Logs the messages that a topic didn't take even after librdkafka's own retries.
+struct Deliveries {
+    topic: String,
+}
+
+impl ClientContext for Deliveries {}
+
+impl ProducerContext for Deliveries {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: ()) {
+        if let Err((e, _)) = result {
+            eprintln!("Failed to deliver to {}: {e}.", self.topic);
+        }
+    }
+}
+
+/// Where records go, shared with the thread sending batches that are due.
+struct Target {
+    name: String,
+    producer: ThreadedProducer<Deliveries>,
+    partition: Option<i32>,
+}
+
+impl Target {
+    /// Hands a record to the producer, waiting with backoff while its queue is full.
+    fn produce(&self, key: Option<&[u8]>, payload: &[u8]) -> std::result::Result<(), KafkaError> {
+        let mut record = BaseRecord::to(&self.name).payload(payload);
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+        if let Some(partition) = self.partition {
+            record = record.partition(partition);
+        }
+        let backoff = Backoff::default();
+        let mut attempt = 0;
+        loop {
+            match self.producer.send(record) {
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), rejected))
+                    if !backoff.exhausted(attempt + 1) =>
+                {
+                    let delay = backoff.jittered_delay(attempt);
+                    eprintln!("Queue for {} is full. Retrying in {delay:?}.", self.name);
+                    thread::sleep(delay);
+                    record = rejected;
+                    attempt += 1;
+                }
+                result => return result.map_err(|(e, _)| e),
+            }
+        }
+    }
+}
+
+/// How the messages of a batch make up one record.
+#[derive(Clone, Copy)]
+enum BatchFormat {
+    /// A JSON array, holding messages that are JSON as they are and others as strings.
+    Json,
+    /// The messages one per line.
+    Lines,
+}
+
+impl BatchFormat {
+    fn encode(self, messages: &[Payload]) -> Vec<u8> {
+        match self {
+            BatchFormat::Json => {
+                let values: Vec<Value> = messages
+                    .iter()
+                    .map(|message| {
+                        let text = message.to_text();
+                        serde_json::from_str(&text).unwrap_or_else(|_| Value::from(text))
+                    })
+                    .collect();
+                serde_json::to_vec(&values).unwrap()
+            }
+            BatchFormat::Lines => messages
+                .iter()
+                .map(Payload::as_bytes)
+                .collect::<Vec<_>>()
+                .join(&b'\n'),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    messages: Vec<Payload>,
+    /// When the batch has to go, whether full or not.
+    due: Option<Instant>,
+    closed: bool,
+}
+
+/// Gathers messages into batches of up to `size`, each produced as one record once full or
+/// `linger` after its first message came, whichever is first.
+struct Batch {
+    target: Arc<Target>,
+    size: usize,
+    linger: Duration,
+    format: BatchFormat,
+    pending: Mutex<Pending>,
+    came: Condvar,
+}
+
+impl Batch {
+    fn push(&self, message: &Payload) -> std::result::Result<(), KafkaError> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.messages.push(message.clone());
+        if pending.messages.len() >= self.size {
+            pending.due = None;
+            let messages = mem::take(&mut pending.messages);
+            drop(pending);
+            return self.produce(&messages);
+        }
+        if pending.due.is_none() {
+            pending.due = Some(Instant::now() + self.linger);
+            self.came.notify_one();
+        }
+        Ok(())
+    }
+
+    fn produce(&self, messages: &[Payload]) -> std::result::Result<(), KafkaError> {
+        self.target.produce(None, &self.format.encode(messages))
+    }
+
+    /// Produces the batches that are due, until the destination is closed, and then what is
+    /// left.
+    fn linger(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let wait = pending
+                .due
+                .map(|due| due.saturating_duration_since(Instant::now()));
+            if !pending.closed && wait != Some(Duration::ZERO) {
+                pending = match wait {
+                    Some(wait) => self.came.wait_timeout(pending, wait).unwrap().0,
+                    None => self.came.wait(pending).unwrap(),
+                };
+                continue;
+            }
+            pending.due = None;
+            let messages = mem::take(&mut pending.messages);
+            let closed = pending.closed;
+            drop(pending);
+            if !messages.is_empty() {
+                if let Err(e) = self.produce(&messages) {
+                    eprintln!(
+                        "Failed to produce a batch of {} messages to {}: {e}.",
+                        messages.len(),
+                        self.target.name
+                    );
+                }
+            }
+            if closed {
+                return;
+            }
+            pending = self.pending.lock().unwrap();
+        }
+    }
+}
+
+struct Topic {
+    target: Arc<Target>,
+    key: Option<Selector>,
+    /// The batch under way, and the thread producing it when it is due.
+    batch: Option<(Arc<Batch>, JoinHandle<()>)>,
+}
+
+impl Topic {
+    fn produce(&self, message: &Payload) -> std::result::Result<(), KafkaError> {
+        if let Some((batch, _)) = &self.batch {
+            return batch.push(message);
+        }
+        let key = self
+            .key
+            .as_ref()
+            .and_then(|key| key.select(&message.to_text()));
+        self.target
+            .produce(key.as_ref().map(String::as_bytes), message.as_bytes())
+    }
+
+    /// Produces what is left of the batch, and waits for the messages still pending to be
+    /// delivered, for up to [`FLUSH_TIMEOUT`].
+    fn close(&mut self) {
+        if let Some((batch, lingering)) = self.batch.take() {
+            batch.pending.lock().unwrap().closed = true;
+            batch.came.notify_one();
+            let _ = lingering.join();
+        }
+        if let Err(e) = self.target.producer.flush(FLUSH_TIMEOUT) {
+            eprintln!("Failed to flush to {}: {e}.", self.target.name);
+        }
+    }
+}
+
+/// Splits a `kafka://<host:port>[,<host:port>...]/<topic>?<query>` option into the bootstrap
+/// servers, the topic and the query parameters.
+pub fn kafka_option(option: &str) -> Result<(&str, &str, HashMap<String, String>)> {
+    let (path, params) = file_option(option);
+    match path.split_once('/') {
+        Some((servers, topic)) if !servers.is_empty() && !topic.is_empty() => {
+            Ok((servers, topic, params))
+        }
+        _ => Err(NetpipeError::invalid(
+            "expected kafka://<host:port>/<topic>",
+        )),
+    }
+}
+
+/// Produces each message to the topic given by `kafka://<host:port>[,<host:port>...]/<topic>`,
+/// keyed by the field picked by the `?key=` selector, if given, and to the partition given by
+/// `?partition=`, if any, and otherwise to the one chosen by the partitioner. Other query
+/// parameters are passed to librdkafka as configuration properties, such as `?acks=all` or
+/// `?partitioner=murmur2`. Messages are delivered in the background, and those still pending
+/// are flushed when netpipe exits normally.
+///
+/// With `?batch_size=` or `?batch_linger=`, messages are instead gathered into batches of up
+/// to that many (1000 by default), each produced as one record once full or once its first
+/// message has waited that long (1s by default). `?batch_format=lines`, the default, puts
+/// one message per line, and `?batch_format=json` makes a JSON array of them. What is left of
+/// a batch is produced when netpipe exits normally. Batches have no key.
+pub struct KafkaBroker {
+    connect_timeout: Duration,
+    topics: RefCell<Vec<Topic>>,
+}
+
+impl KafkaBroker {
+    pub fn new(connect_timeout: Duration) -> KafkaBroker {
+        KafkaBroker {
+            connect_timeout,
+            topics: RefCell::new(vec![]),
+        }
+    }
+}
+
+impl Broker for KafkaBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("kafka://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (servers, topic, mut params) = kafka_option(option)?;
+        let key = params
+            .remove("key")
+            .map(|key| Selector::parse(&key))
+            .transpose()
+            .map_err(NetpipeError::invalid)?;
+        let partition = params
+            .remove("partition")
+            .map(|partition| partition.parse())
+            .transpose()
+            .map_err(NetpipeError::invalid)?;
+        let batch_size = params
+            .remove("batch_size")
+            .map(|size| {
+                size.parse()
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| NetpipeError::invalid(format!("invalid batch_size {size}")))
+            })
+            .transpose()?;
+        let batch_linger = params
+            .remove("batch_linger")
+            .map(|linger| {
+                parse_duration(&linger)
+                    .ok()
+                    .filter(|linger| !linger.is_zero())
+                    .ok_or_else(|| NetpipeError::invalid(format!("invalid batch_linger {linger}")))
+            })
+            .transpose()?;
+        let batched = batch_size.is_some() || batch_linger.is_some();
+        let batch_format = params.remove("batch_format");
+        if !batched && batch_format.is_some() {
+            return Err(NetpipeError::invalid(
+                "batch_format requires batch_size or batch_linger",
+            ));
+        }
+        let format = match batch_format.as_deref() {
+            None | Some("lines") => BatchFormat::Lines,
+            Some("json") => BatchFormat::Json,
+            Some(other) => {
+                return Err(NetpipeError::invalid(format!(
+                    "unknown batch_format {other}"
+                )))
+            }
+        };
+        if batched && key.is_some() {
+            return Err(NetpipeError::invalid("batches can't have a key"));
+        }
+
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", servers);
+        for (name, value) in params {
+            config.set(name, value);
+        }
+        let producer: ThreadedProducer<Deliveries> = config
+            .create_with_context(Deliveries {
+                topic: topic.to_string(),
+            })
+            .map_err(NetpipeError::invalid)?;
+        // Creating the producer doesn't connect, so check that the brokers are reachable.
+        producer
+            .client()
+            .fetch_metadata(Some(topic), self.connect_timeout)
+            .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+        let target = Arc::new(Target {
+            name: topic.to_string(),
+            producer,
+            partition,
+        });
+        let batch = batched.then(|| {
+            let batch = Arc::new(Batch {
+                target: target.clone(),
+                size: batch_size.unwrap_or(DEFAULT_BATCH_SIZE),
+                linger: batch_linger.unwrap_or(DEFAULT_BATCH_LINGER),
+                format,
+                pending: Mutex::default(),
+                came: Condvar::new(),
+            });
+            let lingering = batch.clone();
+            let handle = threads::spawn("kafka-linger", move || lingering.linger());
+            (batch, handle)
+        });
+        self.topics.borrow_mut().push(Topic { target, key, batch });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .topics
+            .borrow()
+            .iter()
+            .map(|topic| {
+                topic.produce(message).map_err(|e| {
+                    eprintln!("Failed to produce to {}: {e}.", topic.target.name);
+                    NetpipeError::Io(std::io::Error::other(e))
+                })
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+
+    fn shutdown(&self) {
+        for topic in self.topics.borrow_mut().iter_mut() {
+            topic.close();
+        }
+    }
+}
@@ -0,0 +1,586 @@
+use super::{file_option, Broker};
+use crate::aws::{self, Credentials};
+use crate::error::{NetpipeError, Result};
+use crate::options::parse_duration;
+use crate::payload::Payload;
+use crate::retry::Backoff;
+use crate::selector::Selector;
+use crate::stats::{DropCounters, Reason};
+use crate::threads;
+use base64::Engine;
+use rand::Rng;
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use std::{
+    cell::RefCell,
+    mem,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// What Kinesis takes in one `PutRecords` request at most: records, and bytes of data and
+/// partition keys, both in all and for each record.
+const MAX_RECORDS: usize = 500;
+const MAX_REQUEST_BYTES: usize = 5 << 20;
+const MAX_RECORD_BYTES: usize = 1 << 20;
+/// How long the first message of a batch waits for others, unless the destination says
+/// otherwise.
+const DEFAULT_BATCH_LINGER: Duration = Duration::from_secs(1);
+/// How long a request may take before it is retried.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+/// The errors with which Kinesis turns down a request or record for now rather than for good.
+const THROTTLED: [&str; 4] = [
+    "ProvisionedThroughputExceededException",
+    "LimitExceededException",
+    "KMSThrottlingException",
+    "InternalFailure",
+];
+
+/// Why a request didn't go through.
+enum Failure {
+    /// Throttled, or a failure of the service or the network, which may pass.
+    Retry(String),
+    /// Anything else, such as a stream that doesn't exist or credentials it doesn't take.
+    Reject(String),
+}
+
+struct Record {
+    message: Payload,
+    key: String,
+}
+
+impl Record {
+    /// The bytes the record counts for against Kinesis's limits.
+    fn size(&self) -> usize {
+        self.message.len() + self.key.len()
+    }
+}
+
+/// A stream that records go to, shared with the thread putting batches that are due.
+struct Stream {
+    name: String,
+    endpoint: Url,
+    region: String,
+    credentials: Credentials,
+    client: Client,
+    drops: DropCounters,
+}
+
+impl Stream {
+    fn call(&self, operation: &str, body: &Value) -> std::result::Result<Value, Failure> {
+        let body = serde_json::to_vec(body).unwrap();
+        let target = format!("Kinesis_20131202.{operation}");
+        let headers = [("content-type", CONTENT_TYPE), ("x-amz-target", &target)];
+        let signed = aws::sign(
+            &self.credentials,
+            "kinesis",
+            &self.region,
+            &self.endpoint,
+            &headers,
+            &body,
+            chrono::Utc::now(),
+        );
+        let mut request = self.client.post(self.endpoint.clone());
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        for (name, value) in signed {
+            request = request.header(name, value);
+        }
+        let response = request
+            .body(body)
+            .send()
+            .map_err(|e| Failure::Retry(e.to_string()))?;
+        let status = response.status();
+        let body = response
+            .bytes()
+            .map_err(|e| Failure::Retry(e.to_string()))?;
+        let value: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        if status.is_success() {
+            return Ok(value);
+        }
+        // Such as `com.amazonaws.kinesis.v20131202#ResourceNotFoundException`.
+        let kind = value["__type"].as_str().unwrap_or_default();
+        let kind = kind.rsplit('#').next().unwrap_or_default();
+        let message = value["message"]
+            .as_str()
+            .or(value["Message"].as_str())
+            .unwrap_or_default();
+        let error = format!("{status} {kind} {message}").trim().to_string();
+        match status.is_server_error() || THROTTLED.contains(&kind) {
+            true => Err(Failure::Retry(error)),
+            false => Err(Failure::Reject(error)),
+        }
+    }
+
+    /// Counts records given up on, and sends them to the deadletter destination.
+    fn reject(&self, records: &[Record], reason: &str) {
+        eprintln!(
+            "Kinesis stream {} rejected {} records: {reason}.",
+            self.name,
+            records.len()
+        );
+        for record in records {
+            self.drops.count_lost(Reason::Rejected, &record.message);
+        }
+    }
+
+    /// Puts records in as few requests as Kinesis's limits allow.
+    fn put(&self, records: Vec<Record>) {
+        let mut request = vec![];
+        let mut bytes = 0;
+        for record in records {
+            if request.len() == MAX_RECORDS || bytes + record.size() > MAX_REQUEST_BYTES {
+                self.put_records(mem::take(&mut request));
+                bytes = 0;
+            }
+            bytes += record.size();
+            request.push(record);
+        }
+        if !request.is_empty() {
+            self.put_records(request);
+        }
+    }
+
+    /// Puts records with one `PutRecords` request, retrying with backoff those that were
+    /// throttled until they go through or the attempts run out.
+    fn put_records(&self, mut records: Vec<Record>) {
+        let backoff = Backoff::default();
+        let mut attempt = 0;
+        loop {
+            let entries: Vec<Value> = records
+                .iter()
+                .map(|record| {
+                    json!({
+                        "Data": base64::engine::general_purpose::STANDARD
+                            .encode(record.message.as_bytes()),
+                        "PartitionKey": record.key,
+                    })
+                })
+                .collect();
+            let body = json!({ "StreamName": self.name, "Records": entries });
+            let reason = match self.call("PutRecords", &body) {
+                Ok(response) => {
+                    let results = response["Records"].as_array().cloned().unwrap_or_default();
+                    if results.len() != records.len() {
+                        return self.reject(&records, "the response doesn't account for them");
+                    }
+                    let mut throttled = vec![];
+                    let mut rejected = vec![];
+                    let mut reason = String::new();
+                    for (record, result) in records.into_iter().zip(results) {
+                        let Some(code) = result["ErrorCode"].as_str() else {
+                            continue;
+                        };
+                        reason =
+                            format!("{code} {}", result["ErrorMessage"].as_str().unwrap_or(""));
+                        match THROTTLED.contains(&code) {
+                            true => throttled.push(record),
+                            false => rejected.push(record),
+                        }
+                    }
+                    if !rejected.is_empty() {
+                        self.reject(&rejected, reason.trim());
+                    }
+                    records = throttled;
+                    reason.trim().to_string()
+                }
+                Err(Failure::Retry(reason)) => reason,
+                Err(Failure::Reject(reason)) => return self.reject(&records, &reason),
+            };
+            if records.is_empty() {
+                return;
+            }
+            if backoff.exhausted(attempt + 1) {
+                return self.reject(&records, &reason);
+            }
+            let delay = backoff.jittered_delay(attempt);
+            eprintln!(
+                "Kinesis stream {} took all but {} records: {reason}. Retrying in {delay:?}.",
+                self.name,
+                records.len()
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    records: Vec<Record>,
+    /// When the batch has to go, whether full or not.
+    due: Option<Instant>,
+    closed: bool,
+}
+
+/// Gathers records into batches of up to `size`, each put once full or `linger` after its
+/// first record came, whichever is first.
+struct Batch {
+    stream: Arc<Stream>,
+    size: usize,
+    linger: Duration,
+    pending: Mutex<Pending>,
+    came: Condvar,
+}
+
+impl Batch {
+    fn push(&self, record: Record) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.records.push(record);
+        if pending.records.len() >= self.size {
+            pending.due = None;
+            let records = mem::take(&mut pending.records);
+            drop(pending);
+            return self.stream.put(records);
+        }
+        if pending.due.is_none() {
+            pending.due = Some(Instant::now() + self.linger);
+            self.came.notify_one();
+        }
+    }
+
+    /// Puts each batch once it is due, and what is left once the batch is closed.
+    fn linger(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let wait = pending
+                .due
+                .map(|due| due.saturating_duration_since(Instant::now()));
+            if !pending.closed && wait != Some(Duration::ZERO) {
+                pending = match wait {
+                    Some(wait) => self.came.wait_timeout(pending, wait).unwrap().0,
+                    None => self.came.wait(pending).unwrap(),
+                };
+                continue;
+            }
+            pending.due = None;
+            let records = mem::take(&mut pending.records);
+            let closed = pending.closed;
+            drop(pending);
+            if !records.is_empty() {
+                self.stream.put(records);
+            }
+            if closed {
+                return;
+            }
+            pending = self.pending.lock().unwrap();
+        }
+    }
+}
+
+struct Destination {
+    key: Option<Selector>,
+    batch: Arc<Batch>,
+    lingering: Option<JoinHandle<()>>,
+}
+
+impl Destination {
+    /// Puts what is left of the batch, and waits for it to be done with.
+    fn close(&mut self) {
+        self.batch.pending.lock().unwrap().closed = true;
+        self.batch.came.notify_one();
+        if let Some(lingering) = self.lingering.take() {
+            let _ = lingering.join();
+        }
+    }
+}
+
+/// Puts each message as a record to the AWS Kinesis data stream given by
+/// `kinesis://<stream>`, in the region of `?region=` or else of `AWS_REGION`, signing the
+/// requests with the credentials found as the AWS CLI finds them, short of instance metadata
+/// and SSO. The partition key is the field picked by the `?partition_key=` selector, and a
+/// random one for messages without it, or all messages if it isn't given. `?endpoint=` sends
+/// the requests elsewhere than to the region's endpoint, such as to LocalStack.
+///
+/// Records are gathered into `PutRecords` requests of up to `?batch_size=` (500, the most
+/// Kinesis takes, by default), each sent once full or once its first record has waited
+/// `?batch_linger=` (1s by default). Records that Kinesis throttles are retried with backoff,
+/// and those it rejects or that run out of attempts, as well as messages over its limit of
+/// 1 MiB, are counted as rejected. What is left of a batch is put when netpipe exits normally.
+pub struct KinesisBroker {
+    connect_timeout: Duration,
+    destinations: RefCell<Vec<Destination>>,
+    drops: DropCounters,
+}
+
+impl KinesisBroker {
+    pub fn new(connect_timeout: Duration, drops: DropCounters) -> KinesisBroker {
+        KinesisBroker {
+            connect_timeout,
+            destinations: RefCell::new(vec![]),
+            drops,
+        }
+    }
+}
+
+impl Broker for KinesisBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("kinesis://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (name, mut params) = file_option(option);
+        if name.is_empty() || name.contains('/') {
+            return Err(NetpipeError::invalid("expected kinesis://<stream>"));
+        }
+        let region = params
+            .remove("region")
+            .or_else(aws::region)
+            .ok_or_else(|| {
+                NetpipeError::invalid("missing region, given by ?region= or AWS_REGION")
+            })?;
+        let endpoint = params
+            .remove("endpoint")
+            .unwrap_or_else(|| format!("https://kinesis.{region}.amazonaws.com/"));
+        let endpoint = Url::parse(&endpoint)
+            .map_err(|_| NetpipeError::invalid(format!("invalid endpoint {endpoint}")))?;
+        if endpoint.host_str().is_none() {
+            return Err(NetpipeError::invalid(format!(
+                "invalid endpoint {endpoint}"
+            )));
+        }
+        let key = params
+            .remove("partition_key")
+            .map(|key| Selector::parse(&key))
+            .transpose()
+            .map_err(NetpipeError::invalid)?;
+        let size = match params.remove("batch_size") {
+            None => MAX_RECORDS,
+            Some(size) => size
+                .parse()
+                .ok()
+                .filter(|size| (1..=MAX_RECORDS).contains(size))
+                .ok_or_else(|| NetpipeError::invalid(format!("invalid batch_size {size}")))?,
+        };
+        let linger = match params.remove("batch_linger") {
+            None => DEFAULT_BATCH_LINGER,
+            Some(linger) => parse_duration(&linger)
+                .ok()
+                .filter(|linger| !linger.is_zero())
+                .ok_or_else(|| NetpipeError::invalid(format!("invalid batch_linger {linger}")))?,
+        };
+        let credentials = Credentials::load().map_err(NetpipeError::invalid)?;
+        let client = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(NetpipeError::invalid)?;
+        let stream = Arc::new(Stream {
+            name: name.to_string(),
+            endpoint,
+            region,
+            credentials,
+            client,
+            drops: self.drops.clone(),
+        });
+        // Check that the stream is there, and takes the credentials, before any record is put.
+        let described = stream.call("DescribeStreamSummary", &json!({ "StreamName": name }));
+        if let Err(Failure::Retry(e) | Failure::Reject(e)) = described {
+            return Err(NetpipeError::Connect(option.to_string(), e.into()));
+        }
+        let batch = Arc::new(Batch {
+            stream,
+            size,
+            linger,
+            pending: Mutex::default(),
+            came: Condvar::new(),
+        });
+        let lingering = batch.clone();
+        let lingering = threads::spawn("kinesis-linger", move || lingering.linger());
+        self.destinations.borrow_mut().push(Destination {
+            key,
+            batch,
+            lingering: Some(lingering),
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        for destination in self.destinations.borrow().iter() {
+            let key = destination
+                .key
+                .as_ref()
+                .and_then(|key| key.select(&message.to_text()))
+                .unwrap_or_else(|| rand::thread_rng().gen::<u64>().to_string());
+            let record = Record {
+                message: message.clone(),
+                key,
+            };
+            if record.size() > MAX_RECORD_BYTES {
+                destination
+                    .batch
+                    .stream
+                    .reject(&[record], "over the limit of 1 MiB per record");
+                continue;
+            }
+            destination.batch.push(record);
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        for destination in self.destinations.borrow_mut().iter_mut() {
+            destination.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::{self, Receiver},
+        },
+    };
+
+    /// Serves Kinesis on loopback with `respond` giving the body of the response to each
+    /// request, and hands over the bodies of the requests as they come.
+    fn serve(respond: impl Fn(&Value) -> Value + Send + Sync + 'static) -> (Url, Receiver<Value>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let respond = Arc::new(respond);
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (respond, tx) = (respond.clone(), tx.clone());
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut length = 0;
+                        let mut line = String::new();
+                        while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                            let header = line.to_ascii_lowercase();
+                            if let Some(value) = header.strip_prefix("content-length:") {
+                                length = value.trim().parse().unwrap();
+                            }
+                            line.clear();
+                        }
+                        if line.is_empty() {
+                            return;
+                        }
+                        let mut body = vec![0; length];
+                        reader.read_exact(&mut body).unwrap();
+                        let request: Value = serde_json::from_slice(&body).unwrap();
+                        let response = respond(&request).to_string();
+                        let _ = tx.send(request);
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\n\r\n{response}",
+                            response.len()
+                        )
+                        .unwrap();
+                    }
+                });
+            }
+        });
+        (url, rx)
+    }
+
+    /// A response that takes all the records of the request.
+    fn took_all(request: &Value) -> Value {
+        let records = request["Records"].as_array().unwrap();
+        json!({ "Records": vec![json!({ "SequenceNumber": "1" }); records.len()] })
+    }
+
+    fn stream(endpoint: Url, drops: DropCounters) -> Stream {
+        Stream {
+            name: "test".to_string(),
+            endpoint,
+            region: "us-east-1".to_string(),
+            credentials: Credentials::new("AKIDEXAMPLE", "secret"),
+            client: Client::new(),
+            drops,
+        }
+    }
+
+    fn record(message: &str) -> Record {
+        Record {
+            message: Payload::Text(message.to_string()),
+            key: "k".to_string(),
+        }
+    }
+
+    fn sizes(requests: &Receiver<Value>) -> Vec<usize> {
+        requests
+            .try_iter()
+            .map(|request| request["Records"].as_array().unwrap().len())
+            .collect()
+    }
+
+    #[test]
+    fn puts_are_split_at_the_most_records_a_request_takes() {
+        let (endpoint, requests) = serve(took_all);
+        let drops = DropCounters::default();
+        let records = (0..MAX_RECORDS + 1).map(|_| record("m")).collect();
+        stream(endpoint, drops.clone()).put(records);
+        assert_eq!(sizes(&requests), [MAX_RECORDS, 1]);
+        assert_eq!(drops.report(), None);
+    }
+
+    #[test]
+    fn puts_are_split_at_the_most_bytes_a_request_takes() {
+        let (endpoint, requests) = serve(took_all);
+        let drops = DropCounters::default();
+        // Five of these, with their key, make up the most a request takes.
+        let message = "m".repeat(MAX_REQUEST_BYTES / 5 - 1);
+        let records = (0..6).map(|_| record(&message)).collect();
+        stream(endpoint, drops.clone()).put(records);
+        assert_eq!(sizes(&requests), [5, 1]);
+        assert_eq!(drops.report(), None);
+    }
+
+    #[test]
+    fn throttled_records_are_retried_and_the_others_rejected() {
+        let attempts = AtomicUsize::new(0);
+        let (endpoint, requests) = serve(move |request| {
+            if attempts.fetch_add(1, Ordering::Relaxed) > 0 {
+                return took_all(request);
+            }
+            json!({
+                "FailedRecordCount": 2,
+                "Records": [
+                    {
+                        "ErrorCode": "ProvisionedThroughputExceededException",
+                        "ErrorMessage": "Rate exceeded",
+                    },
+                    { "ErrorCode": "InvalidArgumentException", "ErrorMessage": "Bad record" },
+                    { "SequenceNumber": "1" },
+                ],
+            })
+        });
+        let (deadletter, dead) = mpsc::channel();
+        let drops = DropCounters::default().with_deadletter(deadletter);
+        let records = ["throttled", "rejected", "taken"].map(record).into();
+        stream(endpoint, drops.clone()).put(records);
+        let requests: Vec<_> = requests.try_iter().collect();
+        assert_eq!(requests.len(), 2);
+        let data = |request: &Value| {
+            request["Records"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|record| record["Data"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let encode = |message: &str| base64::engine::general_purpose::STANDARD.encode(message);
+        assert_eq!(data(&requests[1]), [encode("throttled")]);
+        assert_eq!(
+            drops.report().as_deref(),
+            Some("Dropped messages: rejected 1.")
+        );
+        assert_eq!(
+            dead.try_iter().collect::<Vec<_>>(),
+            [Payload::Text("rejected".to_string())]
+        );
+    }
+}
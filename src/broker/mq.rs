@@ -0,0 +1,96 @@
+use super::{unless_all_failed, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::mqueue::Queue;
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use std::{cell::RefCell, io};
+
+/// Priorities go from 0 up to this, as Linux allows.
+const MAX_PRIORITY: u32 = 32767;
+
+struct Destination {
+    option: String,
+    queue: Queue,
+    priority: u32,
+    /// Whether a full queue is waited on rather than the message dropped.
+    block: bool,
+}
+
+/// Sends each message to the POSIX message queue `mq://<name>`, creating it if missing, with
+/// the priority given by `?priority=` (0 by default, up to 32767), as readers get the highest
+/// first. While the queue is full, messages are dropped, or with `?policy=block` sending waits
+/// for room. Messages longer than the queue takes fail.
+pub struct MessageQueueBroker {
+    destinations: RefCell<Vec<Destination>>,
+    drops: DropCounters,
+}
+
+impl MessageQueueBroker {
+    pub fn new(drops: DropCounters) -> MessageQueueBroker {
+        MessageQueueBroker {
+            destinations: RefCell::new(vec![]),
+            drops,
+        }
+    }
+}
+
+impl Broker for MessageQueueBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("mq://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (_, params) = crate::broker::file_option(option);
+        let block = match params.get("policy").map(String::as_str) {
+            None | Some("drop") => false,
+            Some("block") => true,
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown policy {other}"))),
+        };
+        let priority = match params.get("priority") {
+            None => 0,
+            Some(priority) => priority
+                .parse()
+                .ok()
+                .filter(|&priority| priority <= MAX_PRIORITY)
+                .ok_or_else(|| NetpipeError::invalid(format!("invalid priority {priority}")))?,
+        };
+        let flags = match block {
+            true => libc::O_WRONLY,
+            false => libc::O_WRONLY | libc::O_NONBLOCK,
+        };
+        let (queue, _) = Queue::open(option, flags)?;
+        self.destinations.borrow_mut().push(Destination {
+            option: option.to_string(),
+            queue,
+            priority,
+            block,
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .destinations
+            .borrow()
+            .iter()
+            .map(|destination| loop {
+                match destination
+                    .queue
+                    .send(message.as_bytes(), destination.priority)
+                {
+                    Ok(()) => return Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock && !destination.block => {
+                        self.drops.count_lost(Reason::QueueFull, message);
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to send to {}: {e}.", destination.option);
+                        return Err(NetpipeError::Io(e));
+                    }
+                }
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+}
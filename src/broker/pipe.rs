@@ -0,0 +1,75 @@
+use super::{unless_all_failed, write_line, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::receiver::pipe_path;
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io,
+};
+
+struct Pipe {
+    path: String,
+    file: Option<File>,
+}
+
+impl Pipe {
+    fn open(path: &str) -> io::Result<File> {
+        OpenOptions::new().write(true).open(path)
+    }
+
+    /// Writes a line, reopening the pipe once if the server went away since the last write.
+    fn write_line(&mut self, message: &Payload) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            match write_line(file, message) {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("Pipe disconnected: {}: {e}.", self.path),
+            }
+        }
+        self.file = None;
+        let mut file = Self::open(&self.path)?;
+        write_line(&mut file, message)?;
+        eprintln!("Pipe reconnected: {}.", self.path);
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+/// Writes each message as a line to a named pipe served by another process.
+pub struct PipeBroker {
+    pipes: RefCell<Vec<Pipe>>,
+}
+
+impl PipeBroker {
+    pub fn new() -> PipeBroker {
+        PipeBroker {
+            pipes: RefCell::new(vec![]),
+        }
+    }
+}
+
+impl Broker for PipeBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("pipe://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let path = pipe_path(option);
+        let file = Pipe::open(&path).map_err(|e| NetpipeError::Connect(path.clone(), e.into()))?;
+        self.pipes.borrow_mut().push(Pipe {
+            path,
+            file: Some(file),
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .pipes
+            .borrow_mut()
+            .iter_mut()
+            .map(|pipe| pipe.write_line(message).map_err(NetpipeError::Io))
+            .collect();
+        unless_all_failed(results)
+    }
+}
@@ -0,0 +1,99 @@
+use super::{query_param, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::http::{self, Response};
+use crate::net::{self, ListenOptions};
+use crate::payload::Payload;
+use regex::Regex;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+use url::Url;
+
+type Gauges = Arc<Mutex<BTreeMap<String, f64>>>;
+
+/// Exposes numeric messages as Prometheus gauges on an HTTP endpoint, e.g.
+/// `prometheus://0.0.0.0:9100/metrics`. A message is either `<name> <value>` or a bare number,
+/// which is reported under the destination's `name` query parameter (`netpipe_value` by
+/// default). Anything else is skipped.
+pub struct PrometheusBroker {
+    metric_name: Regex,
+    endpoints: RefCell<Vec<(String, Gauges)>>,
+    listen: ListenOptions,
+}
+
+impl PrometheusBroker {
+    pub fn new(listen: ListenOptions) -> PrometheusBroker {
+        PrometheusBroker {
+            metric_name: Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap(),
+            endpoints: RefCell::new(vec![]),
+            listen,
+        }
+    }
+
+    fn parse<'a>(&self, message: &'a str, default_name: &'a str) -> Option<(&'a str, f64)> {
+        let mut parts = message.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(value), None, None) => Some((default_name, value.parse().ok()?)),
+            (Some(name), Some(value), None) if self.metric_name.is_match(name) => {
+                Some((name, value.parse().ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Broker for PrometheusBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("prometheus://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host_port = format!(
+            "{}:{}",
+            url.host_str()
+                .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let name = query_param(&url, "name").unwrap_or_else(|| "netpipe_value".to_string());
+        if !self.metric_name.is_match(&name) {
+            return Err(NetpipeError::invalid(format!("invalid metric name {name}")));
+        }
+        let path = match url.path() {
+            "/" => "/metrics".to_string(),
+            path => path.to_string(),
+        };
+
+        let gauges = Gauges::default();
+        let gauges_ref = gauges.clone();
+        http::serve(
+            net::listen(host_port, &self.listen).map_err(NetpipeError::Bind)?,
+            move |request_path| {
+                if request_path != path {
+                    return Response::not_found();
+                }
+                let mut body = String::new();
+                for (name, value) in gauges_ref.lock().unwrap().iter() {
+                    writeln!(body, "# TYPE {name} gauge\n{name} {value}").unwrap();
+                }
+                Response::new(200, "text/plain; version=0.0.4", body)
+            },
+        );
+        self.endpoints.borrow_mut().push((name, gauges));
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let message = message.to_text();
+        for (default_name, gauges) in self.endpoints.borrow().iter() {
+            if let Some((name, value)) = self.parse(&message, default_name) {
+                gauges.lock().unwrap().insert(name.to_string(), value);
+            }
+        }
+        Ok(())
+    }
+}
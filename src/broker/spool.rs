@@ -0,0 +1,334 @@
+use crate::payload::Payload;
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Bytes written to a segment before starting the next one.
+const SEGMENT_SIZE: u64 = 16 << 20;
+/// A record's length, as four bytes, and whether it is text (0) or binary (1).
+const HEADER: usize = 5;
+/// Where `next` is kept: the segment number and the offset into it, as eight bytes each.
+const CURSOR: &str = "cursor";
+
+struct Segment {
+    number: u64,
+    len: u64,
+}
+
+/// Messages held on disk until a destination takes them, in a directory of numbered segment
+/// files and a cursor recording how far into the oldest one has been delivered. Segments are
+/// deleted once delivered, and the messages left over by a previous run come first. Writes
+/// aren't synced, so the spool survives netpipe exiting or crashing, but not the machine
+/// going down.
+pub struct Spool {
+    dir: PathBuf,
+    /// The bytes the segments may take up in total.
+    max: u64,
+    /// Oldest first; messages are appended to the last one.
+    segments: VecDeque<Segment>,
+    size: u64,
+    /// The number the next segment gets.
+    next_number: u64,
+    /// Delivered bytes of the oldest segment.
+    offset: u64,
+    reader: Option<BufReader<File>>,
+    /// The oldest undelivered message, once read, with the length of its record.
+    next: Option<(Payload, u64)>,
+    writer: Option<File>,
+    cursor: File,
+}
+
+impl Spool {
+    /// Opens the spool in `dir`, creating it if needed, and cuts off a message that a
+    /// previous run was interrupted writing.
+    pub fn open(dir: &Path, max: u64) -> io::Result<Spool> {
+        fs::create_dir_all(dir)?;
+        let mut numbers = vec![];
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let Some(number) = name.to_str().and_then(|name| name.strip_suffix(".seg")) else {
+                continue;
+            };
+            if let Ok(number) = number.parse::<u64>() {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+
+        let mut cursor = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(CURSOR))?;
+        let mut position = [0; 16];
+        let (delivered, mut offset) = match cursor.read_exact(&mut position) {
+            Ok(()) => (
+                u64::from_le_bytes(position[..8].try_into().unwrap()),
+                u64::from_le_bytes(position[8..].try_into().unwrap()),
+            ),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => (0, 0),
+            Err(e) => return Err(e),
+        };
+
+        let mut segments = VecDeque::new();
+        for number in numbers {
+            let path = segment_path(dir, number);
+            // Delivered already, but not deleted before the previous run ended.
+            if number < delivered {
+                fs::remove_file(path)?;
+                continue;
+            }
+            let len = fs::metadata(&path)?.len();
+            segments.push_back(Segment { number, len });
+        }
+        if segments
+            .front()
+            .is_none_or(|segment| segment.number != delivered)
+        {
+            offset = 0;
+        }
+        if let Some(last) = segments.back_mut() {
+            let path = segment_path(dir, last.number);
+            let complete = complete_len(&path)?;
+            if complete < last.len {
+                eprintln!(
+                    "Cut off an incomplete message at the end of {}.",
+                    path.display()
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(complete)?;
+                last.len = complete;
+            }
+        }
+        let next_number = segments.back().map_or(delivered, |last| last.number + 1);
+        let mut spool = Spool {
+            dir: dir.to_path_buf(),
+            max,
+            size: segments.iter().map(|segment| segment.len).sum(),
+            segments,
+            next_number,
+            offset,
+            reader: None,
+            next: None,
+            writer: None,
+            cursor,
+        };
+        // Also deletes the oldest segment if it was delivered in full.
+        spool.advance(0)?;
+        Ok(spool)
+    }
+
+    /// Appends a message, unless the spool is full.
+    pub fn push(&mut self, message: &Payload) -> io::Result<bool> {
+        let (kind, data) = match message {
+            Payload::Text(text) => (0, text.as_bytes()),
+            Payload::Binary(bytes) => (1, bytes.as_slice()),
+        };
+        let len = u32::try_from(data.len()).map_err(io::Error::other)?;
+        let record_len = (HEADER + data.len()) as u64;
+        if self.size + record_len > self.max {
+            return Ok(false);
+        }
+        let room = self
+            .segments
+            .back()
+            .is_some_and(|last| last.len + record_len <= SEGMENT_SIZE);
+        if !room || self.writer.is_none() {
+            self.open_writer(room)?;
+        }
+        let mut record = Vec::with_capacity(HEADER + data.len());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.push(kind);
+        record.extend_from_slice(data);
+        self.writer.as_mut().unwrap().write_all(&record)?;
+        self.segments.back_mut().unwrap().len += record_len;
+        self.size += record_len;
+        Ok(true)
+    }
+
+    /// Appends to the last segment, if it has room, or a new one otherwise.
+    fn open_writer(&mut self, room: bool) -> io::Result<()> {
+        if let (true, Some(last)) = (room, self.segments.back()) {
+            let path = segment_path(&self.dir, last.number);
+            self.writer = Some(OpenOptions::new().append(true).open(path)?);
+            return Ok(());
+        }
+        let number = self.next_number;
+        let path = segment_path(&self.dir, number);
+        self.writer = Some(OpenOptions::new().append(true).create(true).open(path)?);
+        self.segments.push_back(Segment { number, len: 0 });
+        self.next_number += 1;
+        Ok(())
+    }
+
+    /// The oldest message not delivered yet.
+    pub fn peek(&mut self) -> io::Result<Option<&Payload>> {
+        let Some(head) = self.segments.front() else {
+            return Ok(None);
+        };
+        if self.next.is_none() {
+            if self.reader.is_none() {
+                let mut file = File::open(segment_path(&self.dir, head.number))?;
+                file.seek(SeekFrom::Start(self.offset))?;
+                self.reader = Some(BufReader::new(file));
+            }
+            let record = read_record(self.reader.as_mut().unwrap())?
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "truncated spool"))?;
+            self.next = Some(record);
+        }
+        Ok(self.next.as_ref().map(|(message, _)| message))
+    }
+
+    /// Marks the message returned by [`Spool::peek`] delivered.
+    pub fn pop(&mut self) -> io::Result<()> {
+        match self.next.take() {
+            Some((_, record_len)) => self.advance(record_len),
+            None => Ok(()),
+        }
+    }
+
+    fn advance(&mut self, record_len: u64) -> io::Result<()> {
+        let Some(head) = self.segments.front() else {
+            return Ok(());
+        };
+        self.offset += record_len;
+        let mut number = head.number;
+        if self.offset >= head.len {
+            fs::remove_file(segment_path(&self.dir, head.number))?;
+            self.size -= head.len;
+            self.segments.pop_front();
+            self.reader = None;
+            self.offset = 0;
+            number += 1;
+            if self.segments.is_empty() {
+                self.writer = None;
+            }
+        }
+        let mut position = [0; 16];
+        position[..8].copy_from_slice(&number.to_le_bytes());
+        position[8..].copy_from_slice(&self.offset.to_le_bytes());
+        self.cursor.seek(SeekFrom::Start(0))?;
+        self.cursor.write_all(&position)
+    }
+}
+
+fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{number:020}.seg"))
+}
+
+/// Reads a record, returning it with its length, or `None` at the end of the segment or a
+/// record cut off there.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<(Payload, u64)>> {
+    let mut header = [0; HEADER];
+    let mut data = vec![];
+    let read = reader.read_exact(&mut header).and_then(|()| {
+        data = vec![0; u32::from_le_bytes(header[..4].try_into().unwrap()) as usize];
+        reader.read_exact(&mut data)
+    });
+    match read {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let record_len = (HEADER + data.len()) as u64;
+    let message = match header[4] {
+        0 => Payload::Text(
+            String::from_utf8(data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+        ),
+        1 => Payload::Binary(data),
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "corrupt spool")),
+    };
+    Ok(Some((message, record_len)))
+}
+
+/// The length of the complete records at the start of a segment.
+fn complete_len(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut len = 0;
+    while let Some((_, record_len)) = read_record(&mut reader)? {
+        len += record_len;
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: &str) -> Payload {
+        Payload::Text(message.to_string())
+    }
+
+    /// Takes the oldest message, if any.
+    fn take(spool: &mut Spool) -> Option<Payload> {
+        let message = spool.peek().unwrap().cloned();
+        spool.pop().unwrap();
+        message
+    }
+
+    #[test]
+    fn messages_left_over_come_first_after_a_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        for message in [text("a"), Payload::Binary(vec![0, 0xff]), text("c")] {
+            assert!(spool.push(&message).unwrap());
+        }
+        assert_eq!(take(&mut spool), Some(text("a")));
+        drop(spool);
+
+        let mut spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        assert_eq!(take(&mut spool), Some(Payload::Binary(vec![0, 0xff])));
+        assert!(spool.push(&text("d")).unwrap());
+        assert_eq!(take(&mut spool), Some(text("c")));
+        assert_eq!(take(&mut spool), Some(text("d")));
+        assert_eq!(take(&mut spool), None);
+        // Delivered segments are deleted, leaving only the cursor.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn a_record_cut_off_is_dropped_on_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        assert!(spool.push(&text("a")).unwrap());
+        assert!(spool.push(&text("b")).unwrap());
+        drop(spool);
+        // The header of a five-byte text message, and just one byte of it.
+        let mut segment = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 0))
+            .unwrap();
+        segment.write_all(&[5, 0, 0, 0, 0, b'x']).unwrap();
+        drop(segment);
+
+        let mut spool = Spool::open(dir.path(), 1 << 20).unwrap();
+        assert!(spool.push(&text("c")).unwrap());
+        assert_eq!(take(&mut spool), Some(text("a")));
+        assert_eq!(take(&mut spool), Some(text("b")));
+        assert_eq!(take(&mut spool), Some(text("c")));
+        assert_eq!(take(&mut spool), None);
+    }
+
+    #[test]
+    fn a_full_spool_turns_messages_away_until_a_segment_is_delivered() {
+        let dir = tempfile::tempdir().unwrap();
+        let max = 2 * (HEADER as u64 + 1);
+        let mut spool = Spool::open(dir.path(), max).unwrap();
+        assert!(spool.push(&text("a")).unwrap());
+        assert!(spool.push(&text("b")).unwrap());
+        assert!(!spool.push(&text("c")).unwrap());
+        // The room taken up by a segment comes back once it is deleted.
+        assert_eq!(take(&mut spool), Some(text("a")));
+        assert!(!spool.push(&text("c")).unwrap());
+        assert_eq!(take(&mut spool), Some(text("b")));
+        assert!(spool.push(&text("c")).unwrap());
+        assert_eq!(take(&mut spool), Some(text("c")));
+        assert_eq!(take(&mut spool), None);
+    }
+}
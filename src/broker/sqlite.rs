@@ -0,0 +1,161 @@
+use super::{file_option, unless_all_failed, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::retry::Backoff;
+use regex::Regex;
+use rusqlite::{Connection, ErrorCode};
+use std::{
+    cell::RefCell,
+    io, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Rows are committed in batches of at most this many, or after `COMMIT_INTERVAL`, whichever
+/// comes first, rather than one transaction per message.
+const COMMIT_ROWS: usize = 1000;
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Table {
+    path: String,
+    connection: Connection,
+    insert: String,
+    pending: usize,
+    last_commit: Instant,
+}
+
+impl Table {
+    fn open(path: &str, table: &str) -> rusqlite::Result<Table> {
+        let connection = Connection::open(path)?;
+        // SQLite itself retries while another connection holds the lock, for up to this long.
+        connection.busy_timeout(Duration::from_secs(5))?;
+        connection.execute_batch(&format!(
+            r#"CREATE TABLE IF NOT EXISTS "{table}" (ts INTEGER NOT NULL, message TEXT NOT NULL)"#
+        ))?;
+        Ok(Table {
+            path: path.to_string(),
+            connection,
+            insert: format!(r#"INSERT INTO "{table}" (ts, message) VALUES (?1, ?2)"#),
+            pending: 0,
+            last_commit: Instant::now(),
+        })
+    }
+
+    fn insert(&mut self, message: &Payload) -> rusqlite::Result<()> {
+        if self.connection.is_autocommit() {
+            self.connection.execute_batch("BEGIN")?;
+        }
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64;
+        let mut insert = self.connection.prepare_cached(&self.insert)?;
+        match message {
+            Payload::Text(text) => insert.execute((ts, text))?,
+            Payload::Binary(bytes) => insert.execute((ts, bytes))?,
+        };
+        drop(insert);
+        self.pending += 1;
+        if self.pending >= COMMIT_ROWS || self.last_commit.elapsed() >= COMMIT_INTERVAL {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> rusqlite::Result<()> {
+        if !self.connection.is_autocommit() {
+            self.connection.execute_batch("COMMIT")?;
+        }
+        self.pending = 0;
+        self.last_commit = Instant::now();
+        Ok(())
+    }
+
+    /// Inserts a message, retrying with backoff while the database stays locked beyond the
+    /// busy timeout.
+    fn insert_retrying(&mut self, message: &Payload) -> rusqlite::Result<()> {
+        let backoff = Backoff::default();
+        let mut attempt = 0;
+        loop {
+            match self.insert(message) {
+                Err(e) if is_locked(&e) && !backoff.exhausted(attempt + 1) => {
+                    let delay = backoff.jittered_delay(attempt);
+                    eprintln!("Database {} is locked. Retrying in {delay:?}.", self.path);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Err(e) = self.commit() {
+            eprintln!("Failed to commit to {}: {e}.", self.path);
+        }
+    }
+}
+
+fn is_locked(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
+
+/// Appends each message, with the time in milliseconds since the Unix epoch, as a row of the
+/// table given by `sqlite://<path>?table=<name>` (`messages` by default), which is created
+/// if missing. Binary messages are stored as blobs. Rows still awaiting a commit are
+/// committed when netpipe exits normally.
+pub struct SqliteBroker {
+    identifier: Regex,
+    tables: RefCell<Vec<Table>>,
+}
+
+impl SqliteBroker {
+    pub fn new() -> SqliteBroker {
+        SqliteBroker {
+            identifier: Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap(),
+            tables: RefCell::new(vec![]),
+        }
+    }
+}
+
+impl Broker for SqliteBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("sqlite://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let (path, mut params) = file_option(option);
+        if path.is_empty() {
+            return Err(NetpipeError::invalid("missing database path"));
+        }
+        let table = params
+            .remove("table")
+            .unwrap_or_else(|| "messages".to_string());
+        if !self.identifier.is_match(&table) {
+            return Err(NetpipeError::invalid(format!("invalid table name {table}")));
+        }
+        let table =
+            Table::open(path, &table).map_err(|e| NetpipeError::Bind(io::Error::other(e)))?;
+        self.tables.borrow_mut().push(table);
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .tables
+            .borrow_mut()
+            .iter_mut()
+            .map(|table| {
+                table.insert_retrying(message).map_err(|e| {
+                    eprintln!("Failed to insert into {}: {e}.", table.path);
+                    NetpipeError::Io(io::Error::other(e))
+                })
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+}
@@ -0,0 +1,271 @@
+use super::{unless_all_failed, write_line, Broker, CLOSE_TIMEOUT};
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, ListenOptions};
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::threads;
+use std::{
+    cell::RefCell,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// A client that takes longer than this to accept a write is dropped, so that it can't hold
+/// up the others.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
+    stream: TcpStream,
+    /// The client's address, which is not that of the stream when it comes through a load
+    /// balancer.
+    peer: String,
+}
+
+type Clients = Arc<Mutex<Vec<Client>>>;
+
+/// Listens on `tcp-raw-listen://<host>:<port>` and writes the bytes of each message, with
+/// nothing added, to every connected client, for clients that do their own framing or to
+/// bridge an already framed binary protocol. `tcp-listen://<host>:<port>` writes each message
+/// as a line instead. Clients that fail a write are dropped. With `proxy_protocol`, each
+/// connection starts with a PROXY protocol header naming the client.
+pub struct TcpRawBroker {
+    /// The clients of each listener, and what follows each message written to them.
+    listeners: RefCell<Vec<(Clients, &'static [u8])>>,
+    listen: ListenOptions,
+    proxy_protocol: bool,
+}
+
+impl TcpRawBroker {
+    pub fn new(listen: ListenOptions, proxy_protocol: bool) -> TcpRawBroker {
+        TcpRawBroker {
+            listeners: RefCell::new(vec![]),
+            listen,
+            proxy_protocol,
+        }
+    }
+}
+
+/// Sets up an accepted connection, reading its PROXY protocol header first if it has one.
+fn accept(mut stream: TcpStream, proxy_protocol: bool) -> io::Result<Client> {
+    let client = match proxy_protocol {
+        true => net::read_proxy_header(&mut stream)?,
+        false => None,
+    };
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let peer = match client {
+        Some(client) => client.to_string(),
+        None => stream
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string()),
+    };
+    Ok(Client { stream, peer })
+}
+
+/// Ends a connection with a FIN after what was written to it, and waits until `deadline` for
+/// the other end to close its side, since closing a socket with data from it still unread
+/// resets the connection, which may cut off what it is yet to read.
+fn close(mut stream: TcpStream, deadline: Instant) {
+    if stream.shutdown(Shutdown::Write).is_err() {
+        return;
+    }
+    let mut unread = [0; 4096];
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() || stream.set_read_timeout(Some(timeout)).is_err() {
+            return;
+        }
+        if matches!(stream.read(&mut unread), Ok(0) | Err(_)) {
+            return;
+        }
+    }
+}
+
+impl Broker for TcpRawBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("tcp-raw-listen://") || option.starts_with("tcp-listen://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host_port = format!(
+            "{}:{}",
+            url.host_str()
+                .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let listener = net::listen(host_port, &self.listen).map_err(NetpipeError::Bind)?;
+
+        let clients = Clients::default();
+        let clients_ref = clients.clone();
+        let proxy_protocol = self.proxy_protocol;
+        let client_thread = threads::name("tcp-conn", listener.local_addr());
+        threads::spawn(
+            threads::name("tcp-accept", listener.local_addr()),
+            move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            net::accept_failed(&e);
+                            continue;
+                        }
+                    };
+                    // Each connection is set up on a thread of its own, so that a client that
+                    // is slow to send its PROXY protocol header doesn't hold up the others.
+                    let clients = clients_ref.clone();
+                    threads::spawn(client_thread.clone(), move || {
+                        let client = match accept(stream, proxy_protocol) {
+                            Ok(client) => client,
+                            Err(e) => {
+                                eprintln!("Failed to accept connection: {e}.");
+                                return;
+                            }
+                        };
+                        eprintln!("Connected: {}.", client.peer);
+                        clients.lock().unwrap().push(client);
+                    });
+                }
+            },
+        );
+        let terminator: &[u8] = match option.starts_with("tcp-listen://") {
+            true => b"\n",
+            false => b"",
+        };
+        self.listeners.borrow_mut().push((clients, terminator));
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        for (clients, terminator) in self.listeners.borrow().iter() {
+            let framed = [message.as_bytes(), terminator].concat();
+            clients
+                .lock()
+                .unwrap()
+                .retain_mut(|client| match client.stream.write_all(&framed) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("Dropped {}: {e}.", client.peer);
+                        false
+                    }
+                });
+        }
+        Ok(())
+    }
+
+    /// Closes the connections as [`close`] does, giving the clients up to [`CLOSE_TIMEOUT`] in
+    /// all.
+    fn shutdown(&self) {
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        for (clients, _) in self.listeners.borrow().iter() {
+            for client in clients.lock().unwrap().drain(..) {
+                close(client.stream, deadline);
+            }
+        }
+    }
+}
+
+struct Connection {
+    option: String,
+    host_port: String,
+    stream: Option<TcpStream>,
+}
+
+impl Connection {
+    fn connect(&self, timeout: Duration) -> io::Result<TcpStream> {
+        let stream = net::connect(&self.host_port, timeout)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    /// Writes a line, reconnecting once if the server went away since the last write.
+    fn write_line(&mut self, message: &Payload, timeout: Duration) -> io::Result<()> {
+        if let Some(stream) = &mut self.stream {
+            match write_line(stream, message) {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("Disconnected: {}: {e}.", self.option),
+            }
+        }
+        self.stream = None;
+        let mut stream = self.connect(timeout)?;
+        write_line(&mut stream, message)?;
+        self.stream = Some(stream);
+        eprintln!("Reconnected: {}.", self.option);
+        Ok(())
+    }
+}
+
+/// Connects to `tcp://<host>:<port>` and writes each message as a line, for equipment that
+/// takes newline-separated records on a plain socket. A connection that fails a write is
+/// reestablished once before the message counts as failed, and tried again with the next
+/// message after that.
+pub struct TcpBroker {
+    connections: RefCell<Vec<Connection>>,
+    connect_timeout: Duration,
+}
+
+impl TcpBroker {
+    pub fn new(connect_timeout: Duration) -> TcpBroker {
+        TcpBroker {
+            connections: RefCell::new(vec![]),
+            connect_timeout,
+        }
+    }
+}
+
+impl Broker for TcpBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("tcp://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host_port = format!(
+            "{}:{}",
+            url.host_str()
+                .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let mut connection = Connection {
+            option: option.to_string(),
+            host_port,
+            stream: None,
+        };
+        let stream = reconnect(&Backoff::default(), option, || {
+            connection.connect(self.connect_timeout)
+        })
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+        connection.stream = Some(stream);
+        self.connections.borrow_mut().push(connection);
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .connections
+            .borrow_mut()
+            .iter_mut()
+            .map(|connection| {
+                connection
+                    .write_line(message, self.connect_timeout)
+                    .map_err(NetpipeError::Io)
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+
+    /// Closes the connections as [`close`] does, giving the servers up to [`CLOSE_TIMEOUT`] in
+    /// all.
+    fn shutdown(&self) {
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        for connection in self.connections.borrow_mut().iter_mut() {
+            if let Some(stream) = connection.stream.take() {
+                close(stream, deadline);
+            }
+        }
+    }
+}
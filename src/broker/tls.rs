@@ -0,0 +1,276 @@
+use super::spool::Spool;
+use super::{query_param, unless_all_failed, write_line, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, Stream};
+use crate::options::parse_size;
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::stats::{DropCounters, Reason};
+use crate::tls;
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// The bytes a spool may take up, unless the destination says otherwise.
+const DEFAULT_SPOOL_MAX: u64 = 1 << 30;
+
+struct Connection {
+    option: String,
+    host_port: String,
+    /// The name sent to the server and expected on its certificate.
+    name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    stream: Option<Stream>,
+    spool: Option<Spool>,
+    /// When to try connecting again while spooling, and the failed attempts so far.
+    retry: Option<(Instant, u32)>,
+}
+
+impl Connection {
+    fn connect(&mut self, timeout: Duration) -> io::Result<()> {
+        let sock = net::connect(&self.host_port, timeout)?;
+        self.stream = Some(tls::connect(sock, &self.name, &self.config, timeout)?);
+        Ok(())
+    }
+
+    /// Writes a line, reconnecting once if the server went away since the last write.
+    fn write_line(&mut self, message: &Payload, timeout: Duration) -> io::Result<()> {
+        if let Some(stream) = &mut self.stream {
+            match write_line(stream, message).and_then(|()| stream.flush()) {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("Disconnected: {}: {e}.", self.option),
+            }
+        }
+        self.stream = None;
+        self.connect(timeout)?;
+        let stream = self.stream.as_mut().unwrap();
+        write_line(stream, message)?;
+        stream.flush()?;
+        eprintln!("Reconnected: {}.", self.option);
+        Ok(())
+    }
+
+    /// Writes what is spooled and then the line, or spools the line if the server can't take
+    /// it. Reconnecting is retried with backoff, rather than with each message.
+    fn write_spooled(
+        &mut self,
+        message: &Payload,
+        timeout: Duration,
+        drops: &DropCounters,
+    ) -> io::Result<()> {
+        if self.replay(timeout) {
+            let stream = self.stream.as_mut().unwrap();
+            match write_line(stream, message).and_then(|()| stream.flush()) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    eprintln!("Disconnected: {}: {e}.", self.option);
+                    self.stream = None;
+                }
+            }
+        }
+        if !self.spool.as_mut().unwrap().push(message)? {
+            drops.count_lost(Reason::Backlog, message);
+        }
+        Ok(())
+    }
+
+    /// Connects, if it is time to try, and writes what is spooled, returning whether it all
+    /// went through.
+    fn replay(&mut self, timeout: Duration) -> bool {
+        if self.stream.is_none() {
+            if self.retry.is_some_and(|(at, _)| Instant::now() < at) {
+                return false;
+            }
+            if let Err(e) = self.connect(timeout) {
+                let attempt = self.retry.map_or(0, |(_, attempt)| attempt + 1);
+                let delay = Backoff::default().jittered_delay(attempt);
+                eprintln!(
+                    "Failed to connect to {}: {e}. Spooling, retrying in {delay:?}.",
+                    self.option
+                );
+                self.retry = Some((Instant::now() + delay, attempt));
+                return false;
+            }
+            eprintln!("Reconnected: {}.", self.option);
+            self.retry = None;
+        }
+        let (Some(spool), Some(stream)) = (&mut self.spool, &mut self.stream) else {
+            unreachable!("only called for spooling connections while connected");
+        };
+        let mut replayed = 0;
+        let result = loop {
+            let message = match spool.peek() {
+                Ok(Some(message)) => message,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(format!("failed to read the spool: {e}")),
+            };
+            if let Err(e) = write_line(stream, message).and_then(|()| stream.flush()) {
+                self.stream = None;
+                break Err(e.to_string());
+            }
+            if let Err(e) = spool.pop() {
+                break Err(format!("failed to update the spool: {e}"));
+            }
+            replayed += 1;
+        };
+        if replayed > 0 {
+            eprintln!("Replayed {replayed} spooled messages to {}.", self.option);
+        }
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Stopped replaying to {}: {e}.", self.option);
+                false
+            }
+        }
+    }
+
+    /// Ends the session properly, so that the server can tell it from a truncated one.
+    fn close(&mut self) {
+        if let Some(Stream::TlsClient(mut stream)) = self.stream.take() {
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+    }
+}
+
+/// Connects to `tls://<host>:<port>` and writes each message as a line over TLS, for systems
+/// that expect a plain TLS socket rather than a WebSocket. The server's certificate has to
+/// be valid for the host and signed by a bundled root or one from `--tls-ca`. A connection
+/// that fails a write is reestablished once before the message counts as failed.
+///
+/// With `?spool=<dir>`, messages the server can't take are held in that directory instead,
+/// up to `?spool_max=` bytes (1GB by default) beyond which they are dropped, and written in
+/// order once it is back, ahead of the next message. Reconnecting is retried with backoff for as
+/// long as it takes, and the spool is kept across restarts, so such a destination also
+/// starts while the server is out of reach. A message cut off by a lost connection is
+/// written again, so it may arrive twice. Each destination needs a directory of its own.
+///
+/// `?sni=<name>` sends that name to the server instead of the host and expects it on the
+/// certificate, for connecting through a proxy or by address. `?verify_hostname=false`
+/// accepts a certificate for any name, as long as it is signed by a trusted root.
+pub struct TlsBroker {
+    connections: RefCell<Vec<Connection>>,
+    ca: Option<String>,
+    connect_timeout: Duration,
+    drops: DropCounters,
+}
+
+impl TlsBroker {
+    pub fn new(ca: Option<String>, connect_timeout: Duration, drops: DropCounters) -> TlsBroker {
+        TlsBroker {
+            connections: RefCell::new(vec![]),
+            ca,
+            connect_timeout,
+            drops,
+        }
+    }
+}
+
+impl Broker for TlsBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("tls://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| NetpipeError::invalid("missing host"))?;
+        let host_port = format!(
+            "{host}:{}",
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let spool_max = query_param(&url, "spool_max")
+            .map(|size| {
+                parse_size(&size)
+                    .ok()
+                    .filter(|&size| size > 0)
+                    .ok_or_else(|| NetpipeError::invalid(format!("invalid spool_max {size}")))
+            })
+            .transpose()?;
+        let spool = match query_param(&url, "spool") {
+            Some(dir) => {
+                let max = spool_max.map_or(DEFAULT_SPOOL_MAX, |max| max as u64);
+                let spool = Spool::open(Path::new(&dir), max).map_err(NetpipeError::Io)?;
+                Some(spool)
+            }
+            None if spool_max.is_some() => {
+                return Err(NetpipeError::invalid("spool_max requires spool"))
+            }
+            None => None,
+        };
+        let name = query_param(&url, "sni").unwrap_or_else(|| host.to_string());
+        let name = ServerName::try_from(name.clone())
+            .map_err(|_| NetpipeError::invalid(format!("invalid sni {name}")))?;
+        let verify_hostname = match query_param(&url, "verify_hostname").as_deref() {
+            None | Some("true") => true,
+            Some("false") => false,
+            Some(other) => {
+                return Err(NetpipeError::invalid(format!(
+                    "invalid verify_hostname {other}"
+                )))
+            }
+        };
+        let mut connection = Connection {
+            option: option.to_string(),
+            host_port,
+            name,
+            config: tls::client_config(self.ca.as_deref(), verify_hostname)?,
+            stream: None,
+            spool,
+            retry: None,
+        };
+        if connection.spool.is_some() {
+            // Connects when the first message comes, and spools it if that fails.
+            self.connections.borrow_mut().push(connection);
+            return Ok(());
+        }
+        // Only getting through to the server is retried; a certificate that doesn't check out
+        // won't on a second try either.
+        let sock = reconnect(&Backoff::default(), option, || {
+            net::connect(&connection.host_port, self.connect_timeout)
+        })
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+        let stream = tls::connect(
+            sock,
+            &connection.name,
+            &connection.config,
+            self.connect_timeout,
+        )
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+        connection.stream = Some(stream);
+        self.connections.borrow_mut().push(connection);
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .connections
+            .borrow_mut()
+            .iter_mut()
+            .map(|connection| {
+                match connection.spool {
+                    Some(_) => connection.write_spooled(message, self.connect_timeout, &self.drops),
+                    None => connection.write_line(message, self.connect_timeout),
+                }
+                .map_err(NetpipeError::Io)
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+
+    fn shutdown(&self) {
+        for connection in self.connections.borrow_mut().iter_mut() {
+            connection.close();
+        }
+    }
+}
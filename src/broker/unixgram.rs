@@ -0,0 +1,88 @@
+use super::{unless_all_failed, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use std::{cell::RefCell, io, os::unix::net::UnixDatagram};
+
+struct Destination {
+    path: String,
+    /// Whether nothing was bound to the path when last sent to.
+    unread: bool,
+}
+
+/// Sends each message as one datagram to the Unix datagram socket at `unixgram://<path>`, as
+/// local logging and metrics agents listen on. While nothing is bound to the path, messages
+/// are dropped rather than failing the destination, so that the agent can start, or restart,
+/// after netpipe.
+pub struct UnixDatagramBroker {
+    socket: UnixDatagram,
+    destinations: RefCell<Vec<Destination>>,
+    drops: DropCounters,
+}
+
+impl UnixDatagramBroker {
+    pub fn new(drops: DropCounters) -> UnixDatagramBroker {
+        UnixDatagramBroker {
+            socket: UnixDatagram::unbound().expect("failed to create a Unix datagram socket"),
+            destinations: RefCell::new(vec![]),
+            drops,
+        }
+    }
+}
+
+/// Whether an error sending to a path means that nothing is bound to it.
+fn is_unread(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+    )
+}
+
+impl Broker for UnixDatagramBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("unixgram://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let path = &option["unixgram://".len()..];
+        if path.is_empty() {
+            return Err(NetpipeError::invalid("missing path"));
+        }
+        self.destinations.borrow_mut().push(Destination {
+            path: path.to_string(),
+            unread: false,
+        });
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        let mut destinations = self.destinations.borrow_mut();
+        let results = destinations
+            .iter_mut()
+            .map(
+                |destination| match self.socket.send_to(message.as_bytes(), &destination.path) {
+                    Ok(_) => {
+                        if destination.unread {
+                            eprintln!("Reader back on {}.", destination.path);
+                            destination.unread = false;
+                        }
+                        Ok(())
+                    }
+                    Err(e) if is_unread(&e) => {
+                        if !destination.unread {
+                            eprintln!("No reader on {}, dropping messages: {e}.", destination.path);
+                            destination.unread = true;
+                        }
+                        self.drops.count(Reason::NoReader);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to send to {}: {e}.", destination.path);
+                        Err(NetpipeError::Io(e))
+                    }
+                },
+            )
+            .collect();
+        unless_all_failed(results)
+    }
+}
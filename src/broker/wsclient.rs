@@ -0,0 +1,200 @@
+use super::{Broker, CLOSE_TIMEOUT};
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, Stream};
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::stats::{DropCounters, Reason};
+use std::{
+    cell::RefCell,
+    io::ErrorKind::{ConnectionAborted, ConnectionReset},
+    time::{Duration, Instant},
+};
+use tungstenite::error::Error::{Io, Protocol};
+use tungstenite::error::ProtocolError::ResetWithoutClosingHandshake;
+use tungstenite::{client, Message, WebSocket};
+use url::Url;
+
+/// A server that takes longer than this to accept a write is given up on, and connected to
+/// again.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Connection {
+    option: String,
+    host_port: String,
+    /// The URL of the upgrade request, with `ws://` in place of `ws-connect://`.
+    request: Url,
+    socket: Option<WebSocket<Stream>>,
+    /// When to try connecting again, and the failed attempts so far.
+    retry: Option<(Instant, u32)>,
+}
+
+impl Connection {
+    /// Connects and performs the handshake, neither of which may take longer than `timeout`.
+    fn connect(
+        &self,
+        timeout: Duration,
+    ) -> std::result::Result<WebSocket<Stream>, Box<dyn std::error::Error + Send + Sync>> {
+        let stream = net::connect(&self.host_port, timeout)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let stream = Stream::Tcp(stream);
+        stream.set_read_timeout(Some(timeout))?;
+        let (socket, _) = client(self.request.clone(), stream)
+            .map_err(|e| NetpipeError::Protocol(e.to_string()))?;
+        socket.get_ref().set_read_timeout(None)?;
+        Ok(socket)
+    }
+
+    /// Connects again if the server went away and it is time to try, returning whether there
+    /// is a socket to write to.
+    fn ensure_connected(&mut self, timeout: Duration) -> bool {
+        if self.socket.is_some() {
+            return true;
+        }
+        if self.retry.is_some_and(|(at, _)| Instant::now() < at) {
+            return false;
+        }
+        match self.connect(timeout) {
+            Ok(socket) => {
+                eprintln!("Reconnected: {}.", self.option);
+                self.socket = Some(socket);
+                self.retry = None;
+                true
+            }
+            Err(e) => {
+                let attempt = self.retry.map_or(0, |(_, attempt)| attempt + 1);
+                let delay = Backoff::default().jittered_delay(attempt);
+                eprintln!(
+                    "Failed to connect to {}: {e}. Retrying in {delay:?}.",
+                    self.option
+                );
+                self.retry = Some((Instant::now() + delay, attempt));
+                false
+            }
+        }
+    }
+
+    /// Writes a message, connecting again right away if the server went away since the last
+    /// one, and dropping it if the server can't be reached.
+    fn write(&mut self, message: &Payload, timeout: Duration, drops: &DropCounters) {
+        let frame = match message {
+            Payload::Text(text) => Message::Text(text.clone()),
+            Payload::Binary(bytes) => Message::Binary(bytes.clone()),
+        };
+        if let Some(socket) = &mut self.socket {
+            match socket.write_message(frame.clone()) {
+                Ok(()) => return,
+                Err(e) => self.report_write_error(e),
+            }
+            self.socket = None;
+        }
+        if !self.ensure_connected(timeout) {
+            drops.count_lost(Reason::NoReader, message);
+            return;
+        }
+        if let Err(e) = self.socket.as_mut().unwrap().write_message(frame) {
+            self.report_write_error(e);
+            self.socket = None;
+            drops.count_lost(Reason::NoReader, message);
+        }
+    }
+
+    /// Logs why a write left the socket unusable, as for the clients of `ws://` destinations.
+    fn report_write_error(&self, error: tungstenite::Error) {
+        let option = &self.option;
+        match error {
+            Io(e) if e.kind() == ConnectionAborted => eprintln!("Connection aborted: {option}."),
+            Io(e) if e.kind() == ConnectionReset => eprintln!("Connection reset: {option}."),
+            Protocol(ResetWithoutClosingHandshake) => {
+                eprintln!("Reset without closing handshake: {option}.");
+            }
+            e => eprintln!("Failed to write to {option}: {e}."),
+        }
+    }
+
+    /// Ends the session with a closing handshake, so that the server can tell it from a lost
+    /// connection, waiting for the server's close frame until `deadline`.
+    fn close(&mut self, deadline: Instant) {
+        let Some(mut socket) = self.socket.take() else {
+            return;
+        };
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let timeout = timeout.max(Duration::from_millis(1));
+        if socket.get_ref().set_read_timeout(Some(timeout)).is_ok() && socket.close(None).is_ok() {
+            // Ends with the server's close frame, after which tungstenite reports the
+            // connection closed, or once the server is given up on.
+            while socket.read_message().is_ok() {}
+        }
+    }
+}
+
+/// Connects to the WebSocket server at `ws-connect://<host>:<port>/<path>` and sends each
+/// message to it as a frame of its own type, for pushing to a server that is already running
+/// rather than waiting for clients as `ws://` destinations do. The upgrade request is for
+/// `ws://` with the same host, port, path and query.
+///
+/// Getting through to the server at startup is retried with backoff. A connection that fails
+/// a write is reestablished once before the message is dropped; after that, the messages are
+/// dropped while the server is out of reach, and connecting again is retried with backoff
+/// rather than with each one.
+pub struct WebSocketClientBroker {
+    connections: RefCell<Vec<Connection>>,
+    connect_timeout: Duration,
+    drops: DropCounters,
+}
+
+impl WebSocketClientBroker {
+    pub fn new(connect_timeout: Duration, drops: DropCounters) -> WebSocketClientBroker {
+        WebSocketClientBroker {
+            connections: RefCell::new(vec![]),
+            connect_timeout,
+            drops,
+        }
+    }
+}
+
+impl Broker for WebSocketClientBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("ws-connect://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host_port = format!(
+            "{}:{}",
+            url.host_str()
+                .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let request = Url::parse(&format!("ws://{}", &option["ws-connect://".len()..]))
+            .map_err(NetpipeError::invalid)?;
+        let mut connection = Connection {
+            option: option.to_string(),
+            host_port,
+            request,
+            socket: None,
+            retry: None,
+        };
+        let socket = reconnect(&Backoff::default(), option, || {
+            connection.connect(self.connect_timeout)
+        })
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e))?;
+        connection.socket = Some(socket);
+        self.connections.borrow_mut().push(connection);
+        Ok(())
+    }
+
+    fn send(&self, message: &Payload) -> Result<()> {
+        for connection in self.connections.borrow_mut().iter_mut() {
+            connection.write(message, self.connect_timeout, &self.drops);
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        for connection in self.connections.borrow_mut().iter_mut() {
+            connection.close(deadline);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use regex::{Captures, Regex};
+
+const RESET: &str = "\x1b[0m";
+const CYAN: &str = "\x1b[36m";
+
+pub struct Colorizer {
+    json_key: Regex,
+    level: Regex,
+}
+
+impl Colorizer {
+    pub fn new() -> Colorizer {
+        Colorizer {
+            json_key: Regex::new(r#""(?:[^"\\]|\\.)*"\s*:"#).unwrap(),
+            level: Regex::new(r"\b(ERROR|FATAL|WARN|WARNING|INFO|DEBUG|TRACE)\b").unwrap(),
+        }
+    }
+
+    /// Highlights the keys of JSON messages, and colors other messages as a whole by the first
+    /// log level found in them. Messages matching neither are returned unchanged.
+    pub fn colorize(&self, message: &str) -> String {
+        let trimmed = message.trim_start();
+        if trimmed.starts_with('{') || trimmed.starts_with('[') {
+            return self
+                .json_key
+                .replace_all(message, |c: &Captures| format!("{CYAN}{}{RESET}", &c[0]))
+                .into_owned();
+        }
+        let color = match self.level.captures(message) {
+            Some(c) => match &c[1] {
+                "ERROR" | "FATAL" => "\x1b[31m",
+                "WARN" | "WARNING" => "\x1b[33m",
+                "INFO" => "\x1b[32m",
+                "DEBUG" => "\x1b[34m",
+                _ => "\x1b[2m",
+            },
+            None => return message.to_string(),
+        };
+        format!("{color}{message}{RESET}")
+    }
+}
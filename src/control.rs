@@ -0,0 +1,127 @@
+use crate::http::{self, Response};
+use crate::net::{self, ListenOptions};
+use crate::payload::Payload;
+use crate::receiver::{forward, Messages};
+use crate::stats::{DropCounters, Reason};
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How often a paused pipe checks whether it was resumed.
+const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct State {
+    paused: AtomicBool,
+    buffered: AtomicUsize,
+}
+
+/// Pauses and resumes forwarding on request: `/pause`, `/resume` and, for the current state
+/// as JSON, `/status`.
+pub struct Control {
+    state: Arc<State>,
+}
+
+impl Control {
+    /// Starts answering on `port` on all interfaces, with forwarding running.
+    pub fn serve(port: u16, listen: ListenOptions) -> io::Result<Control> {
+        let state = Arc::new(State::default());
+        let state_ref = state.clone();
+        http::serve(net::listen(("0.0.0.0", port), &listen)?, move |path| {
+            let text = |body: &str| Response::new(200, "text/plain", format!("{body}\n"));
+            match path {
+                "/pause" => {
+                    if !state_ref.paused.swap(true, Ordering::Relaxed) {
+                        eprintln!("Paused.");
+                    }
+                    text("paused")
+                }
+                "/resume" => {
+                    if state_ref.paused.swap(false, Ordering::Relaxed) {
+                        eprintln!("Resumed.");
+                    }
+                    text("resumed")
+                }
+                "/status" => {
+                    let status = json!({
+                        "paused": state_ref.paused.load(Ordering::Relaxed),
+                        "buffered": state_ref.buffered.load(Ordering::Relaxed),
+                    });
+                    Response::new(200, "application/json", status.to_string() + "\n")
+                }
+                _ => Response::not_found(),
+            }
+        });
+        Ok(Control { state })
+    }
+
+    /// Holds back the messages of `source` while paused, keeping up to `limit` of them to
+    /// forward once resumed and dropping the rest.
+    pub fn hold(&self, source: Messages, limit: usize, drops: DropCounters) -> Hold {
+        Hold {
+            messages: forward(source),
+            state: self.state.clone(),
+            buffer: VecDeque::new(),
+            limit,
+            ended: false,
+            drops,
+        }
+    }
+}
+
+/// The messages of a source as let through by a [`Control`]. A source that ends while paused
+/// ends the stream once resumed and the buffer is drained.
+pub struct Hold {
+    messages: Receiver<Payload>,
+    state: Arc<State>,
+    buffer: VecDeque<Payload>,
+    limit: usize,
+    ended: bool,
+    drops: DropCounters,
+}
+
+impl Iterator for Hold {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        loop {
+            let paused = self.state.paused.load(Ordering::Relaxed);
+            if !paused {
+                if let Some(message) = self.buffer.pop_front() {
+                    self.state
+                        .buffered
+                        .store(self.buffer.len(), Ordering::Relaxed);
+                    return Some(message);
+                }
+            }
+            if self.ended {
+                if !paused {
+                    return None;
+                }
+                thread::sleep(POLL);
+                continue;
+            }
+            match self.messages.recv_timeout(POLL) {
+                Ok(message) if !paused => return Some(message),
+                Ok(message) if self.buffer.len() < self.limit => {
+                    self.buffer.push_back(message);
+                    self.state
+                        .buffered
+                        .store(self.buffer.len(), Ordering::Relaxed);
+                }
+                Ok(_) => self.drops.count(Reason::Paused),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => self.ended = true,
+            }
+        }
+    }
+}
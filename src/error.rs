@@ -0,0 +1,48 @@
+use std::{error::Error, fmt, io};
+
+/// Everything that can go wrong while setting up or running a source or destination.
+#[derive(Debug)]
+pub enum NetpipeError {
+    /// A source or destination option that can't be understood.
+    InvalidOption(String),
+    /// A socket, file or listener that couldn't be bound or opened.
+    Bind(io::Error),
+    /// An outbound connection that couldn't be established.
+    Connect(String, Box<dyn Error + Send + Sync>),
+    /// A peer that didn't follow its protocol.
+    Protocol(String),
+    /// Any other I/O failure, such as a write to a closed destination.
+    Io(io::Error),
+    /// A destination whose reader went away, upon which netpipe stops as a Unix tool would
+    /// on SIGPIPE.
+    Closed,
+}
+
+pub type Result<T> = std::result::Result<T, NetpipeError>;
+
+impl NetpipeError {
+    pub fn invalid(reason: impl fmt::Display) -> NetpipeError {
+        NetpipeError::InvalidOption(reason.to_string())
+    }
+}
+
+impl fmt::Display for NetpipeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetpipeError::InvalidOption(reason) => write!(f, "{reason}"),
+            NetpipeError::Bind(e) => write!(f, "failed to bind: {e}"),
+            NetpipeError::Connect(target, e) => write!(f, "failed to connect to {target}: {e}"),
+            NetpipeError::Protocol(reason) => write!(f, "protocol error: {reason}"),
+            NetpipeError::Io(e) => write!(f, "{e}"),
+            NetpipeError::Closed => write!(f, "closed by the reader"),
+        }
+    }
+}
+
+impl Error for NetpipeError {}
+
+impl From<io::Error> for NetpipeError {
+    fn from(e: io::Error) -> NetpipeError {
+        NetpipeError::Io(e)
+    }
+}
@@ -0,0 +1,56 @@
+use crate::error::NetpipeError;
+use crate::strict;
+use std::{fmt, process::ExitCode};
+
+/// Why netpipe stopped, other than its input ending normally (exit status 0). Each reason has
+/// its own exit status so that a supervisor can tell a configuration error, which restarting
+/// won't fix, from a network failure, which it might:
+///
+/// | Status | Reason                                                       |
+/// |--------|--------------------------------------------------------------|
+/// | 2      | Invalid arguments or destination/source options.             |
+/// | 3      | A source or destination couldn't be bound or connected.      |
+/// | 4      | Every destination failed to take a message while forwarding. |
+/// | 5      | Something that `--strict` doesn't let by, as `strict` lists. |
+pub enum Failure {
+    Usage(String),
+    Setup(String, NetpipeError),
+    AllDestinationsFailed(NetpipeError),
+    /// A destination failed to take a message under `--strict`.
+    DestinationFailed(NetpipeError),
+}
+
+impl Failure {
+    /// An invalid source or destination option is a usage error, anything else a failure to
+    /// set it up.
+    pub fn setup(option: &str, e: NetpipeError) -> Failure {
+        match e {
+            NetpipeError::InvalidOption(reason) => {
+                Failure::Usage(format!("Invalid option {option}: {reason}."))
+            }
+            e => Failure::Setup(option.to_string(), e),
+        }
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Failure::Usage(_) => ExitCode::from(2),
+            Failure::Setup(..) => ExitCode::from(3),
+            Failure::AllDestinationsFailed(_) => ExitCode::from(4),
+            Failure::DestinationFailed(_) => ExitCode::from(strict::STATUS),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Usage(message) => write!(f, "{message}"),
+            Failure::Setup(option, e) => write!(f, "Failed to set up {option}: {e}."),
+            Failure::AllDestinationsFailed(e) => write!(f, "All destinations failed: {e}."),
+            Failure::DestinationFailed(e) => {
+                write!(f, "Stopping under --strict: a destination failed: {e}.")
+            }
+        }
+    }
+}
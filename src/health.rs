@@ -0,0 +1,59 @@
+use crate::http::{self, Response};
+use crate::net::{self, ListenOptions};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// What `/healthz` requires of the destinations to report netpipe ready.
+#[derive(Clone, Copy)]
+pub enum Readiness {
+    /// Every destination is set up, and none of the latest sends failed.
+    All,
+    /// At least one destination took the latest message, or has yet to be sent one.
+    Any,
+}
+
+impl Readiness {
+    pub fn parse(value: &str) -> Result<Readiness, String> {
+        match value {
+            "all" => Ok(Readiness::All),
+            "any" => Ok(Readiness::Any),
+            _ => Err(format!(
+                "Expected all or any for --health-require, got {value}."
+            )),
+        }
+    }
+}
+
+/// Readiness as served on `/healthz`: 200 while ready and 503 otherwise, so that it can back
+/// both a liveness and a readiness probe.
+pub struct Health {
+    ready: Arc<AtomicBool>,
+}
+
+impl Health {
+    /// Starts answering on `port` on all interfaces, reporting not ready until told otherwise.
+    pub fn serve(port: u16, listen: ListenOptions) -> io::Result<Health> {
+        let ready = Arc::new(AtomicBool::new(false));
+        let ready_ref = ready.clone();
+        http::serve(
+            net::listen(("0.0.0.0", port), &listen)?,
+            move |path| match (path, ready_ref.load(Ordering::Relaxed)) {
+                ("/healthz", true) => Response::new(200, "text/plain", "ok\n".to_string()),
+                ("/healthz", false) => {
+                    Response::new(503, "text/plain", "unavailable\n".to_string())
+                }
+                _ => Response::not_found(),
+            },
+        );
+        Ok(Health { ready })
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+}
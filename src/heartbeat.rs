@@ -0,0 +1,47 @@
+use crate::payload::Payload;
+use crate::receiver::{forward, Messages};
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+/// Merges a fixed message, repeated at a regular interval, into a stream of messages. The
+/// heartbeat stops together with the source it accompanies.
+pub struct Heartbeat {
+    merged: Receiver<Payload>,
+    message: String,
+    interval: Duration,
+    next_beat: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(source: Messages, message: String, interval: Duration) -> Heartbeat {
+        Heartbeat {
+            merged: forward(source),
+            message,
+            interval,
+            next_beat: Instant::now() + interval,
+        }
+    }
+
+    fn beat(&mut self) -> Payload {
+        self.next_beat = (self.next_beat + self.interval).max(Instant::now());
+        self.message.as_str().into()
+    }
+}
+
+impl Iterator for Heartbeat {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        let now = Instant::now();
+        if now >= self.next_beat {
+            return Some(self.beat());
+        }
+        match self.merged.recv_timeout(self.next_beat - now) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => Some(self.beat()),
+            Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{net, threads};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The longest request head looked at to tell a WebSocket upgrade from a plain request.
+const MAX_HEAD: usize = 8192;
+/// How long a client gets to send the head of its request.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Response {
+        Response {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn not_found() -> Response {
+        Response::new(404, "text/plain", "Not Found\n".to_string())
+    }
+}
+
+/// Serves each GET request on its own short-lived connection, answering with whatever
+/// `handler` returns for the request path.
+pub fn serve(listener: TcpListener, handler: impl Fn(&str) -> Response + Send + 'static) {
+    threads::spawn(
+        threads::name("http-serve", listener.local_addr()),
+        move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        net::accept_failed(&e);
+                        continue;
+                    }
+                };
+                if let Err(e) = respond(&stream, &handler) {
+                    eprintln!("Failed to answer HTTP request: {e}.");
+                }
+            }
+        },
+    );
+}
+
+/// Reads a GET request and answers it with what `handler` returns for the request path.
+pub fn respond(mut stream: &TcpStream, handler: &impl Fn(&str) -> Response) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap();
+    let response = handler(path);
+    let reason = match response.status {
+        200 => "OK",
+        404 => "Not Found",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )
+}
+
+/// Whether the request waiting on `stream` asks to be upgraded to a WebSocket, looking at its
+/// head without reading it, so that the handshake can go on from the start. A client that
+/// doesn't send the head within [`HEAD_TIMEOUT`] is given up on.
+pub fn is_upgrade(stream: &TcpStream) -> io::Result<bool> {
+    let deadline = Instant::now() + HEAD_TIMEOUT;
+    let mut head = vec![0; MAX_HEAD];
+    let len = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        stream.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+        let len = match stream.peek(&mut head) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(io::ErrorKind::TimedOut.into())
+            }
+            Err(e) => return Err(e),
+        };
+        if len == 0 || len == head.len() || head[..len].windows(4).any(|end| end == b"\r\n\r\n") {
+            break len;
+        }
+        if Instant::now() >= deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        // Peeking again returns at once, until more of the head comes.
+        thread::sleep(Duration::from_millis(10));
+    };
+    stream.set_read_timeout(None)?;
+    let head = String::from_utf8_lossy(&head[..len]).to_ascii_lowercase();
+    Ok(head.lines().any(|line| {
+        line.split_once(':')
+            .is_some_and(|(name, value)| name.trim() == "upgrade" && value.contains("websocket"))
+    }))
+}
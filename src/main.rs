@@ -1,41 +1,554 @@
 mod receiver;
-use broker::{Broker, StdoutBroker, UdpBroker, WebSocketBroker};
+use broker::{
+    Broker, HandshakeLog, Lazy, PrometheusBroker, Routed, SendQueue, StartupDelay, StdoutBroker,
+    UdpBroker, WebSocketBroker,
+};
 use receiver::{
-    ReceiverCreator, StdinReceiverCreator, UdpReceiverCreator, WebSocketReceiverCreator,
+    ReceiverCreator, ReplayReceiverCreator, SourceBuffer, StdinReceiverCreator, TcpReceiverCreator,
+    UdpReceiverCreator, WebSocketReceiverCreator,
 };
+#[cfg(feature = "kinesis")]
+mod aws;
 mod broker;
-use std::env;
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
+mod color;
+mod control;
+mod error;
+mod exit;
+mod health;
+mod heartbeat;
+mod http;
+#[cfg(test)]
+mod memory;
+#[cfg(feature = "tui")]
+mod monitor;
+#[cfg(target_os = "linux")]
+mod mqueue;
+mod net;
+mod options;
+mod payload;
+mod retry;
+mod route;
+mod selector;
+mod shutdown;
+mod stats;
+mod strict;
+mod threads;
+#[cfg(feature = "tls")]
+mod tls;
+mod transform;
+mod worker;
+use control::Control;
+use error::NetpipeError;
+use exit::Failure;
+use health::{Health, Readiness};
+use heartbeat::Heartbeat;
+use options::Options;
+use payload::Payload;
+use stats::DropCounters;
+use std::{env, process::ExitCode, sync::Arc, time::Instant};
+use transform::Invalid;
+use worker::{Feed, QueueLimit, Worker};
 
-    let in_option = &args[1];
-    let out_options = &args[2..];
+fn main() -> ExitCode {
+    shutdown::install();
+    match Options::parse(env::args().skip(1))
+        .map(Options::with_env_defaults)
+        .map_err(Failure::Usage)
+        .and_then(run)
+    {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            eprintln!("{failure}");
+            failure.exit_code()
+        }
+    }
+}
 
-    let receiver_creators: Vec<Box<dyn ReceiverCreator>> = vec![
-        Box::new(StdinReceiverCreator),
-        Box::new(WebSocketReceiverCreator),
-        Box::new(UdpReceiverCreator),
+fn run(options: Options) -> Result<(), Failure> {
+    if let Some(size) = options.thread_stack_size {
+        threads::set_stack_size(size);
+    }
+    if options.strict {
+        strict::enable();
+    }
+    let drops = DropCounters::default();
+    let buffer = SourceBuffer {
+        limit: options.source_buffer,
+        drop: options.source_buffer_drop,
+        drops: drops.clone(),
+    };
+    let mut receiver_creators: Vec<Box<dyn ReceiverCreator>> = vec![
+        Box::new(StdinReceiverCreator::new(
+            options.from_stdin_raw.then_some(options.delimiter.as_str()),
+            options.utf8_lossy,
+            options.raw_stdin,
+            options.binary,
+            buffer.clone(),
+        )),
+        Box::new(WebSocketReceiverCreator::new(
+            options.connect_timeout,
+            options.sentinels(),
+            options.reconnect_backoff(),
+            buffer.clone(),
+            options.utf8_lossy,
+            options.tls_ca.clone(),
+        )),
+        Box::new(ReplayReceiverCreator::new(options.speed, options.looping)),
+        Box::new(receiver::FileReceiverCreator),
+        Box::new(TcpReceiverCreator::new(
+            options.connect_timeout,
+            options.sentinels(),
+            options.reconnect_backoff(),
+        )),
+        Box::new(receiver::TcpListenReceiverCreator::new(options.listen())),
+        Box::new(receiver::WebSocketListenReceiverCreator::new(
+            options.listen(),
+        )),
     ];
+    #[cfg(feature = "http-client")]
+    receiver_creators.push(Box::new(receiver::HttpStreamReceiverCreator::new(
+        options.connect_timeout,
+        options.sentinels(),
+        options.reconnect_backoff(),
+    )));
+    #[cfg(feature = "kafka")]
+    receiver_creators.push(Box::new(receiver::KafkaReceiverCreator::new(
+        options.connect_timeout,
+    )));
+    #[cfg(feature = "amqp")]
+    receiver_creators.push(Box::new(receiver::AmqpReceiverCreator::new(
+        options.sentinels(),
+        options.reconnect_backoff(),
+    )));
+    #[cfg(feature = "tls")]
+    receiver_creators.push(Box::new(receiver::TlsReceiverCreator::new(
+        options.tls_cert.clone(),
+        options.tls_key.clone(),
+        options.listen(),
+    )));
+    #[cfg(windows)]
+    receiver_creators.push(Box::new(receiver::PipeReceiverCreator));
+    #[cfg(unix)]
+    receiver_creators.push(Box::new(receiver::UnixDatagramReceiverCreator));
+    #[cfg(target_os = "linux")]
+    receiver_creators.push(Box::new(receiver::MessageQueueReceiverCreator));
+    // Matches any option, so it has to come last.
+    receiver_creators.push(Box::new(UdpReceiverCreator::new(
+        options.listen(),
+        options.multicast_iface,
+        options.udp_sources.clone(),
+        options.udp_buffer_size,
+        buffer,
+    )));
 
-    let mut receiver_creators = receiver_creators.iter();
-    let creator = receiver_creators.find(|c| c.matches(in_option)).unwrap();
-    let receiver = creator.create_receiver(in_option);
+    if let Some(count) = options.peek {
+        return peek(&options, &receiver_creators, count);
+    }
+    #[cfg(feature = "tui")]
+    if options.monitor {
+        return monitor(&options, &receiver_creators, &drops);
+    }
+    // Set up with the plain counters, so that what fails to reach it isn't fed back to it.
+    let deadletter = options
+        .deadletter
+        .as_deref()
+        .map(|destination| feed(&options, destination, &drops))
+        .transpose()?;
+    let drops = match &deadletter {
+        Some(deadletter) => drops.with_deadletter(deadletter.sender()),
+        None => drops,
+    };
+    pipe(
+        &options,
+        &receiver_creators,
+        brokers(&options, &drops),
+        &drops,
+    )
+}
 
-    let brokers: Vec<Box<dyn Broker>> = vec![
-        Box::new(StdoutBroker::new()),
-        Box::new(WebSocketBroker::new()),
-        Box::new(UdpBroker::new()),
+/// A fresh set of the brokers selected by `options`, without destinations yet.
+fn brokers(options: &Options, drops: &DropCounters) -> Vec<Box<dyn Broker>> {
+    let mut brokers: Vec<Box<dyn Broker>> = vec![
+        Box::new(StdoutBroker::new(
+            options.color,
+            options.ignore_broken_pipe,
+            options.binary,
+        )),
+        Box::new(WebSocketBroker::new(
+            options.listen(),
+            options
+                .debug_handshake
+                .then(|| HandshakeLog::new(&options.redact_headers)),
+            options.origin_allowlist.clone(),
+            SendQueue {
+                limit: options.ws_queue_limit,
+                slow: options.slow_client_queue,
+                slow_timeout: options.slow_client_timeout,
+                drops: drops.clone(),
+            },
+            options.proxy_protocol,
+            options.tls_cert.clone(),
+            options.tls_key.clone(),
+        )),
+        Box::new(PrometheusBroker::new(options.listen())),
+        Box::new(broker::FileBroker::new()),
+        Box::new(broker::TcpRawBroker::new(
+            options.listen(),
+            options.proxy_protocol,
+        )),
+        Box::new(broker::TcpBroker::new(options.connect_timeout)),
+        Box::new(broker::ExecBroker::new(drops.clone())),
+        Box::new(broker::WebSocketClientBroker::new(
+            options.connect_timeout,
+            drops.clone(),
+        )),
     ];
+    #[cfg(unix)]
+    brokers.push(Box::new(broker::FdBroker::new()));
+    #[cfg(unix)]
+    brokers.push(Box::new(broker::UnixDatagramBroker::new(drops.clone())));
+    #[cfg(target_os = "linux")]
+    brokers.push(Box::new(broker::MessageQueueBroker::new(drops.clone())));
+    #[cfg(windows)]
+    brokers.push(Box::new(broker::PipeBroker::new()));
+    #[cfg(feature = "sqlite")]
+    brokers.push(Box::new(broker::SqliteBroker::new()));
+    #[cfg(feature = "kafka")]
+    brokers.push(Box::new(broker::KafkaBroker::new(options.connect_timeout)));
+    #[cfg(feature = "kinesis")]
+    brokers.push(Box::new(broker::KinesisBroker::new(
+        options.connect_timeout,
+        drops.clone(),
+    )));
+    #[cfg(feature = "amqp")]
+    brokers.push(Box::new(broker::AmqpBroker::new(drops.clone())));
+    #[cfg(feature = "grpc")]
+    brokers.push(Box::new(broker::GrpcBroker::new(options.listen())));
+    #[cfg(feature = "tls")]
+    brokers.push(Box::new(broker::TlsBroker::new(
+        options.tls_ca.clone(),
+        options.connect_timeout,
+        drops.clone(),
+    )));
+    // Matches any option, so it has to come last.
+    brokers.push(Box::new(UdpBroker::new(
+        options.bind_device.clone(),
+        options.multicast_ttl.map(u32::from),
+        options.multicast_loop,
+        drops.clone(),
+    )));
+    brokers
+        .into_iter()
+        .map(|broker| Box::new(Lazy::new(broker)) as Box<dyn Broker>)
+        .collect()
+}
+
+/// Prints the first `count` messages from the source, each after the time it arrived, to
+/// stderr, for a look at what a source sends before wiring it up.
+fn peek(
+    options: &Options,
+    receiver_creators: &[Box<dyn ReceiverCreator>],
+    count: usize,
+) -> Result<(), Failure> {
+    let [source] = options.arguments.as_slice() else {
+        return Err(Failure::Usage(
+            "Usage: netpipe peek <source> [--count N].".to_string(),
+        ));
+    };
+    let receiver = receiver_creators
+        .iter()
+        .find(|c| c.matches(source))
+        .ok_or_else(|| NetpipeError::invalid("unsupported source"))
+        .and_then(|creator| creator.create_receiver(source))
+        .map_err(|e| Failure::setup(source, e))?;
+    for message in receiver.take(count) {
+        let message = match options.utf8_lossy {
+            true => message.into_lossy_text(),
+            false => message,
+        };
+        let now = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        eprintln!("{now} {message}");
+    }
+    Ok(())
+}
 
-    for option in out_options {
-        let broker = brokers.iter().find(|c| c.matches(option)).unwrap();
-        broker.add_destination(option);
+#[cfg(feature = "tui")]
+fn monitor(
+    options: &Options,
+    receiver_creators: &[Box<dyn ReceiverCreator>],
+    drops: &DropCounters,
+) -> Result<(), Failure> {
+    let [source] = options.arguments.as_slice() else {
+        return Err(Failure::Usage(
+            "Usage: netpipe monitor <source>.".to_string(),
+        ));
+    };
+    let receiver = receiver_creators
+        .iter()
+        .find(|c| c.matches(source))
+        .ok_or_else(|| NetpipeError::invalid("unsupported source"))
+        .and_then(|creator| creator.create_receiver(source))
+        .map_err(|e| Failure::setup(source, e))?;
+    monitor::run(source, receiver, options.sentinels(), drops.clone())
+        .map_err(|e| Failure::Setup("the terminal".to_string(), NetpipeError::Io(e)))
+}
+
+fn queue_limit(options: &Options, drops: &DropCounters) -> QueueLimit {
+    QueueLimit {
+        limit: options.worker_queue,
+        drop: options.worker_queue_drop,
+        drops: drops.clone(),
+    }
+}
+
+/// Sets up a destination such as that of `--on-invalid route:<destination>` or of
+/// `--deadletter` on a broker of its own, so that it only gets the messages fed to it.
+fn feed(options: &Options, destination: &str, drops: &DropCounters) -> Result<Feed, Failure> {
+    let broker = brokers(options, drops)
+        .into_iter()
+        .find(|broker| broker.matches(destination))
+        .ok_or_else(|| {
+            Failure::setup(
+                destination,
+                NetpipeError::invalid("unsupported destination"),
+            )
+        })?;
+    broker
+        .add_destination(destination)
+        .map_err(|e| Failure::setup(destination, e))?;
+    Ok(Feed::spawn(Worker::spawn(
+        broker,
+        &queue_limit(options, drops),
+    )))
+}
+
+/// Forwards messages from the sources in `options` to their destinations, using the first of the
+/// given receiver creators and brokers that matches each of them. Each broker that is used
+/// sends on its own [`Worker`] thread. Messages dropped on the way are counted in `drops`,
+/// which are reported as netpipe exits.
+fn pipe(
+    options: &Options,
+    receiver_creators: &[Box<dyn ReceiverCreator>],
+    mut brokers: Vec<Box<dyn Broker>>,
+    drops: &DropCounters,
+) -> Result<(), Failure> {
+    let (in_options, out_options) = options.split_arguments();
+    if in_options.is_empty() {
+        return Err(Failure::Usage(
+            "Usage: netpipe <source> <destination>..., netpipe --in <source>... --out <destination>..., or set NETPIPE_DEFAULT_IN and NETPIPE_DEFAULT_OUT."
+                .to_string(),
+        ));
     }
+    let capture = options.capture_destination();
+    let out_options: Vec<_> = out_options
+        .iter()
+        .chain(&options.destinations)
+        .chain(&capture)
+        .collect();
+    let health = options
+        .health_port
+        .map(|port| Health::serve(port, options.listen()))
+        .transpose()
+        .map_err(|e| Failure::setup("--health-port", NetpipeError::Bind(e)))?;
+    if let Some(port) = options.stats_port {
+        stats::serve(port, options.listen(), drops.clone())
+            .map_err(|e| Failure::setup("--stats-port", NetpipeError::Bind(e)))?;
+    }
+    let control = options
+        .control_port
+        .map(|port| Control::serve(port, options.listen()))
+        .transpose()
+        .map_err(|e| Failure::setup("--control-port", NetpipeError::Bind(e)))?;
 
+    let receivers = in_options
+        .iter()
+        .map(|in_option| {
+            receiver_creators
+                .iter()
+                .find(|c| c.matches(in_option))
+                .ok_or_else(|| NetpipeError::invalid("unsupported source"))
+                .and_then(|creator| creator.create_receiver(in_option))
+                .map(|receiver| (in_option.clone(), receiver))
+                .map_err(|e| Failure::setup(in_option, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let receiver = shutdown::until_stopped(receivers);
+    let receiver = match options.utf8_lossy {
+        true => Box::new(receiver.map(Payload::into_lossy_text)),
+        false => receiver,
+    };
+    let mut rejects = match &options.on_invalid {
+        Invalid::Route(destination) => Some(feed(options, destination, drops)?),
+        _ => None,
+    };
+    let mut receiver =
+        transform::apply(options, receiver, rejects.as_ref().map(Feed::sender), drops);
+    if let Some(control) = &control {
+        receiver = Box::new(control.hold(receiver, options.pause_buffer, drops.clone()));
+    }
+    if let Some(max_messages) = options.max_messages {
+        receiver = Box::new(receiver.take(max_messages));
+    }
+    if let Some((message, interval)) = &options.heartbeat {
+        receiver = Box::new(Heartbeat::new(receiver, message.clone(), *interval));
+    }
+    if options.binary {
+        receiver = Box::new(receiver.map(Payload::into_binary));
+    }
+
+    let mut active = vec![false; brokers.len()];
+    let mut failed = vec![];
+    let mut last_failure = None;
+    for option in &out_options {
+        let Some(mut index) = brokers.iter().position(|c| c.matches(option)) else {
+            return Err(Failure::setup(
+                option,
+                NetpipeError::invalid("unsupported destination"),
+            ));
+        };
+        let route = options.routes.get(*option);
+        if StartupDelay::wanted(option) || route.is_some() {
+            let mut broker = crate::brokers(options, drops).swap_remove(index);
+            if StartupDelay::wanted(option) {
+                broker = Box::new(StartupDelay::new(broker, drops.clone()));
+            }
+            if let Some(route) = route {
+                broker = Box::new(Routed::new(broker, route.clone()));
+            }
+            brokers.push(broker);
+            active.push(false);
+            index = brokers.len() - 1;
+        }
+        match brokers[index].add_destination(option) {
+            Ok(()) => active[index] = true,
+            Err(e) => match Failure::setup(option, e) {
+                failure @ Failure::Setup(..) if options.best_effort => {
+                    eprintln!("Skipping destination: {failure}");
+                    failed.push((index, *option));
+                    last_failure = Some(failure);
+                }
+                failure => return Err(failure),
+            },
+        }
+    }
+    if let Some(failure) = last_failure {
+        if !active.contains(&true) && options.retry_destinations.is_none() {
+            return Err(failure);
+        }
+    }
+    if options.tee && !out_options.iter().any(|option| *option == "stdout") {
+        let tee = StdoutBroker::new(options.color, options.ignore_broken_pipe, options.binary);
+        tee.add_destination("stdout")
+            .map_err(|e| Failure::setup("--tee", e))?;
+        brokers.push(Box::new(tee));
+        active.push(true);
+    }
+
+    // Only brokers with a destination, or one still to be retried, get a worker.
+    let queue = queue_limit(options, drops);
+    let mut workers: Vec<_> = brokers
+        .into_iter()
+        .enumerate()
+        .map(|(index, broker)| {
+            (active[index] || failed.iter().any(|&(i, _)| i == index))
+                .then(|| Worker::spawn(broker, &queue))
+        })
+        .collect();
+
+    if let Some(health) = &health {
+        let active_workers = active_workers(&mut workers, &active);
+        health.set_ready(ready(
+            options.health_require,
+            &active_workers,
+            failed.is_empty(),
+        ));
+    }
+
+    let mut last_retry = Instant::now();
+    let mut outcome = None;
     for message in receiver {
-        for broker in brokers.iter() {
-            broker.send(&message);
+        if let Some(interval) = options.retry_destinations {
+            if !failed.is_empty() && last_retry.elapsed() >= interval {
+                failed.retain(|&(index, option)| {
+                    let worker = workers[index].as_ref().unwrap();
+                    match worker.add_destination(option) {
+                        Ok(()) => {
+                            eprintln!("Added destination {option}.");
+                            active[index] = true;
+                            false
+                        }
+                        Err(_) => true,
+                    }
+                });
+                last_retry = Instant::now();
+            }
+        }
+
+        let message = Arc::new(message);
+        let mut active_workers = active_workers(&mut workers, &active);
+        for worker in active_workers.iter_mut() {
+            worker.send(message.clone());
+            worker.poll();
         }
+        outcome = check(&mut active_workers);
+        if outcome.is_some() {
+            break;
+        }
+        if let Some(health) = &health {
+            health.set_ready(ready(
+                options.health_require,
+                &active_workers,
+                failed.is_empty(),
+            ));
+        }
+    }
+
+    let mut active_workers = active_workers(&mut workers, &active);
+    for worker in active_workers.iter_mut() {
+        worker.finish();
+    }
+    if let Some(rejects) = &mut rejects {
+        rejects.finish();
+    }
+    if let Some(report) = drops.report() {
+        eprintln!("{report}");
+    }
+    outcome
+        .or_else(|| check(&mut active_workers))
+        .unwrap_or(Ok(()))
+}
+
+fn ready(readiness: Readiness, workers: &[&mut Worker], all_set_up: bool) -> bool {
+    match readiness {
+        Readiness::All => all_set_up && workers.iter().all(|worker| !worker.failing()),
+        Readiness::Any => workers.iter().any(|worker| !worker.failing()),
+    }
+}
+
+fn active_workers<'a>(workers: &'a mut [Option<Worker>], active: &[bool]) -> Vec<&'a mut Worker> {
+    workers
+        .iter_mut()
+        .zip(active)
+        .filter_map(|(worker, active)| worker.as_mut().filter(|_| *active))
+        .collect()
+}
+
+/// Decides whether to stop forwarding: cleanly once a destination was closed by its reader,
+/// or with a failure once the latest send of every active worker failed, or of any under
+/// `--strict`.
+fn check(workers: &mut [&mut Worker]) -> Option<Result<(), Failure>> {
+    if workers.iter().any(|worker| worker.closed()) {
+        return Some(Ok(()));
+    }
+    if strict::enabled() {
+        let e = workers
+            .iter_mut()
+            .find_map(|worker| worker.take_failure())?;
+        return Some(Err(Failure::DestinationFailed(e)));
+    }
+    if workers.is_empty() || !workers.iter().all(|worker| worker.failing()) {
+        return None;
     }
+    let e = workers[0].take_failure()?;
+    Some(Err(Failure::AllDestinationsFailed(e)))
 }
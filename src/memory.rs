@@ -0,0 +1,193 @@
+//! Channel-backed `memory://<name>` sources and destinations, so that [`crate::pipe`] can be
+//! driven deterministically without real sockets. Only built for tests.
+
+use crate::broker::{unless_all_failed, Broker};
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::receiver::{Messages, ReceiverCreator};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{self, ErrorKind},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+fn name(option: &str) -> &str {
+    option.strip_prefix("memory://").unwrap_or(option)
+}
+
+fn unknown(option: &str) -> NetpipeError {
+    NetpipeError::Connect(option.to_string(), "no such channel".into())
+}
+
+pub struct MemoryReceiverCreator {
+    sources: RefCell<HashMap<String, Receiver<Payload>>>,
+}
+
+impl MemoryReceiverCreator {
+    pub fn new() -> MemoryReceiverCreator {
+        MemoryReceiverCreator {
+            sources: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the sender feeding the source `memory://<name>`. The source ends once it and
+    /// its clones are dropped.
+    pub fn source(&self, name: &str) -> Sender<Payload> {
+        let (tx, rx) = mpsc::channel();
+        self.sources.borrow_mut().insert(name.to_string(), rx);
+        tx
+    }
+}
+
+impl ReceiverCreator for MemoryReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("memory://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let source = self.sources.borrow_mut().remove(name(option));
+        let source = source.ok_or_else(|| unknown(option))?;
+        Ok(Box::new(source.into_iter()))
+    }
+}
+
+pub struct MemoryBroker {
+    sinks: RefCell<HashMap<String, Sender<Payload>>>,
+    destinations: RefCell<Vec<Sender<Payload>>>,
+}
+
+impl MemoryBroker {
+    pub fn new() -> MemoryBroker {
+        MemoryBroker {
+            sinks: RefCell::new(HashMap::new()),
+            destinations: RefCell::new(vec![]),
+        }
+    }
+
+    /// Returns the receiver collecting what is sent to the destination `memory://<name>`.
+    pub fn sink(&self, name: &str) -> Receiver<Payload> {
+        let (tx, rx) = mpsc::channel();
+        self.sinks.borrow_mut().insert(name.to_string(), tx);
+        rx
+    }
+}
+
+impl Broker for MemoryBroker {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("memory://")
+    }
+
+    fn add_destination(&self, option: &str) -> Result<()> {
+        let sink = self.sinks.borrow().get(name(option)).cloned();
+        let sink = sink.ok_or_else(|| unknown(option))?;
+        self.destinations.borrow_mut().push(sink);
+        Ok(())
+    }
+
+    /// A destination whose receiver was dropped counts as failed.
+    fn send(&self, message: &Payload) -> Result<()> {
+        let results = self
+            .destinations
+            .borrow()
+            .iter()
+            .map(|sink| {
+                sink.send(message.clone())
+                    .map_err(|_| NetpipeError::Io(io::Error::from(ErrorKind::BrokenPipe)))
+            })
+            .collect();
+        unless_all_failed(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit::Failure;
+    use crate::options::Options;
+    use crate::stats::DropCounters;
+
+    fn text(message: &str) -> Payload {
+        Payload::Text(message.to_string())
+    }
+
+    /// Runs the pipeline on `args`, with each of `sources` fed its messages and ended, and
+    /// returns the outcome and what reached `memory://out`.
+    fn pipe(
+        args: &[&str],
+        sources: &[(&str, &[&str])],
+    ) -> (std::result::Result<(), Failure>, Vec<Payload>) {
+        let options = Options::parse(args.iter().map(|arg| arg.to_string())).unwrap();
+        let receivers = MemoryReceiverCreator::new();
+        for (name, messages) in sources {
+            let source = receivers.source(name);
+            for message in *messages {
+                source.send(text(message)).unwrap();
+            }
+        }
+        let broker = MemoryBroker::new();
+        let sink = broker.sink("out");
+        let drops = DropCounters::default();
+        let outcome = crate::pipe(
+            &options,
+            &[Box::new(receivers)],
+            vec![Box::new(broker)],
+            &drops,
+        );
+        (outcome, sink.try_iter().collect())
+    }
+
+    #[test]
+    fn messages_pass_through_in_order() {
+        let (outcome, out) = pipe(
+            &["memory://in", "memory://out"],
+            &[("in", &["1", "2", "3"])],
+        );
+        assert!(outcome.is_ok());
+        assert_eq!(out, ["1", "2", "3"].map(text));
+    }
+
+    #[test]
+    fn filters_and_replacements_apply_in_turn() {
+        let args = [
+            "--filter",
+            "^error",
+            "--replace",
+            "error: (.*)=>E $1",
+            "--max-messages",
+            "2",
+            "memory://in",
+            "memory://out",
+        ];
+        let input = ["error: one", "info: two", "error: three", "error: four"];
+        let (outcome, out) = pipe(&args, &[("in", &input)]);
+        assert!(outcome.is_ok());
+        assert_eq!(out, ["E one", "E three"].map(text));
+    }
+
+    #[test]
+    fn merged_sources_all_reach_the_destination() {
+        let args = [
+            "--in",
+            "memory://a",
+            "--in",
+            "memory://b",
+            "--out",
+            "memory://out",
+        ];
+        let (outcome, out) = pipe(&args, &[("a", &["a1", "a2"]), ("b", &["b1"])]);
+        assert!(outcome.is_ok());
+        let mut out: Vec<_> = out.iter().map(|message| message.to_text()).collect();
+        out.sort();
+        assert_eq!(out, ["a1", "a2", "b1"]);
+    }
+
+    #[test]
+    fn an_unknown_channel_fails_setup() {
+        let (outcome, out) = pipe(&["memory://in", "memory://elsewhere"], &[("in", &["1"])]);
+        assert!(
+            matches!(outcome, Err(Failure::Setup(option, _)) if option == "memory://elsewhere")
+        );
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+//! `netpipe monitor <source>`: a terminal UI showing the latest messages of a source, how
+//! many arrive per second and whether it is connected, for checking a stream at a glance.
+
+use crate::payload::Payload;
+use crate::receiver::{forward, Messages, Sentinels};
+use crate::stats::DropCounters;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, Paragraph, Sparkline};
+use ratatui::Frame;
+use std::{
+    collections::VecDeque,
+    io,
+    sync::mpsc::{Receiver, TryRecvError},
+    time::{Duration, Instant},
+};
+
+/// Messages kept for display.
+const RECENT: usize = 500;
+/// Seconds of message rate shown.
+const HISTORY: usize = 120;
+/// How often the display is redrawn.
+const TICK: Duration = Duration::from_millis(250);
+/// Messages taken in between redraws at most, so that a flood doesn't freeze the display.
+const BATCH: usize = 100_000;
+
+enum Status {
+    Connected,
+    Disconnected(Instant),
+    Ended,
+}
+
+struct Monitor {
+    source: String,
+    sentinels: Sentinels,
+    drops: DropCounters,
+    status: Status,
+    recent: VecDeque<String>,
+    total: u64,
+    bytes: u64,
+    last: Option<Instant>,
+    /// Messages per second, oldest first, and the count of the second under way.
+    rates: VecDeque<u64>,
+    second: (Instant, u64),
+}
+
+impl Monitor {
+    fn receive(&mut self, message: Payload) {
+        if self.sentinels.disconnected.as_ref() == Some(&message) {
+            self.status = Status::Disconnected(Instant::now());
+            return;
+        }
+        if self.sentinels.reconnected.as_ref() == Some(&message) {
+            self.status = Status::Connected;
+            return;
+        }
+        let now = chrono::Local::now().format("%H:%M:%S%.3f");
+        if self.recent.len() == RECENT {
+            self.recent.pop_front();
+        }
+        self.recent
+            .push_back(format!("{now} {}", message.to_text()));
+        self.total += 1;
+        self.bytes += message.len() as u64;
+        self.last = Some(Instant::now());
+        self.second.1 += 1;
+    }
+
+    /// Closes the seconds that have gone by.
+    fn tick(&mut self) {
+        while self.second.0.elapsed() >= Duration::from_secs(1) {
+            if self.rates.len() == HISTORY {
+                self.rates.pop_front();
+            }
+            self.rates.push_back(self.second.1);
+            self.second = (self.second.0 + Duration::from_secs(1), 0);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [summary, rate, messages] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Length(6),
+            Constraint::Min(3),
+        ])
+        .areas(frame.area());
+
+        let status = match self.status {
+            Status::Connected => Span::styled("connected", Color::Green),
+            Status::Disconnected(since) => Span::styled(
+                format!("disconnected for {}s", since.elapsed().as_secs()),
+                Color::Red,
+            ),
+            Status::Ended => Span::styled("ended", Color::Yellow),
+        };
+        let last = match self.last {
+            Some(last) => format!("{:.1}s ago", last.elapsed().as_secs_f64()),
+            None => "never".to_string(),
+        };
+        let current = self.rates.back().copied().unwrap_or(0);
+        let mut lines = vec![
+            Line::from(vec!["Status: ".into(), status]),
+            Line::from(format!(
+                "Messages: {} ({} bytes), {current}/s, last {last}",
+                self.total, self.bytes
+            )),
+        ];
+        if let Some(report) = self.drops.report() {
+            lines.push(Line::from(report));
+        }
+        let title = format!(" netpipe monitor {} (q to quit) ", self.source);
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            summary,
+        );
+
+        // The most recent seconds that fit.
+        let width = rate.width.saturating_sub(2) as usize;
+        let rates: Vec<u64> = self
+            .rates
+            .iter()
+            .skip(self.rates.len().saturating_sub(width))
+            .copied()
+            .collect();
+        let peak = rates.iter().max().copied().unwrap_or(0);
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(" Messages per second (peak {peak}) ")))
+                .data(&rates)
+                .style(Style::default().fg(Color::Cyan)),
+            rate,
+        );
+
+        let height = messages.height.saturating_sub(2) as usize;
+        let items: Vec<ListItem> = self
+            .recent
+            .iter()
+            .skip(self.recent.len().saturating_sub(height))
+            .map(|message| ListItem::new(message.as_str()))
+            .collect();
+        frame.render_widget(
+            List::new(items).block(Block::bordered().title(" Recent messages ")),
+            messages,
+        );
+    }
+}
+
+/// Whether a key asks to quit: `q`, Escape or Ctrl-C, which raw mode turns into a key.
+fn quits(event: &Event) -> bool {
+    let Event::Key(key) = event else {
+        return false;
+    };
+    key.kind == KeyEventKind::Press
+        && match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => true,
+            KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+            _ => false,
+        }
+}
+
+/// Shows the messages of `receiver` until the user quits. What the source logs to stderr
+/// ends up over the display, so stderr is best redirected.
+pub fn run(
+    source: &str,
+    receiver: Messages,
+    sentinels: Sentinels,
+    drops: DropCounters,
+) -> io::Result<()> {
+    let messages: Receiver<Payload> = forward(receiver);
+    let mut monitor = Monitor {
+        source: source.to_string(),
+        sentinels,
+        drops,
+        status: Status::Connected,
+        recent: VecDeque::new(),
+        total: 0,
+        bytes: 0,
+        last: None,
+        rates: VecDeque::new(),
+        second: (Instant::now(), 0),
+    };
+    let mut terminal = ratatui::init();
+    let result = loop {
+        for _ in 0..BATCH {
+            match messages.try_recv() {
+                Ok(message) => monitor.receive(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    monitor.status = Status::Ended;
+                    break;
+                }
+            }
+        }
+        monitor.tick();
+        if let Err(e) = terminal.draw(|frame| monitor.draw(frame)) {
+            break Err(e);
+        }
+        match event::poll(TICK).and_then(|ready| ready.then(event::read).transpose()) {
+            Ok(Some(event)) if quits(&event) => break Ok(()),
+            Ok(_) => {}
+            Err(e) => break Err(e),
+        }
+    };
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,132 @@
+//! POSIX message queues, as `/dev/mqueue` lists them, for `mq://<name>` sources and
+//! destinations.
+
+use crate::broker::file_option;
+use crate::error::{NetpipeError, Result};
+use std::{collections::HashMap, ffi::CString, fs, io, mem};
+
+/// The permissions of a queue that netpipe creates.
+const MODE: libc::mode_t = 0o660;
+
+/// An open message queue, closed when dropped.
+pub struct Queue {
+    descriptor: libc::mqd_t,
+    /// The largest message the queue takes.
+    message_size: usize,
+}
+
+impl Queue {
+    /// Opens the queue given by `mq://<name>`, creating it if missing with room for
+    /// `?max_messages=` messages of up to `?message_size=` bytes, or the system's defaults, and
+    /// returns it with the query parameters left for the caller. A queue that is there already
+    /// keeps its size.
+    pub fn open(option: &str, flags: libc::c_int) -> Result<(Queue, HashMap<String, String>)> {
+        let (name, mut params) = file_option(option);
+        let name = name.trim_start_matches('/');
+        if name.is_empty() || name.contains('/') {
+            return Err(NetpipeError::invalid("expected mq://<name>"));
+        }
+        let mut number = |key| {
+            params
+                .remove(key)
+                .map(|value| {
+                    value
+                        .parse::<libc::c_long>()
+                        .ok()
+                        .filter(|&value| value > 0)
+                        .ok_or_else(|| NetpipeError::invalid(format!("invalid {key} {value}")))
+                })
+                .transpose()
+        };
+        let (max_messages, message_size) = (number("max_messages")?, number("message_size")?);
+        let wanted = (max_messages.is_some() || message_size.is_some()).then(|| {
+            let mut attributes: libc::mq_attr = unsafe { mem::zeroed() };
+            attributes.mq_maxmsg = max_messages.unwrap_or_else(|| default("msg_default", 10));
+            attributes.mq_msgsize =
+                message_size.unwrap_or_else(|| default("msgsize_default", 8192));
+            attributes
+        });
+        let path = CString::new(format!("/{name}")).map_err(NetpipeError::invalid)?;
+        let attributes = wanted
+            .as_ref()
+            .map_or(std::ptr::null(), |wanted| wanted as *const libc::mq_attr);
+        let flags = flags | libc::O_CREAT | libc::O_CLOEXEC;
+        let descriptor = unsafe { libc::mq_open(path.as_ptr(), flags, MODE, attributes) };
+        if descriptor == -1 {
+            return Err(NetpipeError::Bind(io::Error::last_os_error()));
+        }
+        let mut queue = Queue {
+            descriptor,
+            message_size: 0,
+        };
+        let attributes = queue.attributes().map_err(NetpipeError::Bind)?;
+        if let Some(wanted) = wanted {
+            if (attributes.mq_maxmsg, attributes.mq_msgsize)
+                != (wanted.mq_maxmsg, wanted.mq_msgsize)
+            {
+                eprintln!(
+                    "Using {option} as it is, with room for {} messages of up to {} bytes.",
+                    attributes.mq_maxmsg, attributes.mq_msgsize
+                );
+            }
+        }
+        queue.message_size = attributes.mq_msgsize as usize;
+        Ok((queue, params))
+    }
+
+    fn attributes(&self) -> io::Result<libc::mq_attr> {
+        let mut attributes: libc::mq_attr = unsafe { mem::zeroed() };
+        if unsafe { libc::mq_getattr(self.descriptor, &mut attributes) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(attributes)
+    }
+
+    /// Sends a message with a priority, higher ones being received first.
+    pub fn send(&self, message: &[u8], priority: u32) -> io::Result<()> {
+        let sent = unsafe {
+            libc::mq_send(
+                self.descriptor,
+                message.as_ptr().cast(),
+                message.len(),
+                priority,
+            )
+        };
+        match sent {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the oldest message of the highest priority.
+    pub fn receive(&self) -> io::Result<Vec<u8>> {
+        let mut message = vec![0; self.message_size];
+        let size = unsafe {
+            libc::mq_receive(
+                self.descriptor,
+                message.as_mut_ptr().cast(),
+                message.len(),
+                std::ptr::null_mut(),
+            )
+        };
+        if size == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        message.truncate(size as usize);
+        Ok(message)
+    }
+}
+
+/// A default for new queues from `/proc/sys/fs/mqueue`, or `fallback` if it can't be read.
+fn default(setting: &str, fallback: libc::c_long) -> libc::c_long {
+    fs::read_to_string(format!("/proc/sys/fs/mqueue/{setting}"))
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(fallback)
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        unsafe { libc::mq_close(self.descriptor) };
+    }
+}
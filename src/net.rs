@@ -0,0 +1,556 @@
+#[cfg(feature = "tls")]
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+#[cfg(target_os = "linux")]
+use socket2::SockAddr;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    io::{self, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket,
+    },
+    thread,
+    time::Duration,
+};
+#[cfg(unix)]
+use url::Url;
+
+/// Connects to the first address `host_port` resolves to that accepts within `timeout`.
+pub fn connect(host_port: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in host_port.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::other(format!("{host_port} has no address"))))
+}
+
+/// How the sockets that netpipe listens on are bound.
+#[derive(Clone, Default)]
+pub struct ListenOptions {
+    /// Length of the queue of pending connections, the standard library's default if `None`.
+    pub backlog: Option<i32>,
+    /// Set `SO_REUSEPORT`, so that several netpipe instances can bind the same port and have
+    /// the system spread connections and datagrams among them. Unix only.
+    pub reuse_port: bool,
+    /// Bind to this network interface with `SO_BINDTODEVICE`, so that traffic goes in and out
+    /// through it whatever the routing tables say. Linux only, and before Linux 5.7 it takes
+    /// `CAP_NET_RAW`.
+    pub device: Option<String>,
+}
+
+fn bind(addr: impl ToSocketAddrs, kind: Type, options: &ListenOptions) -> io::Result<Socket> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("no address to listen on"))?;
+    let protocol = if kind == Type::STREAM {
+        Protocol::TCP
+    } else {
+        Protocol::UDP
+    };
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    // As `TcpListener::bind` does, so that a restarted netpipe can rebind right away.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if options.reuse_port {
+        // Elsewhere, --reuse-port is refused up front.
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        socket.set_reuse_port(true)?;
+    }
+    // Elsewhere, --bind-device is refused up front.
+    #[cfg(target_os = "linux")]
+    if let Some(device) = &options.device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Binds a listener to the first address `addr` resolves to.
+pub fn listen(addr: impl ToSocketAddrs, options: &ListenOptions) -> io::Result<TcpListener> {
+    if options.backlog.is_none() && !options.reuse_port && options.device.is_none() {
+        return TcpListener::bind(addr);
+    }
+    let socket = bind(addr, Type::STREAM, options)?;
+    socket.listen(options.backlog.unwrap_or(128))?;
+    Ok(socket.into())
+}
+
+/// A block of addresses, `<address>/<prefix length>` or a single address.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid address block {value}.");
+        let (address, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => bits,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .ok_or_else(invalid)?,
+        };
+        Ok(Cidr { network, prefix })
+    }
+
+    /// Whether `addr` is in the block, taking IPv4 addresses mapped to IPv6, as a dual-stack
+    /// socket sees them, as IPv4 ones.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let mask = |bits: u32| u128::MAX.checked_shl(bits - self.prefix).unwrap_or(0);
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                (u32::from(network) ^ u32::from(addr)) as u128 & mask(32) == 0
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                (u128::from(network) ^ u128::from(addr)) & mask(128) == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Which addresses may send to a socket: those in one of `allow`, or any if it is empty,
+/// unless they are in one of `deny`.
+#[derive(Clone, Default)]
+pub struct SourceFilter {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl SourceFilter {
+    pub fn admits(&self, addr: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr)))
+            && !self.deny.iter().any(|cidr| cidr.contains(addr))
+    }
+}
+
+/// Binds a UDP socket to the first address `addr` resolves to.
+pub fn bind_udp(addr: impl ToSocketAddrs, options: &ListenOptions) -> io::Result<UdpSocket> {
+    if !options.reuse_port && options.device.is_none() {
+        return UdpSocket::bind(addr);
+    }
+    Ok(bind(addr, Type::DGRAM, options)?.into())
+}
+
+/// The network interface that a multicast group is joined on: by one of its IPv4 addresses,
+/// or by its index, which is how IPv6 groups are joined.
+#[derive(Clone, Copy)]
+pub enum Interface {
+    Address(Ipv4Addr),
+    Index(u32),
+}
+
+impl Interface {
+    /// Takes an IPv4 address, an interface index or, on Unix, an interface name.
+    pub fn parse(value: &str) -> Result<Interface, String> {
+        if let Ok(address) = value.parse() {
+            return Ok(Interface::Address(address));
+        }
+        if let Ok(index) = value.parse() {
+            return Ok(Interface::Index(index));
+        }
+        #[cfg(unix)]
+        if let Ok(name) = std::ffi::CString::new(value) {
+            match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+                0 => {}
+                index => return Ok(Interface::Index(index)),
+            }
+        }
+        Err(format!("Unknown network interface {value}."))
+    }
+}
+
+/// Binds a UDP socket to receive on the first address `addr` resolves to and, if that is a
+/// multicast group, joins it on `interface`, or else on the one the routing tables pick. The
+/// socket is bound to the group's address, so that it only gets the group's datagrams, except
+/// on Windows, which can't bind to one and binds to the port alone.
+pub fn bind_udp_source(
+    addr: impl ToSocketAddrs,
+    options: &ListenOptions,
+    interface: Option<Interface>,
+) -> io::Result<UdpSocket> {
+    let group = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other("no address to listen on"))?;
+    if !group.ip().is_multicast() {
+        return bind_udp(addr, options);
+    }
+    #[cfg(windows)]
+    let socket = bind_udp(
+        SocketAddr::new(
+            match group {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            },
+            group.port(),
+        ),
+        options,
+    )?;
+    #[cfg(not(windows))]
+    let socket = bind_udp(group, options)?;
+    match (group.ip(), interface) {
+        (IpAddr::V4(group), None) => socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?,
+        (IpAddr::V4(group), Some(Interface::Address(address))) => {
+            socket.join_multicast_v4(&group, &address)?
+        }
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_vendor = "apple",
+            windows
+        ))]
+        (IpAddr::V4(group), Some(Interface::Index(index))) => SockRef::from(&socket)
+            .join_multicast_v4_n(&group, &socket2::InterfaceIndexOrAddress::Index(index))?,
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_vendor = "apple",
+            windows
+        )))]
+        (IpAddr::V4(_), Some(Interface::Index(_))) => {
+            return Err(io::Error::other(
+                "IPv4 groups can only be joined by interface address here",
+            ))
+        }
+        (IpAddr::V6(group), None) => socket.join_multicast_v6(&group, 0)?,
+        (IpAddr::V6(group), Some(Interface::Index(index))) => {
+            socket.join_multicast_v6(&group, index)?
+        }
+        (IpAddr::V6(_), Some(Interface::Address(_))) => {
+            return Err(io::Error::other(
+                "IPv6 groups are joined by interface name or index, not address",
+            ))
+        }
+    }
+    Ok(socket)
+}
+
+/// Sets the hops that datagrams sent from `socket` to multicast groups may take, if given,
+/// and whether they loop back to this host. Neither applies to other datagrams.
+pub fn set_multicast_options(
+    socket: &UdpSocket,
+    ttl: Option<u32>,
+    multicast_loop: bool,
+) -> io::Result<()> {
+    match socket.local_addr()? {
+        SocketAddr::V4(_) => {
+            if let Some(ttl) = ttl {
+                socket.set_multicast_ttl_v4(ttl)?;
+            }
+            socket.set_multicast_loop_v4(multicast_loop)
+        }
+        SocketAddr::V6(_) => {
+            if let Some(hops) = ttl {
+                SockRef::from(socket).set_multicast_hops_v6(hops)?;
+            }
+            socket.set_multicast_loop_v6(multicast_loop)
+        }
+    }
+}
+
+/// The most datagrams the kernel takes in one `sendmmsg` call, `UIO_MAXIOV`.
+#[cfg(target_os = "linux")]
+const MAX_BATCH: usize = 1024;
+
+/// Sends `message` from `socket` to each of `addrs` in as few `sendmmsg` calls as it takes,
+/// with the outcome for each address. The kernel stops a call at the first datagram that
+/// fails, so that one is reported and the call repeated for the ones after it.
+#[cfg(target_os = "linux")]
+pub fn send_to_all(
+    socket: &UdpSocket,
+    message: &[u8],
+    addrs: &[SocketAddr],
+) -> Vec<io::Result<()>> {
+    let addrs: Vec<SockAddr> = addrs.iter().map(|&addr| addr.into()).collect();
+    let mut iov = libc::iovec {
+        iov_base: message.as_ptr() as *mut libc::c_void,
+        iov_len: message.len(),
+    };
+    let iov: *mut libc::iovec = &mut iov;
+    let mut headers: Vec<libc::mmsghdr> = addrs
+        .iter()
+        .map(|addr| {
+            let mut header: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            header.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            header.msg_hdr.msg_namelen = addr.len();
+            header.msg_hdr.msg_iov = iov;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect();
+    let mut results = Vec::with_capacity(headers.len());
+    while results.len() < headers.len() {
+        let rest = &mut headers[results.len()..];
+        let count = rest.len().min(MAX_BATCH) as libc::c_uint;
+        let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), rest.as_mut_ptr(), count, 0) };
+        match sent {
+            -1 => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => {}
+                e => results.push(Err(e)),
+            },
+            sent => results.extend((0..sent).map(|_| Ok(()))),
+        }
+    }
+    results
+}
+
+/// How long a load balancer may take to send the PROXY protocol header of a connection.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The start of a PROXY protocol version 2 header.
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+fn invalid_proxy_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid PROXY protocol header")
+}
+
+/// Reads the PROXY protocol header, version 1 or 2, that a load balancer such as HAProxy
+/// sends ahead of what its client sends, returning the client's address. That is `None` for
+/// the balancer's own connections, such as health checks, and for clients that aren't on TCP
+/// over IP.
+pub fn read_proxy_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    stream.set_read_timeout(Some(PROXY_HEADER_TIMEOUT))?;
+    let client = parse_proxy_header(stream);
+    stream.set_read_timeout(None)?;
+    client
+}
+
+fn parse_proxy_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 5];
+    stream.read_exact(&mut start)?;
+    if &start == b"PROXY" {
+        // The line is at most 107 bytes, and read a byte at a time so as not to read beyond it.
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == 107 {
+                return Err(invalid_proxy_header());
+            }
+            let mut byte = [0];
+            stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        let line = std::str::from_utf8(&line).map_err(|_| invalid_proxy_header())?;
+        return match line.trim_end().split(' ').collect::<Vec<_>>()[..] {
+            ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+                let source: IpAddr = source.parse().map_err(|_| invalid_proxy_header())?;
+                let port: u16 = port.parse().map_err(|_| invalid_proxy_header())?;
+                Ok(Some(SocketAddr::new(source, port)))
+            }
+            ["PROXY", "UNKNOWN", ..] => Ok(None),
+            _ => Err(invalid_proxy_header()),
+        };
+    }
+    let mut header = [0; 16];
+    header[..5].copy_from_slice(&start);
+    stream.read_exact(&mut header[5..])?;
+    if header[..12] != PROXY_V2_SIGNATURE || header[12] >> 4 != 2 {
+        return Err(invalid_proxy_header());
+    }
+    let mut addresses = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+    stream.read_exact(&mut addresses)?;
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    // The command is LOCAL or PROXY, and the family TCP over IPv4 or IPv6 if it is either.
+    match (header[12] & 0x0f, header[13]) {
+        (0, _) => Ok(None),
+        (1, 0x11) if addresses.len() >= 12 => {
+            let source: [u8; 4] = addresses[..4].try_into().unwrap();
+            Ok(Some(SocketAddr::new(
+                Ipv4Addr::from(source).into(),
+                port(8),
+            )))
+        }
+        (1, 0x21) if addresses.len() >= 36 => {
+            let source: [u8; 16] = addresses[..16].try_into().unwrap();
+            Ok(Some(SocketAddr::new(
+                Ipv6Addr::from(source).into(),
+                port(32),
+            )))
+        }
+        (1, _) => Ok(None),
+        _ => Err(invalid_proxy_header()),
+    }
+}
+
+/// Takes an accepted connection from a load balancer, reading its PROXY protocol header to
+/// tell who the client is.
+pub fn accept_proxied(mut stream: TcpStream) -> io::Result<Stream> {
+    Ok(match read_proxy_header(&mut stream)? {
+        Some(client) => Stream::Proxied(stream, client),
+        None => Stream::Tcp(stream),
+    })
+}
+
+/// A connected socket that a WebSocket or a stream of records can run over.
+pub enum Stream {
+    Tcp(TcpStream),
+    /// A connection from a load balancer, on behalf of the client at the address given in
+    /// its PROXY protocol header.
+    Proxied(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// TLS over TCP, from either end of the connection.
+    #[cfg(feature = "tls")]
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+    #[cfg(feature = "tls")]
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+fn tcp_peer(stream: &TcpStream) -> String {
+    match stream.peer_addr() {
+        Ok(addr) => addr.to_string(),
+        Err(_) => "unknown peer".to_string(),
+    }
+}
+
+impl Stream {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) | Stream::Proxied(stream, _) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(stream) => stream.sock.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(stream) => stream.sock.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) | Stream::Proxied(stream, _) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(stream) => stream.sock.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(stream) => stream.sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// Describes the other end for log messages. Clients of a Unix socket are usually
+    /// unnamed, so they are described by the socket they connected to.
+    pub fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => tcp_peer(stream),
+            Stream::Proxied(_, client) => client.to_string(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => match stream.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("client of {}", path.display()),
+                    None => "unix socket client".to_string(),
+                },
+                Err(_) => "unknown peer".to_string(),
+            },
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(stream) => tcp_peer(&stream.sock),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(stream) => tcp_peer(&stream.sock),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) | Stream::Proxied(stream, _) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) | Stream::Proxied(stream, _) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) | Stream::Proxied(stream, _) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::TlsClient(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::TlsServer(stream) => stream.flush(),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
+
+/// How long to wait after failing to accept a connection before trying again. Such failures
+/// tend to last, as when netpipe is out of file descriptors, and accepting again straight away
+/// would spin.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Reports a failure to accept a connection, and waits a moment before the next attempt.
+pub fn accept_failed(e: &io::Error) {
+    eprintln!("Failed to accept connection: {e}.");
+    thread::sleep(ACCEPT_RETRY_DELAY);
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket file left behind by a process that
+/// is gone, but not one that something is still listening on.
+#[cfg(unix)]
+pub fn listen_unix(path: &str) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() => {
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Splits the target of a `ws+unix://<socket path>[:<request path>]` option into the path of
+/// the socket and that of the WebSocket request, `/` by default.
+#[cfg(unix)]
+pub fn unix_target(url: &Url) -> (String, String) {
+    match url.path().split_once(':') {
+        Some((socket, path)) => (socket.to_string(), path.to_string()),
+        None => (url.path().to_string(), "/".to_string()),
+    }
+}
@@ -0,0 +1,831 @@
+use crate::health::Readiness;
+use crate::net::{Cidr, Interface, ListenOptions, SourceFilter};
+use crate::payload::Payload;
+use crate::receiver::Sentinels;
+use crate::retry::Backoff;
+use crate::route::{Route, Routes};
+use crate::selector::Selector;
+use crate::transform::{
+    Checksum, Enrichment, Function, InputFormat, Invalid, OutputFormat, Protobuf, Replace, Rule,
+    Schema, Threshold, TimeFormat, Window,
+};
+use chrono_tz::Tz;
+use regex::Regex;
+use std::{collections::HashMap, env, str::FromStr, time::Duration};
+
+pub struct Options {
+    pub color: bool,
+    /// Mirror the messages to stdout in addition to the configured destinations.
+    pub tee: bool,
+    /// Keep forwarding to the other destinations once stdout is closed, instead of stopping.
+    pub ignore_broken_pipe: bool,
+    /// Skip destinations that can't be set up instead of exiting.
+    pub best_effort: bool,
+    /// How often to retry the destinations skipped by `best_effort`. Retries happen between
+    /// messages, so they pause while the source is quiet.
+    pub retry_destinations: Option<Duration>,
+    pub heartbeat: Option<(String, Duration)>,
+    /// Read stdin as raw records separated by `delimiter` rather than as lines.
+    pub from_stdin_raw: bool,
+    pub delimiter: String,
+    /// Read stdin lines in batches through a large buffer, for when netpipe is on a busy
+    /// pipe, passing lines that aren't valid UTF-8 on as binary.
+    pub raw_stdin: bool,
+    /// Replace invalid UTF-8 in what the source receives, rather than passing it on as
+    /// binary or, for stdin lines, ending the source.
+    pub utf8_lossy: bool,
+    /// Pass messages on as bytes, unchanged: stdin is read in chunks as they come rather than
+    /// in lines, stdout gets them without a newline, and WebSocket destinations send binary
+    /// frames.
+    pub binary: bool,
+    /// Stop at the anomalies that `strict` lists rather than get past them.
+    pub strict: bool,
+    /// How the messages of the source are read, and how they are written for the
+    /// destinations, such as JSON Lines in and plain values out.
+    pub input_format: Option<InputFormat>,
+    pub output_format: Option<OutputFormat>,
+    /// Check and take off the checksum that a netpipe with `checksum` on the other end of the
+    /// link added to each message, and add one to each forwarded message.
+    pub verify_checksum: Option<Checksum>,
+    pub checksum: Option<Checksum>,
+    /// Take off and follow the sequence numbers that a netpipe with `sequence` on the other end
+    /// of the link put on each message, logging the messages missing, and put one on each
+    /// forwarded message.
+    pub detect_gaps: bool,
+    pub sequence: bool,
+    /// Delimiter on which each message is split into several.
+    pub split: Option<String>,
+    /// Regex filters and substitutions applied to each message in the order given, such as to
+    /// forward only some lines or to redact secrets.
+    pub rules: Vec<Rule>,
+    /// Bounds, in bytes, on the messages that are forwarded. Others are dropped.
+    pub min_bytes: Option<usize>,
+    pub max_bytes: Option<usize>,
+    /// Forward a numeric message only once it moved this far from the last forwarded one,
+    /// taking the number from the field picked by `min_change_by`, if given.
+    pub min_change: Option<Threshold>,
+    pub min_change_by: Option<Selector>,
+    /// Validate each message against a JSON Schema, and deal with invalid ones as `on_invalid`
+    /// says.
+    pub schema: Option<Schema>,
+    pub on_invalid: Invalid,
+    /// Join lines into multi-line records, each starting with a line that matches `multiline`,
+    /// and passed on once the next one starts or after `multiline_timeout` without lines.
+    pub multiline: Option<Regex>,
+    pub multiline_timeout: Duration,
+    /// Decode each message as a protobuf message, forwarding one of its fields as text or the
+    /// whole message in base64.
+    pub protobuf: Option<Protobuf>,
+    /// Rewrite the timestamp in the field picked by `timestamp` from `timestamp_from`, RFC 3339
+    /// by default, to `timestamp_to`, epoch milliseconds by default, in local time if
+    /// `local_time`.
+    pub timestamp: Option<Selector>,
+    pub timestamp_from: Option<TimeFormat>,
+    pub timestamp_to: Option<TimeFormat>,
+    pub local_time: bool,
+    pub reorder_by: Option<Selector>,
+    pub reorder_window: usize,
+    pub reorder_timeout: Duration,
+    /// Summarize numeric messages, taken from the field picked by `aggregate_by`, if given,
+    /// with these functions once per `aggregate_window`.
+    pub aggregate: Vec<Function>,
+    pub aggregate_window: Duration,
+    pub aggregate_by: Option<Selector>,
+    /// Times of day outside which messages are dropped or, with `outside_window_hold`, held
+    /// until one opens, in the time zone `window_tz` or else in local time.
+    pub windows: Vec<Window>,
+    pub window_tz: Option<Tz>,
+    pub outside_window_hold: bool,
+    /// Tag each forwarded message with the host name, process ID and a sequence number.
+    pub enrich: Option<Enrichment>,
+    /// Latency added to each message, plus a random amount of up to `jitter`, for testing.
+    pub delay: Duration,
+    pub jitter: Duration,
+    /// Stop after forwarding this many messages from the source.
+    pub max_messages: Option<usize>,
+    /// Factor by which a `replay://` source speeds up the original timing.
+    pub speed: f64,
+    /// Replay a `replay://` source over and over instead of ending after one pass.
+    pub looping: bool,
+    /// Port on which `/healthz` reports whether the destinations meet `health_require`.
+    pub health_port: Option<u16>,
+    pub health_require: Readiness,
+    /// Port on which `/stats` reports the counts of dropped messages by reason.
+    pub stats_port: Option<u16>,
+    /// Port on which `/pause` and `/resume` stop and restart forwarding, and how many messages
+    /// are kept to forward once resumed.
+    pub control_port: Option<u16>,
+    pub pause_buffer: usize,
+    /// Length of the queue of pending connections on the listeners netpipe opens.
+    pub listen_backlog: Option<i32>,
+    /// Let several instances bind the same ports, see [`ListenOptions::reuse_port`].
+    pub reuse_port: bool,
+    /// Network interface to bind sockets to, see [`ListenOptions::device`].
+    pub bind_device: Option<String>,
+    /// Expect a PROXY protocol header, version 1 or 2, at the start of each connection to a
+    /// WebSocket or `tcp-raw-listen://` destination, from a load balancer such as HAProxy, and
+    /// take the client's address from it.
+    pub proxy_protocol: bool,
+    /// Log the upgrade requests of WebSocket clients, leaving out the values of credentials
+    /// and of the headers in `redact_headers`.
+    pub debug_handshake: bool,
+    pub redact_headers: Vec<String>,
+    /// Origins from which browsers may connect to a WebSocket destination, any if `None`.
+    pub origin_allowlist: Option<Vec<String>>,
+    /// Addresses that may send to a UDP source.
+    pub udp_sources: SourceFilter,
+    /// The largest datagram a UDP source takes, beyond which datagrams are dropped rather than
+    /// passed on cut short.
+    pub udp_buffer_size: usize,
+    /// Network interface that UDP sources on a multicast group join it on.
+    pub multicast_iface: Option<Interface>,
+    /// Hops that what UDP destinations send to multicast groups may take, the system's
+    /// default of 1 if `None`, and whether it loops back to this host, as it does by default.
+    pub multicast_ttl: Option<u8>,
+    pub multicast_loop: bool,
+    /// Messages held for a WebSocket client that doesn't keep up, beyond which the oldest are
+    /// dropped, the depth from which it is reported as slow, and how long it may stay slow
+    /// before it is disconnected.
+    pub ws_queue_limit: Option<usize>,
+    pub slow_client_queue: usize,
+    pub slow_client_timeout: Option<Duration>,
+    /// Messages that may wait for each destination, beyond which the pipeline waits for room
+    /// or, with `worker_queue_drop`, drops the message for that destination.
+    pub worker_queue: usize,
+    pub worker_queue_drop: bool,
+    /// Messages that the stdin, `ws://` and UDP sources may read ahead of the destinations,
+    /// beyond which the source waits for room or, with `source_buffer_drop`, drops the oldest
+    /// of them.
+    pub source_buffer: usize,
+    pub source_buffer_drop: bool,
+    /// The stack size of the threads netpipe starts, the platform's default if `None`.
+    pub thread_stack_size: Option<usize>,
+    /// Limit on establishing an outbound connection, after which the attempt counts as failed.
+    pub connect_timeout: Duration,
+    /// PEM files with the extra root certificates trusted by `tls://` destinations and `wss://`
+    /// sources, and the certificate chain and private key presented by `tls-listen://` sources
+    /// and `wss://` destinations.
+    pub tls_ca: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Print this many messages from the source to stderr and exit, for `netpipe peek
+    /// <source>`.
+    pub peek: Option<usize>,
+    /// Show the messages from the source in a terminal UI, for `netpipe monitor <source>`.
+    pub monitor: bool,
+    /// Messages injected when a `ws://`, `tcp://`, `http-stream://` or `amqp://` source loses
+    /// its connection, and when it gets it back.
+    pub disconnect_sentinel: Option<String>,
+    pub reconnect_sentinel: Option<String>,
+    /// How many times in a row a `ws://`, `http-stream://` or `amqp://` source that lost its
+    /// connection tries to get it back before it ends, without limit if `None`, and how long
+    /// it waits before the first try, doubling with each failed one.
+    pub max_reconnects: Option<u32>,
+    pub reconnect_delay: Duration,
+    /// File to record what is forwarded to, for `replay://`, rotated once it reaches
+    /// `capture_rotate` bytes, 100 MB by default.
+    pub capture: Option<String>,
+    pub capture_rotate: Option<usize>,
+    /// Destination for the messages that brokers gave up on, such as those that overflowed a
+    /// queue or whose send failed.
+    pub deadletter: Option<String>,
+    /// Sources given with `--in`, whose messages are merged. Without any, the first of the
+    /// `arguments` is the source and the rest are destinations; with some, all of them are.
+    pub sources: Vec<String>,
+    /// Destinations given with `--out` or read from `--destinations-file`, in addition to
+    /// those in `arguments`, and the rules of those that only get some messages.
+    pub destinations: Vec<String>,
+    pub routes: HashMap<String, Route>,
+    pub arguments: Vec<String>,
+}
+
+impl Options {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options {
+            color: false,
+            tee: false,
+            ignore_broken_pipe: false,
+            best_effort: false,
+            retry_destinations: None,
+            heartbeat: None,
+            from_stdin_raw: false,
+            raw_stdin: false,
+            delimiter: "\n".to_string(),
+            utf8_lossy: false,
+            binary: false,
+            strict: false,
+            input_format: None,
+            output_format: None,
+            verify_checksum: None,
+            checksum: None,
+            detect_gaps: false,
+            sequence: false,
+            split: None,
+            rules: vec![],
+            min_bytes: None,
+            max_bytes: None,
+            min_change: None,
+            min_change_by: None,
+            schema: None,
+            multiline: None,
+            multiline_timeout: Duration::from_secs(1),
+            protobuf: None,
+            on_invalid: Invalid::Drop,
+            timestamp: None,
+            timestamp_from: None,
+            timestamp_to: None,
+            local_time: false,
+            reorder_by: None,
+            reorder_window: 64,
+            reorder_timeout: Duration::from_secs(1),
+            aggregate: vec![],
+            aggregate_window: Duration::from_secs(1),
+            aggregate_by: None,
+            windows: vec![],
+            window_tz: None,
+            outside_window_hold: false,
+            enrich: None,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            max_messages: None,
+            speed: 1.0,
+            looping: false,
+            health_port: None,
+            health_require: Readiness::All,
+            stats_port: None,
+            control_port: None,
+            pause_buffer: 10_000,
+            listen_backlog: None,
+            reuse_port: false,
+            bind_device: None,
+            proxy_protocol: false,
+            debug_handshake: false,
+            redact_headers: vec![],
+            origin_allowlist: None,
+            udp_sources: SourceFilter::default(),
+            udp_buffer_size: 65_535,
+            multicast_iface: None,
+            multicast_ttl: None,
+            multicast_loop: true,
+            ws_queue_limit: None,
+            slow_client_queue: 100,
+            slow_client_timeout: None,
+            worker_queue: 10_000,
+            worker_queue_drop: false,
+            source_buffer: 10_000,
+            source_buffer_drop: false,
+            thread_stack_size: None,
+            connect_timeout: Duration::from_secs(10),
+            tls_ca: None,
+            tls_cert: None,
+            tls_key: None,
+            peek: None,
+            monitor: false,
+            disconnect_sentinel: None,
+            reconnect_sentinel: None,
+            max_reconnects: None,
+            reconnect_delay: Duration::from_millis(500),
+            capture: None,
+            capture_rotate: None,
+            deadletter: None,
+            destinations: vec![],
+            routes: HashMap::new(),
+            sources: vec![],
+            arguments: vec![],
+        };
+        let mut count = None;
+        let mut routes = Routes::default();
+        let mut outputs = vec![];
+        let (mut protobuf, mut protobuf_message, mut protobuf_field) = (None, None, None);
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if arg.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("Missing value for {name}."))
+            };
+            match name {
+                "--color" => options.color = true,
+                "--tee" => options.tee = true,
+                "--ignore-broken-pipe" => options.ignore_broken_pipe = true,
+                "--best-effort" => options.best_effort = true,
+                "--retry-destinations" => {
+                    options.retry_destinations = Some(parse_duration(&value()?)?)
+                }
+                "--heartbeat" => options.heartbeat = Some(parse_heartbeat(&value()?)?),
+                "--from-stdin-raw" => options.from_stdin_raw = true,
+                "--raw-stdin" => options.raw_stdin = true,
+                "--delimiter" => options.delimiter = non_empty(name, unescape(&value()?))?,
+                "--utf8-lossy" => options.utf8_lossy = true,
+                "--binary" => options.binary = true,
+                "--strict" => options.strict = true,
+                "--enrich" => options.enrich = Some(Enrichment::parse(&value()?)?),
+                "--input-format" => options.input_format = Some(InputFormat::parse(&value()?)?),
+                "--output-format" => options.output_format = Some(OutputFormat::parse(&value()?)?),
+                "--verify-checksum" => {
+                    options.verify_checksum = Some(Checksum::parse(name, &value()?)?)
+                }
+                "--checksum" => options.checksum = Some(Checksum::parse(name, &value()?)?),
+                "--detect-gaps" => options.detect_gaps = true,
+                "--sequence" => options.sequence = true,
+                "--split" => options.split = Some(unescape(&value()?)),
+                "--filter" => options.rules.push(Rule::filter(&value()?)?),
+                "--replace" => options
+                    .rules
+                    .push(Rule::Replace(Replace::parse(&value()?)?)),
+                "--min-bytes" => options.min_bytes = Some(parse_number(name, &value()?)?),
+                "--max-bytes" => options.max_bytes = Some(parse_number(name, &value()?)?),
+                "--min-change" => options.min_change = Some(Threshold::parse(&value()?)?),
+                "--min-change-by" => options.min_change_by = Some(Selector::parse(&value()?)?),
+                "--schema" => options.schema = Some(Schema::load(&value()?)?),
+                "--multiline" => {
+                    let pattern = value()?;
+                    let start = Regex::new(&pattern)
+                        .map_err(|e| format!("Invalid pattern for --multiline: {e}"))?;
+                    options.multiline = Some(start);
+                }
+                "--multiline-timeout" => {
+                    options.multiline_timeout = parse_duration(&value()?)?;
+                    if options.multiline_timeout.is_zero() {
+                        return Err("--multiline-timeout must be positive.".to_string());
+                    }
+                }
+                "--protobuf" => protobuf = Some(value()?),
+                "--protobuf-message" => protobuf_message = Some(value()?),
+                "--protobuf-field" => protobuf_field = Some(value()?),
+                "--on-invalid" => options.on_invalid = Invalid::parse(&value()?)?,
+                "--timestamp" => options.timestamp = Some(Selector::parse(&value()?)?),
+                "--timestamp-from" => options.timestamp_from = Some(TimeFormat::parse(&value()?)?),
+                "--timestamp-to" => options.timestamp_to = Some(TimeFormat::parse(&value()?)?),
+                "--local-time" => options.local_time = true,
+                "--reorder-by" => options.reorder_by = Some(Selector::parse(&value()?)?),
+                "--reorder-window" => options.reorder_window = parse_number(name, &value()?)?,
+                "--reorder-timeout" => options.reorder_timeout = parse_duration(&value()?)?,
+                "--aggregate" => options.aggregate = Function::parse_list(&value()?)?,
+                "--aggregate-window" => {
+                    options.aggregate_window = parse_duration(&value()?)?;
+                    if options.aggregate_window.is_zero() {
+                        return Err("--aggregate-window must be positive.".to_string());
+                    }
+                }
+                "--aggregate-by" => options.aggregate_by = Some(Selector::parse(&value()?)?),
+                "--window" => options.windows.push(Window::parse(&value()?)?),
+                "--window-tz" => options.window_tz = Some(parse_time_zone(&value()?)?),
+                "--outside-window" => {
+                    options.outside_window_hold = match value()?.as_str() {
+                        "drop" => false,
+                        "hold" => true,
+                        other => {
+                            return Err(format!(
+                                "Expected drop or hold for --outside-window, got {other}."
+                            ))
+                        }
+                    }
+                }
+                "--delay" => options.delay = parse_duration(&value()?)?,
+                "--jitter" => options.jitter = parse_duration(&value()?)?,
+                "--max-messages" => options.max_messages = Some(parse_number(name, &value()?)?),
+                "--speed" => options.speed = parse_speed(&value()?)?,
+                "--loop" => options.looping = true,
+                "--health-port" => options.health_port = Some(parse_number(name, &value()?)?),
+                "--health-require" => options.health_require = Readiness::parse(&value()?)?,
+                "--stats-port" => options.stats_port = Some(parse_number(name, &value()?)?),
+                "--control-port" => options.control_port = Some(parse_number(name, &value()?)?),
+                "--pause-buffer" => options.pause_buffer = parse_number(name, &value()?)?,
+                "--listen-backlog" => options.listen_backlog = Some(parse_number(name, &value()?)?),
+                "--reuse-port" => options.reuse_port = true,
+                "--proxy-protocol" => options.proxy_protocol = true,
+                "--bind-device" => options.bind_device = Some(non_empty(name, value()?)?),
+                "--debug-handshake" => options.debug_handshake = true,
+                "--redact-header" => options.redact_headers.push(value()?),
+                "--origin-allowlist" => {
+                    options.origin_allowlist = Some(parse_list(name, &value()?)?)
+                }
+                "--allow-source" => options
+                    .udp_sources
+                    .allow
+                    .extend(parse_cidrs(name, &value()?)?),
+                "--deny-source" => options
+                    .udp_sources
+                    .deny
+                    .extend(parse_cidrs(name, &value()?)?),
+                "--iface" => options.multicast_iface = Some(Interface::parse(&value()?)?),
+                "--multicast-ttl" => options.multicast_ttl = Some(parse_number(name, &value()?)?),
+                "--no-multicast-loop" => options.multicast_loop = false,
+                "--udp-buffer-size" => {
+                    options.udp_buffer_size = positive(name, parse_size(&value()?)?)?
+                }
+                "--ws-queue-limit" => {
+                    options.ws_queue_limit = Some(positive(name, parse_number(name, &value()?)?)?)
+                }
+                "--slow-client-queue" => {
+                    options.slow_client_queue = positive(name, parse_number(name, &value()?)?)?
+                }
+                "--slow-client-timeout" => {
+                    options.slow_client_timeout = Some(parse_duration(&value()?)?)
+                }
+                "--worker-queue" => {
+                    options.worker_queue = positive(name, parse_number(name, &value()?)?)?
+                }
+                "--worker-queue-policy" => {
+                    options.worker_queue_drop = match value()?.as_str() {
+                        "block" => false,
+                        "drop" => true,
+                        other => {
+                            return Err(format!(
+                                "Expected block or drop for --worker-queue-policy, got {other}."
+                            ))
+                        }
+                    }
+                }
+                "--source-buffer" => {
+                    options.source_buffer = positive(name, parse_number(name, &value()?)?)?
+                }
+                "--source-buffer-policy" => {
+                    options.source_buffer_drop = match value()?.as_str() {
+                        "block" => false,
+                        "drop" => true,
+                        other => {
+                            return Err(format!(
+                                "Expected block or drop for --source-buffer-policy, got {other}."
+                            ))
+                        }
+                    }
+                }
+                "--thread-stack-size" => {
+                    options.thread_stack_size = Some(positive(name, parse_size(&value()?)?)?)
+                }
+                "--connect-timeout" => {
+                    options.connect_timeout = parse_duration(&value()?)?;
+                    if options.connect_timeout.is_zero() {
+                        return Err("--connect-timeout must be positive.".to_string());
+                    }
+                }
+                "--tls-ca" => options.tls_ca = Some(value()?),
+                "--tls-cert" => options.tls_cert = Some(value()?),
+                "--tls-key" => options.tls_key = Some(value()?),
+                "--disconnect-sentinel" => options.disconnect_sentinel = Some(value()?),
+                "--reconnect-sentinel" => options.reconnect_sentinel = Some(value()?),
+                "--max-reconnects" => options.max_reconnects = Some(parse_number(name, &value()?)?),
+                "--reconnect-delay" => options.reconnect_delay = parse_duration(&value()?)?,
+                "--count" => count = Some(positive(name, parse_number(name, &value()?)?)?),
+                "peek"
+                    if options.arguments.is_empty()
+                        && options.peek.is_none()
+                        && !options.monitor =>
+                {
+                    options.peek = Some(10)
+                }
+                "monitor" if options.arguments.is_empty() && options.peek.is_none() => {
+                    options.monitor = true
+                }
+                "--capture" => options.capture = Some(non_empty(name, value()?)?),
+                "--capture-rotate" => {
+                    options.capture_rotate = Some(positive(name, parse_size(&value()?)?)?)
+                }
+                "--deadletter" => options.deadletter = Some(value()?),
+                "--destinations-file" => routes.read(&value()?)?,
+                "--in" => options.sources.push(non_empty(name, value()?)?),
+                "--out" => outputs.push(non_empty(name, value()?)?),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {arg}.")),
+                _ => options.arguments.push(arg),
+            }
+        }
+        if options.raw_stdin && options.from_stdin_raw {
+            return Err(
+                "--raw-stdin reads lines, so it can't go with --from-stdin-raw.".to_string(),
+            );
+        }
+        if options.binary && (options.raw_stdin || options.from_stdin_raw) {
+            return Err(
+                "--binary reads stdin in chunks, so it can't go with --raw-stdin or --from-stdin-raw."
+                    .to_string(),
+            );
+        }
+        if options.binary && options.utf8_lossy {
+            return Err(
+                "--binary passes bytes on unchanged, so it can't go with --utf8-lossy.".to_string(),
+            );
+        }
+        if options.reuse_port && cfg!(not(unix)) {
+            return Err("--reuse-port is only supported on Unix.".to_string());
+        }
+        if options.bind_device.is_some() && cfg!(not(target_os = "linux")) {
+            return Err("--bind-device is only supported on Linux.".to_string());
+        }
+        if options.windows.is_empty()
+            && (options.window_tz.is_some() || options.outside_window_hold)
+        {
+            return Err("--window-tz and --outside-window require --window.".to_string());
+        }
+        if options.capture.is_none() && options.capture_rotate.is_some() {
+            return Err("--capture-rotate requires --capture.".to_string());
+        }
+        if options.monitor && cfg!(not(feature = "tui")) {
+            return Err("netpipe monitor requires the tui feature.".to_string());
+        }
+        match (&mut options.peek, count) {
+            (Some(peek), Some(count)) => *peek = count,
+            (None, Some(_)) => return Err("--count requires peek.".to_string()),
+            _ => {}
+        }
+        if options.min_change_by.is_some() && options.min_change.is_none() {
+            return Err("--min-change-by requires --min-change.".to_string());
+        }
+        if options.aggregate.is_empty()
+            && (options.aggregate_by.is_some()
+                || options.aggregate_window != Duration::from_secs(1))
+        {
+            return Err("--aggregate-window and --aggregate-by require --aggregate.".to_string());
+        }
+        if options.schema.is_none() && !matches!(options.on_invalid, Invalid::Drop) {
+            return Err("--on-invalid requires --schema.".to_string());
+        }
+        if options.multiline.is_none() && options.multiline_timeout != Duration::from_secs(1) {
+            return Err("--multiline-timeout requires --multiline.".to_string());
+        }
+        match (protobuf, protobuf_message) {
+            (Some(path), Some(message)) => {
+                options.protobuf = Some(Protobuf::load(&path, &message, protobuf_field.as_deref())?)
+            }
+            (Some(_), None) => return Err("--protobuf requires --protobuf-message.".to_string()),
+            (None, message) if message.is_some() || protobuf_field.is_some() => {
+                return Err(
+                    "--protobuf-message and --protobuf-field require --protobuf.".to_string(),
+                )
+            }
+            (None, _) => {}
+        }
+        if options.timestamp.is_none()
+            && (options.timestamp_from.is_some()
+                || options.timestamp_to.is_some()
+                || options.local_time)
+        {
+            return Err(
+                "--timestamp-from, --timestamp-to and --local-time require --timestamp."
+                    .to_string(),
+            );
+        }
+        if options.tls_cert.is_some() != options.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key go together.".to_string());
+        }
+        if cfg!(not(feature = "tls"))
+            && (options.tls_ca.is_some() || options.tls_cert.is_some() || options.tls_key.is_some())
+        {
+            return Err("--tls-ca, --tls-cert and --tls-key require the tls feature.".to_string());
+        }
+        if let (Some(min), Some(max)) = (options.min_bytes, options.max_bytes) {
+            if min > max {
+                return Err(format!("--min-bytes {min} exceeds --max-bytes {max}."));
+            }
+        }
+        routes.finish()?;
+        if let Some(routed) = options
+            .split_arguments()
+            .1
+            .iter()
+            .chain(&outputs)
+            .find(|d| routes.rules.contains_key(*d))
+        {
+            return Err(format!(
+                "{routed} is given both as an argument and with a rule in --destinations-file."
+            ));
+        }
+        outputs.extend(routes.destinations);
+        (options.destinations, options.routes) = (outputs, routes.rules);
+        Ok(options)
+    }
+
+    /// The sources and the destinations among the arguments: those of `--in` and all the
+    /// arguments, or else the first argument, if any, and the rest.
+    pub fn split_arguments(&self) -> (&[String], &[String]) {
+        match self.sources.is_empty() {
+            true => self.arguments.split_at(self.arguments.len().min(1)),
+            false => (&self.sources, &self.arguments),
+        }
+    }
+
+    pub fn listen(&self) -> ListenOptions {
+        ListenOptions {
+            backlog: self.listen_backlog,
+            reuse_port: self.reuse_port,
+            device: self.bind_device.clone(),
+        }
+    }
+
+    /// The file destination that `--capture` stands for.
+    pub fn capture_destination(&self) -> Option<String> {
+        self.capture.as_ref().map(|path| {
+            format!(
+                "file://{path}?format=json&rotate={}",
+                self.capture_rotate.unwrap_or(100 << 20)
+            )
+        })
+    }
+
+    pub fn sentinels(&self) -> Sentinels {
+        Sentinels {
+            disconnected: self.disconnect_sentinel.as_deref().map(Payload::from),
+            reconnected: self.reconnect_sentinel.as_deref().map(Payload::from),
+        }
+    }
+
+    /// How sources that lose their connection try to get it back, or `None` under
+    /// `--max-reconnects 0`, for them to end right away.
+    pub fn reconnect_backoff(&self) -> Option<Backoff> {
+        (self.max_reconnects != Some(0)).then(|| Backoff {
+            initial: self.reconnect_delay,
+            max_delay: Backoff::default().max_delay.max(self.reconnect_delay),
+            max_attempts: self.max_reconnects,
+        })
+    }
+
+    /// Takes the source from `NETPIPE_DEFAULT_IN` and the destinations from the
+    /// whitespace-separated `NETPIPE_DEFAULT_OUT` where the command line gives none, for
+    /// deployments whose routing is fixed by the environment.
+    pub fn with_env_defaults(mut self) -> Options {
+        let var = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value: &String| !value.trim().is_empty())
+        };
+        if self.arguments.is_empty() && self.sources.is_empty() {
+            self.arguments
+                .extend(var("NETPIPE_DEFAULT_IN").map(|source| source.trim().to_string()));
+        }
+        let (sources, destinations) = self.split_arguments();
+        if !sources.is_empty() && destinations.is_empty() && self.destinations.is_empty() {
+            if let Some(destinations) = var("NETPIPE_DEFAULT_OUT") {
+                self.arguments
+                    .extend(destinations.split_whitespace().map(String::from));
+            }
+        }
+        self
+    }
+}
+
+/// Interprets the escapes `\n`, `\r`, `\t`, `\0` and `\\`, so that delimiters can be given
+/// on the command line.
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('0') => unescaped.push('\0'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+fn non_empty(name: &str, value: String) -> Result<String, String> {
+    match value.is_empty() {
+        true => Err(format!("{name} must not be empty.")),
+        false => Ok(value),
+    }
+}
+
+fn parse_number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid number for {name}: {value}."))
+}
+
+fn positive(name: &str, value: usize) -> Result<usize, String> {
+    match value {
+        0 => Err(format!("{name} must be positive.")),
+        value => Ok(value),
+    }
+}
+
+fn parse_speed(value: &str) -> Result<f64, String> {
+    match parse_number("--speed", value)? {
+        speed if speed > 0.0 && f64::is_finite(speed) => Ok(speed),
+        _ => Err(format!("--speed must be positive, got {value}.")),
+    }
+}
+
+/// Parses a comma-separated list, which has to hold at least one entry.
+fn parse_list(name: &str, value: &str) -> Result<Vec<String>, String> {
+    let entries: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from)
+        .collect();
+    if entries.is_empty() {
+        return Err(format!("{name} must not be empty."));
+    }
+    Ok(entries)
+}
+
+/// Parses a comma-separated list of address blocks.
+fn parse_cidrs(name: &str, value: &str) -> Result<Vec<Cidr>, String> {
+    parse_list(name, value)?
+        .iter()
+        .map(|cidr| Cidr::parse(cidr))
+        .collect()
+}
+
+/// Parses an IANA time zone name, such as `Europe/Berlin`.
+fn parse_time_zone(value: &str) -> Result<Tz, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Unknown time zone for --window-tz: {value}."))
+}
+
+/// Parses `<message>@<interval>`, splitting at the last `@` so the message may contain one.
+fn parse_heartbeat(value: &str) -> Result<(String, Duration), String> {
+    let (message, interval) = value
+        .rsplit_once('@')
+        .ok_or_else(|| format!("Expected <message>@<interval> for --heartbeat, got {value}."))?;
+    let interval = parse_duration(interval)?;
+    if interval.is_zero() {
+        return Err("--heartbeat interval must be positive.".to_string());
+    }
+    Ok((message.to_string(), interval))
+}
+
+/// Parses a size in bytes, with an optional `KB`, `MB` or `GB` suffix for multiples of 1024.
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("Invalid size: {value}."))?;
+    let factor: usize = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1 << 10,
+        "MB" => 1 << 20,
+        "GB" => 1 << 30,
+        _ => return Err(format!("Invalid size unit in {value}.")),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("Size too large: {value}."))
+}
+
+/// Parses a duration such as `250ms`, `5s`, `2m` or `1h`. A bare number is taken as seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: {value}."))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("Invalid duration unit in {value}.")),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| format!("Duration out of range: {value}."))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn a_zero_heartbeat_interval_is_refused() {
+        for interval in ["x@0", "x@0ms"] {
+            let error = parse(&["--heartbeat", interval, "stdin", "stdout"]).err();
+            assert_eq!(
+                error.as_deref(),
+                Some("--heartbeat interval must be positive.")
+            );
+        }
+        assert!(parse(&["--heartbeat", "x@1s", "stdin", "stdout"]).is_ok());
+    }
+
+    #[test]
+    fn a_zero_connect_timeout_is_refused() {
+        let error = parse(&["--connect-timeout", "0s", "stdin", "stdout"]).err();
+        assert_eq!(
+            error.as_deref(),
+            Some("--connect-timeout must be positive.")
+        );
+        assert!(parse(&["--connect-timeout", "1s", "stdin", "stdout"]).is_ok());
+    }
+}
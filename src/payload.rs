@@ -0,0 +1,73 @@
+use std::{borrow::Cow, fmt};
+
+/// A message as it travels from the source to the destinations: text, or bytes that aren't
+/// valid UTF-8, which destinations with a binary format of their own pass on unchanged.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Payload {
+    /// Takes bytes as text if they are valid UTF-8, and as binary otherwise.
+    pub fn from_bytes(bytes: Vec<u8>) -> Payload {
+        match String::from_utf8(bytes) {
+            Ok(text) => Payload::Text(text),
+            Err(e) => Payload::Binary(e.into_bytes()),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Payload::Text(text) => text.as_bytes(),
+            Payload::Binary(bytes) => bytes,
+        }
+    }
+
+    /// The message as text, with invalid UTF-8 in a binary message replaced, for destinations
+    /// and transforms that only deal in text.
+    pub fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            Payload::Text(text) => Cow::Borrowed(text),
+            Payload::Binary(bytes) => String::from_utf8_lossy(bytes),
+        }
+    }
+
+    /// Turns a binary message into text, with invalid UTF-8 replaced, for `--utf8-lossy`.
+    pub fn into_lossy_text(self) -> Payload {
+        match self {
+            Payload::Binary(bytes) => Payload::Text(String::from_utf8_lossy(&bytes).into_owned()),
+            text => text,
+        }
+    }
+
+    /// Turns a text message into binary, for `--binary`.
+    pub fn into_binary(self) -> Payload {
+        match self {
+            Payload::Text(text) => Payload::Binary(text.into_bytes()),
+            binary => binary,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+}
+
+impl From<String> for Payload {
+    fn from(text: String) -> Payload {
+        Payload::Text(text)
+    }
+}
+
+impl From<&str> for Payload {
+    fn from(text: &str) -> Payload {
+        Payload::Text(text.to_string())
+    }
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_text())
+    }
+}
@@ -1,56 +1,1003 @@
-use std::{io::stdin, net::UdpSocket, sync::mpsc, thread};
-use tungstenite::connect;
+use crate::broker::FrameLimits;
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, Interface, ListenOptions, SourceFilter, Stream};
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::stats::{DropCounters, Reason};
+use crate::{strict, threads};
+#[cfg(feature = "tls")]
+use rustls::pki_types::ServerName;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    collections::VecDeque,
+    io::{self, stdin, BufRead, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+use tungstenite::{client, Message, WebSocket};
 use url::Url;
 
+#[cfg(feature = "amqp")]
+mod amqp;
+mod file;
+#[cfg(feature = "http-client")]
+mod http_stream;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(target_os = "linux")]
+mod mq;
+#[cfg(windows)]
+mod pipe;
+mod replay;
+mod tcp;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unixgram;
+mod wslisten;
+#[cfg(feature = "amqp")]
+pub use amqp::AmqpReceiverCreator;
+pub use file::FileReceiverCreator;
+#[cfg(feature = "http-client")]
+pub use http_stream::HttpStreamReceiverCreator;
+#[cfg(feature = "kafka")]
+pub use kafka::KafkaReceiverCreator;
+#[cfg(target_os = "linux")]
+pub use mq::MessageQueueReceiverCreator;
+#[cfg(windows)]
+pub use pipe::{pipe_path, PipeReceiverCreator};
+pub use replay::ReplayReceiverCreator;
+pub use tcp::{TcpListenReceiverCreator, TcpReceiverCreator};
+#[cfg(feature = "tls")]
+pub use tls::TlsReceiverCreator;
+#[cfg(unix)]
+pub use unixgram::UnixDatagramReceiverCreator;
+pub use wslisten::WebSocketListenReceiverCreator;
+
+/// The stream of messages produced by a receiver.
+pub type Messages = Box<dyn Iterator<Item = Payload> + Send>;
+
+/// Moves messages onto a channel fed by a background thread, for consumers that need to wait
+/// for them with a timeout. The thread reads at most [`READ_AHEAD`] messages ahead, so that
+/// the source is still held back by the destinations.
+pub fn forward(messages: Messages) -> Receiver<Payload> {
+    let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+    threads::spawn("forward", move || {
+        for message in messages {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// How many messages sources may be read ahead of the pipeline, which spares handing each
+/// one over between threads on its own.
+pub const READ_AHEAD: usize = 1024;
+
+/// Reads each of several sources, named by their options, on a thread of its own, and sends
+/// their messages on `tx` in the order that they arrive, then `None` once all of them have
+/// ended. `tx` is meant to hold [`READ_AHEAD`] messages, so that the sources are still held
+/// back by the destinations.
+pub fn read_ahead(sources: Vec<(String, Messages)>, tx: SyncSender<Option<Payload>>) {
+    let merged = sources.len() > 1;
+    let remaining = Arc::new(AtomicUsize::new(sources.len()));
+    for (option, messages) in sources {
+        let tx = tx.clone();
+        let remaining = remaining.clone();
+        threads::spawn("read-ahead", move || {
+            for message in messages {
+                if tx.send(Some(message)).is_err() {
+                    return;
+                }
+            }
+            // Logged only when there are others, which carry on.
+            if merged {
+                eprintln!("Source ended: {option}.");
+            }
+            if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                let _ = tx.send(None);
+            }
+        });
+    }
+}
+
+/// How many messages a source may read ahead of the destinations, and what becomes of more,
+/// for the stdin, `ws://` and UDP sources. A source that waits for room leaves the rest to
+/// its own backpressure: a pipe or TCP connection that fills up, or datagrams that the kernel
+/// drops.
+#[derive(Clone)]
+pub struct SourceBuffer {
+    pub limit: usize,
+    /// Drop the oldest message buffered for each that finds the buffer full, rather than
+    /// wait for room, so that what gets through is as recent as it can be.
+    pub drop: bool,
+    pub drops: DropCounters,
+}
+
+impl SourceBuffer {
+    fn channel(&self) -> (BufferedSender, Messages) {
+        if !self.drop {
+            let (tx, rx) = mpsc::sync_channel(self.limit);
+            return (BufferedSender::Wait(tx), Box::new(rx.into_iter()));
+        }
+        let queue = Arc::new(DropOldest {
+            limit: self.limit,
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+        });
+        let drops = self.drops.clone();
+        (
+            BufferedSender::DropOldest(queue.clone(), drops),
+            Box::new(Drain(queue)),
+        )
+    }
+}
+
+/// The sending end of the channel that a source puts its messages on.
+trait Outlet {
+    /// Passes a message on, returning whether the receiving end is still there.
+    fn put(&self, message: Payload) -> bool;
+}
+
+impl Outlet for SyncSender<Payload> {
+    fn put(&self, message: Payload) -> bool {
+        self.send(message).is_ok()
+    }
+}
+
+/// The sending end of a [`SourceBuffer`], counting what it drops.
+enum BufferedSender {
+    Wait(SyncSender<Payload>),
+    DropOldest(Arc<DropOldest>, DropCounters),
+}
+
+impl Outlet for BufferedSender {
+    fn put(&self, message: Payload) -> bool {
+        let (queue, drops) = match self {
+            BufferedSender::Wait(tx) => return tx.send(message).is_ok(),
+            BufferedSender::DropOldest(queue, drops) => (queue, drops),
+        };
+        let mut state = queue.lock();
+        if state.drained {
+            return false;
+        }
+        let dropped = (state.messages.len() >= queue.limit)
+            .then(|| state.messages.pop_front())
+            .flatten();
+        state.messages.push_back(message);
+        drop(state);
+        queue.ready.notify_one();
+        if let Some(message) = dropped {
+            drops.count_lost(Reason::QueueFull, &message);
+        }
+        true
+    }
+}
+
+impl Drop for BufferedSender {
+    fn drop(&mut self) {
+        if let BufferedSender::DropOldest(queue, _) = self {
+            queue.lock().ended = true;
+            queue.ready.notify_one();
+        }
+    }
+}
+
+/// The buffer of a [`SourceBuffer`] that drops the oldest message to make room, which a
+/// channel can't do from the sending end.
+struct DropOldest {
+    limit: usize,
+    state: Mutex<QueueState>,
+    /// Signalled when a message is buffered or the source ends.
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<Payload>,
+    /// The source has ended, and what is buffered is all there is to come.
+    ended: bool,
+    /// The receiving end is gone, so the source may as well end.
+    drained: bool,
+}
+
+impl DropOldest {
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The receiving end of a [`DropOldest`] buffer.
+struct Drain(Arc<DropOldest>);
+
+impl Iterator for Drain {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        let mut state = self.0.lock();
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                return Some(message);
+            }
+            if state.ended {
+                return None;
+            }
+            state = self
+                .0
+                .ready
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl Drop for Drain {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.drained = true;
+        state.messages.clear();
+    }
+}
+
+/// Messages injected by connection-oriented sources when they lose their connection and when
+/// they get it back, so that consumers can tell a gap from a quiet source.
+#[derive(Clone, Default)]
+pub struct Sentinels {
+    pub disconnected: Option<Payload>,
+    pub reconnected: Option<Payload>,
+}
+
+impl Sentinels {
+    fn disconnected(&self, tx: &impl Outlet) {
+        if let Some(sentinel) = &self.disconnected {
+            tx.put(sentinel.clone());
+        }
+    }
+
+    fn reconnected(&self, tx: &impl Outlet) {
+        if let Some(sentinel) = &self.reconnected {
+            tx.put(sentinel.clone());
+        }
+    }
+}
+
 pub trait ReceiverCreator {
-    fn matches(&self, option: &String) -> bool;
-    fn create_receiver(&self, option: &String) -> Box<dyn Iterator<Item = String>>;
+    fn matches(&self, option: &str) -> bool;
+    fn create_receiver(&self, option: &str) -> Result<Messages>;
+}
+
+/// Feeds what a source reads onto `tx` until it ends or fails (which is logged), returning
+/// whether the receiving end is still there.
+fn feed(
+    tx: &impl Outlet,
+    source: &str,
+    messages: impl Iterator<Item = io::Result<String>>,
+) -> bool {
+    for message in messages {
+        match message {
+            Ok(message) => {
+                if !tx.put(message.into()) {
+                    return false;
+                }
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidData {
+                    strict::check(|| format!("a line from {source} isn't valid UTF-8"));
+                }
+                eprintln!("Failed to read from {source}: {e}.");
+                break;
+            }
+        }
+    }
+    true
+}
+
+/// Decodes a message as UTF-8, replacing invalid sequences rather than dropping it.
+fn decode(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| {
+        strict::check(|| "a record isn't valid UTF-8".to_string());
+        eprintln!("Replaced invalid UTF-8 in a record.");
+        String::from_utf8_lossy(e.as_bytes()).into_owned()
+    })
+}
+
+pub struct StdinReceiverCreator {
+    raw_delimiter: Option<Vec<u8>>,
+    utf8_lossy: bool,
+    batched: bool,
+    chunked: bool,
+    buffer: SourceBuffer,
+}
+
+impl StdinReceiverCreator {
+    /// With a raw delimiter, stdin is split on exactly that byte sequence instead of into
+    /// lines, keeping any `\r`, and a final record without a delimiter is still emitted.
+    /// Lines that aren't valid UTF-8 end the source, unless `utf8_lossy`, in which case the
+    /// invalid bytes are replaced. With `batched`, lines are read as [`read_batches`] does,
+    /// and with `chunked`, each read is a binary message of whatever bytes it brought in.
+    pub fn new(
+        raw_delimiter: Option<&str>,
+        utf8_lossy: bool,
+        batched: bool,
+        chunked: bool,
+        buffer: SourceBuffer,
+    ) -> StdinReceiverCreator {
+        StdinReceiverCreator {
+            raw_delimiter: raw_delimiter.map(|d| d.as_bytes().to_vec()),
+            utf8_lossy,
+            batched,
+            chunked,
+            buffer,
+        }
+    }
+}
+
+/// The most bytes that a message read from stdin in chunks holds.
+const STDIN_CHUNK: usize = 64 * 1024;
+
+/// The buffer that [`read_batches`] reads stdin through, and the most lines it hands over at
+/// once.
+const STDIN_BUFFER: usize = 1 << 20;
+const STDIN_BATCH: usize = 1024;
+
+/// Reads lines into one reused buffer and hands them over in batches, of as many lines as
+/// each read brought in, so that a busy pipe costs a channel send per read rather than per
+/// line, until `hand_over` returns false. Lines that aren't valid UTF-8 are passed on as
+/// binary.
+fn read_batches(
+    mut reader: io::BufReader<impl Read>,
+    mut hand_over: impl FnMut(Vec<Payload>) -> bool,
+) -> io::Result<()> {
+    let mut line = Vec::new();
+    let mut batch = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            if !batch.is_empty() {
+                hand_over(batch);
+            }
+            return Ok(());
+        }
+        let mut end = line.len();
+        if line[..end].ends_with(b"\n") {
+            end -= 1;
+        }
+        if line[..end].ends_with(b"\r") {
+            end -= 1;
+        }
+        let message = Payload::from_bytes(line[..end].to_vec());
+        if let Payload::Binary(_) = message {
+            strict::check(|| "a line from stdin isn't valid UTF-8".to_string());
+        }
+        batch.push(message);
+        // Lines still in the buffer are there without waiting for more input.
+        if (reader.buffer().is_empty() || batch.len() == STDIN_BATCH)
+            && !hand_over(std::mem::take(&mut batch))
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads lines like [`BufRead::lines`], but with invalid UTF-8 replaced instead of failing.
+fn lossy_lines(reader: impl BufRead) -> impl Iterator<Item = io::Result<String>> {
+    reader.split(b'\n').map(|line| {
+        let mut line = line?;
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    })
 }
 
-pub struct StdinReceiverCreator;
 impl ReceiverCreator for StdinReceiverCreator {
-    fn matches(&self, option: &String) -> bool {
-        return option.eq("stdin");
+    fn matches(&self, option: &str) -> bool {
+        option.eq("stdin")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        if self.chunked {
+            let (tx, rx) = self.buffer.channel();
+            threads::spawn("stdin-recv", move || {
+                let mut stdin = stdin().lock();
+                let mut chunk = vec![0; STDIN_CHUNK];
+                loop {
+                    let size = match stdin.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(size) => size,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            eprintln!("Failed to read from stdin: {e}.");
+                            break;
+                        }
+                    };
+                    if !tx.put(Payload::Binary(chunk[..size].to_vec())) {
+                        break;
+                    }
+                }
+            });
+            return Ok(rx);
+        }
+        if self.batched && self.buffer.drop {
+            // Messages are dropped one at a time, so they are buffered that way.
+            let (tx, rx) = self.buffer.channel();
+            threads::spawn("stdin-recv", move || {
+                let reader = io::BufReader::with_capacity(STDIN_BUFFER, stdin().lock());
+                let hand_over = |batch: Vec<Payload>| batch.into_iter().all(|m| tx.put(m));
+                if let Err(e) = read_batches(reader, hand_over) {
+                    eprintln!("Failed to read from stdin: {e}.");
+                }
+            });
+            return Ok(rx);
+        }
+        if self.batched {
+            // The buffer holds batches.
+            let (tx, rx) = mpsc::sync_channel(self.buffer.limit.div_ceil(STDIN_BATCH));
+            threads::spawn("stdin-recv", move || {
+                let reader = io::BufReader::with_capacity(STDIN_BUFFER, stdin().lock());
+                if let Err(e) = read_batches(reader, |batch| tx.send(batch).is_ok()) {
+                    eprintln!("Failed to read from stdin: {e}.");
+                }
+            });
+            return Ok(Box::new(rx.into_iter().flatten()));
+        }
+        let (tx, rx) = self.buffer.channel();
+        let option = option.to_string();
+        match self.raw_delimiter.clone() {
+            Some(delimiter) => threads::spawn("stdin-recv", move || {
+                feed(&tx, &option, Records::new(stdin().lock(), delimiter));
+            }),
+            None if self.utf8_lossy => threads::spawn("stdin-recv", move || {
+                feed(&tx, &option, lossy_lines(stdin().lock()));
+            }),
+            None => threads::spawn("stdin-recv", move || {
+                feed(&tx, &option, stdin().lines());
+            }),
+        };
+        Ok(rx)
+    }
+}
+
+/// Reads the records separated by a delimiter, which may be several bytes long.
+struct Records<R> {
+    reader: R,
+    delimiter: Vec<u8>,
+}
+
+impl<R: BufRead> Records<R> {
+    fn new(reader: R, delimiter: Vec<u8>) -> Records<R> {
+        Records { reader, delimiter }
     }
 
-    fn create_receiver(&self, _option: &String) -> Box<dyn Iterator<Item = String>> {
-        return Box::new(stdin().lines().into_iter().map(|l| l.unwrap()));
+    fn read_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let last = *self.delimiter.last().unwrap();
+        let mut record = vec![];
+        loop {
+            if self.reader.read_until(last, &mut record)? == 0 {
+                return Ok((!record.is_empty()).then_some(record));
+            }
+            if record.ends_with(&self.delimiter) {
+                record.truncate(record.len() - self.delimiter.len());
+                return Ok(Some(record));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let record = self.read_record().transpose()?;
+        Some(record.map(decode))
+    }
+}
+
+/// Connects to `ws://`, `ws+unix://` or `wss://` sources and forwards what the server sends.
+/// A connection the server closes or that fails is reestablished with `reconnect`, and the
+/// messages from the new one go on in the same stream. Connecting at startup is retried the
+/// same way.
+#[derive(Clone)]
+pub struct WebSocketReceiverCreator {
+    connect_timeout: Duration,
+    sentinels: Sentinels,
+    reconnect: Option<Backoff>,
+    buffer: SourceBuffer,
+    /// Whether text frames that aren't valid UTF-8 are decoded lossily, for `--utf8-lossy`,
+    /// rather than ending the connection.
+    utf8_lossy: bool,
+    /// The extra root certificates that `wss://` servers are trusted by, from `--tls-ca`.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    ca: Option<String>,
+}
+
+impl WebSocketReceiverCreator {
+    pub fn new(
+        connect_timeout: Duration,
+        sentinels: Sentinels,
+        reconnect: Option<Backoff>,
+        buffer: SourceBuffer,
+        utf8_lossy: bool,
+        ca: Option<String>,
+    ) -> WebSocketReceiverCreator {
+        WebSocketReceiverCreator {
+            connect_timeout,
+            sentinels,
+            reconnect,
+            buffer,
+            utf8_lossy,
+            ca,
+        }
+    }
+
+    /// Connects and performs the handshake, neither of which may take longer than the connect
+    /// timeout. With `ws+unix://<socket path>[:<request path>]`, the connection is made to a
+    /// Unix socket instead, and with `wss://` it is made over TLS, verifying that the server's
+    /// certificate is for its host. What the server sends is bounded by `limits`.
+    fn connect(
+        &self,
+        url: &Url,
+        limits: FrameLimits,
+    ) -> std::result::Result<WebSocket<Relabel>, Box<dyn std::error::Error + Send + Sync>> {
+        let (stream, request) = match url.scheme() {
+            #[cfg(unix)]
+            "ws+unix" => {
+                let (socket, path) = net::unix_target(url);
+                let stream = Stream::Unix(UnixStream::connect(socket)?);
+                (stream, Url::parse(&format!("ws://localhost{path}"))?)
+            }
+            #[cfg(feature = "tls")]
+            "wss" => {
+                let host = url.host_str().ok_or("missing host")?;
+                let port = url.port_or_known_default().ok_or("missing port")?;
+                let name = ServerName::try_from(host.to_string())?;
+                let config = crate::tls::client_config(self.ca.as_deref(), true)?;
+                let stream = net::connect(&format!("{host}:{port}"), self.connect_timeout)?;
+                let stream = crate::tls::connect(stream, &name, &config, self.connect_timeout)?;
+                (stream, url.clone())
+            }
+            _ => {
+                let host = url.host_str().ok_or("missing host")?;
+                let port = url.port_or_known_default().ok_or("missing port")?;
+                let stream = net::connect(&format!("{host}:{port}"), self.connect_timeout)?;
+                (Stream::Tcp(stream), url.clone())
+            }
+        };
+        stream.set_read_timeout(Some(self.connect_timeout))?;
+        let stream = Relabel::new(stream, self.utf8_lossy);
+        let (mut socket, _) =
+            client(request, stream).map_err(|e| NetpipeError::Protocol(e.to_string()))?;
+        socket.get_ref().stream.set_read_timeout(None)?;
+        limits.apply(&mut socket);
+        Ok(socket)
     }
 }
 
-pub struct WebSocketReceiverCreator;
 impl ReceiverCreator for WebSocketReceiverCreator {
-    fn matches(&self, option: &String) -> bool {
-        return option.starts_with("ws://");
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("ws://")
+            || cfg!(unix) && option.starts_with("ws+unix://")
+            || cfg!(feature = "tls") && option.starts_with("wss://")
     }
 
-    fn create_receiver(&self, option: &String) -> Box<dyn Iterator<Item = String>> {
-        let (mut socket, _) = connect(Url::parse(&option).unwrap()).unwrap();
-        let (tx, rx) = mpsc::channel();
-        thread::spawn(move || loop {
-            let message = socket.read_message().unwrap();
-            tx.send(message.into_text().unwrap()).unwrap();
+    /// `?max_frame=<size>` and `?max_message=<size>` bound the frames and messages that the
+    /// server may send, to keep one from running netpipe out of memory, and are left out of
+    /// the request.
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let mut url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let limits = FrameLimits::parse(&url)?;
+        let query: Vec<(String, String)> = url
+            .query_pairs()
+            .into_owned()
+            .filter(|(key, _)| key != "max_frame" && key != "max_message")
+            .collect();
+        url.set_query(None);
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        // Connecting at startup is as persistent as reconnecting later.
+        let backoff = self.reconnect.clone().unwrap_or_else(Backoff::once);
+        let mut socket = reconnect(&backoff, option, || self.connect(&url, limits))
+            .map_err(|e| NetpipeError::Connect(option.to_string(), e))?;
+        let (tx, rx) = self.buffer.channel();
+        let option = option.to_string();
+        let creator = self.clone();
+        let name = match url.port_or_known_default() {
+            Some(port) => format!("ws-recv:{port}"),
+            None => "ws-recv".to_string(),
+        };
+        threads::spawn(name, move || loop {
+            let message = match socket.read_message() {
+                Ok(Message::Text(text)) => Some(Payload::Text(text)),
+                // Under --utf8-lossy, text frames come as binary ones too and are decoded
+                // lossily further on, with the rest of the binary payloads.
+                Ok(Message::Binary(bytes)) => Some(Payload::Binary(bytes)),
+                Ok(Message::Close(_)) => {
+                    eprintln!("Socket closed: {option}.");
+                    None
+                }
+                // Pings are answered by tungstenite itself, and pongs may come unasked for
+                // as a heartbeat.
+                Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                Ok(message) => {
+                    strict::check(|| format!("{option} sent an unexpected frame: {message:?}"));
+                    continue;
+                }
+                Err(tungstenite::Error::Utf8) => {
+                    strict::check(|| format!("a text frame from {option} isn't valid UTF-8"));
+                    eprintln!("Failed to read from {option}: invalid UTF-8.");
+                    None
+                }
+                Err(e) => {
+                    eprintln!("Failed to read from {option}: {e}.");
+                    None
+                }
+            };
+            let Some(message) = message else {
+                creator.sentinels.disconnected(&tx);
+                let Some(backoff) = &creator.reconnect else {
+                    break;
+                };
+                socket = match reconnect(backoff, &option, || creator.connect(&url, limits)) {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        eprintln!("Gave up reconnecting to {option}: {e}.");
+                        break;
+                    }
+                };
+                eprintln!("Reconnected: {option}.");
+                creator.sentinels.reconnected(&tx);
+                continue;
+            };
+            if !tx.put(message) {
+                break;
+            }
         });
-        return Box::new(rx.into_iter());
+        Ok(rx)
+    }
+}
+
+/// Hands a WebSocket server's frames to tungstenite with text ones relabelled as binary, when
+/// `relabel` is set, so that the payload of one that isn't valid UTF-8 is passed on as it came
+/// rather than failing the connection. The handshake response ahead of the frames is passed
+/// through untouched.
+struct Relabel {
+    stream: Stream,
+    relabel: bool,
+    /// How much of the handshake response's `\r\n\r\n` has been read, up to 4 once the frames
+    /// begin.
+    head: usize,
+    /// The header of the next frame, as far as it has been read.
+    header: Vec<u8>,
+    /// What's left of a header that has been read in full but not yet handed over.
+    pending: VecDeque<u8>,
+    /// The bytes left of the current frame's payload.
+    payload: u64,
+}
+
+impl Relabel {
+    fn new(stream: Stream, relabel: bool) -> Relabel {
+        Relabel {
+            stream,
+            relabel,
+            head: 0,
+            header: Vec::new(),
+            pending: VecDeque::new(),
+            payload: 0,
+        }
+    }
+
+    /// The length of the frame header that `header` begins, once it's long enough to tell.
+    /// Frames from a server aren't masked, but a masking key is skipped over all the same.
+    fn header_len(header: &[u8]) -> usize {
+        if header.len() < 2 {
+            return 2;
+        }
+        let extended = match header[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask = if header[1] & 0x80 != 0 { 4 } else { 0 };
+        2 + extended + mask
+    }
+
+    fn payload_len(header: &[u8]) -> u64 {
+        match header[1] & 0x7f {
+            126 => u16::from_be_bytes([header[2], header[3]]).into(),
+            127 => header[2..10]
+                .iter()
+                .fold(0, |len, &byte| len << 8 | u64::from(byte)),
+            len => len.into(),
+        }
+    }
+}
+
+impl Read for Relabel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.relabel || buf.is_empty() {
+            return self.stream.read(buf);
+        }
+        // The handshake response is read a byte at a time so as not to take in the frames
+        // behind it unrelabelled.
+        if self.head < 4 {
+            let n = self.stream.read(&mut buf[..1])?;
+            if n == 1 {
+                self.head = match (self.head, buf[0]) {
+                    (0 | 2, b'\r') => self.head + 1,
+                    (1 | 3, b'\n') => self.head + 1,
+                    (_, b'\r') => 1,
+                    _ => 0,
+                };
+            }
+            return Ok(n);
+        }
+        loop {
+            if !self.pending.is_empty() {
+                let n = self.pending.len().min(buf.len());
+                for (byte, pending) in buf.iter_mut().zip(self.pending.drain(..n)) {
+                    *byte = pending;
+                }
+                return Ok(n);
+            }
+            if self.payload > 0 {
+                let len = usize::try_from(self.payload)
+                    .unwrap_or(usize::MAX)
+                    .min(buf.len());
+                let n = self.stream.read(&mut buf[..len])?;
+                self.payload -= n as u64;
+                return Ok(n);
+            }
+            let needed = Relabel::header_len(&self.header);
+            if self.header.len() < needed {
+                let mut bytes = [0; 14];
+                let n = self.stream.read(&mut bytes[..needed - self.header.len()])?;
+                if n == 0 {
+                    return Ok(0);
+                }
+                self.header.extend_from_slice(&bytes[..n]);
+                continue;
+            }
+            if self.header[0] & 0x0f == 0x1 {
+                self.header[0] = self.header[0] & 0xf0 | 0x2;
+            }
+            self.payload = Relabel::payload_len(&self.header);
+            self.pending.extend(self.header.drain(..));
+        }
+    }
+}
+
+impl Write for Relabel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Receives datagrams on the address given, dropping those from senders that `sources`
+/// doesn't admit and those larger than `buffer_size` bytes, which would otherwise be passed on
+/// cut short. An address that is a multicast group is joined on `interface`.
+pub struct UdpReceiverCreator {
+    listen: ListenOptions,
+    interface: Option<Interface>,
+    sources: SourceFilter,
+    buffer_size: usize,
+    buffer: SourceBuffer,
+}
+
+impl UdpReceiverCreator {
+    pub fn new(
+        listen: ListenOptions,
+        interface: Option<Interface>,
+        sources: SourceFilter,
+        buffer_size: usize,
+        buffer: SourceBuffer,
+    ) -> UdpReceiverCreator {
+        UdpReceiverCreator {
+            listen,
+            interface,
+            sources,
+            buffer_size,
+            buffer,
+        }
     }
 }
 
-pub struct UdpReceiverCreator;
 impl ReceiverCreator for UdpReceiverCreator {
-    fn matches(&self, _option: &String) -> bool {
-        return true;
+    fn matches(&self, _option: &str) -> bool {
+        true
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let socket = net::bind_udp_source(option, &self.listen, self.interface)
+            .map_err(NetpipeError::Bind)?;
+
+        let (tx, rx) = self.buffer.channel();
+        let sources = self.sources.clone();
+        let drops = self.buffer.drops.clone();
+        let buffer_size = self.buffer_size;
+        threads::spawn(threads::name("udp-recv", socket.local_addr()), move || {
+            // A byte more than a datagram may have, so that one cut short to fit shows.
+            let mut buf = vec![0; buffer_size + 1];
+            loop {
+                let buf_size = match socket.recv_from(&mut buf) {
+                    Ok((_, sender)) if !sources.admits(sender.ip()) => {
+                        drops.count(Reason::Denied);
+                        continue;
+                    }
+                    Ok((buf_size, sender)) if buf_size > buffer_size => {
+                        strict::check(|| {
+                            format!("a datagram from {sender} exceeds --udp-buffer-size")
+                        });
+                        eprintln!(
+                            "Dropped a datagram from {sender} of more than {buffer_size} bytes, beyond --udp-buffer-size."
+                        );
+                        drops.count(Reason::Oversize);
+                        continue;
+                    }
+                    Ok((buf_size, _)) => buf_size,
+                    // E.g. an ICMP port unreachable for an earlier send, which doesn't end the
+                    // source.
+                    Err(e) => {
+                        eprintln!("Failed to receive datagram: {e}.");
+                        continue;
+                    }
+                };
+                // Datagrams that aren't valid UTF-8 are passed on as binary messages.
+                if !tx.put(Payload::from_bytes(buf[..buf_size].to_vec())) {
+                    break;
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: &str) -> Payload {
+        Payload::Text(message.to_string())
     }
 
-    fn create_receiver(&self, option: &String) -> Box<dyn Iterator<Item = String>> {
-        let socket = UdpSocket::bind(option).unwrap();
+    #[test]
+    fn a_full_drop_buffer_keeps_the_newest_messages() {
+        let (deadletter, dropped) = mpsc::channel();
+        let buffer = SourceBuffer {
+            limit: 3,
+            drop: true,
+            drops: DropCounters::default().with_deadletter(deadletter),
+        };
+        let (tx, rx) = buffer.channel();
+        for message in ["1", "2", "3", "4", "5"] {
+            assert!(tx.put(text(message)));
+        }
+        drop(tx);
+        assert_eq!(rx.collect::<Vec<_>>(), ["3", "4", "5"].map(text));
+        assert_eq!(dropped.try_iter().collect::<Vec<_>>(), ["1", "2"].map(text));
+        assert_eq!(
+            buffer.drops.report().as_deref(),
+            Some("Dropped messages: queue_full 2.")
+        );
+    }
+
+    #[test]
+    fn a_drop_buffer_hands_over_messages_as_they_come() {
+        let buffer = SourceBuffer {
+            limit: 2,
+            drop: true,
+            drops: DropCounters::default(),
+        };
+        let (tx, rx) = buffer.channel();
+        let sender = threads::spawn("test-send", move || {
+            for message in ["1", "2", "3"] {
+                tx.put(text(message));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+        assert_eq!(rx.collect::<Vec<_>>(), ["1", "2", "3"].map(text));
+        sender.join().unwrap();
+        assert_eq!(buffer.drops.report(), None);
+    }
+
+    #[test]
+    fn a_drop_buffer_tells_the_source_once_nothing_is_reading() {
+        let buffer = SourceBuffer {
+            limit: 2,
+            drop: true,
+            drops: DropCounters::default(),
+        };
+        let (tx, rx) = buffer.channel();
+        assert!(tx.put(text("1")));
+        drop(rx);
+        assert!(!tx.put(text("2")));
+    }
+
+    #[test]
+    fn udp_datagrams_that_arent_utf8_come_through_as_binary() {
+        // A free port, taken up again by the source.
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let creator = UdpReceiverCreator::new(
+            ListenOptions::default(),
+            None,
+            SourceFilter::default(),
+            1024,
+            SourceBuffer {
+                limit: 16,
+                drop: false,
+                drops: DropCounters::default(),
+            },
+        );
+        let address = format!("127.0.0.1:{port}");
+        let messages = forward(creator.create_receiver(&address).unwrap());
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let datagrams: [&[u8]; 3] = [b"caf\xc3\xa9", b"\xff\xfe\x00\x80", b""];
+        for datagram in datagrams {
+            sender.send_to(datagram, &address).unwrap();
+        }
+        let received: Vec<_> = (0..datagrams.len())
+            .map(|_| messages.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        assert_eq!(
+            received,
+            [
+                text("caf\u{e9}"),
+                Payload::Binary(vec![0xff, 0xfe, 0x00, 0x80]),
+                text(""),
+            ]
+        );
+    }
 
-        let (tx, rx) = mpsc::channel();
-        thread::spawn(move || loop {
-            let mut buf = [0; 8192];
-            let buf_size = socket.recv(&mut buf).unwrap();
-            let buf = &buf[..buf_size];
-            tx.send(String::from_utf8(buf.to_vec()).unwrap()).unwrap();
+    #[test]
+    fn a_text_frame_that_isnt_utf8_is_passed_on_under_utf8_lossy() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = tungstenite::accept(stream).unwrap();
+            // An unfragmented text frame whose payload isn't valid UTF-8, then a valid one.
+            socket.get_mut().write_all(b"\x81\x02\xff\xfe").unwrap();
+            socket
+                .write_message(Message::Text("ok".to_string()))
+                .unwrap();
+            socket.close(None).unwrap();
+            while socket.read_message().is_ok() {}
         });
-        return Box::new(rx.into_iter());
+        let creator = WebSocketReceiverCreator::new(
+            Duration::from_secs(5),
+            Sentinels::default(),
+            None,
+            SourceBuffer {
+                limit: 16,
+                drop: false,
+                drops: DropCounters::default(),
+            },
+            true,
+            None,
+        );
+        let received: Vec<_> = creator.create_receiver(&url).unwrap().collect();
+        assert_eq!(
+            received,
+            [
+                Payload::Binary(vec![0xff, 0xfe]),
+                Payload::Binary(b"ok".to_vec())
+            ]
+        );
+        server.join().unwrap();
     }
 }
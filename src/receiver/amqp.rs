@@ -0,0 +1,154 @@
+use super::{Messages, ReceiverCreator, Sentinels, READ_AHEAD};
+use crate::broker::amqp_option;
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::threads;
+use futures_lite::{future, StreamExt};
+use lapin::options::{BasicAckOptions, BasicConsumeOptions, QueueBindOptions, QueueDeclareOptions};
+use lapin::types::FieldTable;
+use lapin::{Connection, ConnectionProperties, Consumer};
+use std::sync::mpsc::{self, SyncSender};
+
+/// What an `amqp://` option asks to consume.
+struct Source {
+    /// The URI of the server, with the virtual host as its path.
+    uri: String,
+    queue: String,
+    /// The exchange the queue is bound to, and the routing key of the binding.
+    binding: Option<(String, String)>,
+}
+
+impl Source {
+    fn parse(option: &str) -> Result<Source> {
+        let (uri, queue, mut params) = amqp_option(option)?;
+        if queue.is_empty() {
+            return Err(NetpipeError::invalid("missing queue"));
+        }
+        let binding = match (params.remove("exchange"), params.remove("routing_key")) {
+            (Some(exchange), routing_key) => Some((exchange, routing_key.unwrap_or_default())),
+            (None, Some(_)) => return Err(NetpipeError::invalid("routing_key requires exchange")),
+            (None, None) => None,
+        };
+        Ok(Source {
+            uri,
+            queue,
+            binding,
+        })
+    }
+
+    /// Declares the queue, binds it and starts consuming from it. The connection has to be
+    /// kept for as long as the consumer is used.
+    async fn subscribe(&self) -> lapin::Result<(Connection, Consumer)> {
+        let connection = Connection::connect(&self.uri, ConnectionProperties::default()).await?;
+        let channel = connection.create_channel().await?;
+        let queue = self.queue.as_str();
+        channel
+            .queue_declare(
+                queue.into(),
+                QueueDeclareOptions::durable(),
+                FieldTable::default(),
+            )
+            .await?;
+        if let Some((exchange, routing_key)) = &self.binding {
+            channel
+                .queue_bind(
+                    queue.into(),
+                    exchange.as_str().into(),
+                    routing_key.as_str().into(),
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+        let consumer = channel
+            .basic_consume(
+                queue.into(),
+                "netpipe".into(),
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        Ok((connection, consumer))
+    }
+}
+
+/// Passes the deliveries on, acknowledging each once it is on its way, until the pipeline is
+/// gone or the consumer fails, with the reason.
+async fn forward(
+    consumer: &mut Consumer,
+    tx: &SyncSender<Payload>,
+) -> std::result::Result<(), String> {
+    while let Some(delivery) = consumer.next().await {
+        let mut delivery = delivery.map_err(|e| e.to_string())?;
+        let data = std::mem::take(&mut delivery.data);
+        if tx.send(Payload::from_bytes(data)).is_err() {
+            return Ok(());
+        }
+        delivery
+            .ack(BasicAckOptions::default())
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Err("consumer cancelled by the server".to_string())
+}
+
+/// Consumes the durable queue given by `amqp://[<user>:<password>@]<host>[:<port>]/<queue>`,
+/// declaring it if missing, in the virtual host `?vhost=` (`/` by default), and forwards each
+/// message's body. With `?exchange=`, the queue is bound to that exchange, with the routing
+/// key `?routing_key=`. Messages are acknowledged once passed on, and a lost connection is
+/// reestablished with `reconnect`, after which the server redelivers what wasn't
+/// acknowledged.
+pub struct AmqpReceiverCreator {
+    sentinels: Sentinels,
+    reconnect: Option<Backoff>,
+}
+
+impl AmqpReceiverCreator {
+    pub fn new(sentinels: Sentinels, reconnect: Option<Backoff>) -> AmqpReceiverCreator {
+        AmqpReceiverCreator {
+            sentinels,
+            reconnect,
+        }
+    }
+}
+
+impl ReceiverCreator for AmqpReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("amqp://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let source = Source::parse(option)?;
+        let (mut connection, mut consumer) = reconnect(&Backoff::default(), option, || {
+            future::block_on(source.subscribe())
+        })
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let option = option.to_string();
+        let sentinels = self.sentinels.clone();
+        let backoff = self.reconnect.clone();
+        threads::spawn("amqp-recv", move || loop {
+            let reason = match future::block_on(forward(&mut consumer, &tx)) {
+                Ok(()) => return,
+                Err(reason) => reason,
+            };
+            eprintln!("Lost {option}: {reason}.");
+            sentinels.disconnected(&tx);
+            drop(connection);
+            let Some(backoff) = &backoff else { return };
+            (connection, consumer) =
+                match reconnect(backoff, &option, || future::block_on(source.subscribe())) {
+                    Ok(subscribed) => subscribed,
+                    Err(e) => {
+                        eprintln!("Gave up reconnecting to {option}: {e}.");
+                        return;
+                    }
+                };
+            eprintln!("Reconnected: {option}.");
+            sentinels.reconnected(&tx);
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
@@ -0,0 +1,49 @@
+use super::replay::open;
+use super::{Messages, ReceiverCreator, Records, READ_AHEAD};
+use crate::broker::file_option;
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::threads;
+use std::sync::mpsc;
+
+/// Reads the file at `file://<path>` line by line and forwards each line as it is, such as to
+/// feed a file destination's output back through netpipe. Reading stays at most
+/// [`READ_AHEAD`] lines ahead of the destinations, so a large file isn't taken into memory
+/// when they are slower. Unlike `replay://`, envelopes are not unwrapped and no delays are
+/// kept. A compressed file is decompressed as `replay://` does, and lines that aren't valid
+/// UTF-8 are passed on as binary.
+pub struct FileReceiverCreator;
+
+impl ReceiverCreator for FileReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("file://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let (path, _) = file_option(option);
+        if path.is_empty() {
+            return Err(NetpipeError::invalid("missing file path"));
+        }
+        let path = path.to_string();
+        let mut records = Records::new(open(&path).map_err(NetpipeError::Io)?, b"\n".to_vec());
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        threads::spawn("file-recv", move || loop {
+            let mut line = match records.read_record() {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("Failed to read from {path}: {e}.");
+                    return;
+                }
+            };
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if tx.send(Payload::from_bytes(line)).is_err() {
+                return;
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
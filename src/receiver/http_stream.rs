@@ -0,0 +1,80 @@
+use super::{feed, Messages, ReceiverCreator, Sentinels, READ_AHEAD};
+use crate::error::{NetpipeError, Result};
+use crate::retry::{reconnect, Backoff};
+use crate::threads;
+use reqwest::blocking::{Client, Response};
+use std::{
+    io::{BufRead, BufReader},
+    sync::mpsc,
+    time::Duration,
+};
+
+/// Follows a streaming HTTP response, such as a chunked `/stream` endpoint, forwarding each
+/// line of its body. `http-stream://` and `https-stream://` stand for `http://` and
+/// `https://`, and the request is repeated with `reconnect` whenever the response ends.
+pub struct HttpStreamReceiverCreator {
+    client: Client,
+    sentinels: Sentinels,
+    reconnect: Option<Backoff>,
+}
+
+impl HttpStreamReceiverCreator {
+    pub fn new(
+        connect_timeout: Duration,
+        sentinels: Sentinels,
+        reconnect: Option<Backoff>,
+    ) -> HttpStreamReceiverCreator {
+        let client = Client::builder()
+            .connect_timeout(connect_timeout)
+            // The response is read for as long as the server keeps it open.
+            .timeout(None)
+            .build()
+            .unwrap();
+        HttpStreamReceiverCreator {
+            client,
+            sentinels,
+            reconnect,
+        }
+    }
+}
+
+fn get(client: &Client, url: &str) -> reqwest::Result<Response> {
+    client.get(url).send()?.error_for_status()
+}
+
+impl ReceiverCreator for HttpStreamReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("http-stream://") || option.starts_with("https-stream://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let url = option.replacen("-stream://", "://", 1);
+        reqwest::Url::parse(&url).map_err(NetpipeError::invalid)?;
+        let mut response = reconnect(&Backoff::default(), option, || get(&self.client, &url))
+            .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let client = self.client.clone();
+        let option = option.to_string();
+        let sentinels = self.sentinels.clone();
+        let backoff = self.reconnect.clone();
+        threads::spawn("http-recv", move || loop {
+            if !feed(&tx, &option, BufReader::new(response).lines()) {
+                break;
+            }
+            eprintln!("Stream ended: {option}.");
+            sentinels.disconnected(&tx);
+            let Some(backoff) = &backoff else { break };
+            response = match reconnect(backoff, &option, || get(&client, &url)) {
+                Ok(response) => response,
+                Err(e) => {
+                    eprintln!("Gave up reconnecting to {option}: {e}.");
+                    break;
+                }
+            };
+            eprintln!("Reconnected: {option}.");
+            sentinels.reconnected(&tx);
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
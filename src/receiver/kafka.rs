@@ -0,0 +1,95 @@
+use super::{Messages, ReceiverCreator, READ_AHEAD};
+use crate::broker::kafka_option;
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::threads;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::Message;
+use std::{sync::mpsc, thread, time::Duration};
+
+/// Consumes the topic given by `kafka://<host:port>[,<host:port>...]/<topic>` as a member of
+/// the consumer group `?group=` (`netpipe` by default), forwarding each message's payload.
+/// Without committed offsets, the group starts from `?offset=latest` (the default) or
+/// `?offset=earliest`. Offsets are committed periodically, covering only messages already
+/// passed on. Other query parameters are passed to librdkafka as configuration properties.
+pub struct KafkaReceiverCreator {
+    connect_timeout: Duration,
+}
+
+impl KafkaReceiverCreator {
+    pub fn new(connect_timeout: Duration) -> KafkaReceiverCreator {
+        KafkaReceiverCreator { connect_timeout }
+    }
+}
+
+impl ReceiverCreator for KafkaReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("kafka://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let (servers, topic, mut params) = kafka_option(option)?;
+        let group = params
+            .remove("group")
+            .unwrap_or_else(|| "netpipe".to_string());
+        let offset = match params.remove("offset").as_deref() {
+            None | Some("latest") => "latest",
+            Some("earliest") => "earliest",
+            Some(other) => return Err(NetpipeError::invalid(format!("unknown offset {other}"))),
+        };
+
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", servers)
+            .set("group.id", group)
+            .set("auto.offset.reset", offset)
+            // Offsets are stored once a message is on its way, and committed from there.
+            .set("enable.auto.offset.store", "false");
+        for (name, value) in params {
+            config.set(name, value);
+        }
+        let consumer: BaseConsumer = config.create().map_err(NetpipeError::invalid)?;
+        reconnect(&Backoff::default(), option, || {
+            consumer.fetch_metadata(Some(topic), self.connect_timeout)
+        })
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+        consumer
+            .subscribe(&[topic])
+            .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let option = option.to_string();
+        let backoff = Backoff {
+            max_attempts: None,
+            ..Backoff::default()
+        };
+        threads::spawn("kafka-recv", move || {
+            let mut failures = 0;
+            loop {
+                let message = match consumer.poll(Duration::from_millis(500)) {
+                    None => continue,
+                    Some(Ok(message)) => message,
+                    // librdkafka reconnects by itself; this only paces the attempts.
+                    Some(Err(e)) => {
+                        let delay = backoff.jittered_delay(failures);
+                        eprintln!("Failed to consume from {option}: {e}. Retrying in {delay:?}.");
+                        thread::sleep(delay);
+                        failures += 1;
+                        continue;
+                    }
+                };
+                failures = 0;
+                let payload = Payload::from_bytes(message.payload().unwrap_or_default().to_vec());
+                if tx.send(payload).is_err() {
+                    break;
+                }
+                if let Err(e) = consumer.store_offset_from_message(&message) {
+                    eprintln!("Failed to store offset for {option}: {e}.");
+                }
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
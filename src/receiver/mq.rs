@@ -0,0 +1,37 @@
+use super::{Messages, ReceiverCreator, READ_AHEAD};
+use crate::error::Result;
+use crate::mqueue::Queue;
+use crate::payload::Payload;
+use crate::threads;
+use std::{io, sync::mpsc};
+
+/// Receives each message of the POSIX message queue `mq://<name>`, highest priority first,
+/// creating the queue if it is missing. Messages that aren't valid UTF-8 are passed on as
+/// binary ones.
+pub struct MessageQueueReceiverCreator;
+
+impl ReceiverCreator for MessageQueueReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("mq://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let (queue, _) = Queue::open(option, libc::O_RDONLY)?;
+        let option = option.to_string();
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        threads::spawn("mq-recv", move || loop {
+            let message = match queue.receive() {
+                Ok(message) => message,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Failed to receive from {option}: {e}.");
+                    break;
+                }
+            };
+            if tx.send(Payload::from_bytes(message)).is_err() {
+                break;
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
@@ -0,0 +1,111 @@
+use super::{Messages, ReceiverCreator, READ_AHEAD};
+use crate::error::{NetpipeError, Result};
+use crate::threads;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    iter,
+    os::windows::{
+        ffi::OsStrExt,
+        io::{AsRawHandle, FromRawHandle},
+    },
+    ptr,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use windows_sys::Win32::{
+    Foundation::{GetLastError, ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE},
+    Storage::FileSystem::PIPE_ACCESS_INBOUND,
+    System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE,
+        PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    },
+};
+
+/// Turns `pipe://name` or `pipe://\\.\pipe\name` into the full pipe path.
+pub fn pipe_path(option: &str) -> String {
+    let name = option.strip_prefix("pipe://").unwrap();
+    match name.starts_with(r"\\") {
+        true => name.to_string(),
+        false => format!(r"\\.\pipe\{name}"),
+    }
+}
+
+/// Serves a named pipe that one writer at a time can connect to, reading lines until it
+/// disconnects and then waiting for the next one.
+pub struct PipeReceiverCreator;
+
+impl PipeReceiverCreator {
+    fn create_instance(path: &[u16]) -> io::Result<File> {
+        let handle = unsafe {
+            CreateNamedPipeW(
+                path.as_ptr(),
+                PIPE_ACCESS_INBOUND,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+                PIPE_UNLIMITED_INSTANCES,
+                0,
+                65536,
+                0,
+                ptr::null(),
+            )
+        };
+        match handle {
+            INVALID_HANDLE_VALUE => Err(io::Error::last_os_error()),
+            handle => Ok(unsafe { File::from_raw_handle(handle as _) }),
+        }
+    }
+
+    fn connect(pipe: &File) -> io::Result<()> {
+        let connected = unsafe { ConnectNamedPipe(pipe.as_raw_handle() as _, ptr::null_mut()) };
+        match connected != 0 || unsafe { GetLastError() } == ERROR_PIPE_CONNECTED {
+            true => Ok(()),
+            false => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+impl ReceiverCreator for PipeReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("pipe://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let path = pipe_path(option);
+        let wide: Vec<u16> = OsStr::new(&path)
+            .encode_wide()
+            .chain(iter::once(0))
+            .collect();
+        let mut pipe = Self::create_instance(&wide).map_err(NetpipeError::Bind)?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        threads::spawn("pipe-recv", move || loop {
+            match Self::connect(&pipe) {
+                Ok(()) => eprintln!("Pipe client connected: {path}."),
+                Err(e) => eprintln!("Failed to wait for a client on {path}: {e}."),
+            }
+            for line in BufReader::new(&pipe).lines() {
+                match line {
+                    Ok(line) => {
+                        if tx.send(line.into()).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+            eprintln!("Pipe client disconnected: {path}.");
+            pipe = loop {
+                match Self::create_instance(&wide) {
+                    Ok(pipe) => break pipe,
+                    Err(e) => {
+                        eprintln!("Failed to recreate {path}: {e}.");
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            };
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
@@ -0,0 +1,138 @@
+use super::{Messages, ReceiverCreator, READ_AHEAD};
+use crate::error::{NetpipeError, Result};
+use crate::{strict, threads};
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Reads a capture from `replay://<path>` and emits its messages with the delays between
+/// them that their timestamps call for, divided by `speed`. A capture holds one message per
+/// line, either as a `{"ts":...,"data":...}` envelope, as written by a WebSocket destination
+/// with `?envelope=json`, or as plain text, which is emitted without delay.
+///
+/// Captures compressed with gzip, with the `gzip` feature, or zstd, as written by a file
+/// destination with `?compress=zstd` and with the `zstd` feature, are decompressed on the fly,
+/// told apart by their first bytes. A compressed capture that is still being written is read
+/// as far as it decompresses, and ends with an error there.
+pub struct ReplayReceiverCreator {
+    speed: f64,
+    looping: bool,
+}
+
+impl ReplayReceiverCreator {
+    pub fn new(speed: f64, looping: bool) -> ReplayReceiverCreator {
+        ReplayReceiverCreator { speed, looping }
+    }
+}
+
+/// Opens a capture, decompressing it if it starts like a gzip or zstd stream.
+pub fn open(path: &str) -> io::Result<Box<dyn BufRead + Send>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let start = reader.fill_buf()?;
+    if start.starts_with(GZIP_MAGIC) {
+        gzip_reader(reader)
+    } else if start.starts_with(ZSTD_MAGIC) {
+        zstd_reader(reader)
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_reader(reader: BufReader<File>) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(Box::new(BufReader::new(
+        flate2::bufread::MultiGzDecoder::new(reader),
+    )))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_reader(_reader: BufReader<File>) -> io::Result<Box<dyn BufRead + Send>> {
+    Err(io::Error::other(
+        "compressed with gzip, which requires the gzip feature",
+    ))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_reader(reader: BufReader<File>) -> io::Result<Box<dyn BufRead + Send>> {
+    Ok(Box::new(BufReader::new(zstd::Decoder::with_buffer(
+        reader,
+    )?)))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_reader(_reader: BufReader<File>) -> io::Result<Box<dyn BufRead + Send>> {
+    Err(io::Error::other(
+        "compressed with zstd, which requires the zstd feature",
+    ))
+}
+
+/// Splits a captured line into its timestamp in milliseconds, if any, and message.
+fn parse(line: String) -> (Option<u64>, String) {
+    if let Ok(Value::Object(envelope)) = serde_json::from_str(&line) {
+        if let (Some(ts), Some(Value::String(data))) = (
+            envelope.get("ts").and_then(Value::as_u64),
+            envelope.get("data"),
+        ) {
+            return (Some(ts), data.clone());
+        }
+    }
+    (None, line)
+}
+
+impl ReceiverCreator for ReplayReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("replay://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let path = option["replay://".len()..].to_string();
+        let mut file = open(&path).map_err(NetpipeError::Bind)?;
+        let speed = self.speed;
+        let looping = self.looping;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        threads::spawn("replay", move || loop {
+            let mut previous = None;
+            for line in file.lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::InvalidData {
+                            strict::check(|| format!("a line of {path} isn't valid UTF-8"));
+                        }
+                        eprintln!("Failed to read from {path}: {e}.");
+                        return;
+                    }
+                };
+                let (ts, message) = parse(line);
+                if let (Some(previous), Some(ts)) = (previous, ts) {
+                    let delay = ts.saturating_sub(previous) as f64 / 1000.0 / speed;
+                    thread::sleep(Duration::from_secs_f64(delay));
+                }
+                previous = ts.or(previous);
+                if tx.send(message.into()).is_err() {
+                    return;
+                }
+            }
+            if !looping {
+                return;
+            }
+            file = match open(&path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Failed to reopen {path}: {e}.");
+                    return;
+                }
+            };
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
@@ -0,0 +1,351 @@
+use super::{Messages, ReceiverCreator, Records, Sentinels, READ_AHEAD};
+use crate::broker::query_param;
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, ListenOptions};
+use crate::options::unescape;
+use crate::payload::Payload;
+use crate::retry::{reconnect, Backoff};
+use crate::threads;
+use std::{
+    io::{self, BufReader, ErrorKind, Read},
+    net::TcpStream,
+    sync::mpsc,
+    time::Duration,
+};
+use url::Url;
+
+/// Frames longer than this are taken as a sign of a corrupt length prefix.
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// How a byte stream is cut into messages.
+#[derive(Clone)]
+enum Framing {
+    /// Records separated by a delimiter, which may be several bytes long.
+    Delimited(Vec<u8>),
+    /// Each message preceded by its length as a 4-byte big-endian integer.
+    LengthPrefixed,
+    /// Each message preceded by its length as a protobuf varint, as protobuf's
+    /// `writeDelimitedTo` writes them.
+    Varint,
+}
+
+impl Framing {
+    fn parse(url: &Url) -> Result<Framing> {
+        let delimiter = query_param(url, "delimiter").map(|d| unescape(&d));
+        match (query_param(url, "framing").as_deref(), delimiter) {
+            (None | Some("delimited"), None) => Ok(Framing::Delimited(b"\n".to_vec())),
+            (None | Some("delimited"), Some(d)) if d.is_empty() => {
+                Err(NetpipeError::invalid("empty delimiter"))
+            }
+            (None | Some("delimited"), Some(d)) => Ok(Framing::Delimited(d.into_bytes())),
+            (Some("length"), None) => Ok(Framing::LengthPrefixed),
+            (Some("varint"), None) => Ok(Framing::Varint),
+            (Some("length" | "varint"), Some(_)) => Err(NetpipeError::invalid(
+                "a delimiter doesn't apply to length-prefixed framing",
+            )),
+            (Some(other), _) => Err(NetpipeError::invalid(format!("unknown framing {other}"))),
+        }
+    }
+}
+
+/// A function reading the next message off a stream, cut as `framing` says, or `None` once
+/// the stream ends.
+type ReadMessage = Box<dyn FnMut() -> io::Result<Option<Vec<u8>>> + Send>;
+
+fn message_reader(stream: TcpStream, framing: Framing) -> ReadMessage {
+    let mut reader = BufReader::new(stream);
+    match framing {
+        Framing::Delimited(delimiter) => {
+            let mut records = Records::new(reader, delimiter);
+            Box::new(move || records.read_record())
+        }
+        Framing::LengthPrefixed => Box::new(move || read_frame(&mut reader)),
+        Framing::Varint => Box::new(move || read_varint_frame(&mut reader)),
+    }
+}
+
+fn host_port(url: &Url) -> Result<String> {
+    Ok(format!(
+        "{}:{}",
+        url.host_str()
+            .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+        url.port()
+            .ok_or_else(|| NetpipeError::invalid("missing port"))?
+    ))
+}
+
+/// Reads one length-prefixed frame, or `None` if the stream ends between frames.
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut prefix = [0; 4];
+    match reader.read_exact(&mut prefix) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    read_frame_of(reader, u32::from_be_bytes(prefix) as usize).map(Some)
+}
+
+/// Reads one frame prefixed with a varint, or `None` if the stream ends between frames.
+fn read_varint_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        match reader.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && shift == 0 => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        length |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            let length = usize::try_from(length).unwrap_or(usize::MAX);
+            return read_frame_of(reader, length).map(Some);
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::InvalidData,
+        "varint length prefix too long",
+    ))
+}
+
+fn read_frame_of(reader: &mut impl Read, length: usize) -> io::Result<Vec<u8>> {
+    if length > MAX_FRAME_LENGTH {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame of {length} bytes exceeds the limit"),
+        ));
+    }
+    let mut frame = vec![0; length];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+/// Connects to `tcp://<host>:<port>` and forwards the messages read from the connection,
+/// newline-separated unless `?delimiter=` gives another delimiter (with the escapes of
+/// `--delimiter`) or `?framing=length` calls for length-prefixed frames, or `?framing=varint`
+/// for the varint-prefixed ones of delimited protobuf streams. Messages that aren't valid
+/// UTF-8 are passed on as binary. Connecting at startup is retried as `--max-reconnects` and
+/// `--reconnect-delay` say.
+pub struct TcpReceiverCreator {
+    connect_timeout: Duration,
+    sentinels: Sentinels,
+    /// How connecting at startup is retried, or a single attempt if `None`.
+    reconnect: Option<Backoff>,
+}
+
+impl TcpReceiverCreator {
+    pub fn new(
+        connect_timeout: Duration,
+        sentinels: Sentinels,
+        reconnect: Option<Backoff>,
+    ) -> TcpReceiverCreator {
+        TcpReceiverCreator {
+            connect_timeout,
+            sentinels,
+            reconnect,
+        }
+    }
+}
+
+impl ReceiverCreator for TcpReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("tcp://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let framing = Framing::parse(&url)?;
+        let host_port = host_port(&url)?;
+        let backoff = self.reconnect.clone().unwrap_or_else(Backoff::once);
+        let stream: TcpStream = reconnect(&backoff, option, || {
+            net::connect(&host_port, self.connect_timeout)
+        })
+        .map_err(|e| NetpipeError::Connect(option.to_string(), e.into()))?;
+
+        let name = threads::name("tcp-recv", stream.peer_addr());
+        let mut read_message = message_reader(stream, framing);
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let option = option.to_string();
+        let sentinels = self.sentinels.clone();
+        threads::spawn(name, move || loop {
+            let message = match read_message() {
+                Ok(Some(message)) => message,
+                Ok(None) => {
+                    eprintln!("Connection closed: {option}.");
+                    sentinels.disconnected(&tx);
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Failed to read from {option}: {e}.");
+                    sentinels.disconnected(&tx);
+                    break;
+                }
+            };
+            if tx.send(Payload::from_bytes(message)).is_err() {
+                break;
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
+
+/// Listens on `tcp-listen://<host>:<port>` and forwards the messages that clients send, from
+/// any number of clients at once, cut into messages as for `tcp://` sources. Clients may come
+/// and go at any time; one that closes its connection just stops sending.
+pub struct TcpListenReceiverCreator {
+    listen: ListenOptions,
+}
+
+impl TcpListenReceiverCreator {
+    pub fn new(listen: ListenOptions) -> TcpListenReceiverCreator {
+        TcpListenReceiverCreator { listen }
+    }
+}
+
+impl ReceiverCreator for TcpListenReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("tcp-listen://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let framing = Framing::parse(&url)?;
+        let listener = net::listen(host_port(&url)?, &self.listen).map_err(NetpipeError::Bind)?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let name = threads::name("tcp-accept", listener.local_addr());
+        threads::spawn(name, move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        net::accept_failed(&e);
+                        continue;
+                    }
+                };
+                let peer = stream
+                    .peer_addr()
+                    .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+                let tx = tx.clone();
+                let name = threads::name("tcp-client", stream.peer_addr());
+                let mut read_message = message_reader(stream, framing.clone());
+                eprintln!("Connected: {peer}.");
+                threads::spawn(name, move || loop {
+                    let message = match read_message() {
+                        Ok(Some(message)) => message,
+                        Ok(None) => {
+                            eprintln!("Disconnected: {peer}.");
+                            break;
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to read from {peer}: {e}.");
+                            break;
+                        }
+                    };
+                    if tx.send(Payload::from_bytes(message)).is_err() {
+                        break;
+                    }
+                });
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    /// Reads what `write` sends, from a thread of its own, over a local TCP connection, cut as
+    /// the `query` of a `tcp://` source says, up to the end of the stream or the first error.
+    fn read_over_tcp(
+        query: &str,
+        write: impl FnOnce(&mut TcpStream) + Send + 'static,
+    ) -> Vec<io::Result<Vec<u8>>> {
+        let url = Url::parse(&format!("tcp://127.0.0.1:1{query}")).unwrap();
+        let framing = Framing::parse(&url).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        threads::spawn("test-send", move || write(&mut client));
+        let mut read_message = message_reader(server, framing);
+        let mut messages = vec![];
+        loop {
+            match read_message() {
+                Ok(Some(message)) => messages.push(Ok(message)),
+                Ok(None) => return messages,
+                Err(e) => {
+                    messages.push(Err(e));
+                    return messages;
+                }
+            }
+        }
+    }
+
+    /// Writes `bytes` in two writes, cut at `at`, with a pause between them so that they
+    /// arrive apart.
+    fn write_split(stream: &mut TcpStream, bytes: &[u8], at: usize) {
+        stream.write_all(&bytes[..at]).unwrap();
+        stream.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        stream.write_all(&bytes[at..]).unwrap();
+    }
+
+    fn frames(messages: Vec<io::Result<Vec<u8>>>) -> Vec<Vec<u8>> {
+        messages.into_iter().map(io::Result::unwrap).collect()
+    }
+
+    fn length_prefixed(message: &[u8]) -> Vec<u8> {
+        let mut frame = (message.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(message);
+        frame
+    }
+
+    #[test]
+    fn newlines_cut_the_stream_by_default() {
+        let messages = read_over_tcp("", |stream| {
+            write_split(stream, b"one\ntwo\nthree\n", 5);
+        });
+        assert_eq!(frames(messages), [&b"one"[..], b"two", b"three"]);
+    }
+
+    #[test]
+    fn a_trailing_record_without_a_delimiter_still_comes_through() {
+        let messages = read_over_tcp("", |stream| stream.write_all(b"one\ntw").unwrap());
+        assert_eq!(frames(messages), [&b"one"[..], b"tw"]);
+    }
+
+    #[test]
+    fn nul_delimited_records_may_hold_newlines() {
+        let messages = read_over_tcp("?delimiter=%5C0", |stream| {
+            write_split(stream, b"one\ntwo\0three\0", 9);
+        });
+        assert_eq!(frames(messages), [&b"one\ntwo"[..], b"three"]);
+    }
+
+    #[test]
+    fn length_prefixed_frames_are_put_back_together() {
+        let mut bytes = length_prefixed(b"hello");
+        bytes.extend(length_prefixed(b"a\nb\0c"));
+        bytes.extend(length_prefixed(b""));
+        let messages = read_over_tcp("?framing=length", move |stream| {
+            // Cuts into the second frame's prefix.
+            write_split(stream, &bytes, 11);
+        });
+        assert_eq!(frames(messages), [&b"hello"[..], b"a\nb\0c", b""]);
+    }
+
+    #[test]
+    fn a_truncated_length_prefixed_frame_is_an_error() {
+        let mut bytes = length_prefixed(b"hello");
+        bytes.extend(&length_prefixed(b"world")[..6]);
+        let mut messages = read_over_tcp("?framing=length", move |stream| {
+            stream.write_all(&bytes).unwrap();
+        });
+        let error = messages.pop().unwrap().unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(frames(messages), [b"hello"]);
+    }
+}
@@ -0,0 +1,107 @@
+use super::{Messages, ReceiverCreator, Records, READ_AHEAD};
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, ListenOptions};
+use crate::payload::Payload;
+use crate::threads;
+use crate::tls;
+use std::{
+    io::{BufReader, ErrorKind},
+    sync::mpsc,
+};
+use url::Url;
+
+/// Listens on `tls-listen://<host>:<port>` and forwards the lines that clients send over TLS,
+/// from any number of clients at once. The listener presents the certificate from
+/// `--tls-cert` with the key from `--tls-key`. Lines that aren't valid UTF-8 are passed on as
+/// binary.
+pub struct TlsReceiverCreator {
+    cert: Option<String>,
+    key: Option<String>,
+    listen: ListenOptions,
+}
+
+impl TlsReceiverCreator {
+    pub fn new(
+        cert: Option<String>,
+        key: Option<String>,
+        listen: ListenOptions,
+    ) -> TlsReceiverCreator {
+        TlsReceiverCreator { cert, key, listen }
+    }
+}
+
+impl ReceiverCreator for TlsReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("tls-listen://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host_port = format!(
+            "{}:{}",
+            url.host_str()
+                .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let (Some(cert), Some(key)) = (&self.cert, &self.key) else {
+            return Err(NetpipeError::invalid(
+                "tls-listen:// requires --tls-cert and --tls-key",
+            ));
+        };
+        let config = tls::server_config(cert, key)?;
+        let listener = net::listen(host_port, &self.listen).map_err(NetpipeError::Bind)?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let name = threads::name("tls-accept", listener.local_addr());
+        threads::spawn(name, move || {
+            for sock in listener.incoming() {
+                let sock = match sock {
+                    Ok(sock) => sock,
+                    Err(e) => {
+                        net::accept_failed(&e);
+                        continue;
+                    }
+                };
+                let config = config.clone();
+                let tx = tx.clone();
+                // Each client gets a thread of its own, so that a slow handshake doesn't hold
+                // up the others.
+                let name = threads::name("tls-recv", sock.peer_addr());
+                threads::spawn(name, move || {
+                    let peer = sock
+                        .peer_addr()
+                        .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+                    let stream = match tls::accept(sock, &config) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            eprintln!("Handshake with {peer} failed: {e}.");
+                            return;
+                        }
+                    };
+                    eprintln!("Connected: {peer}.");
+                    let mut records = Records::new(BufReader::new(stream), b"\n".to_vec());
+                    loop {
+                        match records.read_record() {
+                            Ok(Some(record)) => {
+                                if tx.send(Payload::from_bytes(record)).is_err() {
+                                    return;
+                                }
+                            }
+                            Ok(None) => break,
+                            // Many clients just close the socket without ending the
+                            // session first.
+                            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                            Err(e) => {
+                                eprintln!("Failed to read from {peer}: {e}.");
+                                break;
+                            }
+                        }
+                    }
+                    eprintln!("Disconnected: {peer}.");
+                });
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
@@ -0,0 +1,56 @@
+use super::{Messages, ReceiverCreator, READ_AHEAD};
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::threads;
+use std::{io, os::unix::net::UnixDatagram, sync::mpsc};
+
+/// Binds a Unix datagram socket at `path`, replacing a stale socket file left behind by a
+/// process that is gone, but not one that something is still bound to.
+fn bind(path: &str) -> io::Result<UnixDatagram> {
+    match UnixDatagram::bind(path) {
+        Err(e)
+            if e.kind() == io::ErrorKind::AddrInUse
+                && UnixDatagram::unbound()?.connect(path).is_err() =>
+        {
+            std::fs::remove_file(path)?;
+            UnixDatagram::bind(path)
+        }
+        result => result,
+    }
+}
+
+/// Binds a Unix datagram socket at `unixgram://<path>` and forwards each datagram as a
+/// message.
+pub struct UnixDatagramReceiverCreator;
+
+impl ReceiverCreator for UnixDatagramReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("unixgram://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let path = &option["unixgram://".len()..];
+        if path.is_empty() {
+            return Err(NetpipeError::invalid("missing path"));
+        }
+        let socket = bind(path).map_err(NetpipeError::Bind)?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        threads::spawn("unixgram-recv", move || loop {
+            let mut buf = vec![0; 65536];
+            let size = match socket.recv(&mut buf) {
+                Ok(size) => size,
+                Err(e) => {
+                    eprintln!("Failed to receive datagram: {e}.");
+                    continue;
+                }
+            };
+            buf.truncate(size);
+            // Datagrams that aren't valid UTF-8 are passed on as binary messages.
+            if tx.send(Payload::from_bytes(buf)).is_err() {
+                break;
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
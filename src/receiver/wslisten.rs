@@ -0,0 +1,119 @@
+use super::{Messages, ReceiverCreator, READ_AHEAD};
+use crate::broker::FrameLimits;
+use crate::error::{NetpipeError, Result};
+use crate::net::{self, ListenOptions};
+use crate::payload::Payload;
+use crate::{strict, threads};
+use std::{io::ErrorKind, sync::mpsc};
+use tungstenite::error::Error::{ConnectionClosed, Io, Protocol, Utf8};
+use tungstenite::error::ProtocolError::ResetWithoutClosingHandshake;
+use tungstenite::{accept, Message};
+use url::Url;
+
+/// Listens on `ws-listen://<host>:<port>/` and forwards the messages that WebSocket clients
+/// send, from any number of clients at once and whatever path they ask for, for producers
+/// that connect to netpipe rather than the other way around. Clients may come and go at any
+/// time; one that closes its socket or goes away just stops sending. `?max_frame=<size>` and
+/// `?max_message=<size>` bound the frames and messages that each client may send.
+pub struct WebSocketListenReceiverCreator {
+    listen: ListenOptions,
+}
+
+impl WebSocketListenReceiverCreator {
+    pub fn new(listen: ListenOptions) -> WebSocketListenReceiverCreator {
+        WebSocketListenReceiverCreator { listen }
+    }
+}
+
+impl ReceiverCreator for WebSocketListenReceiverCreator {
+    fn matches(&self, option: &str) -> bool {
+        option.starts_with("ws-listen://")
+    }
+
+    fn create_receiver(&self, option: &str) -> Result<Messages> {
+        let url = Url::parse(option).map_err(NetpipeError::invalid)?;
+        let host_port = format!(
+            "{}:{}",
+            url.host_str()
+                .ok_or_else(|| NetpipeError::invalid("missing host"))?,
+            url.port()
+                .ok_or_else(|| NetpipeError::invalid("missing port"))?
+        );
+        let limits = FrameLimits::parse(&url)?;
+        let listener = net::listen(host_port, &self.listen).map_err(NetpipeError::Bind)?;
+
+        let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+        let name = threads::name("ws-accept", listener.local_addr());
+        threads::spawn(name, move || {
+            for sock in listener.incoming() {
+                let sock = match sock {
+                    Ok(sock) => sock,
+                    Err(e) => {
+                        net::accept_failed(&e);
+                        continue;
+                    }
+                };
+                let tx = tx.clone();
+                // Each client gets a thread of its own, so that a slow handshake doesn't hold
+                // up the others.
+                let name = threads::name("ws-client", sock.peer_addr());
+                threads::spawn(name, move || {
+                    let peer = sock
+                        .peer_addr()
+                        .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string());
+                    let mut socket = match accept(sock) {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            eprintln!("Handshake with {peer} failed: {e}.");
+                            return;
+                        }
+                    };
+                    limits.apply(&mut socket);
+                    eprintln!("Connected: {peer}.");
+                    loop {
+                        let message = match socket.read_message() {
+                            Ok(Message::Text(text)) => Payload::Text(text),
+                            Ok(Message::Binary(bytes)) => Payload::Binary(bytes),
+                            Ok(Message::Close(_)) | Err(ConnectionClosed) => {
+                                eprintln!("Socket closed: {peer}.");
+                                return;
+                            }
+                            // Pings are answered by tungstenite itself, and pongs may come
+                            // unasked for as a heartbeat.
+                            Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+                            Ok(message) => {
+                                strict::check(|| {
+                                    format!("{peer} sent an unexpected frame: {message:?}")
+                                });
+                                continue;
+                            }
+                            Err(Io(e)) if e.kind() == ErrorKind::ConnectionReset => {
+                                eprintln!("Connection reset: {peer}.");
+                                return;
+                            }
+                            Err(Protocol(ResetWithoutClosingHandshake)) => {
+                                eprintln!("Reset without closing handshake: {peer}.");
+                                return;
+                            }
+                            Err(Utf8) => {
+                                strict::check(|| {
+                                    format!("a text frame from {peer} isn't valid UTF-8")
+                                });
+                                eprintln!("Failed to read from {peer}: invalid UTF-8.");
+                                return;
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to read from {peer}: {e}.");
+                                return;
+                            }
+                        };
+                        if tx.send(message).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        Ok(Box::new(rx.into_iter()))
+    }
+}
@@ -0,0 +1,139 @@
+use rand::Rng;
+use std::{fmt::Display, thread, time::Duration};
+
+/// Exponential backoff schedule: the delay doubles with each failed attempt, starting at
+/// `initial` and capped at `max_delay`.
+#[derive(Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max_delay: Duration,
+    /// Attempts allowed in total, or `None` to retry forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: Some(5),
+        }
+    }
+}
+
+impl Backoff {
+    /// A single attempt, for sources connecting at startup under `--max-reconnects 0`.
+    pub fn once() -> Backoff {
+        Backoff {
+            max_attempts: Some(1),
+            ..Backoff::default()
+        }
+    }
+
+    /// Delay before the retry following the given zero-based failed attempt, without jitter.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt);
+        self.initial.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// [`Backoff::delay`] with "equal jitter": a random delay between half and all of it, so
+    /// that many clients dropped at once don't reconnect in lockstep.
+    pub fn jittered_delay(&self, attempt: u32) -> Duration {
+        let delay = self.delay(attempt);
+        delay / 2 + rand::thread_rng().gen_range(Duration::ZERO..=delay / 2)
+    }
+
+    /// Whether no attempt is left after the given number of failed ones.
+    pub fn exhausted(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+}
+
+/// Calls `connect` until it succeeds, sleeping according to `backoff` between attempts. The
+/// last error is returned once the attempts are exhausted.
+pub fn reconnect<T, E: Display>(
+    backoff: &Backoff,
+    target: &str,
+    mut connect: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut attempt = 0;
+    loop {
+        match connect() {
+            Ok(connection) => return Ok(connection),
+            Err(e) if backoff.exhausted(attempt + 1) => return Err(e),
+            Err(e) => {
+                let delay = backoff.jittered_delay(attempt);
+                eprintln!("Failed to connect to {target}: {e}. Retrying in {delay:?}.");
+                thread::sleep(delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(max_attempts: Option<u32>) -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            max_attempts,
+        }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let backoff = backoff(None);
+        let delays: Vec<_> = (0..5).map(|attempt| backoff.delay(attempt)).collect();
+        let millis = [1, 2, 4, 5, 5].map(Duration::from_millis);
+        assert_eq!(delays, millis);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn jittered_delay_stays_within_half_and_all_of_the_delay() {
+        let backoff = Backoff::default();
+        for attempt in 0..8 {
+            let delay = backoff.delay(attempt);
+            for _ in 0..100 {
+                let jittered = backoff.jittered_delay(attempt);
+                assert!(jittered >= delay / 2 && jittered <= delay, "{jittered:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn exhausted_after_max_attempts() {
+        let backoff = backoff(Some(3));
+        assert!(!backoff.exhausted(2));
+        assert!(backoff.exhausted(3));
+        assert!(!self::backoff(None).exhausted(u32::MAX));
+    }
+
+    #[test]
+    fn reconnect_gives_up_after_max_attempts() {
+        let mut calls = 0;
+        let result: Result<(), String> = reconnect(&backoff(Some(3)), "test", || {
+            calls += 1;
+            Err(format!("failure {calls}"))
+        });
+        assert_eq!(result, Err("failure 3".to_string()));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn reconnect_returns_the_first_success() {
+        let mut calls = 0;
+        let result: Result<u32, String> = reconnect(&backoff(Some(5)), "test", || {
+            calls += 1;
+            match calls {
+                3 => Ok(calls),
+                _ => Err("refused".to_string()),
+            }
+        });
+        assert_eq!(result, Ok(3));
+        assert_eq!(calls, 3);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::options::parse_size;
+use crate::payload::Payload;
+use crate::selector::Selector;
+use regex::Regex;
+use std::collections::HashMap;
+
+/// A test on a message that decides whether a destination gets it.
+#[derive(Clone)]
+pub enum Predicate {
+    /// The message matches a regex.
+    Matches(Regex),
+    /// The field picked by a selector, such as `json:level`, holds the value.
+    Equals(Selector, String),
+    /// The message's length in bytes is within the bounds, both inclusive.
+    Size(Option<usize>, Option<usize>),
+}
+
+impl Predicate {
+    fn parse(rule: &str) -> Result<Predicate, String> {
+        if let Some(pattern) = rule.strip_prefix("matches ") {
+            let regex = Regex::new(pattern.trim()).map_err(|e| format!("invalid regex: {e}"))?;
+            return Ok(Predicate::Matches(regex));
+        }
+        if let Some(range) = rule.strip_prefix("size ") {
+            let range = range.trim();
+            let (min, max) = range
+                .split_once("..")
+                .ok_or_else(|| format!("expected <min>..<max> for size, got {range}"))?;
+            let bound = |bound: &str| {
+                let bound = bound.trim();
+                (!bound.is_empty())
+                    .then(|| parse_size(bound).map_err(|_| format!("invalid size {bound}")))
+                    .transpose()
+            };
+            let (min, max) = (bound(min)?, bound(max)?);
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    return Err(format!("size {range} takes in no message"));
+                }
+            }
+            return Ok(Predicate::Size(min, max));
+        }
+        if let Some((selector, value)) = rule.split_once(" == ") {
+            let selector = Selector::parse(selector.trim())?;
+            return Ok(Predicate::Equals(selector, value.trim().to_string()));
+        }
+        Err(format!(
+            "expected matches <regex>, <selector> == <value>, size <min>..<max> or default, got {rule}"
+        ))
+    }
+
+    fn accepts(&self, message: &Payload) -> bool {
+        match self {
+            Predicate::Matches(regex) => regex.is_match(&message.to_text()),
+            Predicate::Equals(selector, value) => {
+                selector.select(&message.to_text()).as_ref() == Some(value)
+            }
+            Predicate::Size(min, max) => {
+                min.is_none_or(|min| message.len() >= min)
+                    && max.is_none_or(|max| message.len() <= max)
+            }
+        }
+    }
+}
+
+/// Which messages a destination from `--destinations-file` with a rule gets.
+#[derive(Clone)]
+pub enum Route {
+    When(Predicate),
+    /// The messages that none of the other destinations' rules take, for the one marked
+    /// `default`.
+    Otherwise(Vec<Predicate>),
+}
+
+impl Route {
+    pub fn accepts(&self, message: &Payload) -> bool {
+        match self {
+            Route::When(predicate) => predicate.accepts(message),
+            Route::Otherwise(predicates) => !predicates.iter().any(|p| p.accepts(message)),
+        }
+    }
+}
+
+/// The destinations read from `--destinations-file`, and the rules of those that have one.
+#[derive(Default)]
+pub struct Routes {
+    pub destinations: Vec<String>,
+    pub rules: HashMap<String, Route>,
+    /// The destination that gets what no rule takes, with where it was given.
+    default: Option<(String, String)>,
+}
+
+impl Routes {
+    /// Reads one destination per line, skipping blank lines and `#` comments. After the
+    /// destination may come a rule for the messages it gets: `matches <regex>`,
+    /// `<selector> == <value>`, `size <min>..<max>` with either bound left out for none, or
+    /// `default` for those that no other rule takes. A destination without a rule gets all.
+    pub fn read(&mut self, path: &str) -> Result<(), String> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}."))?;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = format!("{path}:{}", number + 1);
+            let (destination, rule) = match line.split_once(char::is_whitespace) {
+                Some((destination, rule)) => (destination, rule.trim()),
+                None => (line, ""),
+            };
+            if self.destinations.iter().any(|d| d == destination) {
+                return Err(format!("{at}: {destination} is listed already."));
+            }
+            match rule {
+                "" => {}
+                "default" => {
+                    if let Some((other, given)) = &self.default {
+                        return Err(format!(
+                            "{at}: {destination} can't be the default, as {other} is ({given})."
+                        ));
+                    }
+                    self.default = Some((destination.to_string(), at));
+                }
+                rule => {
+                    let predicate = Predicate::parse(rule).map_err(|e| format!("{at}: {e}."))?;
+                    self.rules
+                        .insert(destination.to_string(), Route::When(predicate));
+                }
+            }
+            self.destinations.push(destination.to_string());
+        }
+        Ok(())
+    }
+
+    /// Gives the default destination the rules of all others, once every file is read.
+    pub fn finish(&mut self) -> Result<(), String> {
+        let Some((default, at)) = self.default.take() else {
+            return Ok(());
+        };
+        let predicates: Vec<Predicate> = self
+            .rules
+            .values()
+            .map(|route| match route {
+                Route::When(predicate) => predicate.clone(),
+                Route::Otherwise(_) => unreachable!("only the default is given the others"),
+            })
+            .collect();
+        if predicates.is_empty() {
+            return Err(format!(
+                "{at}: {default} is the default, but no destination has a rule."
+            ));
+        }
+        self.rules.insert(default, Route::Otherwise(predicates));
+        Ok(())
+    }
+}
@@ -0,0 +1,80 @@
+use regex::Regex;
+use serde_json::Value;
+
+/// Extracts a field from a message: `json:<path>` selects a value from a JSON message by a
+/// dot-separated path (array elements by index), anything else is a regex whose first capture
+/// group, or whole match if it has none, is selected.
+#[derive(Clone)]
+pub enum Selector {
+    Json(Vec<String>),
+    Regex(Regex),
+}
+
+impl Selector {
+    pub fn parse(spec: &str) -> Result<Selector, String> {
+        match spec.strip_prefix("json:") {
+            Some(path) => Ok(Selector::Json(path.split('.').map(String::from).collect())),
+            None => Regex::new(spec)
+                .map(Selector::Regex)
+                .map_err(|e| format!("Invalid selector {spec}: {e}")),
+        }
+    }
+
+    /// Returns the selected field, with JSON strings unquoted and other JSON values in their
+    /// JSON form.
+    pub fn select(&self, message: &str) -> Option<String> {
+        match self {
+            Selector::Json(path) => {
+                let value: Value = serde_json::from_str(message).ok()?;
+                let value = path.iter().try_fold(&value, |value, key| match value {
+                    Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+                    _ => value.get(key),
+                })?;
+                match value {
+                    Value::String(s) => Some(s.clone()),
+                    value => Some(value.to_string()),
+                }
+            }
+            Selector::Regex(regex) => {
+                let captures = regex.captures(message)?;
+                let field = captures.get(1).or_else(|| captures.get(0))?;
+                Some(field.as_str().to_string())
+            }
+        }
+    }
+
+    /// Returns the message with the selected field replaced by what `replace` makes of it, or
+    /// `None` if there is no such field or `replace` declines. A JSON field takes the new value
+    /// as is, and a regex match the new value in its text form, with strings unquoted.
+    pub fn replace(
+        &self,
+        message: &str,
+        replace: impl FnOnce(&str) -> Option<Value>,
+    ) -> Option<String> {
+        match self {
+            Selector::Json(path) => {
+                let mut value: Value = serde_json::from_str(message).ok()?;
+                let field = path.iter().try_fold(&mut value, |value, key| match value {
+                    Value::Array(items) => items.get_mut(key.parse::<usize>().ok()?),
+                    _ => value.get_mut(key),
+                })?;
+                *field = match &*field {
+                    Value::String(s) => replace(s),
+                    other => replace(&other.to_string()),
+                }?;
+                Some(value.to_string())
+            }
+            Selector::Regex(regex) => {
+                let captures = regex.captures(message)?;
+                let field = captures.get(1).or_else(|| captures.get(0))?;
+                let replacement = match replace(field.as_str())? {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                let mut replaced = message.to_string();
+                replaced.replace_range(field.range(), &replacement);
+                Some(replaced)
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Stopping netpipe cleanly on SIGINT or SIGTERM, or Ctrl-C or Ctrl-Break on Windows: the
+//! source is taken to have ended there, so that the messages already read still go out and
+//! every broker gets to [`shut down`](crate::broker::Broker::shutdown), say with WebSocket
+//! clients getting a close frame, and netpipe exits 0. A second signal, or one before
+//! forwarding has started, such as in `netpipe peek`, exits right away with 128 plus the
+//! signal's number, as the signal itself would.
+
+use crate::payload::Payload;
+use crate::receiver::{self, Messages, READ_AHEAD};
+use crate::threads;
+use std::sync::{
+    mpsc::{self, SyncSender},
+    Mutex,
+};
+
+/// Where to tell each source being forwarded that it is to end.
+static SOURCES: Mutex<Vec<SyncSender<Option<Payload>>>> = Mutex::new(vec![]);
+
+/// Interleaves the messages of `sources`, named by their options, as
+/// [`read_ahead`](receiver::read_ahead) does, until all of them have ended or netpipe is asked
+/// to stop, after the messages read so far.
+pub fn until_stopped(sources: Vec<(String, Messages)>) -> Messages {
+    let (tx, rx) = mpsc::sync_channel(READ_AHEAD);
+    SOURCES.lock().unwrap().push(tx.clone());
+    receiver::read_ahead(sources, tx);
+    Box::new(rx.into_iter().map_while(|message| message))
+}
+
+/// Ends the sources passed through [`until_stopped`], or exits if there are none or they were
+/// asked to end already.
+fn stop(signal: &str, number: i32) {
+    let sources = std::mem::take(&mut *SOURCES.lock().unwrap());
+    if sources.is_empty() {
+        std::process::exit(128 + number);
+    }
+    eprintln!("Stopping on {signal}; again to exit right away.");
+    for source in sources {
+        // Taken once the message being forwarded is done with.
+        threads::spawn("stop", move || {
+            let _ = source.send(None);
+        });
+    }
+}
+
+#[cfg(unix)]
+mod handler {
+    use std::{
+        fs::File,
+        io::Read,
+        os::fd::FromRawFd,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    /// The writing end of the pipe that the handler passes signals on through, for a thread
+    /// to act on them, since a handler may do next to nothing itself.
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle(signal: libc::c_int) {
+        let byte = signal as u8;
+        unsafe { libc::write(PIPE.load(Ordering::Relaxed), (&byte as *const u8).cast(), 1) };
+    }
+
+    pub fn install() {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            eprintln!(
+                "Failed to handle signals: {}.",
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        PIPE.store(fds[1], Ordering::Relaxed);
+        let mut signals = unsafe { File::from_raw_fd(fds[0]) };
+        crate::threads::spawn("signals", move || {
+            let mut byte = [0];
+            while signals.read_exact(&mut byte).is_ok() {
+                let number = libc::c_int::from(byte[0]);
+                let name = if number == libc::SIGINT {
+                    "SIGINT"
+                } else {
+                    "SIGTERM"
+                };
+                super::stop(name, number);
+            }
+        });
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = handle as *const () as libc::sighandler_t;
+                // So that blocking calls on other threads carry on rather than fail.
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod handler {
+    use windows_sys::Win32::Foundation::{BOOL, FALSE, TRUE};
+    use windows_sys::Win32::System::Console::{
+        SetConsoleCtrlHandler, CTRL_BREAK_EVENT, CTRL_C_EVENT,
+    };
+
+    /// Runs on a thread of its own, which the system starts for it.
+    unsafe extern "system" fn handle(event: u32) -> BOOL {
+        match event {
+            CTRL_C_EVENT => super::stop("Ctrl-C", 2),
+            CTRL_BREAK_EVENT => super::stop("Ctrl-Break", 21),
+            _ => return FALSE,
+        }
+        TRUE
+    }
+
+    pub fn install() {
+        if unsafe { SetConsoleCtrlHandler(Some(handle), TRUE) } == 0 {
+            eprintln!(
+                "Failed to handle Ctrl-C: {}.",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Takes SIGINT and SIGTERM, or Ctrl-C and Ctrl-Break, as a request to stop.
+pub fn install() {
+    handler::install();
+}
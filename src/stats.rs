@@ -0,0 +1,192 @@
+use crate::http::{self, Response};
+use crate::net::{self, ListenOptions};
+use crate::payload::Payload;
+use serde_json::{json, Map};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+};
+
+/// Why a message was dropped on its way to the destinations.
+#[derive(Clone, Copy)]
+pub enum Reason {
+    /// Outside the `--min-bytes`/`--max-bytes` bounds.
+    Size,
+    /// Not far enough from the last forwarded value for `--min-change`.
+    Unchanged,
+    /// Failed `--schema` validation, or wasn't JSON under `--input-format json`.
+    Invalid,
+    /// Arrived after `--reorder-by` gave up on its place in the sequence.
+    Late,
+    /// Pushed out of the queue of a WebSocket client that doesn't keep up.
+    SlowClient,
+    /// For a UDP destination whose host name doesn't resolve.
+    Unresolved,
+    /// With the queue of an `exec-sink://` destination, the buffer of an `amqp://` one, the
+    /// spool of a `tls://` one or the `?startup_buffer=` of a delayed one full.
+    Backlog,
+    /// Beyond what the `--control-port` buffer holds while forwarding is paused.
+    Paused,
+    /// With a queue full: of a destination under `--worker-queue-policy drop`, of an `mq://`
+    /// destination without `?policy=block`, of a UDP destination paced with `?pps=`, or of a
+    /// source under `--source-buffer-policy drop`.
+    QueueFull,
+    /// Arrived outside every `--window`.
+    OutsideWindow,
+    /// Refused by the server of an `amqp://` destination, or still unconfirmed when netpipe
+    /// gave up on reaching it at exit.
+    Unconfirmed,
+    /// Without a checksum matching it under `--verify-checksum`.
+    Corrupt,
+    /// Sent to a UDP source from an address that `--allow-source` or `--deny-source` keeps
+    /// out.
+    Denied,
+    /// For a `unixgram://` destination that nothing is bound to, or a `ws-connect://` one whose
+    /// server can't be reached.
+    NoReader,
+    /// For a destination still within its `?startup_delay=`, under `?startup_policy=drop`.
+    Startup,
+    /// Refused by the service of a `kinesis://` destination, or still throttled once the
+    /// retries ran out.
+    Rejected,
+    /// Larger than a datagram may be: received beyond `--udp-buffer-size`, or too large to
+    /// send to a UDP destination.
+    Oversize,
+    /// Didn't match a `--filter`.
+    Filtered,
+    /// Still queued for a WebSocket client when it was disconnected for staying slow beyond
+    /// `--slow-client-timeout`, so that each such disconnect shows.
+    Disconnected,
+}
+
+impl Reason {
+    const ALL: [Reason; 19] = [
+        Reason::Size,
+        Reason::Unchanged,
+        Reason::Invalid,
+        Reason::Late,
+        Reason::SlowClient,
+        Reason::Unresolved,
+        Reason::Backlog,
+        Reason::Paused,
+        Reason::QueueFull,
+        Reason::OutsideWindow,
+        Reason::Unconfirmed,
+        Reason::Corrupt,
+        Reason::Denied,
+        Reason::NoReader,
+        Reason::Startup,
+        Reason::Rejected,
+        Reason::Oversize,
+        Reason::Filtered,
+        Reason::Disconnected,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Size => "size",
+            Reason::Unchanged => "unchanged",
+            Reason::Invalid => "invalid",
+            Reason::Late => "late",
+            Reason::SlowClient => "slow_client",
+            Reason::Unresolved => "unresolved",
+            Reason::Backlog => "backlog",
+            Reason::Paused => "paused",
+            Reason::QueueFull => "queue_full",
+            Reason::OutsideWindow => "outside_window",
+            Reason::Unconfirmed => "unconfirmed",
+            Reason::Corrupt => "corrupt",
+            Reason::Denied => "denied",
+            Reason::NoReader => "no_reader",
+            Reason::Startup => "startup",
+            Reason::Rejected => "rejected",
+            Reason::Oversize => "oversize",
+            Reason::Filtered => "filtered",
+            Reason::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// Counts of dropped messages by reason, shared by the transforms and brokers that drop them,
+/// along with where to send the messages that didn't make it to a destination.
+#[derive(Clone, Default)]
+pub struct DropCounters {
+    counts: Arc<[AtomicU64; Reason::ALL.len()]>,
+    deadletter: Option<Sender<Payload>>,
+}
+
+impl DropCounters {
+    /// The same counts, with the messages passed to [`DropCounters::count_lost`] and
+    /// [`DropCounters::deadletter`] sent to `deadletter`.
+    pub fn with_deadletter(&self, deadletter: Sender<Payload>) -> DropCounters {
+        DropCounters {
+            counts: self.counts.clone(),
+            deadletter: Some(deadletter),
+        }
+    }
+
+    pub fn count(&self, reason: Reason) {
+        self.count_many(reason, 1);
+    }
+
+    pub fn count_many(&self, reason: Reason, messages: usize) {
+        self.counts[reason as usize].fetch_add(messages as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a message that a broker gave up on, and sends it to the deadletter destination.
+    pub fn count_lost(&self, reason: Reason, message: &Payload) {
+        self.count(reason);
+        self.deadletter(message);
+    }
+
+    /// Sends a message that didn't make it to a destination to the deadletter destination,
+    /// if there is one.
+    pub fn deadletter(&self, message: &Payload) {
+        if let Some(deadletter) = &self.deadletter {
+            let _ = deadletter.send(message.clone());
+        }
+    }
+
+    fn counts(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        Reason::ALL.into_iter().map(|reason| {
+            (
+                reason.name(),
+                self.counts[reason as usize].load(Ordering::Relaxed),
+            )
+        })
+    }
+
+    /// A line summing up the drops by reason, or `None` if nothing was dropped.
+    pub fn report(&self) -> Option<String> {
+        let counts: Vec<_> = self
+            .counts()
+            .filter(|&(_, count)| count > 0)
+            .map(|(reason, count)| format!("{reason} {count}"))
+            .collect();
+        (!counts.is_empty()).then(|| format!("Dropped messages: {}.", counts.join(", ")))
+    }
+
+    fn to_json(&self) -> String {
+        let dropped: Map<_, _> = self
+            .counts()
+            .map(|(reason, count)| (reason.to_string(), count.into()))
+            .collect();
+        json!({ "dropped": dropped }).to_string()
+    }
+}
+
+/// Serves the drop counts as JSON on `/stats`, on `port` on all interfaces.
+pub fn serve(port: u16, listen: ListenOptions, drops: DropCounters) -> io::Result<()> {
+    http::serve(
+        net::listen(("0.0.0.0", port), &listen)?,
+        move |path| match path {
+            "/stats" => Response::new(200, "application/json", drops.to_json() + "\n"),
+            _ => Response::not_found(),
+        },
+    );
+    Ok(())
+}
@@ -0,0 +1,36 @@
+//! `--strict`: stopping netpipe at the first anomaly it would otherwise get past, for when a
+//! loud failure says more than a log line. Under `--strict`, each of these ends netpipe with
+//! exit status 5:
+//!
+//! - A frame netpipe has no use for: from a `ws://` source, anything but a text, binary, ping
+//!   or close frame, and from a client of a `ws://` destination, a text or binary message that
+//!   isn't a command on a control channel.
+//! - Text that isn't valid UTF-8: a line of stdin, an HTTP stream or a replayed file, a record
+//!   of `--from-stdin-raw`, or a text frame. `--utf8-lossy` still replaces invalid UTF-8.
+//! - A datagram larger than `--udp-buffer-size`, which is dropped otherwise.
+//! - Any destination failing to take a message, rather than every destination.
+//!
+//! Messages still on their way to destinations when netpipe stops are lost.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The exit status of netpipe stopped by `--strict`.
+pub const STATUS: u8 = 5;
+
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    STRICT.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    STRICT.load(Ordering::Relaxed)
+}
+
+/// Stops netpipe, from whichever thread came across `anomaly`, if `--strict` is on.
+pub fn check(anomaly: impl FnOnce() -> String) {
+    if enabled() {
+        eprintln!("Stopping under --strict: {}.", anomaly());
+        std::process::exit(STATUS.into());
+    }
+}
@@ -0,0 +1,41 @@
+//! Every thread netpipe starts is named after what it does and, where there is one, the port
+//! it does it on, such as `ws-accept:11111` or `udp-recv:9000`, so that a panic, a debugger or
+//! `top -H` tells which one it was. Names are kept to the 15 bytes Linux keeps of them.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+};
+
+/// The stack size of new threads in bytes, or 0 for the platform's default.
+static STACK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the stack size of the threads started from now on, for when many destinations make
+/// for many threads and the default reserves more memory than they need.
+pub fn set_stack_size(size: usize) {
+    STACK_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Starts a thread like [`thread::spawn`], with a name and the configured stack size.
+pub fn spawn<F, T>(name: impl Into<String>, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let mut builder = thread::Builder::new().name(name.into());
+    let size = STACK_SIZE.load(Ordering::Relaxed);
+    if size > 0 {
+        builder = builder.stack_size(size);
+    }
+    builder.spawn(f).expect("failed to spawn thread")
+}
+
+/// A thread name of `kind` and the port of `addr`, or just `kind` if it has none.
+pub fn name(kind: &str, addr: io::Result<SocketAddr>) -> String {
+    match addr {
+        Ok(addr) => format!("{kind}:{}", addr.port()),
+        Err(_) => kind.to_string(),
+    }
+}
@@ -0,0 +1,177 @@
+use crate::error::{NetpipeError, Result};
+use crate::net::Stream;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore,
+    ServerConfig, ServerConnection, SignatureScheme, StreamOwned,
+};
+use std::{io, net::TcpStream, sync::Arc, time::Duration};
+
+/// A client that takes longer than this to complete its handshake is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn pem_error(path: &str, e: impl std::fmt::Display) -> NetpipeError {
+    NetpipeError::invalid(format!("failed to read {path}: {e}"))
+}
+
+/// Checks a server's certificate chain like rustls does, but accepts it for whatever name it
+/// carries.
+#[derive(Debug)]
+struct AnyName(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for AnyName {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            verified => verified,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// Trusts the Mozilla root certificates bundled with netpipe, and those in the PEM file `ca`,
+/// for servers whose certificates come from a private CA. Without `verify_hostname`, the
+/// certificate still has to be signed by one of them, but may be for any name.
+pub fn client_config(ca: Option<&str>, verify_hostname: bool) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca) = ca {
+        for cert in CertificateDer::pem_file_iter(ca).map_err(|e| pem_error(ca, e))? {
+            roots
+                .add(cert.map_err(|e| pem_error(ca, e))?)
+                .map_err(|e| NetpipeError::invalid(format!("invalid certificate in {ca}: {e}")))?;
+        }
+    }
+    let provider = Arc::new(ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(NetpipeError::invalid)?;
+    let config = if verify_hostname {
+        builder.with_root_certificates(roots)
+    } else {
+        let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .map_err(NetpipeError::invalid)?;
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyName(verifier)))
+    };
+    Ok(Arc::new(config.with_no_client_auth()))
+}
+
+/// Presents the certificate chain in the PEM file `cert`, signed by the private key in `key`.
+pub fn server_config(cert: &str, key: &str) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert, e))?;
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(NetpipeError::invalid)?
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)
+        .map_err(|e| NetpipeError::invalid(format!("invalid certificate or key: {e}")))?;
+    Ok(Arc::new(config))
+}
+
+/// Says what went wrong in a failed handshake, in particular whether the certificate didn't
+/// check out or the other end refused ours.
+fn describe(e: io::Error) -> io::Error {
+    let message = match e.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()) {
+        Some(rustls::Error::InvalidCertificate(
+            reason @ (CertificateError::NotValidForName
+            | CertificateError::NotValidForNameContext { .. }),
+        )) => format!(
+            "certificate verification failed: {reason}; ?sni= sets the name to expect, \
+             and ?verify_hostname=false accepts any"
+        ),
+        Some(rustls::Error::InvalidCertificate(reason)) => {
+            format!("certificate verification failed: {reason}")
+        }
+        Some(rustls::Error::AlertReceived(alert)) => {
+            format!("handshake refused by the peer: {alert:?}")
+        }
+        Some(other) => format!("TLS handshake failed: {other}"),
+        None if matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ) =>
+        {
+            "TLS handshake timed out".to_string()
+        }
+        None => format!("TLS handshake failed: {e}"),
+    };
+    io::Error::new(e.kind(), message)
+}
+
+/// Completes a handshake over the connection to a server within `timeout`, asking for and
+/// verifying that the server's certificate is valid for `name`.
+pub fn connect(
+    sock: TcpStream,
+    name: &ServerName<'static>,
+    config: &Arc<ClientConfig>,
+    timeout: Duration,
+) -> io::Result<Stream> {
+    let conn = ClientConnection::new(config.clone(), name.clone()).map_err(io::Error::other)?;
+    sock.set_read_timeout(Some(timeout))?;
+    let mut stream = StreamOwned::new(conn, sock);
+    while stream.conn.is_handshaking() {
+        stream
+            .conn
+            .complete_io(&mut stream.sock)
+            .map_err(describe)?;
+    }
+    stream.sock.set_read_timeout(None)?;
+    Ok(Stream::TlsClient(Box::new(stream)))
+}
+
+/// Completes the handshake of a client that connected to a TLS listener.
+pub fn accept(sock: TcpStream, config: &Arc<ServerConfig>) -> io::Result<Stream> {
+    let conn = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+    sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut stream = StreamOwned::new(conn, sock);
+    while stream.conn.is_handshaking() {
+        stream
+            .conn
+            .complete_io(&mut stream.sock)
+            .map_err(describe)?;
+    }
+    stream.sock.set_read_timeout(None)?;
+    Ok(Stream::TlsServer(Box::new(stream)))
+}
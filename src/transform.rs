@@ -0,0 +1,182 @@
+use crate::options::Options;
+use crate::payload::Payload;
+use crate::receiver::Messages;
+use crate::stats::{DropCounters, Reason};
+
+mod aggregate;
+mod checksum;
+mod delay;
+mod enrich;
+mod format;
+mod hysteresis;
+mod multiline;
+mod protobuf;
+mod reorder;
+mod replace;
+mod schema;
+mod sequence;
+mod timestamp;
+mod window;
+use aggregate::Aggregate;
+pub use aggregate::Function;
+pub use checksum::Checksum;
+use delay::Delay;
+use enrich::Enrich;
+pub use enrich::Enrichment;
+pub use format::{InputFormat, OutputFormat};
+use hysteresis::Hysteresis;
+pub use hysteresis::Threshold;
+use multiline::Multiline;
+pub use protobuf::Protobuf;
+use reorder::Reorder;
+pub use replace::{Replace, Rule};
+use schema::Validate;
+pub use schema::{Invalid, Schema};
+use sequence::{Gaps, Sequence};
+use std::sync::mpsc::Sender;
+use timestamp::Retime;
+pub use timestamp::TimeFormat;
+use window::Schedule;
+pub use window::Window;
+
+/// Applies the transforms selected in `options` to the received messages before they are
+/// handed to the brokers. Messages that fail `--schema` validation under `--on-invalid
+/// route:<destination>` go to `rejected` instead. The messages dropped along the way are
+/// counted in `drops`.
+pub fn apply(
+    options: &Options,
+    mut messages: Messages,
+    rejected: Option<Sender<Payload>>,
+    drops: &DropCounters,
+) -> Messages {
+    if let Some(start) = options.multiline.clone() {
+        messages = Box::new(Multiline::new(messages, start, options.multiline_timeout));
+    }
+    if let Some(checksum) = options.verify_checksum {
+        let drops = drops.clone();
+        messages = Box::new(messages.filter_map(move |message| checksum.verify(message, &drops)));
+    }
+    if options.detect_gaps {
+        let mut gaps = Gaps::default();
+        messages = Box::new(messages.map(move |message| gaps.apply(message)));
+    }
+    if let Some(protobuf) = options.protobuf.clone() {
+        let drops = drops.clone();
+        messages = Box::new(messages.filter_map(move |message| protobuf.apply(message, &drops)));
+    }
+    if let Some(format) = options.input_format {
+        let drops = drops.clone();
+        messages =
+            Box::new(messages.flat_map(move |message| format::decode(format, message, &drops)));
+    }
+    if let Some(delimiter) = options.split.clone() {
+        // Binary messages are passed on whole.
+        messages = Box::new(messages.flat_map(move |message| match message {
+            Payload::Text(text) => split(&text, &delimiter),
+            binary => vec![binary],
+        }));
+    }
+    if !options.rules.is_empty() {
+        let rules = options.rules.clone();
+        let drops = drops.clone();
+        messages =
+            Box::new(messages.filter_map(move |message| replace::apply(&rules, message, &drops)));
+    }
+    if let Some(schema) = options.schema.clone() {
+        let validate = Validate::new(schema, options.on_invalid.clone(), rejected, drops.clone());
+        messages = Box::new(messages.filter_map(move |message| validate.apply(message)));
+    }
+    if let Some(selector) = options.timestamp.clone() {
+        let retime = Retime::new(
+            selector,
+            options
+                .timestamp_from
+                .clone()
+                .unwrap_or(TimeFormat::Rfc3339),
+            options
+                .timestamp_to
+                .clone()
+                .unwrap_or(TimeFormat::EpochMillis),
+            options.local_time,
+        );
+        messages = Box::new(messages.map(move |message| retime.apply(message)));
+    }
+    if options.min_bytes.is_some() || options.max_bytes.is_some() {
+        let min = options.min_bytes.unwrap_or(0);
+        let max = options.max_bytes.unwrap_or(usize::MAX);
+        let drops = drops.clone();
+        messages = Box::new(messages.filter(move |message| {
+            let admitted = (min..=max).contains(&message.len());
+            if !admitted {
+                drops.count(Reason::Size);
+            }
+            admitted
+        }));
+    }
+    if let Some(threshold) = options.min_change {
+        let mut hysteresis = Hysteresis::new(threshold, options.min_change_by.clone());
+        let drops = drops.clone();
+        messages = Box::new(messages.filter(move |message| {
+            let admitted = hysteresis.admits(message);
+            if !admitted {
+                drops.count(Reason::Unchanged);
+            }
+            admitted
+        }));
+    }
+    if let Some(sequence) = options.reorder_by.clone() {
+        messages = Box::new(Reorder::new(
+            messages,
+            sequence,
+            options.reorder_window,
+            options.reorder_timeout,
+            drops.clone(),
+        ));
+    }
+    if !options.aggregate.is_empty() {
+        messages = Box::new(Aggregate::new(
+            messages,
+            options.aggregate.clone(),
+            options.aggregate_window,
+            options.aggregate_by.clone(),
+            drops.clone(),
+        ));
+    }
+    if !options.windows.is_empty() {
+        messages = Box::new(Schedule::new(
+            messages,
+            options.windows.clone(),
+            options.window_tz,
+            options.outside_window_hold,
+            drops.clone(),
+        ));
+    }
+    if !options.delay.is_zero() || !options.jitter.is_zero() {
+        messages = Box::new(Delay::new(messages, options.delay, options.jitter));
+    }
+    if let Some(enrichment) = options.enrich {
+        let mut enrich = Enrich::new(enrichment);
+        messages = Box::new(messages.map(move |message| enrich.apply(message)));
+    }
+    if let Some(format) = options.output_format {
+        messages = Box::new(messages.map(move |message| format::encode(format, message)));
+    }
+    if options.sequence {
+        let mut sequence = Sequence::default();
+        messages = Box::new(messages.map(move |message| sequence.apply(message)));
+    }
+    // After the sequence number, so that the checksum covers it too.
+    if let Some(checksum) = options.checksum {
+        messages = Box::new(messages.map(move |message| checksum.append(message)));
+    }
+    messages
+}
+
+/// Splits a message into the non-empty records separated by `delimiter`.
+fn split(message: &str, delimiter: &str) -> Vec<Payload> {
+    message
+        .split(delimiter)
+        .filter(|record| !record.is_empty())
+        .map(Payload::from)
+        .collect()
+}
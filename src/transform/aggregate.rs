@@ -0,0 +1,193 @@
+use crate::payload::Payload;
+use crate::receiver::{forward, Messages};
+use crate::selector::Selector;
+use crate::stats::{DropCounters, Reason};
+use serde_json::Value;
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A summary of the values in a window.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Function {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Mean,
+}
+
+impl Function {
+    /// Parses a comma-separated list such as `count,mean,max`.
+    pub fn parse_list(value: &str) -> Result<Vec<Function>, String> {
+        let functions = value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| match name {
+                "count" => Ok(Function::Count),
+                "sum" => Ok(Function::Sum),
+                "min" => Ok(Function::Min),
+                "max" => Ok(Function::Max),
+                "mean" => Ok(Function::Mean),
+                _ => Err(format!(
+                    "Expected count, sum, min, max or mean for --aggregate, got {name}."
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if functions.is_empty() {
+            return Err("--aggregate must not be empty.".to_string());
+        }
+        Ok(functions)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Count => "count",
+            Function::Sum => "sum",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Mean => "mean",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Totals {
+    fn add(&mut self, value: f64) {
+        if self.count == 0 {
+            (self.min, self.max) = (value, value);
+        }
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn value(&self, function: Function) -> Value {
+        match function {
+            Function::Count => self.count.into(),
+            Function::Sum => self.sum.into(),
+            Function::Min => self.min.into(),
+            Function::Max => self.max.into(),
+            Function::Mean => (self.sum / self.count as f64).into(),
+        }
+    }
+}
+
+/// Replaces numeric messages with one summary per `window` of time, such as
+/// `{"end":1700000000000,"count":120,"mean":21.5}`, with the end of the window in
+/// milliseconds since the Unix epoch. Windows are aligned on the epoch, so an instance
+/// aggregating by the minute summarizes whole minutes, and windows without messages are
+/// skipped. The value is the whole message, or the field picked by `selector`, and messages
+/// without one are dropped. What is left of the last window goes out when the source ends.
+pub struct Aggregate {
+    source: Receiver<Payload>,
+    functions: Vec<Function>,
+    window: Duration,
+    selector: Option<Selector>,
+    /// The end of the current window, in milliseconds since the Unix epoch.
+    end: u64,
+    totals: Totals,
+    drops: DropCounters,
+}
+
+impl Aggregate {
+    pub fn new(
+        source: Messages,
+        functions: Vec<Function>,
+        window: Duration,
+        selector: Option<Selector>,
+        drops: DropCounters,
+    ) -> Aggregate {
+        let mut aggregate = Aggregate {
+            source: forward(source),
+            functions,
+            window,
+            selector,
+            end: 0,
+            totals: Totals::default(),
+            drops,
+        };
+        aggregate.end = aggregate.window_end(now());
+        aggregate
+    }
+
+    /// The end of the window that `time` falls in.
+    fn window_end(&self, time: u64) -> u64 {
+        let window = (self.window.as_millis() as u64).max(1);
+        (time / window + 1) * window
+    }
+
+    fn value(&self, message: &Payload) -> Option<f64> {
+        let text = message.to_text();
+        let field = match &self.selector {
+            Some(selector) => selector.select(&text)?,
+            None => text.into_owned(),
+        };
+        field
+            .trim()
+            .parse()
+            .ok()
+            .filter(|value: &f64| value.is_finite())
+    }
+
+    /// Starts the window that `time` falls in, returning the summary of the one before, if it
+    /// had any values.
+    fn close(&mut self, time: u64) -> Option<Payload> {
+        let totals = std::mem::take(&mut self.totals);
+        let next = self.window_end(time);
+        let end = std::mem::replace(&mut self.end, next);
+        if totals.count == 0 {
+            return None;
+        }
+        let mut summary = format!(r#"{{"end":{end}"#);
+        for &function in &self.functions {
+            summary += &format!(r#","{}":{}"#, function.name(), totals.value(function));
+        }
+        summary.push('}');
+        Some(summary.into())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl Iterator for Aggregate {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        loop {
+            let time = now();
+            if time >= self.end {
+                if let Some(summary) = self.close(time) {
+                    return Some(summary);
+                }
+                continue;
+            }
+            match self
+                .source
+                .recv_timeout(Duration::from_millis(self.end - time))
+            {
+                Ok(message) => match self.value(&message) {
+                    Some(value) => self.totals.add(value),
+                    None => self.drops.count(Reason::Invalid),
+                },
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return self.close(time),
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use sha2::{Digest, Sha256};
+
+/// A checksum carried at the end of each message, after a tab, in lowercase hex, so that
+/// what comes over a lossy link such as UDP can be checked on the other end.
+#[derive(Clone, Copy)]
+pub enum Checksum {
+    Crc32,
+    Sha256,
+}
+
+impl Checksum {
+    pub fn parse(name: &str, value: &str) -> Result<Checksum, String> {
+        match value {
+            "crc32" => Ok(Checksum::Crc32),
+            "sha256" => Ok(Checksum::Sha256),
+            _ => Err(format!("Expected crc32 or sha256 for {name}, got {value}.")),
+        }
+    }
+
+    fn digest(self, bytes: &[u8]) -> String {
+        match self {
+            Checksum::Crc32 => format!("{:08x}", crc32fast::hash(bytes)),
+            Checksum::Sha256 => Sha256::digest(bytes)
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        }
+    }
+
+    /// Adds the checksum of the message to its end.
+    pub fn append(self, message: Payload) -> Payload {
+        let digest = self.digest(message.as_bytes());
+        match message {
+            Payload::Text(text) => format!("{text}\t{digest}").into(),
+            Payload::Binary(mut bytes) => {
+                bytes.push(b'\t');
+                bytes.extend_from_slice(digest.as_bytes());
+                Payload::Binary(bytes)
+            }
+        }
+    }
+
+    /// Takes the checksum off the end of the message, dropping the message if it has none or
+    /// it doesn't match.
+    pub fn verify(self, message: Payload, drops: &DropCounters) -> Option<Payload> {
+        let bytes = message.as_bytes();
+        let verified = bytes
+            .iter()
+            .rposition(|&byte| byte == b'\t')
+            .filter(|&tab| self.digest(&bytes[..tab]).as_bytes() == &bytes[tab + 1..]);
+        let Some(tab) = verified else {
+            drops.count(Reason::Corrupt);
+            return None;
+        };
+        Some(match message {
+            Payload::Text(mut text) => {
+                text.truncate(tab);
+                text.into()
+            }
+            Payload::Binary(mut bytes) => {
+                bytes.truncate(tab);
+                Payload::from_bytes(bytes)
+            }
+        })
+    }
+}
@@ -0,0 +1,51 @@
+use crate::payload::Payload;
+use crate::receiver::Messages;
+use crate::threads;
+use rand::Rng;
+use std::{
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Holds each message back until `delay`, plus a random amount of up to `jitter`, has passed
+/// since it arrived, to simulate a slow or uneven network. A message is never released
+/// before the one that arrived ahead of it, so the order is kept even with jitter.
+pub struct Delay {
+    arrivals: Receiver<(Instant, Payload)>,
+    delay: Duration,
+    jitter: Duration,
+    last_due: Instant,
+}
+
+impl Delay {
+    pub fn new(source: Messages, delay: Duration, jitter: Duration) -> Delay {
+        let (tx, arrivals) = mpsc::channel();
+        threads::spawn("delay", move || {
+            for message in source {
+                if tx.send((Instant::now(), message)).is_err() {
+                    break;
+                }
+            }
+        });
+        Delay {
+            arrivals,
+            delay,
+            jitter,
+            last_due: Instant::now(),
+        }
+    }
+}
+
+impl Iterator for Delay {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        let (arrived, message) = self.arrivals.recv().ok()?;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=self.jitter);
+        let due = (arrived + self.delay + jitter).max(self.last_due);
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        self.last_due = due;
+        Some(message)
+    }
+}
@@ -0,0 +1,78 @@
+use crate::payload::Payload;
+use serde_json::json;
+use std::process;
+
+/// How messages are tagged with where they passed through: behind a `<host> <pid> <seq> `
+/// prefix, or in a `{"host":...,"pid":...,"seq":...,"data":...}` JSON envelope.
+#[derive(Clone, Copy)]
+pub enum Enrichment {
+    Prefix,
+    Json,
+}
+
+impl Enrichment {
+    pub fn parse(value: &str) -> Result<Enrichment, String> {
+        match value {
+            "prefix" => Ok(Enrichment::Prefix),
+            "json" => Ok(Enrichment::Json),
+            _ => Err(format!(
+                "Expected prefix or json for --enrich, got {value}."
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    let result = unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..end]).into_owned()
+}
+
+#[cfg(windows)]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Tags each message with the host name, the process ID and the message's number, counting
+/// from 1, so that a collector fed by several netpipe instances can tell them apart.
+pub struct Enrich {
+    enrichment: Enrichment,
+    host: String,
+    pid: u32,
+    seq: u64,
+}
+
+impl Enrich {
+    pub fn new(enrichment: Enrichment) -> Enrich {
+        Enrich {
+            enrichment,
+            host: hostname(),
+            pid: process::id(),
+            seq: 0,
+        }
+    }
+
+    pub fn apply(&mut self, message: Payload) -> Payload {
+        self.seq += 1;
+        match self.enrichment {
+            Enrichment::Prefix => {
+                let mut tagged = format!("{} {} {} ", self.host, self.pid, self.seq).into_bytes();
+                tagged.extend_from_slice(message.as_bytes());
+                Payload::from_bytes(tagged)
+            }
+            Enrichment::Json => json!({
+                "host": self.host,
+                "pid": self.pid,
+                "seq": self.seq,
+                "data": message.to_text(),
+            })
+            .to_string()
+            .into(),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use serde_json::Value;
+
+/// What the messages of the source are: `lines` of text, taken as they come, `json` values,
+/// one per message as in JSON Lines, or `json-array`s whose elements are messages each.
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Lines,
+    Json,
+    JsonArray,
+}
+
+impl InputFormat {
+    pub fn parse(value: &str) -> Result<InputFormat, String> {
+        match value {
+            "lines" => Ok(InputFormat::Lines),
+            "json" => Ok(InputFormat::Json),
+            "json-array" => Ok(InputFormat::JsonArray),
+            _ => Err(format!(
+                "Expected lines, json or json-array for --input-format, got {value}."
+            )),
+        }
+    }
+}
+
+/// What the destinations get: `lines` of plain text, with JSON strings unquoted, or `json`
+/// values, with messages that aren't JSON turned into strings.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Lines,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<OutputFormat, String> {
+        match value {
+            "lines" => Ok(OutputFormat::Lines),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "Expected lines or json for --output-format, got {value}."
+            )),
+        }
+    }
+}
+
+/// Reads a message in `format` into the messages it holds, in compact JSON for the JSON
+/// formats. Messages that aren't JSON are dropped, and binary messages are passed on whole.
+pub fn decode(format: InputFormat, message: Payload, drops: &DropCounters) -> Vec<Payload> {
+    let Payload::Text(text) = message else {
+        return vec![message];
+    };
+    if format == InputFormat::Lines {
+        return vec![Payload::Text(text)];
+    }
+    match serde_json::from_str(&text) {
+        Ok(Value::Array(elements)) if format == InputFormat::JsonArray => elements
+            .into_iter()
+            .map(|element| element.to_string().into())
+            .collect(),
+        Ok(value) => vec![value.to_string().into()],
+        Err(_) => {
+            drops.count(Reason::Invalid);
+            vec![]
+        }
+    }
+}
+
+/// Writes a message in `format`. Binary messages are passed on whole.
+pub fn encode(format: OutputFormat, message: Payload) -> Payload {
+    let Payload::Text(text) = message else {
+        return message;
+    };
+    match (format, serde_json::from_str(&text)) {
+        (OutputFormat::Lines, Ok(Value::String(plain))) => plain.into(),
+        (OutputFormat::Json, Err(_)) => Value::String(text).to_string().into(),
+        _ => Payload::Text(text),
+    }
+}
@@ -0,0 +1,74 @@
+use crate::payload::Payload;
+use crate::selector::Selector;
+
+/// How far a value has to move from the last forwarded one to be forwarded again.
+#[derive(Clone, Copy)]
+pub enum Threshold {
+    Absolute(f64),
+    /// A percentage of the last forwarded value.
+    Percent(f64),
+}
+
+impl Threshold {
+    /// Parses `<number>` or `<number>%`.
+    pub fn parse(value: &str) -> Result<Threshold, String> {
+        let (number, percent) = match value.strip_suffix('%') {
+            Some(number) => (number, true),
+            None => (value, false),
+        };
+        match number.parse::<f64>() {
+            Ok(number) if number >= 0.0 && percent => Ok(Threshold::Percent(number)),
+            Ok(number) if number >= 0.0 => Ok(Threshold::Absolute(number)),
+            _ => Err(format!("Invalid threshold for --min-change: {value}.")),
+        }
+    }
+}
+
+/// Forwards a numeric message only when its value differs from the last forwarded one by more
+/// than the threshold, the first one always. The value is the whole message, or the field
+/// picked by `selector`, and messages without one are dropped.
+pub struct Hysteresis {
+    threshold: Threshold,
+    selector: Option<Selector>,
+    last: Option<f64>,
+}
+
+impl Hysteresis {
+    pub fn new(threshold: Threshold, selector: Option<Selector>) -> Hysteresis {
+        Hysteresis {
+            threshold,
+            selector,
+            last: None,
+        }
+    }
+
+    fn value(&self, message: &Payload) -> Option<f64> {
+        let text = message.to_text();
+        let field = match &self.selector {
+            Some(selector) => selector.select(&text)?,
+            None => text.into_owned(),
+        };
+        field
+            .trim()
+            .parse()
+            .ok()
+            .filter(|value: &f64| value.is_finite())
+    }
+
+    pub fn admits(&mut self, message: &Payload) -> bool {
+        let Some(value) = self.value(message) else {
+            return false;
+        };
+        let changed = match (self.last, self.threshold) {
+            (None, _) => true,
+            (Some(last), Threshold::Absolute(threshold)) => (value - last).abs() > threshold,
+            (Some(last), Threshold::Percent(percent)) => {
+                (value - last).abs() > last.abs() * percent / 100.0
+            }
+        };
+        if changed {
+            self.last = Some(value);
+        }
+        changed
+    }
+}
@@ -0,0 +1,74 @@
+use crate::payload::Payload;
+use crate::receiver::{forward, Messages};
+use regex::Regex;
+use std::{
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+/// Joins lines into multi-line records, such as stack traces, each starting with a line that
+/// matches `start` and taking the lines that follow, joined with newlines, until the next one
+/// that does. A record goes out once the next one starts, once no line has come for
+/// `timeout`, or when the source ends. Binary messages are passed on whole, after the record
+/// under way.
+pub struct Multiline {
+    source: Receiver<Payload>,
+    start: Regex,
+    timeout: Duration,
+    record: Option<String>,
+    /// A binary message to pass on after the record it ended.
+    binary: Option<Payload>,
+}
+
+impl Multiline {
+    pub fn new(source: Messages, start: Regex, timeout: Duration) -> Multiline {
+        Multiline {
+            source: forward(source),
+            start,
+            timeout,
+            record: None,
+            binary: None,
+        }
+    }
+}
+
+impl Iterator for Multiline {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        if let Some(binary) = self.binary.take() {
+            return Some(binary);
+        }
+        loop {
+            let line = match self.record {
+                None => self
+                    .source
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+                Some(_) => self.source.recv_timeout(self.timeout),
+            };
+            match line {
+                Ok(Payload::Text(line)) => match &mut self.record {
+                    Some(record) if !self.start.is_match(&line) => {
+                        record.push('\n');
+                        record.push_str(&line);
+                    }
+                    record => {
+                        if let Some(ended) = record.replace(line) {
+                            return Some(ended.into());
+                        }
+                    }
+                },
+                Ok(binary) => match self.record.take() {
+                    Some(record) => {
+                        self.binary = Some(binary);
+                        return Some(record.into());
+                    }
+                    None => return Some(binary),
+                },
+                // Timed out or ended, with the record under way if there is one.
+                Err(_) => return self.record.take().map(Payload::from),
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::payload::Payload;
+use crate::stats::DropCounters;
+#[cfg(feature = "protobuf")]
+use crate::stats::Reason;
+#[cfg(feature = "protobuf")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+#[cfg(feature = "protobuf")]
+use prost::Message;
+#[cfg(feature = "protobuf")]
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind, MessageDescriptor, Value,
+};
+
+/// A protobuf message type, from a descriptor set such as `protoc --descriptor_set_out` or
+/// `buf build` writes, that messages are decoded as.
+#[derive(Clone)]
+pub struct Protobuf {
+    #[cfg(feature = "protobuf")]
+    message: MessageDescriptor,
+    /// The fields leading to the one forwarded, the last of which is a single value and the
+    /// others messages. The whole message is forwarded if empty.
+    #[cfg(feature = "protobuf")]
+    field: Vec<FieldDescriptor>,
+}
+
+impl Protobuf {
+    /// Loads the message type named `message` from the descriptor set in `path`, and looks up
+    /// the field picked by a dotted path such as `reading.celsius`.
+    #[cfg(feature = "protobuf")]
+    pub fn load(path: &str, message: &str, field: Option<&str>) -> Result<Protobuf, String> {
+        let descriptors =
+            std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}."))?;
+        let pool = DescriptorPool::decode(descriptors.as_slice())
+            .map_err(|e| format!("Invalid descriptor set {path}: {e}."))?;
+        let message = pool
+            .get_message_by_name(message)
+            .ok_or_else(|| format!("No message {message} in {path}."))?;
+        let mut fields: Vec<FieldDescriptor> = vec![];
+        let mut parent = message.clone();
+        for name in field.into_iter().flat_map(|field| field.split('.')) {
+            if let Some(previous) = fields.last() {
+                parent = match previous.kind() {
+                    Kind::Message(child) => child,
+                    _ => return Err(format!("{} isn't a message.", previous.full_name())),
+                };
+            }
+            fields.push(field_of(&parent, name)?);
+        }
+        Ok(Protobuf {
+            message,
+            field: fields,
+        })
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    pub fn load(_path: &str, _message: &str, _field: Option<&str>) -> Result<Protobuf, String> {
+        Err("--protobuf requires the protobuf feature.".to_string())
+    }
+
+    /// Decodes a message, replacing it with the field as text, or with the whole message in
+    /// base64 if no field was picked. Messages that don't decode are dropped.
+    #[cfg(feature = "protobuf")]
+    pub fn apply(&self, message: Payload, drops: &DropCounters) -> Option<Payload> {
+        let decoded = match DynamicMessage::decode(self.message.clone(), message.as_bytes()) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                eprintln!(
+                    "Skipped a message that isn't a valid {}: {e}.",
+                    self.message.full_name()
+                );
+                drops.count(Reason::Invalid);
+                return None;
+            }
+        };
+        let Some((last, parents)) = self.field.split_last() else {
+            return Some(STANDARD.encode(message.as_bytes()).into());
+        };
+        let mut current = decoded;
+        for field in parents {
+            current = match current.get_field(field).into_owned() {
+                Value::Message(child) => child,
+                _ => unreachable!("only message fields lead to others"),
+            };
+        }
+        Some(text(last, &current.get_field(last)).into())
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    pub fn apply(&self, _message: Payload, _drops: &DropCounters) -> Option<Payload> {
+        unreachable!("Protobuf::load fails without the protobuf feature")
+    }
+}
+
+#[cfg(feature = "protobuf")]
+fn field_of(message: &MessageDescriptor, name: &str) -> Result<FieldDescriptor, String> {
+    let field = message
+        .get_field_by_name(name)
+        .ok_or_else(|| format!("No field {name} in {}.", message.full_name()))?;
+    if field.is_list() || field.is_map() {
+        return Err(format!(
+            "{} holds several values, so it can't be forwarded.",
+            field.full_name()
+        ));
+    }
+    Ok(field)
+}
+
+/// A field's value as text: enums by the name of the value, and bytes and messages in base64.
+#[cfg(feature = "protobuf")]
+fn text(field: &FieldDescriptor, value: &Value) -> String {
+    match value {
+        Value::Bool(value) => value.to_string(),
+        Value::I32(value) => value.to_string(),
+        Value::I64(value) => value.to_string(),
+        Value::U32(value) => value.to_string(),
+        Value::U64(value) => value.to_string(),
+        Value::F32(value) => value.to_string(),
+        Value::F64(value) => value.to_string(),
+        Value::String(value) => value.clone(),
+        Value::Bytes(value) => STANDARD.encode(value),
+        Value::EnumNumber(number) => match field.kind() {
+            Kind::Enum(descriptor) => descriptor
+                .get_value(*number)
+                .map_or_else(|| number.to_string(), |value| value.name().to_string()),
+            _ => number.to_string(),
+        },
+        Value::Message(message) => STANDARD.encode(message.encode_to_vec()),
+        Value::List(_) | Value::Map(_) => unreachable!("fields with several values are refused"),
+    }
+}
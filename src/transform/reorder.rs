@@ -0,0 +1,125 @@
+use crate::payload::Payload;
+use crate::receiver::{forward, Messages};
+use crate::selector::Selector;
+use crate::stats::{DropCounters, Reason};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+/// Holds back messages that arrive ahead of their sequence number and releases them in order.
+/// A missing sequence number is given up on once `window` messages are waiting behind it or it
+/// has been missing for `timeout`; the gap is logged and the waiting messages are released.
+/// Messages without a sequence number pass straight through, and ones arriving after their
+/// turn was skipped are dropped.
+pub struct Reorder {
+    source: Receiver<Payload>,
+    sequence: Selector,
+    window: usize,
+    timeout: Duration,
+    expected: Option<u64>,
+    pending: BTreeMap<u64, Payload>,
+    gap_since: Option<Instant>,
+    ready: VecDeque<Payload>,
+    drops: DropCounters,
+}
+
+impl Reorder {
+    pub fn new(
+        source: Messages,
+        sequence: Selector,
+        window: usize,
+        timeout: Duration,
+        drops: DropCounters,
+    ) -> Reorder {
+        Reorder {
+            source: forward(source),
+            sequence,
+            window,
+            timeout,
+            expected: None,
+            pending: BTreeMap::new(),
+            gap_since: None,
+            ready: VecDeque::new(),
+            drops,
+        }
+    }
+
+    fn receive(&mut self, message: Payload) {
+        let Some(number) = self
+            .sequence
+            .select(&message.to_text())
+            .and_then(|n| n.parse::<u64>().ok())
+        else {
+            self.ready.push_back(message);
+            return;
+        };
+        let expected = *self.expected.get_or_insert(number);
+        if number < expected {
+            eprintln!("Dropped message {number} arriving after its gap was skipped.");
+            self.drops.count(Reason::Late);
+            return;
+        }
+        self.pending.insert(number, message);
+        self.release();
+        if self.pending.len() > self.window {
+            self.skip_gap();
+        }
+    }
+
+    /// Moves the in-sequence messages at the front of `pending` to `ready`.
+    fn release(&mut self) {
+        let mut expected = self.expected.unwrap();
+        while let Some(message) = self.pending.remove(&expected) {
+            self.ready.push_back(message);
+            expected += 1;
+        }
+        self.expected = Some(expected);
+        self.gap_since = match self.pending.is_empty() {
+            true => None,
+            false => self.gap_since.or_else(|| Some(Instant::now())),
+        };
+    }
+
+    fn skip_gap(&mut self) {
+        if let Some(&next) = self.pending.keys().next() {
+            match self.expected.unwrap() {
+                missing if missing == next - 1 => eprintln!("Missing message {missing}, skipped."),
+                first => eprintln!("Missing messages {first} to {}, skipped.", next - 1),
+            }
+            self.expected = Some(next);
+            self.gap_since = None;
+            self.release();
+        }
+    }
+}
+
+impl Iterator for Reorder {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        loop {
+            if let Some(message) = self.ready.pop_front() {
+                return Some(message);
+            }
+            let received = match self.gap_since {
+                Some(since) => {
+                    let deadline = since + self.timeout;
+                    self.source
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => self
+                    .source
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(message) => self.receive(message),
+                Err(RecvTimeoutError::Timeout) => self.skip_gap(),
+                Err(RecvTimeoutError::Disconnected) if self.pending.is_empty() => return None,
+                Err(RecvTimeoutError::Disconnected) => self.skip_gap(),
+            }
+        }
+    }
+}
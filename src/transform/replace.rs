@@ -0,0 +1,121 @@
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use regex::Regex;
+use std::borrow::Cow;
+
+/// A step of `--filter` and `--replace`, which are taken in the order given.
+#[derive(Clone)]
+pub enum Rule {
+    /// Drops the messages that don't match, binary ones matched as text as with `?filter=`.
+    Filter(Regex),
+    Replace(Replace),
+}
+
+impl Rule {
+    pub fn filter(value: &str) -> Result<Rule, String> {
+        Regex::new(value)
+            .map(Rule::Filter)
+            .map_err(|e| format!("Invalid pattern for --filter: {e}"))
+    }
+}
+
+/// A regex substitution, `<pattern>=><replacement>` or `<pattern>=<replacement>`, where the
+/// replacement may refer to the pattern's capture groups as `$1` or `${name}`. The two are
+/// split at the first `=>` that isn't escaped, or else at the first `=` that isn't, so that a
+/// pattern matches a literal one as `\=`, which the regex takes as is.
+#[derive(Clone)]
+pub struct Replace {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl Replace {
+    pub fn parse(value: &str) -> Result<Replace, String> {
+        let unescaped = |separator: &str| {
+            let split = value.match_indices(separator).map(|(i, _)| i).find(|&i| {
+                let backslashes = value[..i].bytes().rev().take_while(|&b| b == b'\\').count();
+                backslashes % 2 == 0
+            });
+            split.map(|i| (&value[..i], &value[i + separator.len()..]))
+        };
+        let Some((pattern, replacement)) = unescaped("=>").or_else(|| unescaped("=")) else {
+            return Err(format!(
+                "Expected <pattern>=><replacement> or <pattern>=<replacement> for --replace, got {value}."
+            ));
+        };
+        let pattern =
+            Regex::new(pattern).map_err(|e| format!("Invalid pattern for --replace: {e}"))?;
+        Ok(Replace {
+            pattern,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+/// Applies the rules in order to a message, substituting every match in a text message and
+/// dropping a message at the first filter it doesn't match. Binary messages are passed on
+/// whole by substitutions.
+pub fn apply(rules: &[Rule], mut message: Payload, drops: &DropCounters) -> Option<Payload> {
+    for rule in rules {
+        match (rule, &mut message) {
+            (Rule::Filter(pattern), message) if !pattern.is_match(&message.to_text()) => {
+                drops.count(Reason::Filtered);
+                return None;
+            }
+            (Rule::Replace(rule), Payload::Text(text)) => {
+                if let Cow::Owned(replaced) = rule.pattern.replace_all(text, &rule.replacement) {
+                    *text = replaced;
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replace(rule: &str, message: &str) -> String {
+        let rules = [Rule::Replace(Replace::parse(rule).unwrap())];
+        let message = Payload::Text(message.to_string());
+        apply(&rules, message, &DropCounters::default())
+            .unwrap()
+            .to_text()
+            .into_owned()
+    }
+
+    #[test]
+    fn the_replacement_follows_the_first_equals_sign() {
+        assert_eq!(replace("(\\w+)@example=$1@***", "ann@example"), "ann@***");
+        assert_eq!(replace("secret=token=***", "a secret"), "a token=***");
+    }
+
+    #[test]
+    fn an_arrow_separates_the_replacement_first() {
+        assert_eq!(replace("a=>b", "a"), "b");
+        assert_eq!(replace("token=\\w+=>token=***", "token=abc"), "token=***");
+        assert_eq!(replace("(\\d+)=>=$1=", "42"), "=42=");
+    }
+
+    #[test]
+    fn an_escaped_equals_sign_is_part_of_the_pattern() {
+        let message = "user=ann token=abc123";
+        assert_eq!(
+            replace("token\\=\\w+=token=***", message),
+            "user=ann token=***"
+        );
+        assert_eq!(replace("\\\\=x", "a\\=b"), "ax=b");
+    }
+
+    #[test]
+    fn a_replacement_is_required() {
+        assert_eq!(
+            Replace::parse("token\\=abc").err().as_deref(),
+            Some(
+                "Expected <pattern>=><replacement> or <pattern>=<replacement> for --replace, got token\\=abc."
+            )
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use serde_json::json;
+#[cfg(feature = "json-schema")]
+use serde_json::Value;
+use std::sync::mpsc::Sender;
+#[cfg(feature = "json-schema")]
+use std::sync::Arc;
+
+/// What becomes of a message that fails validation: dropped, forwarded as a
+/// `{"errors":[...],"data":...}` annotation instead, or routed to a destination of its own.
+#[derive(Clone)]
+pub enum Invalid {
+    Drop,
+    Annotate,
+    Route(String),
+}
+
+impl Invalid {
+    /// Parses `drop`, `annotate` or `route:<destination>`.
+    pub fn parse(value: &str) -> Result<Invalid, String> {
+        match value {
+            "drop" => Ok(Invalid::Drop),
+            "annotate" => Ok(Invalid::Annotate),
+            _ => match value.strip_prefix("route:") {
+                Some(destination) if !destination.is_empty() => {
+                    Ok(Invalid::Route(destination.to_string()))
+                }
+                _ => Err(format!("Invalid policy for --on-invalid: {value}.")),
+            },
+        }
+    }
+}
+
+/// A JSON Schema that messages are validated against.
+#[derive(Clone)]
+pub struct Schema {
+    #[cfg(feature = "json-schema")]
+    validator: Arc<jsonschema::Validator>,
+}
+
+impl Schema {
+    #[cfg(feature = "json-schema")]
+    pub fn load(path: &str) -> Result<Schema, String> {
+        let schema =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}."))?;
+        let schema: Value =
+            serde_json::from_str(&schema).map_err(|e| format!("Invalid JSON in {path}: {e}."))?;
+        let validator = jsonschema::validator_for(&schema)
+            .map_err(|e| format!("Invalid schema {path}: {e}."))?;
+        Ok(Schema {
+            validator: Arc::new(validator),
+        })
+    }
+
+    #[cfg(not(feature = "json-schema"))]
+    pub fn load(_path: &str) -> Result<Schema, String> {
+        Err("--schema requires the json-schema feature.".to_string())
+    }
+
+    /// The reasons a message fails validation, none if it passes. A message that isn't JSON
+    /// fails.
+    #[cfg(feature = "json-schema")]
+    fn errors(&self, message: &Payload) -> Vec<String> {
+        match serde_json::from_slice::<Value>(message.as_bytes()) {
+            Ok(value) => self
+                .validator
+                .iter_errors(&value)
+                .map(|e| format!("{}: {e}", e.instance_path()))
+                .collect(),
+            Err(e) => vec![format!("not JSON: {e}")],
+        }
+    }
+
+    #[cfg(not(feature = "json-schema"))]
+    fn errors(&self, _message: &Payload) -> Vec<String> {
+        unreachable!("Schema::load fails without the json-schema feature")
+    }
+}
+
+/// Validates each message, passing on valid ones as they are and invalid ones according to
+/// the policy. Routed messages are handed to `rejected`, and gone if it is `None`.
+pub struct Validate {
+    schema: Schema,
+    invalid: Invalid,
+    rejected: Option<Sender<Payload>>,
+    drops: DropCounters,
+}
+
+impl Validate {
+    pub fn new(
+        schema: Schema,
+        invalid: Invalid,
+        rejected: Option<Sender<Payload>>,
+        drops: DropCounters,
+    ) -> Validate {
+        Validate {
+            schema,
+            invalid,
+            rejected,
+            drops,
+        }
+    }
+
+    pub fn apply(&self, message: Payload) -> Option<Payload> {
+        let errors = self.schema.errors(&message);
+        if errors.is_empty() {
+            return Some(message);
+        }
+        match self.invalid {
+            Invalid::Drop => {
+                self.drops.count(Reason::Invalid);
+                None
+            }
+            Invalid::Annotate => {
+                let annotation = json!({ "errors": errors, "data": message.to_text() });
+                Some(annotation.to_string().into())
+            }
+            Invalid::Route(_) => {
+                if let Some(rejected) = &self.rejected {
+                    let _ = rejected.send(message);
+                }
+                None
+            }
+        }
+    }
+}
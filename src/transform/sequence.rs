@@ -0,0 +1,76 @@
+use crate::payload::Payload;
+
+/// Splits the sequence number that [`Sequence`] put before a tab off the start of a message.
+fn split(bytes: &[u8]) -> Option<(u64, usize)> {
+    let tab = bytes.iter().position(|&byte| byte == b'\t')?;
+    let number = std::str::from_utf8(&bytes[..tab]).ok()?;
+    // Only plain digits, so that a message that merely starts with a number isn't taken for one.
+    if number.is_empty() || !number.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((number.parse().ok()?, tab + 1))
+}
+
+/// Puts a sequence number at the start of each message, before a tab, counting up from 0 for
+/// each run of netpipe, so that a netpipe with `--detect-gaps` on the other end of a lossy
+/// link such as UDP can tell which messages went missing.
+#[derive(Default)]
+pub struct Sequence {
+    next: u64,
+}
+
+impl Sequence {
+    pub fn apply(&mut self, message: Payload) -> Payload {
+        let number = self.next;
+        self.next += 1;
+        match message {
+            Payload::Text(text) => format!("{number}\t{text}").into(),
+            Payload::Binary(bytes) => {
+                let mut numbered = format!("{number}\t").into_bytes();
+                numbered.extend_from_slice(&bytes);
+                Payload::Binary(numbered)
+            }
+        }
+    }
+}
+
+/// Takes the sequence number of [`Sequence`] off the start of each message and logs the
+/// messages missing from the sequence. A sequence starting over from 0 is taken for its sender
+/// having restarted, and a message behind the sequence for one that came out of order.
+/// Messages without a sequence number are passed on as they are.
+#[derive(Default)]
+pub struct Gaps {
+    expected: Option<u64>,
+}
+
+impl Gaps {
+    pub fn apply(&mut self, message: Payload) -> Payload {
+        let Some((number, start)) = split(message.as_bytes()) else {
+            return message;
+        };
+        match self.expected {
+            Some(expected) if number > expected => {
+                let missed = number - expected;
+                eprintln!("Missed {missed} messages before message {number}.");
+            }
+            Some(expected) if number < expected => match number {
+                0 => eprintln!("Sequence started over, after message {}.", expected - 1),
+                _ => eprintln!("Message {number} arrived out of order."),
+            },
+            _ => {}
+        }
+        if number == 0 || self.expected.is_none_or(|expected| number >= expected) {
+            self.expected = Some(number + 1);
+        }
+        match message {
+            Payload::Text(mut text) => {
+                text.drain(..start);
+                text.into()
+            }
+            Payload::Binary(mut bytes) => {
+                bytes.drain(..start);
+                Payload::from_bytes(bytes)
+            }
+        }
+    }
+}
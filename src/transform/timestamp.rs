@@ -0,0 +1,98 @@
+use crate::payload::Payload;
+use crate::selector::Selector;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, Utc};
+use serde_json::Value;
+
+/// How a timestamp is written: `rfc3339`, `epoch` for seconds or `epoch-ms` for milliseconds
+/// since the Unix epoch, or anything else as a strftime format.
+#[derive(Clone)]
+pub enum TimeFormat {
+    Rfc3339,
+    Epoch,
+    EpochMillis,
+    Custom(String),
+}
+
+impl TimeFormat {
+    pub fn parse(value: &str) -> Result<TimeFormat, String> {
+        match value {
+            "rfc3339" => Ok(TimeFormat::Rfc3339),
+            "epoch" => Ok(TimeFormat::Epoch),
+            "epoch-ms" => Ok(TimeFormat::EpochMillis),
+            _ if StrftimeItems::new(value).any(|item| item == Item::Error) => {
+                Err(format!("Invalid time format: {value}."))
+            }
+            _ => Ok(TimeFormat::Custom(value.to_string())),
+        }
+    }
+
+    /// Reads a timestamp, taking one without a time zone to be in UTC.
+    fn read(&self, text: &str) -> Option<DateTime<Utc>> {
+        let text = text.trim();
+        match self {
+            TimeFormat::Rfc3339 => DateTime::parse_from_rfc3339(text).ok().map(|t| t.to_utc()),
+            TimeFormat::Epoch => DateTime::from_timestamp(text.parse().ok()?, 0),
+            TimeFormat::EpochMillis => DateTime::from_timestamp_millis(text.parse().ok()?),
+            TimeFormat::Custom(format) => match DateTime::parse_from_str(text, format) {
+                Ok(time) => Some(time.to_utc()),
+                Err(_) => NaiveDateTime::parse_from_str(text, format)
+                    .ok()
+                    .map(|time| time.and_utc()),
+            },
+        }
+    }
+
+    /// Writes a timestamp in UTC, or in the local time zone if `local`. Epoch timestamps are
+    /// written as numbers.
+    fn write(&self, time: DateTime<Utc>, local: bool) -> Value {
+        match self {
+            TimeFormat::Epoch => time.timestamp().into(),
+            TimeFormat::EpochMillis => time.timestamp_millis().into(),
+            TimeFormat::Rfc3339 if local => time
+                .with_timezone(&Local)
+                .to_rfc3339_opts(SecondsFormat::AutoSi, false)
+                .into(),
+            TimeFormat::Rfc3339 => time.to_rfc3339_opts(SecondsFormat::AutoSi, true).into(),
+            TimeFormat::Custom(format) if local => {
+                time.with_timezone(&Local).format(format).to_string().into()
+            }
+            TimeFormat::Custom(format) => time.format(format).to_string().into(),
+        }
+    }
+}
+
+/// Rewrites the timestamp in the field picked by `selector` from one format into another.
+/// Messages without the field, or whose field doesn't hold a timestamp in the expected format,
+/// pass through unchanged.
+pub struct Retime {
+    selector: Selector,
+    from: TimeFormat,
+    to: TimeFormat,
+    local: bool,
+}
+
+impl Retime {
+    pub fn new(selector: Selector, from: TimeFormat, to: TimeFormat, local: bool) -> Retime {
+        Retime {
+            selector,
+            from,
+            to,
+            local,
+        }
+    }
+
+    pub fn apply(&self, message: Payload) -> Payload {
+        let Payload::Text(text) = &message else {
+            return message;
+        };
+        let replaced = self.selector.replace(text, |field| {
+            let time = self.from.read(field)?;
+            Some(self.to.write(time, self.local))
+        });
+        match replaced {
+            Some(replaced) => replaced.into(),
+            None => message,
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::payload::Payload;
+use crate::receiver::Messages;
+use crate::stats::{DropCounters, Reason};
+use chrono::{Datelike, Local, NaiveTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use std::{thread, time::Duration};
+
+/// A time of day during which messages are forwarded, on some days of the week:
+/// `[<days>] <from>-<to>`, such as `Mon-Fri 09:00-17:30`, `Sat,Sun 10:00-14:00` or
+/// `22:00-06:00`, every day without days. A window that ends before it starts runs past
+/// midnight, and its days are those it starts on.
+#[derive(Clone)]
+pub struct Window {
+    /// Indexed by the number of days from Monday.
+    days: [bool; 7],
+    from: NaiveTime,
+    to: NaiveTime,
+}
+
+impl Window {
+    pub fn parse(value: &str) -> Result<Window, String> {
+        let invalid = || format!("Expected [<days>] <HH:MM>-<HH:MM> for --window, got {value}.");
+        let (days, times) = match value.trim().rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim()).ok_or_else(invalid)?, times),
+            None => ([true; 7], value.trim()),
+        };
+        let (from, to) = times.split_once('-').ok_or_else(invalid)?;
+        let time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").map_err(|_| invalid());
+        let (from, to) = (time(from)?, time(to)?);
+        if from == to {
+            return Err(format!("--window {value} is empty."));
+        }
+        Ok(Window { days, from, to })
+    }
+
+    fn contains(&self, weekday: Weekday, time: NaiveTime) -> bool {
+        let on = |day: Weekday| self.days[day.num_days_from_monday() as usize];
+        if self.from < self.to {
+            on(weekday) && self.from <= time && time < self.to
+        } else {
+            (on(weekday) && self.from <= time) || (on(weekday.pred()) && time < self.to)
+        }
+    }
+}
+
+/// Parses days such as `Mon-Fri`, `Sat,Sun` or `Mon,Wed-Fri`, where a range may wrap around
+/// the end of the week.
+fn parse_days(value: &str) -> Option<[bool; 7]> {
+    let mut days = [false; 7];
+    for part in value.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let (mut day, last): (Weekday, Weekday) =
+            (first.trim().parse().ok()?, last.trim().parse().ok()?);
+        loop {
+            days[day.num_days_from_monday() as usize] = true;
+            if day == last {
+                break;
+            }
+            day = day.succ();
+        }
+    }
+    Some(days)
+}
+
+/// Forwards messages only while the current time, in `zone` or else local time, is in one of
+/// the windows. Messages outside them are dropped or, with `hold`, kept waiting until a
+/// window opens, along with those arriving behind them.
+pub struct Schedule {
+    messages: Messages,
+    windows: Vec<Window>,
+    zone: Option<Tz>,
+    hold: bool,
+    drops: DropCounters,
+}
+
+impl Schedule {
+    pub fn new(
+        messages: Messages,
+        windows: Vec<Window>,
+        zone: Option<Tz>,
+        hold: bool,
+        drops: DropCounters,
+    ) -> Schedule {
+        Schedule {
+            messages,
+            windows,
+            zone,
+            hold,
+            drops,
+        }
+    }
+
+    fn open(&self) -> bool {
+        let (weekday, time) = match self.zone {
+            Some(zone) => {
+                let now = Utc::now().with_timezone(&zone);
+                (now.weekday(), now.time())
+            }
+            None => {
+                let now = Local::now();
+                (now.weekday(), now.time())
+            }
+        };
+        self.windows
+            .iter()
+            .any(|window| window.contains(weekday, time))
+    }
+}
+
+impl Iterator for Schedule {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        loop {
+            let message = self.messages.next()?;
+            if self.hold {
+                // Windows open on the minute, in any time zone.
+                while !self.open() {
+                    let second = Utc::now().second();
+                    thread::sleep(Duration::from_secs(60 - u64::from(second.min(59))));
+                }
+                return Some(message);
+            }
+            if self.open() {
+                return Some(message);
+            }
+            self.drops.count(Reason::OutsideWindow);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Each broker runs on a thread of its own and takes its messages from a queue, so that a
+//! destination that is slow to write to only delays itself and not the fan-out to the others.
+//! The pipeline hands every message to each worker's queue without waiting, and learns how the
+//! sends went from the outcomes the workers report back as they complete them.
+//!
+//! Each destination takes the messages in the order they arrived, and destinations may be as
+//! far apart as a queue holds. A [`QueueLimit`] bounds the queues, so that a destination that
+//! stalls doesn't make netpipe's memory grow with the backlog, either holding up the pipeline,
+//! and with it every other destination, while a queue is full, or dropping the messages that
+//! find it full.
+
+use crate::broker::Broker;
+use crate::error::{NetpipeError, Result};
+use crate::payload::Payload;
+use crate::stats::{DropCounters, Reason};
+use crate::threads;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+enum Job {
+    Send(Arc<Payload>),
+    AddDestination(String, Sender<Result<()>>),
+}
+
+/// How many messages may wait in a worker's queue, and what becomes of more.
+#[derive(Clone)]
+pub struct QueueLimit {
+    pub limit: usize,
+    /// Drop the messages that find the queue full, rather than wait for room.
+    pub drop: bool,
+    pub drops: DropCounters,
+}
+
+pub struct Worker {
+    /// Dropped, along with `thread`, once the worker is finished.
+    jobs: Option<SyncSender<Job>>,
+    queue: QueueLimit,
+    outcomes: Receiver<Result<()>>,
+    thread: Option<JoinHandle<()>>,
+    /// The error of the most recently completed send, if it failed.
+    failure: Option<NetpipeError>,
+    closed: bool,
+}
+
+impl Worker {
+    /// Moves a broker, with the destinations already added to it, onto its own thread. The
+    /// broker is shut down and dropped there once the worker is finished.
+    pub fn spawn(broker: Box<dyn Broker>, queue: &QueueLimit) -> Worker {
+        let (jobs, job_rx) = mpsc::sync_channel(queue.limit);
+        let (outcome_tx, outcomes) = mpsc::channel();
+        let drops = queue.drops.clone();
+        let thread = threads::spawn("worker", move || {
+            for job in job_rx {
+                match job {
+                    Job::Send(message) => {
+                        let outcome = broker.send(&message);
+                        if outcome
+                            .as_ref()
+                            .is_err_and(|e| !matches!(e, NetpipeError::Closed))
+                        {
+                            drops.deadletter(&message);
+                        }
+                        if outcome_tx.send(outcome).is_err() {
+                            break;
+                        }
+                    }
+                    Job::AddDestination(option, reply) => {
+                        let _ = reply.send(broker.add_destination(&option));
+                    }
+                }
+            }
+            broker.shutdown();
+        });
+        Worker {
+            jobs: Some(jobs),
+            queue: queue.clone(),
+            outcomes,
+            thread: Some(thread),
+            failure: None,
+            closed: false,
+        }
+    }
+
+    /// Adds a destination once the sends queued before it are done, waiting for the result.
+    pub fn add_destination(&self, option: &str) -> Result<()> {
+        let (reply, result) = mpsc::channel();
+        let job = Job::AddDestination(option.to_string(), reply);
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+        result.recv().unwrap_or(Err(NetpipeError::Closed))
+    }
+
+    pub fn send(&self, message: Arc<Payload>) {
+        match &self.jobs {
+            Some(jobs) if self.queue.drop => {
+                if let Err(TrySendError::Full(Job::Send(message))) =
+                    jobs.try_send(Job::Send(message))
+                {
+                    self.queue.drops.count_lost(Reason::QueueFull, &message);
+                }
+            }
+            Some(jobs) => {
+                let _ = jobs.send(Job::Send(message));
+            }
+            None => {}
+        }
+    }
+
+    /// Takes in the outcomes of the sends completed since the last call.
+    pub fn poll(&mut self) {
+        while let Ok(outcome) = self.outcomes.try_recv() {
+            self.record(outcome);
+        }
+    }
+
+    fn record(&mut self, outcome: Result<()>) {
+        match outcome {
+            Err(NetpipeError::Closed) => self.closed = true,
+            outcome => self.failure = outcome.err(),
+        }
+    }
+
+    /// Whether the most recently completed send failed.
+    pub fn failing(&self) -> bool {
+        self.failure.is_some()
+    }
+
+    pub fn take_failure(&mut self) -> Option<NetpipeError> {
+        self.failure.take()
+    }
+
+    /// Whether one of the broker's destinations asked netpipe to stop.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Waits for the queued messages to be sent and the broker to be shut down and dropped.
+    pub fn finish(&mut self) {
+        self.jobs = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.poll();
+    }
+}
+
+/// A worker fed from a channel rather than by the pipeline, for messages that leave it on the
+/// way, such as those a transform rejects. The channel's senders may outlive the pipeline, so
+/// the worker is finished when asked rather than when they are all gone.
+pub struct Feed {
+    sender: Sender<Payload>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Feed {
+    pub fn spawn(mut worker: Worker) -> Feed {
+        let (sender, messages) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = threads::spawn("feed", move || {
+            loop {
+                match messages.recv_timeout(Duration::from_millis(100)) {
+                    Ok(message) => {
+                        worker.send(Arc::new(message));
+                        worker.poll();
+                    }
+                    Err(RecvTimeoutError::Timeout) if !stopped.load(Ordering::Relaxed) => {}
+                    Err(_) => break,
+                }
+            }
+            worker.finish();
+        });
+        Feed {
+            sender,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn sender(&self) -> Sender<Payload> {
+        self.sender.clone()
+    }
+
+    /// Waits for the messages fed so far to be sent, and the worker to be finished.
+    pub fn finish(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Feed {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,76 @@
+//! The `stdout` destination as seen by whatever reads netpipe's output, running the netpipe
+//! binary built along with the tests.
+
+use std::{
+    io::{Read, Write},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+/// A netpipe process, killed once the test is done with it.
+struct Netpipe(Child);
+
+impl Netpipe {
+    fn spawn(args: &[&str]) -> Netpipe {
+        let child = Command::new(env!("CARGO_BIN_EXE_netpipe"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start netpipe");
+        Netpipe(child)
+    }
+}
+
+impl Drop for Netpipe {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn a_closed_stdout_stops_netpipe_cleanly() {
+    let mut netpipe = Netpipe::spawn(&["stdin", "stdout"]);
+    drop(netpipe.0.stdout.take());
+    let mut stdin = netpipe.0.stdin.take().unwrap();
+    // Netpipe may be gone before all of it is written.
+    let _ = stdin.write_all(&b"line\n".repeat(10_000));
+    drop(stdin);
+    let mut stderr = String::new();
+    netpipe
+        .0
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    let status = netpipe.0.wait().unwrap();
+    assert!(status.success(), "{status}: {stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+}
+
+#[test]
+fn binary_messages_reach_stdout_while_netpipe_runs() {
+    let mut netpipe = Netpipe::spawn(&["--binary", "stdin", "stdout"]);
+    // Kept open, so that netpipe keeps running and exiting doesn't flush stdout for it.
+    let mut stdin = netpipe.0.stdin.take().unwrap();
+    stdin.write_all(b"hello").unwrap();
+    stdin.flush().unwrap();
+    let mut stdout = netpipe.0.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut message = [0; 5];
+        let _ = tx.send(stdout.read_exact(&mut message).map(|()| message));
+    });
+    let message = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("nothing on stdout while netpipe runs")
+        .unwrap();
+    assert_eq!(&message, b"hello");
+    assert!(netpipe.0.try_wait().unwrap().is_none());
+    drop(stdin);
+}